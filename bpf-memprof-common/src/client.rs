@@ -2,10 +2,12 @@
 // SPDX-License-Identifier: MIT
 
 use std::{
+    fs::File,
     io::{self, Write},
-    fmt,
-    os::unix::net::UnixStream,
-    path::Path,
+    fmt, thread,
+    os::unix::{io::{FromRawFd, RawFd}, net::UnixStream},
+    path::{Path, PathBuf},
+    time::Duration,
 };
 use passfd::FdPassingExt;
 use ebpf_user::RingBufferRegistry;
@@ -19,22 +21,61 @@ use super::event::{RssStat, PercpuAlloc, PercpuFree, AddToPageCache, RemoveFromP
 
 pub struct Client {
     stream: UnixStream,
+    path: PathBuf,
+    retry: Retry,
 }
 
 pub trait ClientCallback {
     fn arrive(&mut self, client: &mut Client, data: &[u8]);
 }
 
+/// How to wait for the socket of the bpf module, it might not be created yet.
+/// The interval doubles after each failed attempt, up to `max_interval`.
+#[derive(Debug, Clone)]
+pub struct Retry {
+    pub attempts: u32,
+    pub interval: Duration,
+    pub max_interval: Duration,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Retry {
+            attempts: 20,
+            interval: Duration::from_millis(100),
+            max_interval: Duration::from_secs(2),
+        }
+    }
+}
+
+impl Retry {
+    /// Fail at once if the socket is not there
+    pub fn none() -> Self {
+        Retry {
+            attempts: 1,
+            ..Retry::default()
+        }
+    }
+}
+
 impl Client {
     pub fn connect<P, F>(path: P, cb: F) -> io::Result<RingBufferRegistry>
     where
         P: AsRef<Path>,
         F: ClientCallback + 'static,
     {
-        let stream = UnixStream::connect(path)?;
-        let fd = stream.recv_fd()?;
+        Self::connect_with_retry(path, cb, Retry::default())
+    }
+
+    pub fn connect_with_retry<P, F>(path: P, cb: F, retry: Retry) -> io::Result<RingBufferRegistry>
+    where
+        P: AsRef<Path>,
+        F: ClientCallback + 'static,
+    {
+        let path = path.as_ref().to_path_buf();
+        let (stream, fd) = Self::open(&path, &retry)?;
         let mut rb = RingBufferRegistry::default();
-        let mut client = Client { stream };
+        let mut client = Client { stream, path, retry };
         let mut cb = cb;
         rb.add_fd(fd, move |data| cb.arrive(&mut client, data))
             .map_err(|_| io::Error::last_os_error())?;
@@ -42,11 +83,64 @@ impl Client {
         Ok(rb)
     }
 
+    /// Connect to the socket and receive the ring buffer fd,
+    /// wait while the socket is missing or nobody listens it
+    fn open(path: &Path, retry: &Retry) -> io::Result<(UnixStream, RawFd)> {
+        let mut interval = retry.interval;
+        let mut attempt = 1;
+        let stream = loop {
+            match UnixStream::connect(path) {
+                Ok(stream) => break stream,
+                Err(error) if attempt < retry.attempts && Self::is_not_ready(&error) => {
+                    thread::sleep(interval);
+                    interval = (interval * 2).min(retry.max_interval);
+                    attempt += 1;
+                },
+                Err(error) => return Err(error),
+            }
+        };
+        let fd = stream.recv_fd()?;
+        Ok((stream, fd))
+    }
+
+    fn is_not_ready(error: &io::Error) -> bool {
+        matches!(
+            error.kind(),
+            io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused,
+        )
+    }
+
+    fn is_dropped(error: &io::Error) -> bool {
+        matches!(
+            error.kind(),
+            io::ErrorKind::BrokenPipe
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::NotConnected,
+        )
+    }
+
     pub fn send_command<C>(&mut self, cmd: C) -> io::Result<()>
     where
         C: fmt::Display,
     {
-        self.stream.write_fmt(format_args!("{}\n", cmd))
+        match self.stream.write_fmt(format_args!("{}\n", cmd)) {
+            // the module closed the socket, connect again and repeat the command
+            Err(error) if Self::is_dropped(&error) => {
+                self.reconnect()?;
+                self.stream.write_fmt(format_args!("{}\n", cmd))
+            },
+            r => r,
+        }
+    }
+
+    fn reconnect(&mut self) -> io::Result<()> {
+        let (stream, fd) = Self::open(&self.path, &self.retry)?;
+        // the module sends the ring buffer fd to each new client, the registry keeps
+        // the fd received at connect and cannot take another one from here, close it
+        drop(unsafe { File::from_raw_fd(fd) });
+        self.stream = stream;
+        Ok(())
     }
 }
 
@@ -295,3 +389,65 @@ impl Event {
         Ok(Event { header, pid, event, stack })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env, fs, thread,
+        fs::File,
+        io::{BufRead, BufReader, ErrorKind},
+        os::unix::{
+            io::{AsRawFd, FromRawFd},
+            net::UnixListener,
+        },
+        time::Duration,
+    };
+    use passfd::FdPassingExt;
+    use super::{Client, Retry};
+
+    #[test]
+    fn socket_appears_later() {
+        let path = env::temp_dir().join(format!("bpf-memprof-retry-{}.sock", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let server = {
+            let path = path.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(300));
+                let listener = UnixListener::bind(&path).unwrap();
+                let (stream, _) = listener.accept().unwrap();
+                // any fd will do, the test does not read the ring buffer
+                stream.send_fd(listener.as_raw_fd()).unwrap();
+                let mut line = String::new();
+                BufReader::new(stream).read_line(&mut line).unwrap();
+                line
+            })
+        };
+
+        let retry = Retry {
+            attempts: 50,
+            interval: Duration::from_millis(10),
+            max_interval: Duration::from_millis(50),
+        };
+        let (stream, fd) = Client::open(&path, &retry).unwrap();
+        drop(unsafe { File::from_raw_fd(fd) });
+        let mut client = Client {
+            stream,
+            path: path.clone(),
+            retry,
+        };
+        client.send_command("stack_depth 16").unwrap();
+        assert_eq!(server.join().unwrap(), "stack_depth 16\n");
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn no_retry() {
+        let path = env::temp_dir().join(format!("bpf-memprof-none-{}.sock", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let error = Client::open(&path, &Retry::none()).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+    }
+}
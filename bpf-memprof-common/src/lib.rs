@@ -14,6 +14,59 @@ pub use self::event::{RssStat, PercpuAlloc, PercpuFree, AddToPageCache, RemoveFr
 #[cfg(feature = "client")]
 mod client;
 #[cfg(feature = "client")]
-pub use self::client::{Client, ClientCallback, EventKind, Event, Stack};
+pub use self::client::{Client, ClientCallback, Retry, EventKind, Event, Stack};
 
 pub const STACK_MAX_DEPTH: usize = 127;
+
+/// The number of frames to capture for a stack of `depth` frames.
+/// The length is rounded up to a power of two, so the kernel reserves few distinct sizes,
+/// and is limited by `cap`, zero means `STACK_MAX_DEPTH`.
+#[inline(always)]
+pub fn quantize_stack_len(depth: usize, cap: usize) -> usize {
+    let len = if depth > 64 {
+        STACK_MAX_DEPTH
+    } else if depth > 32 {
+        64
+    } else if depth > 16 {
+        32
+    } else if depth > 8 {
+        16
+    } else if depth > 4 {
+        8
+    } else if depth > 2 {
+        4
+    } else if depth > 1 {
+        2
+    } else if depth > 0 {
+        1
+    } else {
+        0
+    };
+
+    if cap == 0 || cap > STACK_MAX_DEPTH {
+        len
+    } else if len > cap {
+        cap
+    } else {
+        len
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{quantize_stack_len, STACK_MAX_DEPTH};
+
+    #[test]
+    fn stack_len_cap() {
+        for depth in 0..=STACK_MAX_DEPTH {
+            let len = quantize_stack_len(depth, 16);
+            assert!(len <= 16, "depth: {}, len: {}", depth, len);
+            assert!(len >= depth.min(16), "depth: {}, len: {}", depth, len);
+        }
+        assert_eq!(quantize_stack_len(100, 0), STACK_MAX_DEPTH);
+        assert_eq!(quantize_stack_len(100, 1000), STACK_MAX_DEPTH);
+        assert_eq!(quantize_stack_len(5, 0), 8);
+        assert_eq!(quantize_stack_len(5, 6), 6);
+        assert_eq!(quantize_stack_len(0, 16), 0);
+    }
+}
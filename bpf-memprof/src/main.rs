@@ -18,6 +18,9 @@ pub struct App {
     pub pid: ebpf::HashMapRef<4, 4>,
     #[hashmap(size = 1)]
     pub lost_events: ebpf::HashMapRef<4, 4>,
+    // the maximal depth of captured stack, set by the user, zero or missing means no limit
+    #[hashmap(size = 1)]
+    pub stack_depth: ebpf::HashMapRef<4, 4>,
     #[array_percpu(size = 1)]
     pub stack: ebpf::ArrayPerCpuRef<0x400>,
     #[ringbuf(size = 0x8000000)]
@@ -40,7 +43,7 @@ pub struct App {
 
 #[cfg(feature = "kern")]
 use {
-    bpf_memprof_common::{Pod, STACK_MAX_DEPTH},
+    bpf_memprof_common::{Pod, quantize_stack_len},
     bpf_memprof_common::{
         KFree, KMAlloc, KMAllocNode, CacheAlloc, CacheAllocNode, CacheFree, PageAlloc, PageFree,
         PageFreeBatched, RssStat, PercpuAlloc, PercpuFree, AddToPageCache, RemoveFromPageCache,
@@ -201,27 +204,13 @@ impl App {
         } else {
             0
         };
-        
-
-        let stack_len = if stack_len > 64 {
-            STACK_MAX_DEPTH
-        } else if stack_len > 32 {
-            64
-        } else if stack_len > 16 {
-            32
-        } else if stack_len > 8 {
-            16
-        } else if stack_len > 4 {
-            8
-        } else if stack_len > 2 {
-            4
-        } else if stack_len > 1 {
-            2
-        } else if stack_len > 0 {
-            1
-        } else {
-            0
-        };
+
+        let cap = self
+            .stack_depth
+            .get(&0u32.to_ne_bytes())
+            .map(|&cap_bytes| u32::from_ne_bytes(cap_bytes) as usize)
+            .unwrap_or(0);
+        let stack_len = quantize_stack_len(stack_len, cap);
 
         let size = 0x10 + T::SIZE + 0x08 + stack_len * 8;
         let mut data = self.event_queue.reserve(size)
@@ -399,10 +388,13 @@ fn run_bpf() -> (ebpf::Skeleton<App>, i32) {
 
 #[cfg(feature = "user")]
 fn main() {
-    use std::{time::Duration, io, sync::{Arc, atomic::{Ordering, AtomicBool}}};
+    use std::{time::{Duration, Instant}, io, sync::{Arc, atomic::{Ordering, AtomicBool}}};
     use tracing::Level;
     use ebpf::RingBufferRegistry;
-    use tezedge_memprof::{Consumer, StackResolver, server};
+    use tezedge_memprof::{
+        Consumer, StackResolver, LostEventsMonitor, CsvReport, TlsConfig, Correlation, RssHistory,
+        EventsFileWriter, server,
+    };
     //use passfd::FdPassingExt;
 
     sudo::escalate_if_needed().expect("failed to obtain superuser permission");
@@ -417,7 +409,26 @@ fn main() {
     }
 
     // attack bpf module and acquire fd of event stream
-    let (skeleton, fd) = run_bpf();
+    let (mut skeleton, fd) = run_bpf();
+
+    // capture at most this number of innermost frames, the shorter stack saves the ring buffer,
+    // but the outer callers are lost
+    let stack_depth = std::env::args()
+        .skip_while(|s| s != "--stack-depth")
+        .nth(1)
+        .and_then(|s| s.parse::<u32>().ok());
+    if let Some(stack_depth) = stack_depth {
+        match skeleton.app.stack_depth.insert(0u32.to_ne_bytes(), stack_depth.to_ne_bytes()) {
+            Ok(()) => log::info!("stack depth is limited: {}", stack_depth),
+            Err(code) => {
+                log::error!(
+                    "failed to set stack depth, code {}, error {}",
+                    code,
+                    io::Error::last_os_error(),
+                );
+            },
+        }
+    }
 
     /*let stream = accept_client();
     stream
@@ -431,34 +442,117 @@ fn main() {
         aggregator.lock().unwrap().turn_on_dump();
     }
 
+    // how many resolved symbols to keep in memory
+    let symbol_cache = std::env::args()
+        .skip_while(|s| s != "--symbol-cache")
+        .nth(1)
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(StackResolver::DEFAULT_CACHE_CAPACITY);
+
+    // how long to wait for events in the ring buffer, in milliseconds
+    let poll_timeout = std::env::args()
+        .skip_while(|s| s != "--poll-timeout")
+        .nth(1)
+        .and_then(|s| s.parse().ok())
+        .map(Duration::from_millis)
+        .unwrap_or(Duration::from_secs(1));
+
     // spawn a thread monitoring process map from `/proc/<pid>/maps` and loading symbol tables
-    let resolver = StackResolver::spawn(cli.pid());
+    let resolver = StackResolver::spawn(cli.pid(), symbol_cache);
+
+    // the prefixes of the names of the allocator frames stripped from the top of the stacks,
+    // comma separated, the empty string disables the stripping
+    let excluded_frames = std::env::args()
+        .skip_while(|s| s != "--exclude-frames")
+        .nth(1);
+    if let Some(prefixes) = excluded_frames {
+        let prefixes = prefixes
+            .split(',')
+            .filter(|p| !p.is_empty())
+            .map(str::to_string)
+            .collect();
+        resolver.write().unwrap().set_excluded_frames(prefixes);
+    }
 
-    // spawn a thread-pool serving http requests, using tokio
-    let server = server::run(cli.reporter(), resolver, cli.pid());
+    // spawn a thread appending the top allocation sites to the csv file, every interval in seconds
+    let arg = |name: &str| std::env::args().skip_while(|s| s != name).nth(1);
+    if let Some(path) = arg("--csv") {
+        let interval = arg("--csv-interval")
+            .and_then(|s| s.parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(Duration::from_secs(60));
+        let top = arg("--csv-top")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(CsvReport::DEFAULT_TOP);
+        let max_size = arg("--csv-max-size")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(CsvReport::DEFAULT_MAX_SIZE);
+        log::info!("writing csv report: {}, every {:?}", path, interval);
+        CsvReport::new(path, top, max_size).spawn(
+            cli.reporter(),
+            resolver.clone(),
+            interval,
+            running.clone(),
+        );
+    }
+
+    // spawn a thread sampling the memory of the node every second, `/v1/correlation` aligns it
+    // with the message counts fetched from the recorder at this url
+    let rss = Arc::new(RssHistory::default());
+    rss.clone().spawn(cli.pid(), Duration::from_secs(1), running.clone());
+    let correlation = Correlation::new(rss, arg("--recorder-url"), Correlation::recorder_token());
+
+    // the certificate and the key in PEM files, the server is https
+    let tls = match (arg("--tls-cert"), arg("--tls-key")) {
+        (Some(cert), Some(key)) => Some(TlsConfig { cert, key }),
+        (None, None) => None,
+        _ => panic!("--tls-cert and --tls-key go together"),
+    };
+    if let Some(tls) = &tls {
+        tls.check().unwrap_or_else(|e| panic!("{}", e));
+    }
 
+    // spawn a thread-pool serving http requests, using tokio
+    let server = server::run(
+        cli.reporter(),
+        resolver,
+        cli.pid(),
+        cli.capture(),
+        cli.filter(),
+        Arc::new(correlation),
+        server::DEFAULT_PORT,
+        tls,
+        server::api_token(),
+    );
+
+    let pid = cli.pid();
     let mut rb = RingBufferRegistry::default();
     let mut cli = cli;
+    // append the events to the file as they arrive, `tezedge-memprof --events-file` replays them
+    if let Some(path) = arg("--dump-events") {
+        let writer = std::fs::File::create(&path)
+            .and_then(|file| EventsFileWriter::new(io::BufWriter::new(file)))
+            .unwrap_or_else(|e| panic!("cannot create {}: {}", path, e));
+        log::info!("dumping events: {}", path);
+        cli.dump_events(writer);
+    }
     rb.add_fd(fd, move |data| cli.arrive(data))
         .map_err(|_| io::Error::last_os_error())
         .expect("failed to setup ring buffer");
 
-    let mut overall_cnt = 0;
-    let mut old_cnt = 0;
+    let mut lost_events = LostEventsMonitor::new(LostEventsMonitor::DEFAULT_INTERVAL, Instant::now());
     while running.load(Ordering::Relaxed) {
-        match rb.poll(Duration::from_secs(1)) {
+        match rb.poll(poll_timeout) {
             Ok(_) => {
-                overall_cnt += 1;
-                if overall_cnt & 0xffff == 0 {
-                    let cnt = skeleton.app.lost_events.get(&0u32.to_ne_bytes())
+                let lost = lost_events.check(Instant::now(), || {
+                    skeleton.app.lost_events.get(&0u32.to_ne_bytes())
                         .map(u32::from_le_bytes)
-                        .unwrap_or(0);
-                    if cnt - old_cnt != 0 {
-                        log::warn!("lost events: {}", cnt - old_cnt);
-                        old_cnt = cnt;
-                    } else {
-                        log::debug!("check: ok");
-                    }
+                        .unwrap_or(0)
+                });
+                match lost {
+                    Some(0) => log::debug!("check: ok"),
+                    Some(lost) => log::warn!("lost events: {}", lost),
+                    None => (),
                 }
             },
             Err(c) => {
@@ -471,6 +565,15 @@ fn main() {
     }
 
     aggregator.lock().unwrap().store_dump();
+    // save the history and the process map, it can be browsed offline by `tezedge-memprof --load`
+    aggregator.lock().unwrap().store_snapshot();
+    let pid = pid.load(Ordering::Relaxed);
+    if pid != 0 {
+        let path = format!("/proc/{}/maps", pid);
+        if let Err(error) = std::fs::read(&path).and_then(|m| std::fs::write("target/maps", m)) {
+            log::error!("failed to save process map: {}", error);
+        }
+    }
     log::info!("stop server");
     let _ = server;
 }
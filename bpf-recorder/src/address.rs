@@ -1,6 +1,7 @@
 // Copyright (c) SimpleStaking and Tezedge Contributors
 // SPDX-License-Identifier: MIT
 
+#[cfg(feature = "kern")]
 use ebpf_kern::helpers;
 
 pub struct Address {
@@ -9,12 +10,23 @@ pub struct Address {
 }
 
 impl Address {
+    const AF_UNIX: u16 = 1;
     const AF_INET: u16 = 2;
     const AF_INET6: u16 = 10;
 
+    // only `sun_family`, the socket is unnamed, the path or the abstract name might follow
+    const SOCKADDR_UN_MIN_LEN: u64 = 2;
+    // size_of::<sockaddr_un>(), the event carries the whole path
+    pub const SOCKADDR_UN_LEN: usize = 110;
+    // size_of::<sockaddr_in>()
+    const SOCKADDR_IN_LEN: u64 = 16;
+    // size_of::<sockaddr_in6>(), including flowinfo and scope_id
+    const SOCKADDR_IN6_LEN: u64 = 28;
+
+    #[cfg(feature = "kern")]
     #[inline(always)]
     pub fn read(addr_ptr: u64, addr_len: u64) -> Result<Option<Self>, i32> {
-        if addr_len < 4 {
+        if addr_len < Self::SOCKADDR_UN_MIN_LEN {
             return Err(-1);
         }
 
@@ -25,12 +37,36 @@ impl Address {
         if c < 0 {
             return Err(c as _);
         }
+        Self::from_header(address_header, addr_len)
+    }
+
+    /// The header is `sa_family` and `sin_port`/`sin6_port`,
+    /// they are at the same offset in `sockaddr_in` and `sockaddr_in6`,
+    /// but the length of the whole structure is different.
+    /// The unix socket has no port, the header holds the beginning of the path.
+    #[inline(always)]
+    pub fn from_header(header: [[u8; 2]; 2], addr_len: u64) -> Result<Option<Self>, i32> {
         let address = Address {
-            sa_family: u16::from_ne_bytes(address_header[0]),
-            port: u16::from_be_bytes(address_header[1]),
+            sa_family: u16::from_ne_bytes(header[0]),
+            port: u16::from_be_bytes(header[1]),
         };
-        if address.sa_family != Self::AF_INET && address.sa_family != Self::AF_INET6 {
-            return Ok(None);
+        let min_len = match address.sa_family {
+            Self::AF_UNIX => {
+                return if addr_len < Self::SOCKADDR_UN_MIN_LEN {
+                    Err(-1)
+                } else {
+                    Ok(Some(Address {
+                        sa_family: Self::AF_UNIX,
+                        port: 0,
+                    }))
+                };
+            },
+            Self::AF_INET => Self::SOCKADDR_IN_LEN,
+            Self::AF_INET6 => Self::SOCKADDR_IN6_LEN,
+            _ => return Ok(None),
+        };
+        if addr_len < min_len {
+            return Err(-1);
         }
 
         Ok(Some(address))
@@ -40,4 +76,84 @@ impl Address {
     pub fn port(&self) -> u16 {
         self.port
     }
+
+    #[inline(always)]
+    pub fn is_unix(&self) -> bool {
+        self.sa_family == Self::AF_UNIX
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Address;
+
+    fn header(b: &[u8]) -> [[u8; 2]; 2] {
+        [[b[0], b[1]], [b[2], b[3]]]
+    }
+
+    fn sockaddr_in(port: u16) -> [u8; 16] {
+        let mut b = [0; 16];
+        b[0..2].clone_from_slice(&Address::AF_INET.to_ne_bytes());
+        b[2..4].clone_from_slice(&port.to_be_bytes());
+        b[4..8].clone_from_slice(&[127, 0, 0, 1]);
+        b
+    }
+
+    fn sockaddr_in6(port: u16, scope_id: u32) -> [u8; 28] {
+        let mut b = [0; 28];
+        b[0..2].clone_from_slice(&Address::AF_INET6.to_ne_bytes());
+        b[2..4].clone_from_slice(&port.to_be_bytes());
+        b[23] = 1;
+        b[24..28].clone_from_slice(&scope_id.to_ne_bytes());
+        b
+    }
+
+    #[test]
+    fn v4() {
+        let b = sockaddr_in(9732);
+        let address = Address::from_header(header(&b), b.len() as u64)
+            .unwrap()
+            .unwrap();
+        assert_eq!(address.port(), 9732);
+    }
+
+    #[test]
+    fn v6() {
+        let b = sockaddr_in6(9732, 3);
+        let address = Address::from_header(header(&b), b.len() as u64)
+            .unwrap()
+            .unwrap();
+        assert_eq!(address.port(), 9732);
+    }
+
+    #[test]
+    fn short() {
+        let b = sockaddr_in(9732);
+        assert!(Address::from_header(header(&b), 8).is_err());
+        // the v6 header, but the length is only enough for v4
+        let b = sockaddr_in6(9732, 0);
+        assert!(Address::from_header(header(&b), 16).is_err());
+    }
+
+    #[test]
+    fn unix() {
+        // `/tmp/node.sock`
+        let mut b = [0; 16];
+        b[0..2].clone_from_slice(&Address::AF_UNIX.to_ne_bytes());
+        b[2..16].clone_from_slice(b"/tmp/node.sock");
+        let len = Address::SOCKADDR_UN_LEN as u64;
+        let address = Address::from_header(header(&b), len).unwrap().unwrap();
+        assert!(address.is_unix());
+        assert_eq!(address.port(), 0);
+        // unnamed, only the family
+        let address = Address::from_header(header(&b), 2).unwrap().unwrap();
+        assert!(address.is_unix());
+    }
+
+    #[test]
+    fn unknown_family() {
+        // AF_NETLINK
+        let b = [16, 0, 0, 0];
+        assert!(Address::from_header(header(&b), 12).unwrap().is_none());
+    }
 }
@@ -3,15 +3,70 @@
 
 use std::{
     convert::TryFrom,
-    io::{self, Write},
+    fmt,
+    io::{self, Read, Write},
     mem,
-    net::{SocketAddr, IpAddr},
+    ops::Range,
+    net::{SocketAddr, SocketAddrV6, IpAddr},
     os::unix::net::UnixStream,
     path::Path,
 };
 use bpf_ring_buffer::{RingBuffer, RingBufferSync, RingBufferData};
 use passfd::FdPassingExt;
-use super::{EventId, DataDescriptor, DataTag, Command};
+use super::{EventId, DataDescriptor, DataTag, Command, ContextStats};
+
+/// The peer of the connected socket
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerAddress {
+    Inet(SocketAddr),
+    // `sun_path` of `sockaddr_un`
+    UnixPath(String),
+    // the name in the abstract namespace, without the leading null byte
+    UnixAbstract(String),
+    // the socket has no name, for example, created by `socketpair`
+    UnixUnnamed,
+}
+
+impl PeerAddress {
+    /// The unix socket is described by the rest of `sockaddr_un` after `sun_family`,
+    /// the buffer might be padded by zeros
+    fn unix(b: &[u8]) -> Self {
+        match b.split_first() {
+            None => PeerAddress::UnixUnnamed,
+            Some((0, name)) => {
+                let len = name.iter().rposition(|c| *c != 0).map_or(0, |p| p + 1);
+                if len == 0 {
+                    PeerAddress::UnixUnnamed
+                } else {
+                    PeerAddress::UnixAbstract(String::from_utf8_lossy(&name[..len]).into_owned())
+                }
+            },
+            Some(_) => {
+                let len = b.iter().position(|c| *c == 0).unwrap_or(b.len());
+                PeerAddress::UnixPath(String::from_utf8_lossy(&b[..len]).into_owned())
+            },
+        }
+    }
+
+    pub fn inet(&self) -> Option<SocketAddr> {
+        match self {
+            PeerAddress::Inet(address) => Some(*address),
+            _ => None,
+        }
+    }
+}
+
+/// The abstract name is prefixed by `@`, like `ss` shows it
+impl fmt::Display for PeerAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerAddress::Inet(address) => write!(f, "{}", address),
+            PeerAddress::UnixPath(path) => write!(f, "{}", path),
+            PeerAddress::UnixAbstract(name) => write!(f, "@{}", name),
+            PeerAddress::UnixUnnamed => write!(f, "unix:unnamed"),
+        }
+    }
+}
 
 pub enum SnifferEvent {
     Data {
@@ -22,7 +77,7 @@ pub enum SnifferEvent {
     },
     Connect {
         id: EventId,
-        address: SocketAddr,
+        address: PeerAddress,
     },
     Bind {
         id: EventId,
@@ -34,11 +89,25 @@ pub enum SnifferEvent {
     Accept {
         id: EventId,
         listen_on_fd: u32,
-        address: SocketAddr,
+        address: PeerAddress,
     },
     Close {
         id: EventId,
     },
+    Shutdown {
+        id: EventId,
+    },
+    // socket operation failed, the code is negative `errno`
+    Error {
+        id: EventId,
+        code: i32,
+    },
+    // the outgoing connect failed, the code is negative `errno`
+    ConnectFailed {
+        id: EventId,
+        address: PeerAddress,
+        code: i32,
+    },
     GetFd {
         id: EventId,
     },
@@ -48,6 +117,24 @@ pub enum SnifferEvent {
     },
 }
 
+impl SnifferEvent {
+    pub fn id(&self) -> &EventId {
+        match self {
+            SnifferEvent::Data { id, .. } => id,
+            SnifferEvent::Connect { id, .. } => id,
+            SnifferEvent::Bind { id, .. } => id,
+            SnifferEvent::Listen { id } => id,
+            SnifferEvent::Accept { id, .. } => id,
+            SnifferEvent::Close { id } => id,
+            SnifferEvent::Shutdown { id } => id,
+            SnifferEvent::Error { id, .. } => id,
+            SnifferEvent::ConnectFailed { id, .. } => id,
+            SnifferEvent::GetFd { id } => id,
+            SnifferEvent::Debug { id, .. } => id,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum SnifferError {
     SliceTooShort(usize),
@@ -125,21 +212,36 @@ impl RingBufferData for SnifferEvent {
     fn from_rb_slice(value: &[u8]) -> Result<Self, Self::Error> {
         fn parse_socket_address(b: &[u8]) -> Result<SocketAddr, SnifferErrorCode> {
             let e = SnifferErrorCode::SliceTooShort(28, b.len());
-            let address_family = u16::from_ne_bytes(TryFrom::try_from(&b[0..2]).map_err(|_| e)?);
-            let port = u16::from_be_bytes(TryFrom::try_from(&b[2..4]).map_err(|_| e)?);
+            let get = |range: Range<usize>| b.get(range).ok_or(e);
+            let address_family = u16::from_ne_bytes(TryFrom::try_from(get(0..2)?).map_err(|_| e)?);
+            let port = u16::from_be_bytes(TryFrom::try_from(get(2..4)?).map_err(|_| e)?);
             match address_family {
                 2 => {
-                    let ip = <[u8; 4]>::try_from(&b[4..8]).map_err(|_| e)?;
+                    let ip = <[u8; 4]>::try_from(get(4..8)?).map_err(|_| e)?;
                     Ok(SocketAddr::new(IpAddr::V4(ip.into()), port))
                 },
                 10 => {
-                    let ip = <[u8; 16]>::try_from(&b[8..24]).map_err(|_| e)?;
-                    Ok(SocketAddr::new(IpAddr::V6(ip.into()), port))
+                    let flowinfo = <[u8; 4]>::try_from(get(4..8)?).map_err(|_| e)?;
+                    let ip = <[u8; 16]>::try_from(get(8..24)?).map_err(|_| e)?;
+                    let scope_id = <[u8; 4]>::try_from(get(24..28)?).map_err(|_| e)?;
+                    Ok(SocketAddr::V6(SocketAddrV6::new(
+                        ip.into(),
+                        port,
+                        u32::from_be_bytes(flowinfo),
+                        u32::from_ne_bytes(scope_id),
+                    )))
                 },
                 u => Err(SnifferErrorCode::UnknownAddressFamily(u)),
             }
         }
 
+        fn parse_peer_address(b: &[u8]) -> Result<PeerAddress, SnifferErrorCode> {
+            match b.get(0..2).map(|f| u16::from_ne_bytes([f[0], f[1]])) {
+                Some(1) => Ok(PeerAddress::unix(&b[2..])),
+                _ => parse_socket_address(b).map(PeerAddress::Inet),
+            }
+        }
+
         let descriptor = DataDescriptor::try_from(value)
             .map_err(|()| SnifferError::SliceTooShort(value.len()))?;
         let data = &value[mem::size_of::<DataDescriptor>()..];
@@ -186,7 +288,14 @@ impl RingBufferData for SnifferEvent {
             },
             DataTag::Connect => Ok(SnifferEvent::Connect {
                 id: descriptor.id.clone(),
-                address: parse_socket_address(data).map_err(|code| {
+                // the size is the length of the address given to the syscall
+                address: parse_peer_address(
+                    usize::try_from(descriptor.size)
+                        .ok()
+                        .and_then(|size| data.get(..size))
+                        .unwrap_or(data),
+                )
+                .map_err(|code| {
                     SnifferError::ConnectBadAddress {
                         id: descriptor.id,
                         code,
@@ -205,8 +314,15 @@ impl RingBufferData for SnifferEvent {
             DataTag::Listen => Ok(SnifferEvent::Listen { id: descriptor.id }),
             DataTag::Accept => Ok(SnifferEvent::Accept {
                 id: descriptor.id.clone(),
-                listen_on_fd: 0,
-                address: parse_socket_address(data).map_err(|code| {
+                // the address is padded, the fd of the listening socket is the last 4 bytes,
+                // the padding is 28 bytes in the streams recorded by older versions
+                listen_on_fd: usize::try_from(descriptor.size)
+                    .ok()
+                    .and_then(|size| size.checked_sub(4))
+                    .and_then(|offset| data.get(offset..(offset + 4)))
+                    .map(|b| u32::from_ne_bytes(TryFrom::try_from(b).unwrap()))
+                    .unwrap_or(0),
+                address: parse_peer_address(data).map_err(|code| {
                     SnifferError::AcceptBadAddress {
                         id: descriptor.id,
                         code,
@@ -214,6 +330,27 @@ impl RingBufferData for SnifferEvent {
                 })?,
             }),
             DataTag::Close => Ok(SnifferEvent::Close { id: descriptor.id }),
+            DataTag::Shutdown => Ok(SnifferEvent::Shutdown { id: descriptor.id }),
+            DataTag::Error => Ok(SnifferEvent::Error {
+                id: descriptor.id,
+                code: descriptor.size,
+            }),
+            DataTag::ConnectFailed => Ok(SnifferEvent::ConnectFailed {
+                id: descriptor.id.clone(),
+                // the address is padded as of `Accept`, the code is the last 4 bytes
+                code: usize::try_from(descriptor.size)
+                    .ok()
+                    .and_then(|size| size.checked_sub(4))
+                    .and_then(|offset| data.get(offset..(offset + 4)))
+                    .map(|b| i32::from_ne_bytes(TryFrom::try_from(b).unwrap()))
+                    .unwrap_or(0),
+                address: parse_peer_address(data).map_err(|code| {
+                    SnifferError::ConnectBadAddress {
+                        id: descriptor.id,
+                        code,
+                    }
+                })?,
+            }),
             DataTag::GetFd => Ok(SnifferEvent::GetFd { id: descriptor.id }),
             DataTag::Debug => {
                 SnifferError::debug(descriptor.id, descriptor.size, data.len()).map(|(id, size)| {
@@ -255,4 +392,75 @@ impl BpfModuleClient {
     pub fn send_command(&mut self, cmd: Command) -> io::Result<()> {
         self.stream.write_fmt(format_args!("{}\n", cmd))
     }
+
+    /// How many syscall contexts the bpf module evicted, it means some syscall exits were missed
+    pub fn fetch_counter(&mut self) -> io::Result<u32> {
+        self.send_command(Command::FetchCounter)?;
+        self.read_line()?
+            .parse()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// How many syscall contexts the bpf module pushed, popped and evicted since it attached
+    pub fn fetch_context_stats(&mut self) -> io::Result<ContextStats> {
+        self.send_command(Command::FetchContextStats)?;
+        let line = self.read_line()?;
+        let mut words = line.split(' ').map(str::parse::<u64>);
+        let mut next = || match words.next() {
+            Some(Ok(v)) => Ok(v),
+            Some(Err(e)) => Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+            None => Err(io::Error::new(io::ErrorKind::InvalidData, "too few counters")),
+        };
+        Ok(ContextStats {
+            pushed: next()?,
+            popped: next()?,
+            evicted: next()?,
+        })
+    }
+
+    fn read_line(&mut self) -> io::Result<String> {
+        let mut line = Vec::new();
+        let mut byte = [0];
+        loop {
+            self.stream.read_exact(&mut byte)?;
+            if byte[0] == b'\n' {
+                break;
+            }
+            line.push(byte[0]);
+        }
+        Ok(String::from_utf8_lossy(&line).into_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PeerAddress;
+
+    #[test]
+    fn unix_pathname() {
+        let mut b = [0; 108];
+        b[..18].clone_from_slice(b"/run/tezos/rpc.ipc");
+        let address = PeerAddress::unix(&b);
+        assert_eq!(address, PeerAddress::UnixPath("/run/tezos/rpc.ipc".to_string()));
+        assert_eq!(address.to_string(), "/run/tezos/rpc.ipc");
+        assert!(address.inet().is_none());
+        // exact length, not terminated
+        assert_eq!(PeerAddress::unix(&b[..18]), address);
+    }
+
+    #[test]
+    fn unix_abstract() {
+        let mut b = [0; 108];
+        b[1..11].clone_from_slice(b"tezos-node");
+        let address = PeerAddress::unix(&b);
+        assert_eq!(address, PeerAddress::UnixAbstract("tezos-node".to_string()));
+        assert_eq!(address.to_string(), "@tezos-node");
+        assert_eq!(PeerAddress::unix(&b[..11]), address);
+        // the abstract name might contain null bytes
+        b[5] = 0;
+        assert_eq!(PeerAddress::unix(&b[..11]).to_string(), "@tezo\0node");
+
+        assert_eq!(PeerAddress::unix(&[]), PeerAddress::UnixUnnamed);
+        assert_eq!(PeerAddress::unix(&[0; 108]), PeerAddress::UnixUnnamed);
+    }
 }
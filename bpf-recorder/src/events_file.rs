@@ -0,0 +1,467 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Recorded stream of events, allows to replay the stream without root and live kernel.
+//! The file starts with the header, `MAGIC` followed by `VERSION`, 4 bytes little endian.
+//! Then the records, each is 4 bytes little endian length, then 1 byte type, then the body,
+//! the length counts the type and the body, so the reader skips the record of unknown type.
+//! The body of `RECORD_EVENT_SPAN` is
+//! `[tag(4)][pid(4)][fd(4)][ts_start(8)][ts_finish(8)][size(4)][payload]`,
+//! all integers little endian, the tag is `DataTag`, the size is as in `DataDescriptor`.
+//! The files of version 1 have `RECORD_EVENT` instead, it is the same without `ts_start`,
+//! such events are read with `ts_start` zero, it means unknown.
+
+use std::{
+    convert::TryFrom,
+    io::{self, Read, Write},
+    mem, ptr,
+};
+use bpf_ring_buffer::RingBufferData;
+use super::{DataDescriptor, DataTag, EventId, SocketId};
+
+pub const MAGIC: [u8; 8] = *b"TZEVENTS";
+/// The version of the framing, the new kinds of data are the new record types
+pub const VERSION: u32 = 2;
+/// Written by version 1, only read
+pub const RECORD_EVENT: u8 = 1;
+pub const RECORD_EVENT_SPAN: u8 = 2;
+
+/// The event as it is in the ring buffer, not parsed
+pub struct RawEvent(pub Vec<u8>);
+
+impl RingBufferData for RawEvent {
+    type Error = ();
+
+    fn from_rb_slice(slice: &[u8]) -> Result<Self, Self::Error> {
+        Ok(RawEvent(slice.to_vec()))
+    }
+}
+
+/// The event of the bpf module, the descriptor split into the fields
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    pub id: EventId,
+    pub tag: DataTag,
+    pub size: i32,
+    pub payload: Vec<u8>,
+}
+
+impl Event {
+    /// Split the bytes of the ring buffer, `None` if they are shorter than the descriptor
+    pub fn from_raw(raw: &RawEvent) -> Option<Self> {
+        let descriptor = DataDescriptor::try_from(raw.0.as_slice()).ok()?;
+        Some(Event {
+            id: descriptor.id,
+            tag: descriptor.tag,
+            size: descriptor.size,
+            payload: raw.0[mem::size_of::<DataDescriptor>()..].to_vec(),
+        })
+    }
+
+    /// The bytes as the bpf module puts them in the ring buffer
+    pub fn to_raw(&self) -> RawEvent {
+        let descriptor = DataDescriptor {
+            id: self.id.clone(),
+            tag: self.tag,
+            size: self.size,
+        };
+        let mut v = vec![0; mem::size_of::<DataDescriptor>()];
+        // the same layout `DataDescriptor::try_from` reads
+        unsafe { ptr::write_unaligned(v.as_mut_ptr() as *mut DataDescriptor, descriptor) };
+        v.extend_from_slice(&self.payload);
+        RawEvent(v)
+    }
+}
+
+pub fn write_header<W>(w: &mut W) -> io::Result<()>
+where
+    W: Write,
+{
+    w.write_all(&MAGIC)?;
+    w.write_all(&VERSION.to_le_bytes())
+}
+
+/// Check the header, the file of a newer version is fine as long as the framing is the same
+pub fn read_header<R>(r: &mut R) -> io::Result<u32>
+where
+    R: Read,
+{
+    let mut header = [0; 12];
+    r.read_exact(&mut header)?;
+    if header[..8] != MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not an events file, or recorded by an older recorder",
+        ));
+    }
+    let version = u32::from_le_bytes(TryFrom::try_from(&header[8..]).unwrap());
+    if version == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "events file version 0"));
+    }
+    Ok(version)
+}
+
+fn write_record<W>(w: &mut W, ty: u8, body: &[u8]) -> io::Result<()>
+where
+    W: Write,
+{
+    w.write_all(&(body.len() as u32 + 1).to_le_bytes())?;
+    w.write_all(&[ty])?;
+    w.write_all(body)
+}
+
+/// The type and the body of the next record, `None` at the end of the file
+fn read_record<R>(r: &mut R) -> io::Result<Option<(u8, Vec<u8>)>>
+where
+    R: Read,
+{
+    let mut length = [0; 4];
+    match r.read_exact(&mut length) {
+        Ok(()) => (),
+        Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(error) => return Err(error),
+    }
+    let length = u32::from_le_bytes(length) as usize;
+    if length == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "empty record"));
+    }
+    let mut data = vec![0; length];
+    r.read_exact(&mut data)?;
+    let body = data.split_off(1);
+    Ok(Some((data[0], body)))
+}
+
+pub fn write_event<W>(w: &mut W, event: &Event) -> io::Result<()>
+where
+    W: Write,
+{
+    let mut body = Vec::with_capacity(32 + event.payload.len());
+    body.extend_from_slice(&(event.tag as u32).to_le_bytes());
+    body.extend_from_slice(&event.id.socket_id.pid.to_le_bytes());
+    body.extend_from_slice(&event.id.socket_id.fd.to_le_bytes());
+    body.extend_from_slice(&event.id.ts_start().to_le_bytes());
+    body.extend_from_slice(&event.id.ts_finish().to_le_bytes());
+    body.extend_from_slice(&event.size.to_le_bytes());
+    body.extend_from_slice(&event.payload);
+    write_record(w, RECORD_EVENT_SPAN, &body)
+}
+
+/// `None` if the body is too short or the tag is unknown, a newer recorder might write it,
+/// `span` tells the body has both timestamps, see `RECORD_EVENT_SPAN`
+fn parse_event(body: &[u8], span: bool) -> Option<Event> {
+    let u32_at = |i: usize| {
+        let bytes = TryFrom::try_from(body.get(i..(i + 4))?).ok()?;
+        Some(u32::from_le_bytes(bytes))
+    };
+    let tag = match u32_at(0)? {
+        0 => DataTag::Write,
+        1 => DataTag::Read,
+        2 => DataTag::Send,
+        3 => DataTag::Recv,
+        4 => DataTag::Connect,
+        5 => DataTag::Bind,
+        6 => DataTag::Listen,
+        7 => DataTag::Accept,
+        8 => DataTag::Close,
+        9 => DataTag::GetFd,
+        10 => DataTag::Debug,
+        11 => DataTag::Shutdown,
+        12 => DataTag::Error,
+        13 => DataTag::ConnectFailed,
+        _ => return None,
+    };
+    let socket_id = SocketId {
+        pid: u32_at(4)?,
+        fd: u32_at(8)?,
+    };
+    let u64_at = |i: usize| {
+        let bytes = TryFrom::try_from(body.get(i..(i + 8))?).ok()?;
+        Some(u64::from_le_bytes(bytes))
+    };
+    let (ts_start, ts_finish, offset) = if span {
+        (u64_at(12)?, u64_at(20)?, 28)
+    } else {
+        (0, u64_at(12)?, 20)
+    };
+    Some(Event {
+        id: EventId::new(socket_id, ts_start, ts_finish),
+        tag,
+        size: u32_at(offset)? as i32,
+        payload: body[(offset + 4)..].to_vec(),
+    })
+}
+
+/// The next event, the records of unknown type are skipped, `None` at the end of the file
+pub fn read_event<R>(r: &mut R) -> io::Result<Option<Event>>
+where
+    R: Read,
+{
+    loop {
+        match read_record(r)? {
+            None => return Ok(None),
+            Some((ty @ RECORD_EVENT, body)) | Some((ty @ RECORD_EVENT_SPAN, body)) => {
+                match parse_event(&body, ty == RECORD_EVENT_SPAN) {
+                    Some(event) => return Ok(Some(event)),
+                    None => log::debug!("events file skip unknown event, {} bytes", body.len()),
+                }
+            },
+            Some((ty, body)) => {
+                log::debug!("events file skip record type: {}, {} bytes", ty, body.len())
+            },
+        }
+    }
+}
+
+/// The events of the file after the header
+pub struct Reader<R> {
+    inner: R,
+    version: u32,
+}
+
+impl<R> Reader<R>
+where
+    R: Read,
+{
+    pub fn new(mut inner: R) -> io::Result<Self> {
+        let version = read_header(&mut inner)?;
+        Ok(Reader { inner, version })
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+}
+
+impl<R> Iterator for Reader<R>
+where
+    R: Read,
+{
+    type Item = io::Result<Event>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        read_event(&mut self.inner).transpose()
+    }
+}
+
+pub struct EventsFileWriter<W> {
+    inner: W,
+}
+
+impl<W> EventsFileWriter<W>
+where
+    W: Write,
+{
+    /// The new file, writes the header
+    pub fn new(mut inner: W) -> io::Result<Self> {
+        write_header(&mut inner)?;
+        Ok(EventsFileWriter { inner })
+    }
+
+    /// Continue the file which already has the header
+    pub fn append(inner: W) -> Self {
+        EventsFileWriter { inner }
+    }
+
+    pub fn write(&mut self, event: &RawEvent) -> io::Result<()> {
+        let event = Event::from_raw(event).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "event is shorter than descriptor")
+        })?;
+        write_event(&mut self.inner, &event)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub struct EventsFileReader<R> {
+    inner: Reader<R>,
+}
+
+impl<R> EventsFileReader<R>
+where
+    R: Read,
+{
+    pub fn new(inner: R) -> io::Result<Self> {
+        Ok(EventsFileReader {
+            inner: Reader::new(inner)?,
+        })
+    }
+
+    /// The next event as the bpf module put it in the ring buffer, `None` at the end of the file
+    pub fn read_raw(&mut self) -> io::Result<Option<RawEvent>> {
+        self.inner.next().transpose().map(|e| e.map(|e| e.to_raw()))
+    }
+
+    /// Read at most `limit` events and parse them, like the ring buffer does,
+    /// the events which cannot be parsed are skipped. Empty at the end of the file.
+    pub fn read<D>(&mut self, limit: usize) -> io::Result<Vec<D>>
+    where
+        D: RingBufferData,
+    {
+        let mut events = Vec::with_capacity(limit);
+        while events.len() < limit {
+            match self.read_raw()? {
+                Some(RawEvent(data)) => match D::from_rb_slice(&data) {
+                    Ok(event) => events.push(event),
+                    Err(error) => log::error!("events file parse data: {:?}", error),
+                },
+                None => break,
+            }
+        }
+        Ok(events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{mem, ptr};
+    use crate::{DataDescriptor, DataTag, EventId, SocketId, SnifferEvent};
+    use super::{
+        RawEvent, Event, EventsFileWriter, EventsFileReader, Reader, write_header, write_record,
+        write_event, read_event, RECORD_EVENT, VERSION,
+    };
+
+    fn raw(pid: u32, tag: DataTag, payload: &[u8]) -> RawEvent {
+        let id = EventId::new(SocketId { pid, fd: 7 }, 0, 1_000);
+        let size = payload.len() as i32;
+        let mut v = vec![0; mem::size_of::<DataDescriptor>()];
+        unsafe {
+            ptr::write_unaligned(
+                v.as_mut_ptr() as *mut DataDescriptor,
+                DataDescriptor { id, tag, size },
+            )
+        };
+        v.extend_from_slice(payload);
+        RawEvent(v)
+    }
+
+    #[test]
+    fn round_trip() {
+        let events = vec![
+            raw(1, DataTag::Write, b"hello"),
+            raw(1, DataTag::Read, b""),
+            raw(2, DataTag::Close, b""),
+        ];
+        let mut writer = EventsFileWriter::new(Vec::new()).unwrap();
+        for event in &events {
+            writer.write(event).unwrap();
+        }
+        let file = writer.inner;
+
+        let mut reader = EventsFileReader::new(file.as_slice()).unwrap();
+        for event in &events {
+            assert_eq!(reader.read_raw().unwrap().unwrap().0, event.0);
+        }
+        assert!(reader.read_raw().unwrap().is_none());
+
+        let mut reader = EventsFileReader::new(file.as_slice()).unwrap();
+        let parsed = reader.read::<SnifferEvent>(2).unwrap();
+        match &parsed[..] {
+            [SnifferEvent::Data {
+                id,
+                data,
+                net: false,
+                incoming: false,
+            }, SnifferEvent::Data { incoming: true, .. }] => {
+                assert_eq!(id.socket_id, SocketId { pid: 1, fd: 7 });
+                assert_eq!(id.ts_finish(), 1_000);
+                assert_eq!(data, b"hello");
+            },
+            _ => panic!("unexpected events"),
+        }
+        let parsed = reader.read::<SnifferEvent>(2).unwrap();
+        assert!(matches!(&parsed[..], [SnifferEvent::Close { .. }]));
+        assert!(reader.read::<SnifferEvent>(2).unwrap().is_empty());
+    }
+
+    #[test]
+    fn event_round_trip() {
+        let event = Event {
+            id: EventId::new(
+                SocketId { pid: 3, fd: 12 },
+                1_617_005_682_953_901_377,
+                1_617_005_682_953_928_051,
+            ),
+            tag: DataTag::Error,
+            size: -104,
+            payload: vec![],
+        };
+        let data = Event::from_raw(&raw(4, DataTag::Recv, b"chunk")).unwrap();
+        assert_eq!(data.payload, b"chunk");
+        assert_eq!(data.size, 5);
+
+        let mut file = Vec::new();
+        write_header(&mut file).unwrap();
+        write_event(&mut file, &event).unwrap();
+        write_event(&mut file, &data).unwrap();
+
+        let mut reader = Reader::new(file.as_slice()).unwrap();
+        assert_eq!(reader.version(), VERSION);
+        let read = reader.next().unwrap().unwrap();
+        assert_eq!(read, event);
+        assert_eq!(read.id.ts_start(), 1_617_005_682_953_901_377);
+        assert_eq!(read.id.ts_finish(), 1_617_005_682_953_928_051);
+        assert_eq!(reader.next().unwrap().unwrap(), data);
+        assert!(reader.next().is_none());
+        // the bytes of the ring buffer are restored exactly
+        assert_eq!(data.to_raw().0, raw(4, DataTag::Recv, b"chunk").0);
+
+        // not an events file
+        assert!(Reader::new(&b"\x05\x00\x00\x00hello, world"[..]).is_err());
+    }
+
+    #[test]
+    fn version_1() {
+        // the event record of the version 1 has no `ts_start`
+        let mut body = Vec::new();
+        body.extend_from_slice(&(DataTag::Recv as u32).to_le_bytes());
+        body.extend_from_slice(&5u32.to_le_bytes());
+        body.extend_from_slice(&9u32.to_le_bytes());
+        body.extend_from_slice(&1_000u64.to_le_bytes());
+        body.extend_from_slice(&5i32.to_le_bytes());
+        body.extend_from_slice(b"chunk");
+        let mut file = b"TZEVENTS".to_vec();
+        file.extend_from_slice(&1u32.to_le_bytes());
+        write_record(&mut file, RECORD_EVENT, &body).unwrap();
+
+        let mut reader = Reader::new(file.as_slice()).unwrap();
+        assert_eq!(reader.version(), 1);
+        let event = reader.next().unwrap().unwrap();
+        assert_eq!(event.id, EventId::new(SocketId { pid: 5, fd: 9 }, 0, 1_000));
+        assert_eq!(event.tag, DataTag::Recv);
+        assert_eq!(event.payload, b"chunk");
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn skip_unknown_record() {
+        let event = |fd| Event {
+            id: EventId::new(SocketId { pid: 1, fd }, 0, 1_000),
+            tag: DataTag::Close,
+            size: 0,
+            payload: vec![],
+        };
+        let mut file = Vec::new();
+        write_header(&mut file).unwrap();
+        write_event(&mut file, &event(1)).unwrap();
+        // a record type of some future version
+        write_record(&mut file, 0x7f, b"something new").unwrap();
+        // the known record with the tag of some future version
+        let mut body = 100u32.to_le_bytes().to_vec();
+        body.extend_from_slice(&[0; 20]);
+        write_record(&mut file, RECORD_EVENT, &body).unwrap();
+        write_event(&mut file, &event(2)).unwrap();
+
+        let events = Reader::new(file.as_slice())
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(events, vec![event(1), event(2)]);
+
+        let mut body = &file[12..];
+        assert_eq!(read_event(&mut body).unwrap(), Some(event(1)));
+        assert_eq!(read_event(&mut body).unwrap(), Some(event(2)));
+        assert_eq!(read_event(&mut body).unwrap(), None);
+    }
+}
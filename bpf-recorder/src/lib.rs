@@ -6,7 +6,12 @@
 #[cfg(feature = "client")]
 mod client;
 #[cfg(feature = "client")]
-pub use self::client::{SnifferEvent, SnifferError, SnifferErrorCode, BpfModuleClient};
+pub use self::client::{SnifferEvent, SnifferError, SnifferErrorCode, BpfModuleClient, PeerAddress};
+
+#[cfg(feature = "client")]
+mod events_file;
+#[cfg(feature = "client")]
+pub use self::events_file::{RawEvent, EventsFileWriter, EventsFileReader};
 
 use core::{fmt, mem, ptr, convert::TryFrom};
 
@@ -33,9 +38,35 @@ impl fmt::Display for SocketId {
 }
 
 pub enum Command {
+    // the port `0` means any port, used to detect the port of the node by its bind
     WatchPort { port: u16 },
+    UnwatchPort { port: u16 },
     IgnoreConnection { pid: u32, fd: u32 },
+    // the process which bound the port before the recorder started
+    WatchProcess { pid: u32, port: u16 },
     FetchCounter,
+    FetchContextStats,
+}
+
+/// The counter of syscall contexts, the key in the `syscall_contexts_counters` map
+#[derive(Clone, Copy)]
+#[repr(u32)]
+pub enum ContextCounter {
+    // the context was replaced by the next syscall of the same thread, or evicted as the oldest
+    // when the storage is full, the exit was missed
+    Evicted = 0,
+    // the syscall entered
+    Pushed = 1,
+    // the syscall exited and took its context
+    Popped = 2,
+}
+
+/// Lifetime counters of syscall contexts since the bpf module attached
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ContextStats {
+    pub pushed: u64,
+    pub popped: u64,
+    pub evicted: u64,
 }
 
 #[cfg(feature = "user")]
@@ -53,6 +84,14 @@ impl FromStr for Command {
                     .map_err(|e| format!("failed to parse port: {}", e))?;
                 Ok(Command::WatchPort { port })
             },
+            Some("unwatch_port") => {
+                let port = words
+                    .next()
+                    .ok_or_else(|| "bad port".to_string())?
+                    .parse()
+                    .map_err(|e| format!("failed to parse port: {}", e))?;
+                Ok(Command::UnwatchPort { port })
+            },
             Some("ignore_connection") => {
                 let pid = words
                     .next()
@@ -66,7 +105,21 @@ impl FromStr for Command {
                     .map_err(|e| format!("failed to parse fd: {}", e))?;
                 Ok(Command::IgnoreConnection { pid, fd })
             },
+            Some("watch_process") => {
+                let pid = words
+                    .next()
+                    .ok_or_else(|| "bad pid".to_string())?
+                    .parse()
+                    .map_err(|e| format!("failed to parse pid: {}", e))?;
+                let port = words
+                    .next()
+                    .ok_or_else(|| "bad port".to_string())?
+                    .parse()
+                    .map_err(|e| format!("failed to parse port: {}", e))?;
+                Ok(Command::WatchProcess { pid, port })
+            },
             Some("fetch_counter") => Ok(Command::FetchCounter),
+            Some("fetch_context_stats") => Ok(Command::FetchContextStats),
             _ => Err("unexpected command".to_string()),
         }
     }
@@ -76,8 +129,11 @@ impl fmt::Display for Command {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Command::WatchPort { port } => write!(f, "watch_port {}", port),
+            Command::UnwatchPort { port } => write!(f, "unwatch_port {}", port),
             Command::IgnoreConnection { pid, fd } => write!(f, "ignore_connection {} {}", pid, fd),
+            Command::WatchProcess { pid, port } => write!(f, "watch_process {} {}", pid, port),
             Command::FetchCounter => write!(f, "fetch_counter"),
+            Command::FetchContextStats => write!(f, "fetch_context_stats"),
         }
     }
 }
@@ -92,21 +148,23 @@ pub struct DataDescriptor {
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
 pub struct EventId {
     pub socket_id: SocketId,
-    //ts: Range<u64>,
+    // when the syscall entered, `0` if unknown
+    ts_start: u64,
     ts: u64,
 }
 
 impl EventId {
     #[inline(always)]
-    pub fn new(socket_id: SocketId, _ts_start: u64, ts_finish: u64) -> Self {
+    pub fn new(socket_id: SocketId, ts_start: u64, ts_finish: u64) -> Self {
         EventId {
             socket_id,
+            ts_start,
             ts: ts_finish,
         }
     }
 
     pub fn ts_start(&self) -> u64 {
-        0
+        self.ts_start
     }
 
     pub fn ts_finish(&self) -> u64 {
@@ -134,7 +192,7 @@ impl TryFrom<&[u8]> for DataDescriptor {
 }
 
 #[repr(u32)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DataTag {
     Write,
     Read,
@@ -149,4 +207,10 @@ pub enum DataTag {
 
     GetFd,
     Debug,
+
+    Shutdown,
+    // the size of the descriptor is the error code of the failed syscall
+    Error,
+    // the outgoing connect failed, the address is followed by the error code, see `Accept`
+    ConnectFailed,
 }
@@ -25,8 +25,15 @@ pub struct App {
     pub processes: ebpf::HashMapRef<4, 2>,
     #[hashmap(size = 0x2000)]
     pub connections: ebpf::HashMapRef<{ mem::size_of::<SocketId>() }, 4>,
-    #[hashmap(size = 0x100)]
-    pub syscall_contexts: ebpf::HashMapRef<4, 0x20>,
+    // size is `syscall_context::SLOTS`, the key is the thread id
+    #[hashmap(size = 0x1000)]
+    pub syscall_contexts: ebpf::HashMapRef<4, 0x28>,
+    // size is `syscall_context::SLOTS`, the thread id and the timestamp in the order of push
+    #[hashmap(size = 0x1000)]
+    pub syscall_contexts_order: ebpf::HashMapRef<4, 0x10>,
+    // lifetime counters of syscall contexts, the key is `ContextCounter`
+    #[hashmap(size = 4)]
+    pub syscall_contexts_counters: ebpf::HashMapRef<4, 8>,
     #[prog("tracepoint/syscalls/sys_enter_bind")]
     pub enter_bind: ebpf::ProgRef,
     #[prog("tracepoint/syscalls/sys_exit_bind")]
@@ -43,6 +50,10 @@ pub struct App {
     pub enter_close: ebpf::ProgRef,
     #[prog("tracepoint/syscalls/sys_exit_close")]
     pub exit_close: ebpf::ProgRef,
+    #[prog("tracepoint/syscalls/sys_enter_shutdown")]
+    pub enter_shutdown: ebpf::ProgRef,
+    #[prog("tracepoint/syscalls/sys_exit_shutdown")]
+    pub exit_shutdown: ebpf::ProgRef,
     #[prog("tracepoint/syscalls/sys_enter_write")]
     pub enter_write: ebpf::ProgRef,
     #[prog("tracepoint/syscalls/sys_exit_write")]
@@ -61,24 +72,87 @@ pub struct App {
     pub exit_recvfrom: ebpf::ProgRef,
 }
 
-#[cfg(feature = "kern")]
+#[cfg(any(feature = "kern", test))]
+#[cfg_attr(not(feature = "kern"), allow(dead_code))]
 mod syscall_context;
 
 #[cfg(feature = "kern")]
 mod send;
 
-#[cfg(feature = "kern")]
+#[cfg(any(feature = "kern", test))]
 mod address;
 
 #[cfg(feature = "kern")]
 use {
     core::ptr,
     ebpf::helpers,
-    bpf_recorder::{EventId, DataTag},
-    self::syscall_context::{SyscallContext, SyscallContextData},
+    bpf_recorder::{EventId, DataTag, ContextCounter},
+    self::syscall_context::{
+        SyscallContext, SyscallContextData, ContextStorage, ContextOrder, ContextCounters,
+    },
     self::address::Address,
 };
 
+#[cfg(feature = "kern")]
+impl ContextStorage for ebpf::HashMapRef<4, 0x28> {
+    #[inline(always)]
+    fn ts(&self, thread_id: u32) -> Option<u64> {
+        self.get(&thread_id.to_ne_bytes())
+            .map(|bytes| SyscallContext::from_ne_bytes(bytes).ts)
+    }
+
+    #[inline(always)]
+    fn insert(&mut self, thread_id: u32, context: SyscallContext) -> Result<(), i32> {
+        self.insert_unsafe(thread_id.to_ne_bytes(), context)
+    }
+
+    #[inline(always)]
+    fn remove(&mut self, thread_id: u32) -> Result<Option<SyscallContext>, i32> {
+        self.remove_unsafe::<SyscallContext>(&thread_id.to_ne_bytes())
+    }
+}
+
+#[cfg(feature = "kern")]
+impl ContextOrder for ebpf::HashMapRef<4, 0x10> {
+    #[inline(always)]
+    fn get(&self, position: u32) -> Option<(u32, u64)> {
+        let bytes = self.get(&position.to_ne_bytes())?;
+        let mut thread_id = [0; 4];
+        thread_id.clone_from_slice(&bytes[..4]);
+        let mut ts = [0; 8];
+        ts.clone_from_slice(&bytes[8..]);
+        Some((u32::from_ne_bytes(thread_id), u64::from_ne_bytes(ts)))
+    }
+
+    #[inline(always)]
+    fn set(&mut self, position: u32, thread_id: u32, ts: u64) -> Result<(), i32> {
+        let mut bytes = [0; 0x10];
+        bytes[..4].clone_from_slice(&thread_id.to_ne_bytes());
+        bytes[8..].clone_from_slice(&ts.to_ne_bytes());
+        self.insert(position.to_ne_bytes(), bytes)
+    }
+}
+
+#[cfg(feature = "kern")]
+impl ContextCounters for ebpf::HashMapRef<4, 8> {
+    #[inline(always)]
+    fn get(&self, counter: ContextCounter) -> u64 {
+        let key = (counter as u32).to_ne_bytes();
+        self.get(&key).map(|cnt| u64::from_ne_bytes(*cnt)).unwrap_or(0)
+    }
+
+    #[inline(always)]
+    fn inc(&mut self, counter: ContextCounter) -> Result<(), i32> {
+        let key = (counter as u32).to_ne_bytes();
+        if let Some(cnt) = self.get_mut(&key) {
+            *cnt = u64::from_ne_bytes(*cnt).wrapping_add(1).to_ne_bytes();
+            Ok(())
+        } else {
+            self.insert(key, 1u64.to_ne_bytes())
+        }
+    }
+}
+
 #[cfg(feature = "kern")]
 impl App {
     #[inline(always)]
@@ -86,14 +160,16 @@ impl App {
         let mut context = SyscallContext {
             data: SyscallContextData::Empty,
             ts,
+            thread_id,
         };
         // bpf validator forbids reading from stack uninitialized data
         // different variants of this enum has different length,
         unsafe { ptr::write_volatile(&mut context.data, mem::zeroed()) };
         context.data = data;
 
-        self.syscall_contexts
-            .insert_unsafe(thread_id.to_ne_bytes(), context)
+        let order = &mut self.syscall_contexts_order;
+        let counters = &mut self.syscall_contexts_counters;
+        syscall_context::push(&mut self.syscall_contexts, order, counters, context).map(drop)
     }
 
     #[inline(always)]
@@ -104,12 +180,10 @@ impl App {
         };
         let ts1 = unsafe { helpers::ktime_get_ns() };
 
-        match self
-            .syscall_contexts
-            .remove_unsafe::<SyscallContext>(&thread_id.to_ne_bytes())?
-        {
+        let counters = &mut self.syscall_contexts_counters;
+        match syscall_context::pop(&mut self.syscall_contexts, counters, thread_id)? {
             Some(context) => {
-                let SyscallContext { data, ts: ts0 } = context;
+                let SyscallContext { data, ts: ts0, .. } = context;
                 let ret = ctx.read_here(0x10);
                 self.on_ret(ret, data, ts0, ts1, pid)
             },
@@ -133,7 +207,9 @@ impl App {
     }
 
     fn is_interesting_port(&self, port: u16) -> bool {
+        // the port `0` is the wildcard, the ephemeral port is never interesting
         self.ports.get(&port.to_ne_bytes()).is_some()
+            || (port != 0 && self.ports.get(&0u16.to_ne_bytes()).is_some())
     }
 
     fn reg_process(&mut self, pid: u32, port: u16) -> Result<(), i32> {
@@ -217,6 +293,45 @@ impl App {
         pid: u32,
     ) -> Result<(), i32> {
         if ret < 0 {
+            // report the failure of socket operation, it tells why the connection is closed,
+            // EAGAIN and EINTR are not failures
+            const EAGAIN: i64 = -11;
+            const EINTR: i64 = -4;
+            // the connect is not over, see below
+            const EINPROGRESS: i64 = -115;
+            const EALREADY: i64 = -114;
+            match &data {
+                &SyscallContextData::Write { fd, .. }
+                | &SyscallContextData::Send { fd, .. }
+                | &SyscallContextData::Read { fd, .. }
+                | &SyscallContextData::Recv { fd, .. } => {
+                    if ret != EAGAIN && ret != EINTR {
+                        let id = EventId::new(SocketId { pid, fd }, ts0, ts1);
+                        send::code(id, DataTag::Error, ret as i32, &mut self.event_queue);
+                    }
+                    return Ok(());
+                },
+                &SyscallContextData::Connect {
+                    fd,
+                    addr_ptr,
+                    addr_len,
+                } if !matches!(ret, EAGAIN | EINTR | EINPROGRESS | EALREADY) => {
+                    // the node tried to reach the peer and failed, like ECONNREFUSED or ETIMEDOUT
+                    if Address::read(addr_ptr, addr_len)?.is_some() {
+                        let id = EventId::new(SocketId { pid, fd }, ts0, ts1);
+                        send::connect_failed(
+                            id,
+                            addr_ptr as *const u8,
+                            addr_len as usize,
+                            ret as i32,
+                            &mut self.event_queue,
+                        );
+                    }
+                    return Ok(());
+                },
+                _ => (),
+            }
+
             // TODO: need a better fix
             // EINPROGRESS
             //     The socket is nonblocking and the connection cannot be
@@ -229,7 +344,6 @@ impl App {
             //     zero) or unsuccessfully (SO_ERROR is one of the usual
             //     error codes listed here, explaining the reason for the
             //     failure).
-            const EINPROGRESS: i64 = -115;
             if !(matches!(&data, &SyscallContextData::Connect { .. }) && ret == EINPROGRESS) {
                 return Ok(());
             }
@@ -244,7 +358,8 @@ impl App {
             } => {
                 let address = Address::read(addr_ptr, addr_len)?.ok_or(-1)?;
                 let port = address.port();
-                if !self.is_interesting_port(port) {
+                // the unix socket has no port, it is never the p2p socket of the node
+                if address.is_unix() || !self.is_interesting_port(port) {
                     return Ok(());
                 }
                 self.reg_process(pid, port)?;
@@ -270,7 +385,8 @@ impl App {
                 }
                 self.reg_connection(socket_id, false)?;
                 let id = EventId::new(socket_id, ts0, ts1);
-                send::sized::<typenum::U28, typenum::B0>(
+                // enough for `sockaddr_un`
+                send::sized::<typenum::U110, typenum::B0>(
                     id,
                     DataTag::Connect,
                     addr_ptr as *const u8,
@@ -284,7 +400,6 @@ impl App {
                 addr_ptr,
                 addr_len,
             } => {
-                let _ = listen_on_fd;
                 let fd = ret as u32;
                 let socket_id = SocketId { pid, fd };
                 if Address::read(addr_ptr, addr_len)?.is_none() {
@@ -292,11 +407,11 @@ impl App {
                 }
                 self.reg_connection(socket_id, true)?;
                 let id = EventId::new(socket_id, ts0, ts1);
-                send::sized::<typenum::U28, typenum::B0>(
+                send::accept(
                     id,
-                    DataTag::Accept,
                     addr_ptr as *const u8,
                     addr_len as usize,
+                    listen_on_fd,
                     &mut self.event_queue,
                 );
                 Ok(())
@@ -399,6 +514,42 @@ impl App {
         Ok(())
     }
 
+    #[inline(always)]
+    pub fn enter_shutdown(&mut self, ctx: ebpf::Context) -> Result<(), i32> {
+        let fd = ctx.read_here::<u64>(0x10) as u32;
+        let (pid, _) = {
+            let x = unsafe { helpers::get_current_pid_tgid() };
+            ((x >> 32) as u32, (x & 0xffffffff) as u32)
+        };
+        let ts = unsafe { helpers::ktime_get_ns() };
+        let socket_id = SocketId { pid, fd };
+
+        if !self.is_process(pid) {
+            return Ok(());
+        }
+        if !self.is_connected(socket_id) {
+            return Ok(());
+        }
+
+        // the socket is still open, the close will follow
+        let id = EventId::new(SocketId { pid, fd }, ts, ts);
+        send::sized::<typenum::U0, typenum::B0>(
+            id,
+            DataTag::Shutdown,
+            ptr::null(),
+            0,
+            &mut self.event_queue,
+        );
+
+        Ok(())
+    }
+
+    #[inline(always)]
+    pub fn exit_shutdown(&mut self, ctx: ebpf::Context) -> Result<(), i32> {
+        let _ = ctx;
+        Ok(())
+    }
+
     #[inline(always)]
     pub fn enter_write(&mut self, ctx: ebpf::Context) -> Result<(), i32> {
         self.on_data(ctx, false, false)
@@ -448,15 +599,22 @@ fn main() {
     };
     use std::{
         fs,
-        io::{Error, BufReader, BufRead},
+        io::{Error, BufReader, BufRead, Write},
         os::unix::{fs::PermissionsExt, net::UnixListener},
         process,
         str::FromStr,
     };
-    use bpf_recorder::Command;
+    use bpf_recorder::{Command, ContextCounter};
     use tracing::Level;
     use passfd::FdPassingExt;
 
+    fn fetch_context_counter(app: &App, counter: ContextCounter) -> u64 {
+        app.syscall_contexts_counters
+            .get(&(counter as u32).to_ne_bytes())
+            .map(u64::from_ne_bytes)
+            .unwrap_or(0)
+    }
+
     sudo::escalate_if_needed().expect("failed to obtain superuser permission");
     ctrlc::set_handler(move || process::exit(0)).expect("failed to setup ctrl+c handler");
     tracing_subscriber::fmt().with_max_level(Level::INFO).init();
@@ -496,6 +654,7 @@ fn main() {
         .send_fd(fd)
         .expect("failed to send ring buffer access");
 
+    let mut response = stream.try_clone().expect("failed to clone stream");
     let stream = BufReader::new(stream);
     for line in stream.lines() {
         // handle line
@@ -504,7 +663,21 @@ fn main() {
                 log::info!("command: {}", line);
                 Command::from_str(&line)
             } {
-                Ok(Command::FetchCounter) => (),
+                Ok(Command::FetchCounter) => {
+                    let evicted = fetch_context_counter(&skeleton.app, ContextCounter::Evicted);
+                    if let Err(error) = writeln!(response, "{}", evicted as u32) {
+                        tracing::error!("failed to send counter, error {}", error);
+                    }
+                },
+                Ok(Command::FetchContextStats) => {
+                    let app = &skeleton.app;
+                    let pushed = fetch_context_counter(app, ContextCounter::Pushed);
+                    let popped = fetch_context_counter(app, ContextCounter::Popped);
+                    let evicted = fetch_context_counter(app, ContextCounter::Evicted);
+                    if let Err(error) = writeln!(response, "{} {} {}", pushed, popped, evicted) {
+                        tracing::error!("failed to send context stats, error {}", error);
+                    }
+                },
                 Ok(Command::WatchPort { port }) => {
                     match skeleton
                         .app
@@ -522,6 +695,37 @@ fn main() {
                         },
                     }
                 },
+                Ok(Command::UnwatchPort { port }) => {
+                    match skeleton.app.ports.remove(&port.to_ne_bytes()) {
+                        Ok(()) => (),
+                        Err(code) => {
+                            tracing::error!(
+                                "failed to unwatch port {}, code {}, error {}",
+                                port,
+                                code,
+                                Error::last_os_error(),
+                            );
+                        },
+                    }
+                },
+                Ok(Command::WatchProcess { pid, port }) => {
+                    match skeleton
+                        .app
+                        .processes
+                        .insert(pid.to_ne_bytes(), port.to_ne_bytes())
+                    {
+                        Ok(()) => (),
+                        Err(code) => {
+                            tracing::error!(
+                                "failed to watch process {} at port {}, code {}, error {}",
+                                pid,
+                                port,
+                                code,
+                                Error::last_os_error(),
+                            );
+                        },
+                    }
+                },
                 Ok(Command::IgnoreConnection { pid, fd }) => {
                     let socket_id = SocketId { pid, fd };
                     match skeleton.app.connections.remove(&socket_id.to_ne_bytes()) {
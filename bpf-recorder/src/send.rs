@@ -61,6 +61,81 @@ where
     }
 }
 
+/// Report the error code of the syscall, there is no payload
+#[inline(always)]
+pub fn code(id: EventId, tag: DataTag, code: i32, rb: &mut RingBufferRef) {
+    if let Ok(mut buffer) = rb.reserve(mem::size_of::<DataDescriptor>()) {
+        let descriptor = DataDescriptor {
+            id,
+            tag,
+            size: code,
+        };
+        unsafe {
+            ptr::write(buffer.as_mut().as_mut_ptr() as *mut _, descriptor);
+        }
+        buffer.submit();
+    }
+}
+
+/// The address of the accepted connection, followed by the fd of the listening socket,
+/// the size in the descriptor covers both, so the fd is the last 4 bytes
+#[inline(always)]
+pub fn accept(id: EventId, data: *const u8, len: usize, listen_on_fd: u32, rb: &mut RingBufferRef) {
+    address_with_trailer(id, DataTag::Accept, data, len, listen_on_fd.to_ne_bytes(), rb)
+}
+
+/// The address the outgoing connect failed to reach, followed by the error code,
+/// the layout is the same as of `accept`
+#[inline(always)]
+pub fn connect_failed(id: EventId, data: *const u8, len: usize, code: i32, rb: &mut RingBufferRef) {
+    address_with_trailer(id, DataTag::ConnectFailed, data, len, code.to_ne_bytes(), rb)
+}
+
+#[inline(always)]
+fn address_with_trailer(
+    id: EventId,
+    tag: DataTag,
+    data: *const u8,
+    len: usize,
+    trailer: [u8; 4],
+    rb: &mut RingBufferRef,
+) {
+    // enough for `sockaddr_un`
+    const ADDRESS_SIZE: usize = super::address::Address::SOCKADDR_UN_LEN;
+    const HEADER_SIZE: usize = mem::size_of::<DataDescriptor>();
+
+    if let Ok(mut buffer) = rb.reserve(HEADER_SIZE + ADDRESS_SIZE + 4) {
+        let p_buffer = buffer.as_mut().as_mut_ptr() as *mut DataDescriptor;
+
+        let to_copy = ADDRESS_SIZE.min(len);
+        let result = if to_copy > 0 {
+            unsafe {
+                helpers::probe_read_user(
+                    p_buffer.offset(1) as *mut _,
+                    to_copy as u32,
+                    data as *const _,
+                )
+            }
+        } else {
+            0
+        };
+
+        let size = if result == 0 {
+            (ADDRESS_SIZE + 4) as i32
+        } else {
+            result as i32
+        };
+        let trailer_offset = HEADER_SIZE + ADDRESS_SIZE;
+        buffer.as_mut()[trailer_offset..(trailer_offset + 4)].clone_from_slice(&trailer);
+        let descriptor = DataDescriptor { id, tag, size };
+        unsafe {
+            ptr::write(p_buffer, descriptor);
+        }
+
+        buffer.submit();
+    }
+}
+
 type SizeOfDataDescriptor = typenum::U24;
 type DecByDataDescriptor<S> = <S as Sub<SizeOfDataDescriptor>>::Output;
 
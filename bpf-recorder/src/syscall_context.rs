@@ -2,21 +2,131 @@
 // SPDX-License-Identifier: MIT
 
 use core::convert::TryFrom;
-use bpf_recorder::DataTag;
+use bpf_recorder::{DataTag, ContextCounter};
+
+/// Number of syscalls which can be in flight simultaneously,
+/// must be equal to the size of `syscall_contexts` and `syscall_contexts_order` maps.
+/// The context is stored under the thread id at enter and removed at exit of the syscall.
+/// If the exit is missed (the tracepoint is detached, the thread is killed, etc.)
+/// the context leaks until the next syscall of the same thread replaces it.
+/// When the storage is full, the oldest context is evicted to store the new one,
+/// so the number of leaked contexts is bounded by `SLOTS`.
+pub const SLOTS: u32 = 0x1000;
+
+/// How many entries of the push order are looked through to find the oldest context
+/// when the storage is full, the bpf validator requires the loop to be bounded
+pub const EVICT_SCAN: u32 = 0x20;
 
 #[derive(Clone, Copy)]
 #[repr(C)]
 pub struct SyscallContext {
     pub data: SyscallContextData,
     pub ts: u64,
+    pub thread_id: u32,
+}
+
+/// The storage of in-flight syscall contexts by thread id, it is the bpf map in the kernel.
+/// The insert of a new thread id fails when the storage is full.
+pub trait ContextStorage {
+    fn ts(&self, thread_id: u32) -> Option<u64>;
+    fn insert(&mut self, thread_id: u32, context: SyscallContext) -> Result<(), i32>;
+    fn remove(&mut self, thread_id: u32) -> Result<Option<SyscallContext>, i32>;
+}
+
+/// The thread id and the timestamp of each pushed context in the order of push,
+/// the position is the number of contexts pushed before modulo `SLOTS`,
+/// it is the bpf map in the kernel.
+pub trait ContextOrder {
+    fn get(&self, position: u32) -> Option<(u32, u64)>;
+    fn set(&mut self, position: u32, thread_id: u32, ts: u64) -> Result<(), i32>;
+}
+
+/// The lifetime counters of contexts, it is the bpf map in the kernel.
+pub trait ContextCounters {
+    fn get(&self, counter: ContextCounter) -> u64;
+    fn inc(&mut self, counter: ContextCounter) -> Result<(), i32>;
+}
+
+/// Store the context, replace the older context of the same thread, its exit was missed.
+/// If the storage is full, evict the oldest context of another thread.
+/// Returns `true` if the older context was replaced or evicted.
+#[inline(always)]
+pub fn push<S, O, C>(
+    storage: &mut S,
+    order: &mut O,
+    counters: &mut C,
+    context: SyscallContext,
+) -> Result<bool, i32>
+where
+    S: ContextStorage,
+    O: ContextOrder,
+    C: ContextCounters,
+{
+    let SyscallContext { thread_id, ts, .. } = context;
+    let pushed = counters.get(ContextCounter::Pushed);
+    let mut evicted = storage.ts(thread_id).is_some();
+    if storage.insert(thread_id, context).is_err() {
+        let oldest = oldest(storage, order, pushed).ok_or(-1)?;
+        storage.remove(oldest)?;
+        storage.insert(thread_id, context)?;
+        evicted = true;
+    }
+    order.set((pushed % SLOTS as u64) as u32, thread_id, ts)?;
+    counters.inc(ContextCounter::Pushed)?;
+    if evicted {
+        counters.inc(ContextCounter::Evicted)?;
+    }
+    Ok(evicted)
+}
+
+/// The order is a ring, the entry to be overwritten by the next push is the oldest,
+/// the first entry whose context is still stored is the oldest context.
+/// The entry is stale if the context was popped, or replaced by the next syscall of the thread.
+/// The context which outlived `SLOTS` pushes is not in the order anymore, it is not evicted,
+/// it is a long syscall, or a leak of the thread which exited.
+#[inline(always)]
+fn oldest<S, O>(storage: &S, order: &O, pushed: u64) -> Option<u32>
+where
+    S: ContextStorage,
+    O: ContextOrder,
+{
+    for i in 0..EVICT_SCAN {
+        let position = ((pushed + i as u64) % SLOTS as u64) as u32;
+        if let Some((thread_id, ts)) = order.get(position) {
+            if storage.ts(thread_id) == Some(ts) {
+                return Some(thread_id);
+            }
+        }
+    }
+    None
+}
+
+/// Take the context of the thread.
+#[inline(always)]
+pub fn pop<S, C>(
+    storage: &mut S,
+    counters: &mut C,
+    thread_id: u32,
+) -> Result<Option<SyscallContext>, i32>
+where
+    S: ContextStorage,
+    C: ContextCounters,
+{
+    match storage.remove(thread_id)? {
+        Some(context) => {
+            counters.inc(ContextCounter::Popped)?;
+            Ok(Some(context))
+        },
+        None => Ok(None),
+    }
 }
 
 impl SyscallContext {
     #[allow(dead_code)]
     #[inline(always)]
-    pub fn to_ne_bytes(self) -> [u8; 0x20] {
-        let SyscallContext { data, ts } = self;
-        let mut b = [0; 0x20];
+    pub fn to_ne_bytes(self) -> [u8; 0x28] {
+        let SyscallContext { data, ts, thread_id } = self;
+        let mut b = [0; 0x28];
         let (p, q) = match data {
             SyscallContextData::Empty => (0, 0),
             SyscallContextData::Bind {
@@ -70,12 +180,13 @@ impl SyscallContext {
         b[0x08..0x10].clone_from_slice(&p.to_ne_bytes());
         b[0x10..0x18].clone_from_slice(&q.to_ne_bytes());
         b[0x18..0x20].clone_from_slice(&ts.to_ne_bytes());
+        b[0x20..0x24].clone_from_slice(&thread_id.to_ne_bytes());
         b
     }
 
     #[allow(dead_code)]
     #[inline(always)]
-    pub fn from_ne_bytes(bytes: &[u8; 0x20]) -> Self {
+    pub fn from_ne_bytes(bytes: &[u8; 0x28]) -> Self {
         let data = match u32::from_ne_bytes(TryFrom::try_from(&bytes[0x00..0x04]).unwrap()) {
             0x5 => {
                 let fd = u32::from_ne_bytes(TryFrom::try_from(&bytes[0x04..0x08]).unwrap());
@@ -130,7 +241,12 @@ impl SyscallContext {
             _ => SyscallContextData::Empty,
         };
         let ts = u64::from_ne_bytes(TryFrom::try_from(&bytes[0x18..0x20]).unwrap());
-        SyscallContext { data, ts }
+        let thread_id = u32::from_ne_bytes(TryFrom::try_from(&bytes[0x20..0x24]).unwrap());
+        SyscallContext {
+            data,
+            ts,
+            thread_id,
+        }
     }
 }
 
@@ -186,3 +302,209 @@ impl SyscallContextData {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use bpf_recorder::{ContextCounter, ContextStats};
+    use super::{
+        SyscallContext, SyscallContextData, ContextStorage, ContextOrder, ContextCounters, SLOTS,
+        push, pop,
+    };
+
+    /// Behaves like the bpf hash map of `SLOTS` entries
+    #[derive(Default)]
+    struct Storage(HashMap<u32, SyscallContext>);
+
+    impl ContextStorage for Storage {
+        fn ts(&self, thread_id: u32) -> Option<u64> {
+            self.0.get(&thread_id).map(|c| c.ts)
+        }
+
+        fn insert(&mut self, thread_id: u32, context: SyscallContext) -> Result<(), i32> {
+            if self.0.len() == SLOTS as usize && !self.0.contains_key(&thread_id) {
+                return Err(-7);
+            }
+            self.0.insert(thread_id, context);
+            Ok(())
+        }
+
+        fn remove(&mut self, thread_id: u32) -> Result<Option<SyscallContext>, i32> {
+            Ok(self.0.remove(&thread_id))
+        }
+    }
+
+    #[derive(Default)]
+    struct Order(HashMap<u32, (u32, u64)>);
+
+    impl ContextOrder for Order {
+        fn get(&self, position: u32) -> Option<(u32, u64)> {
+            self.0.get(&position).cloned()
+        }
+
+        fn set(&mut self, position: u32, thread_id: u32, ts: u64) -> Result<(), i32> {
+            self.0.insert(position, (thread_id, ts));
+            Ok(())
+        }
+    }
+
+    #[derive(Default)]
+    struct Counters(ContextStats);
+
+    impl ContextCounters for Counters {
+        fn get(&self, counter: ContextCounter) -> u64 {
+            match counter {
+                ContextCounter::Evicted => self.0.evicted,
+                ContextCounter::Pushed => self.0.pushed,
+                ContextCounter::Popped => self.0.popped,
+            }
+        }
+
+        fn inc(&mut self, counter: ContextCounter) -> Result<(), i32> {
+            match counter {
+                ContextCounter::Evicted => self.0.evicted += 1,
+                ContextCounter::Pushed => self.0.pushed += 1,
+                ContextCounter::Popped => self.0.popped += 1,
+            }
+            Ok(())
+        }
+    }
+
+    #[derive(Default)]
+    struct Maps {
+        storage: Storage,
+        order: Order,
+        counters: Counters,
+    }
+
+    impl Maps {
+        fn push(&mut self, thread_id: u32, ts: u64) -> bool {
+            let c = SyscallContext {
+                data: SyscallContextData::Read { fd: 3, data_ptr: 0 },
+                ts,
+                thread_id,
+            };
+            push(&mut self.storage, &mut self.order, &mut self.counters, c).unwrap()
+        }
+
+        fn pop(&mut self, thread_id: u32) -> Option<SyscallContext> {
+            pop(&mut self.storage, &mut self.counters, thread_id).unwrap()
+        }
+    }
+
+    #[test]
+    fn missed_exits() {
+        let mut maps = Maps::default();
+
+        // every thread enters the syscall, but the exit is missed
+        let mut evicted = 0;
+        for thread_id in 0..(SLOTS * 4) {
+            if maps.push(thread_id, thread_id as u64) {
+                evicted += 1;
+            }
+        }
+        assert_eq!(maps.storage.0.len(), SLOTS as usize);
+        assert_eq!(evicted, SLOTS * 3);
+
+        // the leaked contexts of the old threads are evicted, the new threads exit normally
+        assert!(maps.pop(1).is_none());
+        assert!(maps.pop(SLOTS * 3 - 1).is_none());
+        let thread_id = SLOTS * 3 + 1;
+        let c = maps.pop(thread_id).unwrap();
+        assert_eq!(c.thread_id, thread_id);
+        assert_eq!(maps.storage.0.len(), SLOTS as usize - 1);
+
+        // the exit of the same thread again, the context is already taken
+        assert!(maps.pop(thread_id).is_none());
+
+        let expected = ContextStats {
+            pushed: SLOTS as u64 * 4,
+            popped: 1,
+            evicted: SLOTS as u64 * 3,
+        };
+        assert_eq!(maps.counters.0, expected);
+    }
+
+    #[test]
+    fn evict_oldest() {
+        let mut maps = Maps::default();
+
+        // the storage is full, some contexts are already popped
+        for thread_id in 0..SLOTS {
+            maps.push(thread_id, thread_id as u64);
+        }
+        for thread_id in (0..16).step_by(2) {
+            maps.pop(thread_id);
+        }
+        for thread_id in SLOTS..(SLOTS + 8) {
+            maps.push(thread_id, thread_id as u64);
+        }
+        assert_eq!(maps.storage.0.len(), SLOTS as usize);
+
+        // the popped entries of the order are skipped, only the oldest context is evicted
+        assert!(maps.push(SLOTS * 2, SLOTS as u64 * 2));
+        assert!(maps.pop(9).is_none());
+        assert!(maps.pop(11).is_some());
+        assert!(maps.pop(SLOTS * 2).is_some());
+        // the order holds the last `SLOTS` pushes, the older context is not evicted,
+        // it is likely a long syscall
+        assert!(maps.pop(1).is_some());
+    }
+
+    #[test]
+    fn counters() {
+        let mut maps = Maps::default();
+
+        // matched enter and exit
+        for thread_id in 0..10 {
+            maps.push(thread_id, 0);
+        }
+        for thread_id in 0..10 {
+            assert!(maps.pop(thread_id).is_some());
+        }
+        let expected = ContextStats {
+            pushed: 10,
+            popped: 10,
+            evicted: 0,
+        };
+        assert_eq!(maps.counters.0, expected);
+
+        // the exit without enter, for example, the syscall entered before the module attached
+        assert!(maps.pop(20).is_none());
+        assert_eq!(maps.counters.0, expected);
+
+        // the thread enters twice, the exit of the first syscall is missed
+        maps.push(30, 0);
+        maps.push(30, 1);
+        assert_eq!(maps.pop(30).unwrap().ts, 1);
+
+        // the thread ids which are equal modulo `SLOTS` do not collide
+        maps.push(40, 0);
+        maps.push(40 + SLOTS, 0);
+        assert!(maps.pop(40).is_some());
+        assert!(maps.pop(40 + SLOTS).is_some());
+
+        // a long syscall is not evicted while the others enter and exit many times
+        maps.push(50, 0);
+        for i in 0..(SLOTS * 4) {
+            maps.push(60 + i % 8, i as u64);
+            maps.pop(60 + i % 8);
+        }
+        assert!(maps.pop(50).is_some());
+
+        let expected = ContextStats {
+            pushed: 15 + SLOTS as u64 * 4,
+            popped: 14 + SLOTS as u64 * 4,
+            evicted: 1,
+        };
+        assert_eq!(maps.counters.0, expected);
+        // every pushed context is either popped, evicted or still in flight
+        let in_flight = maps.storage.0.len() as u64;
+        let ContextStats {
+            pushed,
+            popped,
+            evicted,
+        } = maps.counters.0;
+        assert_eq!(pushed, popped + evicted + in_flight);
+    }
+}
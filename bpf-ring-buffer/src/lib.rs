@@ -3,14 +3,17 @@
 
 use std::{
     fmt,
+    fs::{File, OpenOptions},
     io::{self, Write},
     marker::PhantomData,
+    path::PathBuf,
     ptr, slice, mem,
     sync::{
         Arc,
         atomic::{Ordering, AtomicUsize, AtomicBool},
     },
     task::{Context, Poll},
+    time::Duration,
     pin::Pin,
     os::unix::io::AsRawFd,
 };
@@ -36,42 +39,88 @@ pub struct RingBuffer<D> {
     phantom_data: PhantomData<D>,
 }
 
+/// The events of the polling, kept in memory up to `max_size` bytes,
+/// then appended to the file, so the long run does not run out of memory
 struct RingBufferReport {
     inner: Vec<u8>,
+    max_size: usize,
+    path: PathBuf,
+    // the file is truncated at the first flush, the next flushes append to it
+    flushed: bool,
+    // the file cannot be written, nothing is recorded anymore
+    stopped: bool,
 }
 
 impl RingBufferReport {
-    fn new() -> Self {
+    // the position record, the biggest one
+    const MAX_RECORD: usize = 17;
+
+    fn new(path: PathBuf, max_size: usize) -> Self {
+        let max_size = max_size.max(Self::MAX_RECORD);
         RingBufferReport {
-            inner: Vec::with_capacity(16 * 1024 * 1024),
+            inner: Vec::with_capacity(max_size),
+            max_size,
+            path,
+            flushed: false,
+            stopped: false,
+        }
+    }
+
+    fn push(&mut self, record: &[u8]) {
+        if self.inner.len() + record.len() > self.max_size {
+            self.flush();
+        }
+        if !self.stopped {
+            self.inner.extend_from_slice(record);
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.stopped || self.inner.is_empty() {
+            return;
+        }
+        let file = if self.flushed {
+            OpenOptions::new().append(true).open(&self.path)
+        } else {
+            File::create(&self.path)
+        };
+        match file.and_then(|mut f| f.write_all(&self.inner)) {
+            Ok(()) => self.flushed = true,
+            Err(error) => {
+                log::error!(
+                    "cannot write ring buffer report {:?}: {}, stop recording it",
+                    self.path,
+                    error,
+                );
+                self.stopped = true;
+            },
         }
+        self.inner.clear();
     }
 
     fn on_poll(&mut self) {
-        self.inner.push(0x00);
+        self.push(&[0x00]);
     }
 
     fn on_pending(&mut self) {
-        self.inner.push(0x01);
+        self.push(&[0x01]);
     }
 
     fn on_ready(&mut self) {
-        self.inner.push(0x02);
+        self.push(&[0x02]);
     }
 
     fn on_pos(&mut self, p_pos: usize, c_pos: usize) {
-        self.inner.push(0x03);
-        self.inner.extend_from_slice(&(c_pos as u64).to_be_bytes());
-        self.inner.extend_from_slice(&(p_pos as u64).to_be_bytes());
+        let mut record = [0x03; Self::MAX_RECORD];
+        record[1..9].copy_from_slice(&(c_pos as u64).to_be_bytes());
+        record[9..].copy_from_slice(&(p_pos as u64).to_be_bytes());
+        self.push(&record);
     }
 }
 
 impl Drop for RingBufferReport {
     fn drop(&mut self) {
-        if !self.inner.is_empty() {
-            let mut f = std::fs::File::create("target/rb_report").unwrap();
-            f.write_all(&self.inner).unwrap();
-        }
+        self.flush();
     }
 }
 
@@ -132,8 +181,21 @@ impl<D> RingBuffer<D> {
         })
     }
 
-    pub fn with_report(mut self) -> Self {
-        self.report = Some(RingBufferReport::new());
+    /// The report is kept in memory up to this many bytes, then appended to the file
+    pub const DEFAULT_REPORT_SIZE: usize = 16 * 1024 * 1024;
+
+    /// Record the events of the polling to `target/rb_report`
+    pub fn with_report(self) -> Self {
+        self.with_report_at("target/rb_report", Self::DEFAULT_REPORT_SIZE)
+    }
+
+    /// Record the events of the polling to the file at `path`,
+    /// at most `max_size` bytes are kept in memory between the writes
+    pub fn with_report_at<P>(mut self, path: P, max_size: usize) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        self.report = Some(RingBufferReport::new(path.into(), max_size));
         self
     }
 
@@ -316,30 +378,60 @@ impl RingBufferSync {
         }
     }
 
-    fn wait(&self, running: &AtomicBool) {
+    // wait for the data at most `timeout`, `false` if nothing is readable,
+    // the error if the fd is closed or broken, polling it again would not wait
+    fn wait(&self, timeout: Duration) -> io::Result<bool> {
         let mut fds = libc::pollfd {
             fd: self.as_raw_fd(),
             events: libc::POLLIN,
             revents: 0,
         };
-        while running.load(Ordering::Relaxed) {
-            match unsafe { libc::poll(&mut fds, 1, 1_000) } {
-                0 => log::debug!("ringbuf wait timeout"),
-                1 => {
-                    if fds.revents & libc::POLLIN != 0 {
-                        break;
-                    }
-                },
-                i32::MIN..=-1 => {
-                    let error = io::Error::last_os_error();
-                    if io::ErrorKind::Interrupted != error.kind() {
-                        log::error!("ringbuf error: {:?}", error);
-                    }
-                },
-                // poll should not return bigger then number of fds, we have 1
-                r @ 2..=i32::MAX => log::error!("ringbuf poll {}", r),
-            }
-            fds.revents = 0;
+        match unsafe { libc::poll(&mut fds, 1, timeout.as_millis() as i32) } {
+            0 => {
+                log::debug!("ringbuf wait timeout");
+                Ok(false)
+            },
+            1 => {
+                if fds.revents & (libc::POLLHUP | libc::POLLERR | libc::POLLNVAL) != 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::BrokenPipe,
+                        format!("ring buffer hangup, revents: 0x{:x}", fds.revents),
+                    ));
+                }
+                Ok(fds.revents & libc::POLLIN != 0)
+            },
+            i32::MIN..=-1 => {
+                let error = io::Error::last_os_error();
+                if io::ErrorKind::Interrupted != error.kind() {
+                    log::error!("ringbuf error: {:?}", error);
+                }
+                Ok(false)
+            },
+            // poll should not return bigger then number of fds, we have 1
+            r @ 2..=i32::MAX => {
+                log::error!("ringbuf poll {}", r);
+                Ok(false)
+            },
+        }
+    }
+
+    /// Read what is available, wait for the data at most `timeout`,
+    /// the empty batch if nothing arrived, so the caller can do its periodic work
+    pub fn read_timeout<D>(&mut self, timeout: Duration) -> io::Result<SmallVec<[D; 64]>>
+    where
+        D: RingBufferData,
+    {
+        match self.read() {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+            x => return x,
+        }
+        if !self.wait(timeout)? {
+            return Ok(SmallVec::new());
+        }
+        match self.read() {
+            // false ready, or the kernel is writing the slice right now
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(SmallVec::new()),
+            x => x,
         }
     }
 
@@ -347,22 +439,13 @@ impl RingBufferSync {
     where
         D: RingBufferData,
     {
-        let mut tries = 0;
-        loop {
-            if tries > 10 {
-                log::warn!("cannot read ring buffer: {} attempts", tries);
-            }
-            match self.read() {
-                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                    self.wait(running);
-                    if !running.load(Ordering::Relaxed) {
-                        break Ok(SmallVec::new());
-                    }
-                },
-                x => break x,
+        while running.load(Ordering::Relaxed) {
+            let data = self.read_timeout(Duration::from_secs(1))?;
+            if !data.is_empty() {
+                return Ok(data);
             }
-            tries += 1;
         }
+        Ok(SmallVec::new())
     }
 }
 
@@ -442,3 +525,37 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+    use super::RingBufferReport;
+
+    #[test]
+    fn report_rotation() {
+        let path = env::temp_dir().join(format!("bpf-ring-buffer-report-{}", std::process::id()));
+        let mut report = RingBufferReport::new(path.clone(), 64);
+        let capacity = report.inner.capacity();
+        for i in 0..1000 {
+            report.on_poll();
+            report.on_pending();
+            if i % 10 == 0 {
+                report.on_pos(i + 1, i);
+            }
+            report.on_ready();
+            assert!(report.inner.len() <= 64);
+        }
+        // the buffer never grows past the cap
+        assert_eq!(report.inner.capacity(), capacity);
+        assert!(report.flushed);
+        drop(report);
+
+        // nothing is lost, the records are in order
+        let content = fs::read(&path).unwrap();
+        assert_eq!(content.len(), 1000 * 3 + 100 * 17);
+        assert_eq!(&content[..4], &[0x00, 0x01, 0x03, 0x00]);
+        assert_eq!(&content[content.len() - 3..], &[0x00, 0x01, 0x02]);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
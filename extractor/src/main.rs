@@ -1,11 +1,16 @@
 // Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
 // SPDX-License-Identifier: MIT
 
+/// Print the decrypted bytes of one recorded message of each type as json `[{ ty, hex }]`,
+/// usage: `extractor [recorder url]`
 #[tokio::main]
 async fn main() {
+    let recorder = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "http://debug.dev.tezedge.com:17742".to_string());
     let types = "connection_message,metadata,ack_message,disconnect,advertise,swap_request,\
         swap_ack,bootstrap,get_current_branch,current_branch,deactivate,\
-        get_current_head,current_head,get_block_headers,block_header,get_operations,operation\
+        get_current_head,current_head,get_block_headers,block_header,get_operations,operation,\
         get_protocols,protocol,get_operation_hashes_for_blocks,operation_hashes_for_block,\
         get_operations_for_blocks,operations_for_blocks";
 
@@ -17,7 +22,7 @@ async fn main() {
 
     let mut examples = Vec::<Example>::new();
     for ty in types.split(',') {
-        let url = format!("http://debug.dev.tezedge.com:17742/v3/messages?types={}&limit=1", ty);
+        let url = format!("{}/v3/messages?types={}&limit=1", recorder, ty);
         let list = reqwest::get(url)
             .await.unwrap()
             .text()
@@ -26,7 +31,7 @@ async fn main() {
 
         if let Some(item) = list.as_array().and_then(|x| x.first()) {
             let id = item.as_object().unwrap().get("id").unwrap().as_u64().unwrap();
-            let url = format!("http://debug.dev.tezedge.com:17742/v3/message/{}", id);
+            let url = format!("{}/v3/message/{}", recorder, id);
             let item = reqwest::get(url)
                 .await.unwrap()
                 .text()
@@ -0,0 +1,111 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::sync::Arc;
+use warp::{
+    Filter, Rejection,
+    reject::{self, Reject},
+    reply::{self, WithHeader, WithStatus, Json},
+    http::StatusCode,
+};
+
+#[derive(Debug)]
+struct Unauthorized;
+
+impl Reject for Unauthorized {}
+
+/// Passes the request with `Authorization: Bearer <token>`, without the token passes any request
+pub fn bearer(
+    token: Option<String>,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone + Sync + Send + 'static {
+    let token = token.map(Arc::<str>::from);
+    warp::header::optional::<String>("authorization")
+        .and_then(move |header: Option<String>| {
+            let token = token.clone();
+            async move {
+                let authorized = match &token {
+                    None => true,
+                    Some(token) => header
+                        .as_deref()
+                        .and_then(|header| header.strip_prefix("Bearer "))
+                        .is_some_and(|t| constant_time_eq(t.as_bytes(), token.as_bytes())),
+                };
+                if authorized {
+                    Ok(())
+                } else {
+                    Err(reject::custom(Unauthorized))
+                }
+            }
+        })
+        .untuple_one()
+}
+
+/// 401 for the request rejected by `bearer`, other rejections are handled as usual
+pub async fn recover(rejection: Rejection) -> Result<WithHeader<WithStatus<Json>>, Rejection> {
+    if rejection.find::<Unauthorized>().is_some() {
+        let r = "missing or invalid bearer token";
+        let reply = reply::with_status(reply::json(&r), StatusCode::UNAUTHORIZED);
+        Ok(reply::with_header(reply, "WWW-Authenticate", "Bearer"))
+    } else {
+        Err(rejection)
+    }
+}
+
+/// The time does not depend on where the tokens differ, only on their length
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use warp::{Filter, http::StatusCode};
+    use super::{bearer, recover, constant_time_eq};
+
+    #[test]
+    fn compare() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(!constant_time_eq(b"", b"secret"));
+    }
+
+    #[tokio::test]
+    async fn filter() {
+        let routes = |token: Option<&str>| {
+            bearer(token.map(str::to_string))
+                .map(|| "ok")
+                .recover(recover)
+        };
+        let request = |header: Option<&str>| {
+            let request = warp::test::request().path("/");
+            match header {
+                Some(header) => request.header("authorization", header),
+                None => request,
+            }
+        };
+
+        let secret = routes(Some("secret"));
+        let rejected = [
+            None,
+            Some("Bearer wrong"),
+            Some("secret"),
+            Some("Basic secret"),
+        ];
+        for header in &rejected {
+            let response = request(*header).reply(&secret).await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{:?}", header);
+            assert_eq!(response.headers()["www-authenticate"], "Bearer");
+        }
+
+        let response = request(Some("Bearer secret")).reply(&secret).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body().as_ref(), b"ok");
+
+        // without the token any request passes
+        let response = request(None).reply(&routes(None)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
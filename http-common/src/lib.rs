@@ -0,0 +1,7 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+// the parts of the http servers shared by the recorder and the memory profiler
+
+pub mod auth;
+pub mod tls;
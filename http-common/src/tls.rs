@@ -0,0 +1,143 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::{fs, future::Future, io, net::SocketAddr, pin::Pin};
+use serde::Deserialize;
+use thiserror::Error;
+use warp::{Filter, Reply};
+
+/// The certificate chain and the private key of the http servers, PEM files,
+/// without it the servers are plain http
+#[derive(Debug, Clone, Deserialize)]
+pub struct TlsConfig {
+    pub cert: String,
+    pub key: String,
+}
+
+#[derive(Error, Debug)]
+pub enum TlsError {
+    #[error("tls: cannot read {}: {}", path, error)]
+    Read { path: String, error: io::Error },
+    #[error("tls: no certificate in {}", _0)]
+    NoCertificate(String),
+    #[error("tls: no pkcs8 or rsa private key in {}", _0)]
+    NoKey(String),
+    #[error("tls: invalid key {}: {}", path, error)]
+    InvalidKey {
+        path: String,
+        error: rustls::TLSError,
+    },
+}
+
+impl TlsConfig {
+    /// Load the files the same way warp does, warp panics in the server task if they are wrong
+    pub fn check(&self) -> Result<(), TlsError> {
+        use rustls::{internal::pemfile, NoClientAuth, ServerConfig};
+
+        let read = |path: &str| {
+            fs::read(path).map_err(|error| TlsError::Read {
+                path: path.to_string(),
+                error,
+            })
+        };
+
+        let cert = read(&self.cert)?;
+        let certs = pemfile::certs(&mut cert.as_slice()).unwrap_or_default();
+        if certs.is_empty() {
+            return Err(TlsError::NoCertificate(self.cert.clone()));
+        }
+        let key = read(&self.key)?;
+        let mut keys = pemfile::pkcs8_private_keys(&mut key.as_slice()).unwrap_or_default();
+        if keys.is_empty() {
+            keys = pemfile::rsa_private_keys(&mut key.as_slice()).unwrap_or_default();
+        }
+        let key = keys
+            .into_iter()
+            .next()
+            .ok_or_else(|| TlsError::NoKey(self.key.clone()))?;
+        ServerConfig::new(NoClientAuth::new())
+            .set_single_cert(certs, key)
+            .map_err(|error| TlsError::InvalidKey {
+                path: self.key.clone(),
+                error,
+            })
+    }
+}
+
+/// Plain http, or https if `tls` is configured, it should be checked before
+pub fn serve<F>(
+    filter: F,
+    addr: impl Into<SocketAddr>,
+    tls: Option<&TlsConfig>,
+) -> Pin<Box<dyn Future<Output = ()> + Send>>
+where
+    F: Filter + Clone + Send + Sync + 'static,
+    F::Extract: Reply,
+{
+    // warp's `run` holds the address across the await, the boxed future must be `Send`
+    let addr: SocketAddr = addr.into();
+    let server = warp::serve(filter);
+    match tls {
+        Some(tls) => Box::pin(server.tls().cert_path(&tls.cert).key_path(&tls.key).run(addr)),
+        None => Box::pin(server.run(addr)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, time::Duration};
+    use warp::Filter;
+    use super::{TlsConfig, TlsError, serve};
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn https() {
+        // self-signed for `localhost`
+        let path = |name: &str| format!("{}/tests/tls/{}", env!("CARGO_MANIFEST_DIR"), name);
+        let tls = TlsConfig {
+            cert: path("cert.pem"),
+            key: path("key.pem"),
+        };
+        tls.check().unwrap();
+
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let addr = ([127, 0, 0, 1], port);
+        let version = warp::path!("version").map(|| warp::reply::json(&"0.1.0"));
+        let server = tokio::spawn(serve(version, addr, Some(&tls)));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let client = reqwest::Client::builder()
+            .danger_accept_invalid_certs(true)
+            .build()
+            .unwrap();
+        let url = format!("https://localhost:{}/version", port);
+        let response = client.get(&url).send().await.unwrap();
+        assert_eq!(response.status(), 200);
+        let body = response.text().await.unwrap();
+        assert!(serde_json::from_str::<String>(&body).is_ok());
+        // plain http is not served
+        let url = format!("http://localhost:{}/version", port);
+        assert!(reqwest::get(&url).await.is_err());
+        server.abort();
+
+        // the errors are reported before the server starts
+        let missing = TlsConfig {
+            cert: path("missing.pem"),
+            ..tls.clone()
+        };
+        assert!(matches!(missing.check(), Err(TlsError::Read { .. })));
+        let no_key = TlsConfig {
+            key: path("cert.pem"),
+            ..tls.clone()
+        };
+        assert!(matches!(no_key.check(), Err(TlsError::NoKey(_))));
+        let no_cert = TlsConfig {
+            cert: path("key.pem"),
+            ..tls
+        };
+        assert!(matches!(no_cert.check(), Err(TlsError::NoCertificate(_))));
+    }
+}
@@ -0,0 +1,65 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+use serde::Serialize;
+
+/// The time window of the capture, shared by the consumer of the events and the server.
+/// The timestamps are milliseconds since the unix epoch.
+pub struct CaptureTime {
+    capture_start: u64,
+    started: Instant,
+    // zero until the first event is processed
+    first_event: AtomicU64,
+    last_event: AtomicU64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CaptureReport {
+    pub capture_start: u64,
+    pub uptime_seconds: u64,
+    pub first_event: Option<u64>,
+    pub last_event: Option<u64>,
+}
+
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+impl Default for CaptureTime {
+    fn default() -> Self {
+        CaptureTime {
+            capture_start: now_millis(),
+            started: Instant::now(),
+            first_event: AtomicU64::new(0),
+            last_event: AtomicU64::new(0),
+        }
+    }
+}
+
+impl CaptureTime {
+    /// Call on each processed event
+    pub fn event(&self) {
+        let now = now_millis();
+        let _ = self
+            .first_event
+            .compare_exchange(0, now, Ordering::Relaxed, Ordering::Relaxed);
+        self.last_event.store(now, Ordering::Relaxed);
+    }
+
+    pub fn report(&self) -> CaptureReport {
+        let non_zero = |v: &AtomicU64| Some(v.load(Ordering::Relaxed)).filter(|v| *v != 0);
+        CaptureReport {
+            capture_start: self.capture_start,
+            uptime_seconds: self.started.elapsed().as_secs(),
+            first_event: non_zero(&self.first_event),
+            last_event: non_zero(&self.last_event),
+        }
+    }
+}
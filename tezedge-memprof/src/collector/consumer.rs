@@ -3,8 +3,10 @@
 
 use std::ops::Deref;
 use std::sync::{Arc, Mutex, atomic::{Ordering, AtomicU32}};
-use bpf_memprof_common::{EventKind, Event};
+use std::{fs::File, io::{self, BufWriter, Read}};
+use bpf_memprof_common::{EventKind, Event, Stack};
 use super::{Reporter, StackResolver, FrameReport, aggregator::Aggregator};
+use crate::{CaptureTime, EventFilter, EventsFileWriter, EventsFileReader};
 
 impl Reporter for Aggregator {
     fn short_report(&self) -> (u64, u64) {
@@ -21,13 +23,9 @@ impl Reporter for Aggregator {
     where
         R: Deref<Target = StackResolver>,
     {
-        let mut report = FrameReport::new(resolver);
+        let mut report = FrameReport::new(resolver, reverse);
         for (value, cache_value, stack) in self.report() {
-            if reverse {
-                report.inner.insert(stack.iter().rev(), value, cache_value);
-            } else {
-                report.inner.insert(stack.iter(), value, cache_value);
-            }
+            report.insert(stack, value, cache_value);
         }
         report.inner.strip(threshold);
 
@@ -42,6 +40,10 @@ pub struct Consumer {
     pid: Arc<AtomicU32>,
     aggregator: Arc<Mutex<Aggregator>>,
     last: Option<EventKind>,
+    capture: Arc<CaptureTime>,
+    filter: Arc<EventFilter>,
+    // the events as they arrive, see `dump_events`
+    events_dump: Option<EventsFileWriter<BufWriter<File>>>,
 }
 
 impl Consumer {
@@ -52,10 +54,46 @@ impl Consumer {
     pub fn pid(&self) -> Arc<AtomicU32> {
         self.pid.clone()
     }
+
+    /// The consumer is created at start, so it is the start of the capture
+    pub fn capture(&self) -> Arc<CaptureTime> {
+        self.capture.clone()
+    }
+
+    /// The allocations to track, the server changes it at runtime
+    pub fn filter(&self) -> Arc<EventFilter> {
+        self.filter.clone()
+    }
+
+    /// Append each event to the file as it arrives, before it is processed,
+    /// `replay` rebuilds the same history from the file
+    pub fn dump_events(&mut self, writer: EventsFileWriter<BufWriter<File>>) {
+        self.events_dump = Some(writer);
+    }
+
+    /// Process the events recorded by `dump_events`, as if they arrived from the kernel,
+    /// return the number of events
+    pub fn replay<R>(&mut self, reader: &mut EventsFileReader<R>) -> io::Result<u64>
+    where
+        R: Read,
+    {
+        let mut count = 0;
+        while let Some(data) = reader.read()? {
+            self.arrive(&data);
+            count += 1;
+        }
+        Ok(count)
+    }
 }
 
 impl Consumer {
     pub fn arrive(&mut self, data: &[u8]) {
+        if let Some(dump) = &mut self.events_dump {
+            if let Err(error) = dump.write(data) {
+                log::error!("failed to dump event: {}, stop dumping", error);
+                self.events_dump = None;
+            }
+        }
         let event = match Event::from_slice(data) {
             Ok(v) => v,
             Err(error) => {
@@ -72,9 +110,7 @@ impl Consumer {
         }
         match &event.event {
             &EventKind::PageAlloc(ref v) if v.pfn.0 != 0 => {
-                self.has_pid = true;
-                self.pid.store(event.pid, Ordering::SeqCst);
-                self.aggregator.lock().unwrap().track_alloc(v.pfn.0 as u32, v.order as u8, &event.stack);
+                self.alloc(event.pid, v.pfn.0 as u32, v.order, v.gfp_flags.0, &event.stack);
             }
             &EventKind::PageFree(ref v) if v.pfn.0 != 0 && self.has_pid => {
                 self.aggregator.lock().unwrap().track_free(v.pfn.0 as u32);
@@ -90,6 +126,60 @@ impl Consumer {
             }
             _ => (),
         }
+        self.capture.event();
         self.last = Some(event.event);
     }
+
+    /// The page allocated again is freed before, if the new allocation is filtered out,
+    /// forget the page, so its later free does not affect the tracked allocations
+    fn alloc(&mut self, pid: u32, page: u32, order: u32, gfp_flags: u32, stack: &Stack) {
+        let mut aggregator = self.aggregator.lock().unwrap();
+        if self.filter.matches(order, gfp_flags, pid) {
+            self.has_pid = true;
+            self.pid.store(pid, Ordering::SeqCst);
+            aggregator.track_alloc(page, order as u8, stack);
+        } else {
+            aggregator.track_free(page);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bpf_memprof_common::Stack;
+    use crate::{Reporter, StackResolver, FilterConfig};
+    use super::Consumer;
+
+    #[test]
+    fn order_filter() {
+        let mut consumer = Consumer::default();
+        consumer.filter().set(FilterConfig {
+            min_order: Some(1),
+            max_order: Some(2),
+            ..FilterConfig::default()
+        });
+        // the site `func_N` allocates the page of order `N - 1`
+        for order in 0..4 {
+            let stack = Stack::from_frames(&[order as u64 + 1]);
+            consumer.alloc(1, 0x100 + order, order, 0, &stack);
+        }
+
+        let resolver = StackResolver::mock();
+        let aggregator = consumer.reporter();
+        let sites = |aggregator: &super::Aggregator| {
+            aggregator
+                .tree_report(&resolver, 0, false)
+                .top_sites(10)
+                .into_iter()
+                .map(|site| (site.name, site.value))
+                .collect::<Vec<_>>()
+        };
+        let expected = [("func_3".to_string(), 4 * 4), ("func_2".to_string(), 2 * 4)];
+        assert_eq!(sites(&aggregator.lock().unwrap()), expected);
+
+        // the page of `func_2` is allocated again, but the allocation is filtered out
+        consumer.alloc(1, 0x101, 3, 0, &Stack::from_frames(&[4]));
+        let expected = [("func_3".to_string(), 4 * 4)];
+        assert_eq!(sites(&aggregator.lock().unwrap()), expected);
+    }
 }
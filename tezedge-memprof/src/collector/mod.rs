@@ -8,3 +8,6 @@ pub use self::aggregator::{Aggregator, RawEvent};
 
 mod consumer;
 pub use self::consumer::Consumer;
+
+mod snapshot;
+pub use self::snapshot::Snapshot;
@@ -0,0 +1,102 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::ops::Deref;
+use serde::{Serialize, Deserialize};
+use bpf_memprof_common::Hex64;
+use super::{Reporter, StackResolver, FrameReport, aggregator::Aggregator};
+
+/// The usage by stack, enough to build the reports without live bpf attachment.
+#[derive(Default, Serialize, Deserialize)]
+pub struct Snapshot {
+    groups: Vec<SnapshotGroup>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SnapshotGroup {
+    value: u64,
+    cache_value: u64,
+    stack: Vec<u64>,
+}
+
+impl Aggregator {
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            groups: self
+                .report()
+                .map(|(value, cache_value, stack)| SnapshotGroup {
+                    value,
+                    cache_value,
+                    stack: stack.iter().map(|ip| ip.0).collect(),
+                })
+                .collect(),
+        }
+    }
+
+    pub fn store_snapshot(&self) {
+        log::info!("writing history...");
+        let result = std::fs::File::create("target/history.json")
+            .map_err(|e| e.to_string())
+            .and_then(|f| serde_json::to_writer(f, &self.snapshot()).map_err(|e| e.to_string()));
+        match result {
+            Ok(()) => log::info!("done history"),
+            Err(error) => log::error!("failed to write history: {}", error),
+        }
+    }
+}
+
+impl Reporter for Snapshot {
+    fn short_report(&self) -> (u64, u64) {
+        let (mut value, mut cache_value) = (0, 0);
+        for group in &self.groups {
+            value += group.value;
+            cache_value += group.cache_value;
+        }
+
+        (value, cache_value)
+    }
+
+    fn tree_report<R>(&self, resolver: R, threshold: u64, reverse: bool) -> FrameReport<R>
+    where
+        R: Deref<Target = StackResolver>,
+    {
+        let mut report = FrameReport::new(resolver, reverse);
+        for group in &self.groups {
+            let stack = group.stack.iter().cloned().map(Hex64).collect::<Vec<_>>();
+            report.insert(&stack, group.value, group.cache_value);
+        }
+        report.inner.strip(threshold);
+
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bpf_memprof_common::Stack;
+    use crate::{Aggregator, Reporter, StackResolver};
+    use super::Snapshot;
+
+    #[test]
+    fn round_trip() {
+        let mut aggregator = Aggregator::default();
+        for i in 1..100 {
+            aggregator.track_alloc(i, 0, &Stack::from_frames(&[i as u64 / 3, i as u64 % 5]));
+        }
+        aggregator.mark_cache(7, true);
+        aggregator.track_free(11);
+
+        let s = serde_json::to_string(&aggregator.snapshot()).unwrap();
+        let snapshot = serde_json::from_str::<Snapshot>(&s).unwrap();
+        assert_eq!(snapshot.short_report(), aggregator.short_report());
+
+        let resolver = StackResolver::mock();
+        let expected = aggregator.tree_report(&resolver, 0, false);
+        let actual = snapshot.tree_report(&resolver, 0, false);
+        assert_eq!(
+            serde_json::to_value(&actual).unwrap(),
+            serde_json::to_value(&expected).unwrap(),
+        );
+    }
+}
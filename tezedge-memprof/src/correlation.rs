@@ -0,0 +1,329 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs::File,
+    io::{self, BufRead, BufReader},
+    sync::{Arc, Mutex, atomic::{Ordering, AtomicBool, AtomicU32}},
+    thread,
+    time::Duration,
+};
+use serde::{Serialize, Deserialize};
+use thiserror::Error;
+use super::capture::now_millis;
+
+/// Do not allow the client to request too many buckets, the same limit as the recorder has
+pub const MAX_BUCKETS: u64 = 0x10000;
+
+/// The anonymous resident memory of the process in kilobytes, `RssAnon` of `/proc/<pid>/status`
+pub fn rss_anon(pid: u32) -> io::Result<u64> {
+    let f = File::open(format!("/proc/{}/status", pid))?;
+    let reader = BufReader::new(f);
+    let mut v = 0;
+    for line in reader.lines() {
+        let line = line?;
+        let mut words = line.split_whitespace();
+        if let Some("RssAnon:") = words.next() {
+            v = words.next().map(|s| s.parse().unwrap_or(0)).unwrap_or(0);
+        }
+    }
+
+    Ok(v)
+}
+
+/// Do not let the slow recorder hold the request to `/v1/correlation`
+pub const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The sources of `/v1/correlation`, the recorder is not queried without its url,
+/// for example `http://localhost:17732`, the token is sent if the recorder requires one
+pub struct Correlation {
+    pub rss: Arc<RssHistory>,
+    pub recorder_url: Option<String>,
+    pub recorder_token: Option<String>,
+    client: reqwest::Client,
+}
+
+impl Default for Correlation {
+    fn default() -> Self {
+        Correlation::new(Arc::default(), None, None)
+    }
+}
+
+impl Correlation {
+    /// The token the recorder requires, it is not passed in the command line to keep it hidden
+    pub const RECORDER_TOKEN_VAR: &'static str = "TEZEDGE_MEMPROF_RECORDER_TOKEN";
+
+    /// The token from `RECORDER_TOKEN_VAR`, the empty one means no token
+    pub fn recorder_token() -> Option<String> {
+        std::env::var(Self::RECORDER_TOKEN_VAR)
+            .ok()
+            .filter(|token| !token.is_empty())
+    }
+
+    pub fn new(
+        rss: Arc<RssHistory>,
+        recorder_url: Option<String>,
+        recorder_token: Option<String>,
+    ) -> Self {
+        // one client for all requests, it keeps the connections to the recorder alive
+        let client = reqwest::Client::builder()
+            .timeout(FETCH_TIMEOUT)
+            .build()
+            .expect("tls backend or resolver cannot be initialized");
+        Correlation {
+            rss,
+            recorder_url,
+            recorder_token,
+            client,
+        }
+    }
+
+    /// Fetch the message counts from `/v3/throughput` of the recorder served at `url`
+    pub async fn fetch_throughput(
+        &self,
+        url: &str,
+        from: u64,
+        to: u64,
+        width: u64,
+    ) -> Result<Vec<ThroughputBucket>, FetchError> {
+        let url = format!(
+            "{}/v3/throughput?from={}&to={}&bucket={}",
+            url.trim_end_matches('/'),
+            from,
+            to,
+            width,
+        );
+        let mut request = self.client.get(&url);
+        if let Some(token) = &self.recorder_token {
+            request = request.bearer_auth(token);
+        }
+        let response = request.send().await?;
+        if !response.status().is_success() {
+            return Err(FetchError::Status(response.status().as_u16()));
+        }
+        let body = response.bytes().await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+}
+
+/// The resident memory of the node at the moment, the timestamp is milliseconds since unix epoch
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RssSample {
+    pub timestamp: u64,
+    pub rss_anon: u64,
+}
+
+/// The samples of the resident memory of the node, the oldest are dropped above the capacity
+pub struct RssHistory {
+    capacity: usize,
+    samples: Mutex<VecDeque<RssSample>>,
+}
+
+impl Default for RssHistory {
+    fn default() -> Self {
+        RssHistory::new(RssHistory::DEFAULT_CAPACITY)
+    }
+}
+
+impl RssHistory {
+    /// A day of samples taken every second
+    pub const DEFAULT_CAPACITY: usize = 86400;
+
+    pub fn new(capacity: usize) -> Self {
+        RssHistory {
+            capacity,
+            samples: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn record(&self, sample: RssSample) {
+        let mut samples = self.samples.lock().unwrap();
+        if samples.len() >= self.capacity {
+            samples.pop_front();
+        }
+        samples.push_back(sample);
+    }
+
+    /// The samples in `[from, to)`
+    pub fn samples(&self, from: u64, to: u64) -> Vec<RssSample> {
+        self.samples
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|sample| sample.timestamp >= from && sample.timestamp < to)
+            .cloned()
+            .collect()
+    }
+
+    /// Sample the memory of the process every `interval` until `running` is reset,
+    /// nothing is sampled while the process is not known
+    pub fn spawn(
+        self: Arc<Self>,
+        pid: Arc<AtomicU32>,
+        interval: Duration,
+        running: Arc<AtomicBool>,
+    ) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            while running.load(Ordering::Relaxed) {
+                thread::sleep(interval);
+                let pid = pid.load(Ordering::Relaxed);
+                if pid == 0 {
+                    continue;
+                }
+                match rss_anon(pid) {
+                    Ok(rss_anon) => self.record(RssSample {
+                        timestamp: now_millis(),
+                        rss_anon,
+                    }),
+                    Err(error) => log::debug!("cannot sample memory of {}: {}", pid, error),
+                }
+            }
+        })
+    }
+}
+
+/// The peak resident memory of the samples in `[timestamp, timestamp + width)`,
+/// `None` if there is no sample
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RssBucket {
+    pub timestamp: u64,
+    pub rss_anon: Option<u64>,
+}
+
+/// For given samples, take the peak into buckets of `width` milliseconds
+/// in range `[from, to)`, the same buckets as `/v3/throughput` of the recorder has
+pub fn rss_buckets(samples: &[RssSample], from: u64, to: u64, width: u64) -> Vec<RssBucket> {
+    let width = width.max(1);
+    let number = (to.saturating_sub(from).saturating_add(width - 1) / width).min(MAX_BUCKETS);
+    let mut v = (0..number)
+        .map(|i| RssBucket {
+            timestamp: from + i * width,
+            rss_anon: None,
+        })
+        .collect::<Vec<_>>();
+    for sample in samples.iter().filter(|s| s.timestamp >= from && s.timestamp < to) {
+        if let Some(bucket) = v.get_mut(((sample.timestamp - from) / width) as usize) {
+            bucket.rss_anon = Some(bucket.rss_anon.unwrap_or(0).max(sample.rss_anon));
+        }
+    }
+    v
+}
+
+/// Number of messages and bytes the recorder captured in `[timestamp, timestamp + width)`,
+/// as `/v3/throughput` of the recorder serves
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+pub struct ThroughputBucket {
+    pub timestamp: u64,
+    pub count: u64,
+    pub bytes: u64,
+}
+
+/// The memory and the network activity in `[timestamp, timestamp + width)`,
+/// `None` where the series has no such bucket
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CorrelationBucket {
+    pub timestamp: u64,
+    pub rss_anon: Option<u64>,
+    pub messages: Option<u64>,
+    pub bytes: Option<u64>,
+}
+
+impl CorrelationBucket {
+    fn empty(timestamp: u64) -> Self {
+        CorrelationBucket {
+            timestamp,
+            rss_anon: None,
+            messages: None,
+            bytes: None,
+        }
+    }
+}
+
+/// Align the buckets of both series by timestamp, sorted by timestamp
+pub fn merge(rss: &[RssBucket], throughput: &[ThroughputBucket]) -> Vec<CorrelationBucket> {
+    let mut buckets = BTreeMap::new();
+    for r in rss {
+        let bucket = buckets
+            .entry(r.timestamp)
+            .or_insert_with(|| CorrelationBucket::empty(r.timestamp));
+        bucket.rss_anon = r.rss_anon;
+    }
+    for t in throughput {
+        let bucket = buckets
+            .entry(t.timestamp)
+            .or_insert_with(|| CorrelationBucket::empty(t.timestamp));
+        bucket.messages = Some(t.count);
+        bucket.bytes = Some(t.bytes);
+    }
+    buckets.into_iter().map(|(_, bucket)| bucket).collect()
+}
+
+#[derive(Debug, Error)]
+pub enum FetchError {
+    #[error("request to the recorder failed: {}", _0)]
+    Request(#[from] reqwest::Error),
+    #[error("the recorder responded with status {}", _0)]
+    Status(u16),
+    #[error("cannot parse the response of the recorder: {}", _0)]
+    Parse(#[from] serde_json::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        RssSample, RssHistory, ThroughputBucket, CorrelationBucket, rss_buckets, merge,
+    };
+
+    #[test]
+    fn merge_series() {
+        // the memory grows while the peer floods the node at `[2000, 3000)`
+        let samples = [(1000, 100), (1500, 120), (2000, 150), (2999, 400), (4000, 410)]
+            .iter()
+            .map(|&(timestamp, rss_anon)| RssSample { timestamp, rss_anon })
+            .collect::<Vec<_>>();
+        let rss = rss_buckets(&samples, 1000, 4000, 1000);
+        assert_eq!(rss.len(), 3);
+        // the recorder started capturing earlier
+        let throughput = [(0, 1, 10), (1000, 3, 300), (2000, 500, 50000), (3000, 2, 200)]
+            .iter()
+            .map(|&(timestamp, count, bytes)| ThroughputBucket { timestamp, count, bytes })
+            .collect::<Vec<_>>();
+
+        let bucket = |timestamp, rss_anon, messages, bytes| CorrelationBucket {
+            timestamp,
+            rss_anon,
+            messages,
+            bytes,
+        };
+        assert_eq!(
+            merge(&rss, &throughput),
+            [
+                bucket(0, None, Some(1), Some(10)),
+                bucket(1000, Some(120), Some(3), Some(300)),
+                bucket(2000, Some(400), Some(500), Some(50000)),
+                bucket(3000, None, Some(2), Some(200)),
+            ],
+        );
+        // no recorder
+        assert!(merge(&rss, &[]).iter().all(|b| b.messages.is_none()));
+    }
+
+    #[test]
+    fn history_capacity() {
+        let history = RssHistory::new(3);
+        for timestamp in 0..5 {
+            history.record(RssSample {
+                timestamp,
+                rss_anon: 100,
+            });
+        }
+        let timestamps = history
+            .samples(0, u64::MAX)
+            .into_iter()
+            .map(|s| s.timestamp)
+            .collect::<Vec<_>>();
+        assert_eq!(timestamps, [2, 3, 4]);
+        assert_eq!(history.samples(3, 4).len(), 1);
+    }
+}
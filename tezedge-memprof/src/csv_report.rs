@@ -0,0 +1,170 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    ops::Deref,
+    path::PathBuf,
+    sync::{Arc, Mutex, RwLock, atomic::{Ordering, AtomicBool}},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use super::{FrameReport, Reporter, StackResolver};
+
+/// Appends the top allocation sites to the csv file periodically, so the time series
+/// of the biggest consumers accumulates. The file is rotated to `<path>.1` when it exceeds
+/// the size, so at most two files of at most `max_size` bytes are kept.
+pub struct CsvReport {
+    path: PathBuf,
+    top: usize,
+    max_size: u64,
+}
+
+impl CsvReport {
+    pub const DEFAULT_TOP: usize = 20;
+    pub const DEFAULT_MAX_SIZE: u64 = 64 << 20;
+
+    const HEADER: &'static str = "timestamp,symbol,outstanding_bytes,count\n";
+
+    pub fn new<P>(path: P, top: usize, max_size: u64) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        CsvReport {
+            path: path.into(),
+            top,
+            max_size,
+        }
+    }
+
+    fn rotated_path(&self) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".1");
+        path.into()
+    }
+
+    /// Append a row per site, `timestamp` is seconds since unix epoch,
+    /// the `count` is the number of distinct stacks allocated at the site
+    pub fn write<R>(&self, report: &FrameReport<R>, timestamp: u64) -> io::Result<()>
+    where
+        R: Deref<Target = StackResolver>,
+    {
+        let rows = report
+            .top_sites(self.top)
+            .into_iter()
+            .map(|site| {
+                let name = quote(&site.name);
+                format!("{},{},{},{}\n", timestamp, name, site.value * 1024, site.count)
+            })
+            .collect::<String>();
+
+        let mut size = fs::metadata(&self.path).map(|m| m.len()).unwrap_or(0);
+        if size != 0 && size + rows.len() as u64 > self.max_size {
+            fs::rename(&self.path, self.rotated_path())?;
+            size = 0;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        if size == 0 {
+            file.write_all(Self::HEADER.as_bytes())?;
+        }
+        file.write_all(rows.as_bytes())
+    }
+
+    /// Write the report every `interval` until `running` is reset
+    pub fn spawn<T>(
+        self,
+        reporter: Arc<Mutex<T>>,
+        resolver: Arc<RwLock<StackResolver>>,
+        interval: Duration,
+        running: Arc<AtomicBool>,
+    ) -> thread::JoinHandle<()>
+    where
+        T: Reporter + Send + 'static,
+    {
+        thread::spawn(move || {
+            while running.load(Ordering::Relaxed) {
+                thread::sleep(interval);
+                let timestamp = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or(0);
+                let resolver = resolver.read().unwrap();
+                // the threshold is irrelevant, only the first level is written
+                let report = reporter.lock().unwrap().tree_report(resolver, 0, false);
+                if let Err(error) = self.write(&report, timestamp) {
+                    log::error!("failed to write csv report {}: {}", self.path.display(), error);
+                }
+            }
+        })
+    }
+}
+
+/// The demangled names contain commas
+fn quote(s: &str) -> String {
+    if s.contains(&[',', '"', '\n'][..]) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+    use bpf_memprof_common::Stack;
+    use crate::{Aggregator, Reporter, StackResolver};
+    use super::{CsvReport, quote};
+
+    #[test]
+    fn intervals() {
+        let path = env::temp_dir().join(format!("tezedge-memprof-csv-{}", std::process::id()));
+        let csv = CsvReport::new(&path, 10, CsvReport::DEFAULT_MAX_SIZE);
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(csv.rotated_path());
+
+        let resolver = StackResolver::mock();
+        let mut aggregator = Aggregator::default();
+        aggregator.track_alloc(1, 0, &Stack::from_frames(&[1, 10]));
+        aggregator.track_alloc(2, 0, &Stack::from_frames(&[1, 11]));
+        aggregator.track_alloc(3, 0, &Stack::from_frames(&[1, 11]));
+        aggregator.track_alloc(4, 0, &Stack::from_frames(&[2, 10]));
+        csv.write(&aggregator.tree_report(&resolver, 0, false), 1000).unwrap();
+        aggregator.track_free(1);
+        aggregator.track_free(4);
+        csv.write(&aggregator.tree_report(&resolver, 0, false), 1005).unwrap();
+
+        let text = fs::read_to_string(&path).unwrap();
+        let lines = text.lines().collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            [
+                "timestamp,symbol,outstanding_bytes,count",
+                "1000,func_1,12288,2",
+                "1000,func_2,4096,1",
+                "1005,func_1,8192,1",
+            ],
+        );
+
+        // too small, rotated on each write
+        let csv = CsvReport::new(&path, 10, 64);
+        csv.write(&aggregator.tree_report(&resolver, 0, false), 1010).unwrap();
+        let rotated = fs::read_to_string(csv.rotated_path()).unwrap();
+        assert_eq!(rotated, text);
+        let text = fs::read_to_string(&path).unwrap();
+        assert_eq!(text, "timestamp,symbol,outstanding_bytes,count\n1010,func_1,8192,1\n");
+
+        fs::remove_file(csv.rotated_path()).unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn quoted() {
+        assert_eq!(quote("malloc"), "malloc");
+        assert_eq!(quote("<A as B<C, D>>::f"), "\"<A as B<C, D>>::f\"");
+        assert_eq!(quote("say \"hi\", x"), "\"say \"\"hi\"\", x\"");
+    }
+}
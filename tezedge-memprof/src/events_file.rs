@@ -0,0 +1,174 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Recorded stream of the events of the bpf module, allows to rebuild the history offline,
+//! without root and live kernel, see `tezedge-memprof --events-file`.
+//! The file starts with the header, `MAGIC` followed by `VERSION`, 4 bytes little endian.
+//! Then the records, each is 4 bytes little endian length, then the event as it is
+//! in the ring buffer, the bytes `Event::from_slice` reads, their integers are native endian,
+//! so the file is replayed on the machine of the same endianness.
+
+use std::{
+    convert::TryFrom,
+    io::{self, Read, Write},
+};
+
+pub const MAGIC: [u8; 8] = *b"TZMEMEVT";
+pub const VERSION: u32 = 1;
+
+pub struct EventsFileWriter<W> {
+    inner: W,
+}
+
+impl<W> EventsFileWriter<W>
+where
+    W: Write,
+{
+    /// The new file, writes the header
+    pub fn new(mut inner: W) -> io::Result<Self> {
+        inner.write_all(&MAGIC)?;
+        inner.write_all(&VERSION.to_le_bytes())?;
+        Ok(EventsFileWriter { inner })
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.inner.write_all(&(data.len() as u32).to_le_bytes())?;
+        self.inner.write_all(data)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub struct EventsFileReader<R> {
+    inner: R,
+}
+
+impl<R> EventsFileReader<R>
+where
+    R: Read,
+{
+    /// Check the header
+    pub fn new(mut inner: R) -> io::Result<Self> {
+        let mut header = [0; 12];
+        inner.read_exact(&mut header)?;
+        if header[..8] != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a memprof events file",
+            ));
+        }
+        let version = u32::from_le_bytes(TryFrom::try_from(&header[8..]).unwrap());
+        if version != VERSION {
+            let msg = format!("unsupported memprof events file version {}", version);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        }
+        Ok(EventsFileReader { inner })
+    }
+
+    /// The next event as the bpf module put it in the ring buffer, `None` at the end of the file
+    pub fn read(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut length = [0; 4];
+        match self.inner.read_exact(&mut length) {
+            Ok(()) => (),
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(error) => return Err(error),
+        }
+        let mut data = vec![0; u32::from_le_bytes(length) as usize];
+        self.inner.read_exact(&mut data)?;
+        Ok(Some(data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env,
+        fs::{self, File},
+        io::{BufReader, BufWriter},
+    };
+    use crate::{Consumer, Aggregator, Reporter, StackResolver};
+    use super::{EventsFileWriter, EventsFileReader};
+
+    /// The event of `mm_page_alloc` as the bpf module puts it in the ring buffer
+    fn page_alloc(pid: u32, pfn: u64, order: u32, stack: &[u64]) -> Vec<u8> {
+        let mut v = vec![0; 8];
+        v.extend_from_slice(&pid.to_ne_bytes());
+        v.extend_from_slice(&7u32.to_ne_bytes());
+        v.extend_from_slice(&pfn.to_ne_bytes());
+        v.extend_from_slice(&order.to_ne_bytes());
+        // gfp flags and migrate type
+        v.extend_from_slice(&[0; 8]);
+        v.extend_from_slice(&(stack.len() as u64).to_ne_bytes());
+        for ip in stack {
+            v.extend_from_slice(&ip.to_ne_bytes());
+        }
+        v
+    }
+
+    /// The event of `mm_page_free`, without the stack
+    fn page_free(pid: u32, pfn: u64) -> Vec<u8> {
+        let mut v = vec![0; 8];
+        v.extend_from_slice(&pid.to_ne_bytes());
+        v.extend_from_slice(&10u32.to_ne_bytes());
+        v.extend_from_slice(&pfn.to_ne_bytes());
+        v.extend_from_slice(&0u32.to_ne_bytes());
+        v.extend_from_slice(&0u64.to_ne_bytes());
+        v
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = env::temp_dir().join(format!("tezedge-memprof-events-{}", std::process::id()));
+        let events = [
+            page_alloc(1, 0x100, 0, &[1]),
+            page_alloc(1, 0x101, 2, &[2]),
+            page_alloc(1, 0x102, 1, &[3]),
+            page_free(1, 0x100),
+            page_alloc(1, 0x103, 0, &[2]),
+        ];
+
+        // the live consumer dumps the events as they arrive
+        let mut live = Consumer::default();
+        let file = File::create(&path).unwrap();
+        live.dump_events(EventsFileWriter::new(BufWriter::new(file)).unwrap());
+        for event in &events {
+            live.arrive(event);
+        }
+        let live_reporter = live.reporter();
+        // the file is flushed
+        drop(live);
+
+        // the history is rebuilt from the file alone
+        let mut offline = Consumer::default();
+        let file = BufReader::new(File::open(&path).unwrap());
+        let mut reader = EventsFileReader::new(file).unwrap();
+        assert_eq!(offline.replay(&mut reader).unwrap(), events.len() as u64);
+        assert_eq!(offline.pid().load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        let resolver = StackResolver::mock();
+        let sites = |aggregator: &Aggregator| {
+            aggregator
+                .tree_report(&resolver, 0, false)
+                .top_sites(10)
+                .into_iter()
+                .map(|site| (site.name, site.value))
+                .collect::<Vec<_>>()
+        };
+        let offline_reporter = offline.reporter();
+        let live = live_reporter.lock().unwrap();
+        let offline = offline_reporter.lock().unwrap();
+        // the page of `func_1` is freed
+        let expected = [("func_2".to_string(), 4 * 4 + 4), ("func_3".to_string(), 2 * 4)];
+        assert_eq!(sites(&live), expected);
+        assert_eq!(sites(&offline), expected);
+        assert_eq!(live.short_report(), offline.short_report());
+
+        // not an events file
+        fs::write(&path, b"{\"history\": []}").unwrap();
+        assert!(EventsFileReader::new(File::open(&path).unwrap()).is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
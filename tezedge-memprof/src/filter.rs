@@ -0,0 +1,53 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::sync::RwLock;
+use serde::{Serialize, Deserialize};
+
+/// Which page allocations are tracked, each missing field matches anything.
+/// Only the allocations are filtered, never the frees, so the page tracked
+/// before the filter changed is still freed.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FilterConfig {
+    // inclusive, the allocation is `1 << order` pages
+    pub min_order: Option<u32>,
+    pub max_order: Option<u32>,
+    // the allocation has any of these gfp flags
+    pub gfp_mask: Option<u32>,
+    pub pid: Option<u32>,
+}
+
+impl FilterConfig {
+    pub fn is_valid(&self) -> bool {
+        match (self.min_order, self.max_order) {
+            (Some(min), Some(max)) => min <= max,
+            _ => true,
+        }
+    }
+
+    pub fn matches(&self, order: u32, gfp_flags: u32, pid: u32) -> bool {
+        self.min_order.map_or(true, |min| order >= min)
+            && self.max_order.map_or(true, |max| order <= max)
+            && self.gfp_mask.map_or(true, |mask| gfp_flags & mask != 0)
+            && self.pid.map_or(true, |p| pid == p)
+    }
+}
+
+/// The filter shared by the consumer of the events and the server, see `/v1/config`
+#[derive(Default)]
+pub struct EventFilter(RwLock<FilterConfig>);
+
+impl EventFilter {
+    pub fn get(&self) -> FilterConfig {
+        self.0.read().unwrap().clone()
+    }
+
+    pub fn set(&self, config: FilterConfig) {
+        *self.0.write().unwrap() = config;
+    }
+
+    pub fn matches(&self, order: u32, gfp_flags: u32, pid: u32) -> bool {
+        self.0.read().unwrap().matches(order, gfp_flags, pid)
+    }
+}
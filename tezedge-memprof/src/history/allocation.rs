@@ -207,16 +207,11 @@ impl Reporter for AllocationState {
     where
         R: Deref<Target = StackResolver>,
     {
-        let mut report = FrameReport::new(resolver);
+        let mut report = FrameReport::new(resolver, reverse);
         for usage in self.group.iter() {
             let value = (usage.node as u64) * 4;
             let cache_value = (usage.cache as u64) * 4;
-
-            if reverse {
-                report.inner.insert(usage.stack.0.iter().rev(), value, cache_value);
-            } else {
-                report.inner.insert(usage.stack.0.iter(), value, cache_value);
-            }
+            report.insert(&usage.stack.0, value, cache_value);
         }
         report.inner.strip(threshold);
 
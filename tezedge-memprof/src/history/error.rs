@@ -4,7 +4,7 @@
 use serde::Serialize;
 use super::page::Page;
 
-#[derive(Default, Serialize)]
+#[derive(Default, Clone, Serialize)]
 pub struct ErrorReport {
     enabled: bool,
     double_free: Vec<Page>,
@@ -18,6 +18,13 @@ impl ErrorReport {
         self.enabled = true;
     }
 
+    /// Forget the reported pages, keep the report enabled or disabled
+    pub fn reset(&mut self) {
+        self.double_free.clear();
+        self.without_alloc.clear();
+        self.double_alloc.clear();
+    }
+
     pub fn double_free(&mut self, page: &Page) {
         if self.enabled {
             self.double_free.push(page.clone());
@@ -32,11 +32,19 @@ impl Serialize for StackShort {
     }
 }
 
-#[derive(Default, Serialize)]
+/// The amount allocated and freed since the last snapshot with reset
+#[derive(Default, Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct IntervalDelta {
+    pub allocated_kib: u64,
+    pub freed_kib: u64,
+}
+
+#[derive(Default, Clone, Serialize)]
 pub struct History<H> {
     error_report: ErrorReport,
     group: HashMap<StackShort, HashMap<Page, H>>,
     last_stack: HashMap<Page, StackShort>,
+    interval: IntervalDelta,
 }
 
 impl<H> Tracker for History<H>
@@ -46,24 +54,31 @@ where
     fn track_alloc(&mut self, page: Page, stack: &Stack, flags: Hex32, pid: u32) {
         let _ = pid;
         let stack = StackShort::new(stack);
+        let size_kib = page.size_kib();
 
         // if we have a last_stack for some page then `self.group` contains entry for this stack
         // and the entry contains history for the page, so unwrap here is ok
         if let Some(last_stack) = self.last_stack.get(&page) {
             if last_stack.eq(&stack) {
                 let history = self.group.get_mut(last_stack).unwrap().get_mut(&page).unwrap();
-                Self::track_alloc_error(&mut self.error_report, history, &page, flags);
+                if Self::track_alloc_error(&mut self.error_report, history, &page, flags) {
+                    self.interval.allocated_kib += size_kib;
+                }
             } else {
                 // fix it to track precise history, do not remove it in previous stack
                 let mut history = self.group.get_mut(last_stack).unwrap().remove(&page).unwrap();
-                Self::track_alloc_error(&mut self.error_report, &mut history, &page, flags);
+                if Self::track_alloc_error(&mut self.error_report, &mut history, &page, flags) {
+                    self.interval.allocated_kib += size_kib;
+                }
                 self.group.entry(stack.clone()).or_default().insert(page.clone(), history);
                 self.last_stack.insert(page, stack);
             }
         } else {
             let group = self.group.entry(stack.clone()).or_default();
             let history = group.entry(page.clone()).or_default();
-            Self::track_alloc_error(&mut self.error_report, history, &page, flags);
+            if Self::track_alloc_error(&mut self.error_report, history, &page, flags) {
+                self.interval.allocated_kib += size_kib;
+            }
             self.last_stack.insert(page, stack);
         }
     }
@@ -72,7 +87,9 @@ where
         let _ = pid; // TODO:
         if let Some(stack) = self.last_stack.get(&page).cloned() {
             let history = self.group.entry(stack.clone()).or_default().entry(page.clone()).or_default();
-            Self::track_free_error(&mut self.error_report, history, &page);
+            if Self::track_free_error(&mut self.error_report, history, &page) {
+                self.interval.freed_kib += page.size_kib();
+            }
 
             if history.is_empty() {
                 let group = self.group.get_mut(&stack).unwrap();
@@ -124,7 +141,7 @@ where
     where
         R: Deref<Target = StackResolver>,
     {
-        let mut report = FrameReport::new(resolver);
+        let mut report = FrameReport::new(resolver, reverse);
         for (stack, group) in &self.group {
             let mut value = 0;
             let mut cache_value = 0;
@@ -136,11 +153,7 @@ where
                     }
                 }
             }
-            if reverse {
-                report.inner.insert(stack.0.iter().rev(), value, cache_value);
-            } else {
-                report.inner.insert(stack.0.iter(), value, cache_value);
-            }
+            report.insert(&stack.0, value, cache_value);
         }
         report.inner.strip(threshold);
 
@@ -152,24 +165,53 @@ impl<H> History<H>
 where
     H: PageHistory + Default,
 {
-    fn track_alloc_error(error_report: &mut ErrorReport, history: &mut H, page: &Page, flags: Hex32) {
+    /// Returns `true` if the page is newly allocated
+    fn track_alloc_error(error_report: &mut ErrorReport, history: &mut H, page: &Page, flags: Hex32) -> bool {
         if let Err(AllocError) = history.track_alloc(flags) {
             error_report.double_alloc(page);
+            false
+        } else {
+            true
         }
     }
 
+    /// Returns `true` if the allocated page is freed
     #[allow(dead_code)]
-    fn track_free_error(error_report: &mut ErrorReport, history: &mut H, page: &Page) {
+    fn track_free_error(error_report: &mut ErrorReport, history: &mut H, page: &Page) -> bool {
         match history.track_free() {
-            Ok(()) => (),
-            Err(FreeError::DoubleFree) => error_report.double_free(&page),
+            Ok(()) => true,
+            Err(FreeError::DoubleFree) => {
+                error_report.double_free(&page);
+                false
+            },
             Err(FreeError::WithoutAlloc) => {
                 error_report.without_alloc(&page);
                 debug_assert!(false);
+                false
             },
         }
     }
 
+    pub fn interval(&self) -> &IntervalDelta {
+        &self.interval
+    }
+
+    /// The copy of the current state, taken between two events, so nothing is lost.
+    /// If `reset` is set, a new interval starts: the error report and the interval deltas
+    /// are cleared, while the outstanding allocations and their stacks are retained,
+    /// so the free of a page allocated before the snapshot is still accounted.
+    pub fn take_snapshot(&mut self, reset: bool) -> Self
+    where
+        H: Clone,
+    {
+        let snapshot = self.clone();
+        if reset {
+            self.error_report.reset();
+            self.interval = IntervalDelta::default();
+        }
+        snapshot
+    }
+
     #[cfg(test)]
     fn is_empty(&self) -> bool {
         self.last_stack.is_empty() && self.group.is_empty()
@@ -179,7 +221,7 @@ where
 #[cfg(test)]
 mod test {
     use bpf_memprof_common::{Hex64, Hex32, Stack};
-    use crate::{History, EventLast, Page, Tracker, Reporter};
+    use crate::{History, IntervalDelta, EventLast, Page, Tracker, Reporter};
 
     #[test]
     fn overflow() {
@@ -196,4 +238,39 @@ mod test {
         assert_eq!(h.short_report(), (0, 0));
         assert!(h.is_empty());
     }
+
+    #[test]
+    fn snapshot_reset() {
+        let mut h = History::<EventLast>::default();
+        for i in 1..5 {
+            h.track_alloc(Page::new(Hex64(i), 0), &Stack::from_frames(&[i]), Hex32(0), 0);
+        }
+        h.track_free(Page::new(Hex64(4), 0), 0);
+
+        let snapshot = h.take_snapshot(true);
+        assert_eq!(snapshot.short_report(), (12, 0));
+        let delta = IntervalDelta {
+            allocated_kib: 16,
+            freed_kib: 4,
+        };
+        assert_eq!(snapshot.interval(), &delta);
+        // the outstanding allocations are retained, the deltas start from zero
+        assert_eq!(h.short_report(), (12, 0));
+        assert_eq!(h.interval(), &IntervalDelta::default());
+
+        // the page allocated before the snapshot is freed in the new interval
+        h.track_free(Page::new(Hex64(1), 0), 0);
+        assert_eq!(h.short_report(), (8, 0));
+        assert_eq!(h.interval().freed_kib, 4);
+        // the snapshot is not affected
+        assert_eq!(snapshot.short_report(), (12, 0));
+
+        // without reset the deltas keep growing
+        let snapshot = h.take_snapshot(false);
+        assert_eq!(snapshot.interval(), h.interval());
+        h.track_free(Page::new(Hex64(2), 0), 0);
+        h.track_free(Page::new(Hex64(3), 0), 0);
+        assert_eq!(h.interval().freed_kib, 12);
+        assert!(h.is_empty());
+    }
 }
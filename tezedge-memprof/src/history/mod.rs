@@ -16,8 +16,8 @@ pub use self::allocation::AllocationState;
 pub use self::{
     page::Page,
     page_history::{PageHistory, EventLast},
-    history::History,
-    report::FrameReport,
+    history::{History, IntervalDelta},
+    report::{FrameReport, SiteReport},
 };
 
 #[cfg(test)]
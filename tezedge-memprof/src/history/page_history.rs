@@ -6,7 +6,7 @@ use bpf_memprof_common::Hex32;
 use thiserror::Error;
 use serde::Serialize;
 
-#[derive(Serialize)]
+#[derive(Clone, Serialize)]
 pub struct TimeRange(Range<u64>);
 
 impl TimeRange {
@@ -36,7 +36,7 @@ impl TimeRange {
     }
 }
 
-#[derive(Serialize)]
+#[derive(Clone, Serialize)]
 pub struct Event {
     time_range: TimeRange,
     flags: Hex32,
@@ -84,7 +84,7 @@ pub trait PageHistory {
     fn is_empty(&self) -> bool;
 }
 
-#[derive(Default, Serialize)]
+#[derive(Default, Clone, Serialize)]
 pub struct EventLast(Option<Event>);
 
 impl PageHistory for EventLast {
@@ -10,6 +10,8 @@ use super::stack::{SymbolInfo, StackResolver};
 pub struct FrameReportInner {
     value: u64,
     cache_value: u64,
+    // the number of stacks with outstanding memory passing through the frame
+    count: u64,
     frames: HashMap<Hex64, FrameReportInner>,
     under_threshold: u64,
     cache_under_threshold: u64,
@@ -57,14 +59,17 @@ impl FrameReportInner {
     where
         StackIter: Iterator<Item = &'a Hex64>,
     {
+        let live = (value != 0) as u64;
         let mut node = self;
         for stack_frame in stack {
             node.value += value;
             node.cache_value += cache_value;
+            node.count += live;
             node = node.frames.entry(*stack_frame).or_default();
         }
         node.value += value;
         node.cache_value += cache_value;
+        node.count += live;
     }
 
     pub fn strip(&mut self, threshold: u64) {
@@ -83,17 +88,39 @@ impl FrameReportInner {
         self.cache_under_threshold = cache_under_threshold;
     }
 
-    pub fn sorted(&self, resolver: &StackResolver, name: Option<SymbolInfo>) -> FrameReportSorted {
+    /// Each frame expands into the chain of the functions inlined there,
+    /// the chain goes from the caller to the callee if the report is `reverse`
+    pub fn sorted(
+        &self,
+        resolver: &StackResolver,
+        name: Option<SymbolInfo>,
+        reverse: bool,
+    ) -> FrameReportSorted {
         let mut frames = BTreeMap::new();
         let mut unknown = self.value - self.under_threshold;
         let mut cache_unknown = self.cache_value - self.cache_under_threshold;
         for (key, value) in &self.frames {
-            if let Some(name) = resolver.resolve(key.0) {
-                let key = SortKey {
-                    inv_value: !value.value,
-                    name: name.clone(),
-                };
-                frames.insert(key, value.sorted(resolver, Some(name)));
+            if let Some(mut chain) = resolve_chain(resolver, key.0, reverse) {
+                // the last function of the chain holds the subtree,
+                // each other is the only child of the previous one
+                let mut name = chain.pop().expect("the chain is never empty");
+                let mut node = value.sorted(resolver, Some(name.clone()), reverse);
+                while let Some(outer) = chain.pop() {
+                    let mut inner = BTreeMap::new();
+                    inner.insert(SortKey { inv_value: !value.value, name }, node);
+                    node = FrameReportSorted {
+                        name: Some(outer.clone()),
+                        value: value.value,
+                        cache_value: value.cache_value,
+                        frames: inner,
+                        under_threshold: 0,
+                        cache_under_threshold: 0,
+                        unknown: 0,
+                        cache_unknown: 0,
+                    };
+                    name = outer;
+                }
+                frames.insert(SortKey { inv_value: !value.value, name }, node);
                 unknown -= value.value;
                 cache_unknown -= value.cache_value;
             }
@@ -112,14 +139,26 @@ impl FrameReportInner {
     }
 }
 
+/// The functions at the address in the order of the report, the function
+/// and the functions inlined there if `reverse`, otherwise the innermost inlined first
+fn resolve_chain(resolver: &StackResolver, address: u64, reverse: bool) -> Option<Vec<SymbolInfo>> {
+    let mut chain = resolver.resolve_inlined(address)?;
+    if !reverse {
+        chain.reverse();
+    }
+    Some(chain)
+}
+
 pub struct FrameReport<R> {
     resolver: R,
+    reverse: bool,
     pub(crate) inner: FrameReportInner,
 }
 
 impl<R> FrameReport<R> {
-    pub fn new(resolver: R) -> Self {
-        FrameReport { resolver, inner: FrameReportInner::default() }
+    /// The `reverse` report goes from the callers to the callees
+    pub fn new(resolver: R, reverse: bool) -> Self {
+        FrameReport { resolver, reverse, inner: FrameReportInner::default() }
     }
 
     pub fn value(&self) -> u64 {
@@ -131,6 +170,57 @@ impl<R> FrameReport<R> {
     }
 }
 
+/// The function of the first level of the report, the allocation site if the report is not reversed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SiteReport {
+    pub name: String,
+    // kilobytes
+    pub value: u64,
+    pub count: u64,
+}
+
+impl<R> FrameReport<R>
+where
+    R: Deref<Target = StackResolver>,
+{
+    /// Insert the stack, the innermost frame goes first; the excluded allocator frames
+    /// on top of the stack are skipped, so the tree roots at the application frames
+    pub fn insert(&mut self, stack: &[Hex64], value: u64, cache_value: u64) {
+        let excluded = stack
+            .iter()
+            .take_while(|frame| self.resolver.is_excluded(frame.0))
+            .count();
+        let stack = &stack[excluded..];
+        if self.reverse {
+            self.inner.insert(stack.iter().rev(), value, cache_value);
+        } else {
+            self.inner.insert(stack.iter(), value, cache_value);
+        }
+    }
+
+    /// The biggest sites, the frames resolved to the same function are merged
+    pub fn top_sites(&self, limit: usize) -> Vec<SiteReport> {
+        let mut sites = HashMap::<String, SiteReport>::new();
+        for (key, frame) in &self.inner.frames {
+            if frame.value == 0 {
+                continue;
+            }
+            // the innermost inlined function is the allocation site
+            let name = match resolve_chain(&self.resolver, key.0, self.reverse) {
+                Some(chain) => chain[0].display_name(),
+                None => continue,
+            };
+            let site = sites.entry(name.clone()).or_insert(SiteReport { name, value: 0, count: 0 });
+            site.value += frame.value;
+            site.count += frame.count;
+        }
+        let mut sites = sites.into_iter().map(|(_, site)| site).collect::<Vec<_>>();
+        sites.sort_by(|a, b| b.value.cmp(&a.value).then(a.name.cmp(&b.name)));
+        sites.truncate(limit);
+        sites
+    }
+}
+
 impl ser::Serialize for FrameReportSorted {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -229,7 +319,7 @@ where
     where
         S: ser::Serializer,
     {
-        let sorted = self.inner.sorted(&self.resolver, None);
+        let sorted = self.inner.sorted(&self.resolver, None, self.reverse);
         sorted.serialize(serializer)
     }
 }
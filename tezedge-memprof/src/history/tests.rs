@@ -246,6 +246,38 @@ where
     let _ = serde_json::to_string_pretty(&tree).unwrap();
 }
 
+fn excluded_frames<T>()
+where
+    T: Default + Tracker + Reporter,
+{
+    let mut history = T::default();
+    // the allocator frames `func_1` and `func_2` are on top of the application frame `func_3`
+    let stack = Stack::from_frames(&[1, 2, 3]);
+    history.track_alloc(Page::new(Hex64(0), 0), &stack, Hex32(0), 0);
+    // the frame `func_1` is not on top, it stays in the tree
+    let stack = Stack::from_frames(&[4, 1]);
+    history.track_alloc(Page::new(Hex64(1), 0), &stack, Hex32(0), 0);
+
+    let roots = |resolver: &StackResolver| {
+        history
+            .tree_report(resolver, 0, false)
+            .top_sites(10)
+            .into_iter()
+            .map(|site| site.name)
+            .collect::<Vec<_>>()
+    };
+
+    let mut resolver = StackResolver::mock();
+    assert_eq!(roots(&resolver), ["func_1", "func_4"]);
+
+    resolver.set_excluded_frames(vec!["func_1".to_string(), "func_2".to_string()]);
+    assert_eq!(roots(&resolver), ["func_3", "func_4"]);
+    let tree = history.tree_report(&resolver, 0, false);
+    assert_eq!(tree.value(), 2 * 4);
+    let json = serde_json::to_string(&tree).unwrap();
+    assert!(json.contains("func_1") && !json.contains("func_2"));
+}
+
 #[test]
 fn alloc_simple() {
     alloc::<AllocationState>()
@@ -390,3 +422,18 @@ fn alloc_in_different_stacks_history() {
 fn alloc_in_different_stacks_aggregator() {
     alloc_in_different_stacks::<Aggregator>()
 }
+
+#[test]
+fn excluded_frames_simple() {
+    excluded_frames::<AllocationState>()
+}
+
+#[test]
+fn excluded_frames_history() {
+    excluded_frames::<History<EventLast>>()
+}
+
+#[test]
+fn excluded_frames_aggregator() {
+    excluded_frames::<Aggregator>()
+}
@@ -9,14 +9,39 @@ mod state;
 pub use self::state::{AtomicState, Reporter as StateReporter};
 
 mod history;
-pub use self::history::{Page, History, AllocationState, FrameReport, EventLast, Tracker, Reporter};
+pub use self::history::{
+    Page, History, IntervalDelta, AllocationState, FrameReport, SiteReport, EventLast, Tracker,
+    Reporter,
+};
 
 mod stack;
 pub use self::stack::StackResolver;
 
 mod table;
 
+mod lru;
+
+mod lost_events;
+pub use self::lost_events::LostEventsMonitor;
+
+mod csv_report;
+pub use self::csv_report::CsvReport;
+
+mod capture;
+pub use self::capture::{CaptureTime, CaptureReport};
+
+mod correlation;
+pub use self::correlation::{Correlation, RssHistory, RssSample};
+
+mod filter;
+pub use self::filter::{EventFilter, FilterConfig};
+
 pub mod server;
 
+pub use http_common::tls::{TlsConfig, TlsError};
+
 mod collector;
-pub use self::collector::{Consumer, Aggregator, RawEvent};
+pub use self::collector::{Consumer, Aggregator, RawEvent, Snapshot};
+
+mod events_file;
+pub use self::events_file::{EventsFileWriter, EventsFileReader};
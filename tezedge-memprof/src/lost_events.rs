@@ -0,0 +1,75 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::time::{Duration, Instant};
+
+/// Reports events lost by the kernel because the ring buffer was full.
+/// The check happens by time, independent of how often the ring buffer is polled.
+pub struct LostEventsMonitor {
+    interval: Duration,
+    last_check: Instant,
+    reported: u32,
+}
+
+impl LostEventsMonitor {
+    pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(5);
+
+    pub fn new(interval: Duration, now: Instant) -> Self {
+        LostEventsMonitor {
+            interval,
+            last_check: now,
+            reported: 0,
+        }
+    }
+
+    /// If the interval elapsed since the last check, read the counter of lost events
+    /// and return how many events were lost since the last check.
+    pub fn check<F>(&mut self, now: Instant, read_counter: F) -> Option<u32>
+    where
+        F: FnOnce() -> u32,
+    {
+        if now.saturating_duration_since(self.last_check) < self.interval {
+            return None;
+        }
+        self.last_check = now;
+        let counter = read_counter();
+        let lost = counter.wrapping_sub(self.reported);
+        self.reported = counter;
+        Some(lost)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::Cell,
+        time::{Duration, Instant},
+    };
+    use super::LostEventsMonitor;
+
+    #[test]
+    fn interval() {
+        let start = Instant::now();
+        let interval = Duration::from_secs(5);
+        let mut monitor = LostEventsMonitor::new(interval, start);
+        let reads = Cell::new(0);
+        let counter = |value| {
+            reads.set(reads.get() + 1);
+            value
+        };
+
+        // many polls within the interval do not read the counter
+        for ms in 0..5000 {
+            let now = start + Duration::from_millis(ms);
+            assert_eq!(monitor.check(now, || counter(10)), None);
+        }
+        assert_eq!(reads.get(), 0);
+
+        assert_eq!(monitor.check(start + interval, || counter(10)), Some(10));
+        assert_eq!(monitor.check(start + interval * 2, || counter(10)), Some(0));
+        // a long pause between polls, still a single report
+        assert_eq!(monitor.check(start + interval * 10, || counter(25)), Some(15));
+        assert_eq!(monitor.check(start + interval * 10, || counter(30)), None);
+        assert_eq!(reads.get(), 3);
+    }
+}
@@ -0,0 +1,106 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Serve the history saved by the memory profiler without live bpf attachment
+//! `tezedge-memprof --load target/history.json [--maps target/maps] [--port 17832]`
+//! `[--tls-cert cert.pem --tls-key key.pem]`
+//! or rebuild the history from the events dumped by `bpf-memprof-user --dump-events`
+//! `tezedge-memprof --events-file target/events [--maps target/maps] [--port 17832]`
+
+use std::{
+    env,
+    fs::File,
+    io::BufReader,
+    sync::{Arc, Mutex, RwLock, atomic::AtomicU32},
+};
+use tracing::Level;
+use tezedge_memprof::{
+    Snapshot, StackResolver, CaptureTime, EventFilter, Correlation, TlsConfig, Consumer,
+    EventsFileReader, server,
+};
+
+fn arg(name: &str) -> Option<String> {
+    env::args().skip_while(|s| s != name).nth(1)
+}
+
+/// Comma separated list, the empty string disables the stripping
+fn parse_prefixes(s: &str) -> Vec<String> {
+    s.split(',').filter(|p| !p.is_empty()).map(str::to_string).collect()
+}
+
+fn main() {
+    tracing_subscriber::fmt().with_max_level(Level::INFO).init();
+
+    let port = arg("--port")
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(server::DEFAULT_PORT);
+    let tls = match (arg("--tls-cert"), arg("--tls-key")) {
+        (Some(cert), Some(key)) => Some(TlsConfig { cert, key }),
+        (None, None) => None,
+        _ => panic!("--tls-cert and --tls-key go together"),
+    };
+    if let Some(tls) = &tls {
+        tls.check().unwrap_or_else(|e| panic!("{}", e));
+    }
+
+    let mut resolver = match arg("--maps") {
+        Some(maps) => StackResolver::load(&maps).unwrap_or_else(|e| panic!("{}", e)),
+        None => StackResolver::default(),
+    };
+    if let Some(prefixes) = arg("--exclude-frames") {
+        resolver.set_excluded_frames(parse_prefixes(&prefixes));
+    }
+
+    let resolver = Arc::new(RwLock::new(resolver));
+    // no memory is sampled, there is nothing to correlate
+    let correlation = Arc::new(Correlation::default());
+    let api_token = server::api_token();
+
+    let (server, runtime) = if let Some(path) = arg("--events-file") {
+        let file = File::open(&path).unwrap_or_else(|e| panic!("cannot open {}: {}", path, e));
+        let mut reader = EventsFileReader::new(BufReader::new(file))
+            .unwrap_or_else(|e| panic!("cannot read {}: {}", path, e));
+        let mut consumer = Consumer::default();
+        let count = consumer
+            .replay(&mut reader)
+            .unwrap_or_else(|e| panic!("cannot read {}: {}", path, e));
+        log::info!("replayed {} events of {}, serving at port {}", count, path, port);
+        server::run(
+            consumer.reporter(),
+            resolver,
+            // the pid of the recorded process, it is not running
+            consumer.pid(),
+            consumer.capture(),
+            // the history is rebuilt as it is, changing the filter has no effect
+            consumer.filter(),
+            correlation,
+            port,
+            tls,
+            api_token,
+        )
+    } else {
+        let path = arg("--load").expect(
+            "usage: tezedge-memprof --load history.json | --events-file events \
+             [--maps maps] [--port 17832]",
+        );
+        let file = File::open(&path).unwrap_or_else(|e| panic!("cannot open {}: {}", path, e));
+        let snapshot = serde_json::from_reader::<_, Snapshot>(BufReader::new(file))
+            .unwrap_or_else(|e| panic!("cannot parse {}: {}", path, e));
+        log::info!("serving {} at port {}", path, port);
+        server::run(
+            Arc::new(Mutex::new(snapshot)),
+            resolver,
+            // there is no live process
+            Arc::new(AtomicU32::new(0)),
+            // no events are processed, the capture starts now
+            Arc::new(CaptureTime::default()),
+            // the history is loaded as it is, changing the filter has no effect
+            Arc::new(EventFilter::default()),
+            correlation,
+            port,
+            tls,
+            api_token,
+        )
+    };
+    runtime.block_on(server).unwrap();
+}
@@ -1,14 +1,22 @@
 // Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
 // SPDX-License-Identifier: MIT
 
-use std::{ops::Range, num::Wrapping, str::FromStr, io::{self, Read}, fs::File, path::PathBuf};
+use std::{ops::Range, num::Wrapping, str::FromStr, io::{self, Read}, fs::File, path::{Path, PathBuf}};
 
-#[derive(Default, Clone, PartialEq, Eq)]
+#[derive(Default, Clone, PartialEq, Eq, Hash)]
 pub struct ProcessMap(Vec<MemoryMapEntry>);
 
 impl ProcessMap {
     pub fn new(pid: u32) -> io::Result<Self> {
-        MemoryMapEntry::new(pid).map(ProcessMap)
+        Self::load(format!("/proc/{}/maps", pid))
+    }
+
+    /// Load the map from a file in format of `/proc/<pid>/maps`, it might be a saved snapshot
+    pub fn load<P>(path: P) -> io::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        MemoryMapEntry::load(path).map(ProcessMap)
     }
 
     pub fn files(&self) -> Vec<String> {
@@ -39,7 +47,7 @@ impl ProcessMap {
     }
 }
 
-#[derive(Clone, PartialEq, Eq)]
+#[derive(Clone, PartialEq, Eq, Hash)]
 struct MemoryMapEntry {
     range: Range<usize>,
     flags: String,
@@ -47,7 +55,7 @@ struct MemoryMapEntry {
     name: EntryName,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 enum EntryName {
     Nothing,
     FileName(PathBuf),
@@ -72,9 +80,12 @@ impl EntryName {
 }
 
 impl MemoryMapEntry {
-    fn new(pid: u32) -> Result<Vec<Self>, io::Error> {
+    fn load<P>(path: P) -> Result<Vec<Self>, io::Error>
+    where
+        P: AsRef<Path>,
+    {
         let mut entries = String::new();
-        File::open(&format!("/proc/{}/maps", pid))?
+        File::open(path)?
             .read_to_string(&mut entries)?;
 
         let mut map = vec![];
@@ -1,30 +1,49 @@
 // Copyright (c) SimpleStaking and Tezedge Contributors
 // SPDX-License-Identifier: MIT
 
-use std::{
-    sync::{Arc, atomic::{Ordering, AtomicU32}, Mutex, RwLock},
-    fs::File,
-    io::{Error, BufReader, BufRead},
-};
+use std::sync::{Arc, atomic::{Ordering, AtomicU32}, Mutex, RwLock};
 use warp::{
     Filter, Rejection, Reply,
     reply::{WithStatus, Json, self},
     http::StatusCode,
 };
 use serde::{Serialize, Deserialize};
-use super::{StackResolver, Reporter};
+use http_common::{auth, tls};
+use super::{
+    StackResolver, Reporter, CaptureTime, CaptureReport, EventFilter, FilterConfig, TlsConfig,
+    Correlation,
+    capture::now_millis,
+    correlation::{MAX_BUCKETS, rss_anon, rss_buckets, merge},
+};
+
+pub const DEFAULT_PORT: u16 = 17832;
+
+/// The requests need `Authorization: Bearer <token>` if the variable is set
+pub const API_TOKEN_VAR: &str = "TEZEDGE_MEMPROF_API_TOKEN";
+
+/// The token from `API_TOKEN_VAR`, the empty one means no token
+pub fn api_token() -> Option<String> {
+    std::env::var(API_TOKEN_VAR).ok().filter(|token| !token.is_empty())
+}
 
+#[allow(clippy::too_many_arguments)]
 pub fn run<T>(
     reporter: Arc<Mutex<T>>,
     resolver: Arc<RwLock<StackResolver>>,
     pid: Arc<AtomicU32>,
+    capture: Arc<CaptureTime>,
+    filter: Arc<EventFilter>,
+    correlation: Arc<Correlation>,
+    port: u16,
+    tls: Option<TlsConfig>,
+    api_token: Option<String>,
 ) -> (tokio::task::JoinHandle<()>, tokio::runtime::Runtime)
 where
     T: Reporter + Send + 'static,
 {
     let runtime = tokio::runtime::Runtime::new().unwrap();
-    let server = routes(reporter, resolver, pid.clone());
-    let handler = runtime.spawn(warp::serve(server).run(([0, 0, 0, 0], 17832)));
+    let server = routes(reporter, resolver, pid.clone(), capture, filter, correlation, api_token);
+    let handler = runtime.spawn(tls::serve(server, ([0, 0, 0, 0], port), tls.as_ref()));
     (handler, runtime)
 }
 
@@ -32,42 +51,126 @@ fn routes<T>(
     reporter: Arc<Mutex<T>>,
     resolver: Arc<RwLock<StackResolver>>,
     pid: Arc<AtomicU32>,
+    capture: Arc<CaptureTime>,
+    filter: Arc<EventFilter>,
+    correlation: Arc<Correlation>,
+    api_token: Option<String>,
 ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone + Sync + Send + 'static
 where
     T: Reporter + Send + 'static,
 {
     use warp::reply::with;
 
-    warp::get()
-        .and(tree(reporter, resolver, pid.clone()).or(get_pid(pid)).or(openapi()))
+    let routes = warp::get()
+        .and(
+            tree(reporter, resolver, pid.clone())
+                .or(get_pid(pid, capture))
+                .or(get_config(filter.clone()))
+                .or(get_correlation(correlation))
+                .or(openapi()),
+        )
+        .or(warp::post().and(set_config(filter)));
+    auth::bearer(api_token)
+        .and(routes)
+        .recover(auth::recover)
         .with(with::header("Content-Type", "application/json"))
         .with(with::header("Access-Control-Allow-Origin", "*"))
 }
 
-fn get_pid(p: Arc<AtomicU32>) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static {
+fn get_pid(
+    p: Arc<AtomicU32>,
+    capture: Arc<CaptureTime>,
+) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static {
+    #[derive(Serialize)]
+    struct PidReport {
+        pid: u32,
+        #[serde(flatten)]
+        capture: CaptureReport,
+    }
+
     warp::path!("v1" / "pid")
         .and(warp::query::query())
         .map(move |()| -> WithStatus<Json> {
-            reply::with_status(reply::json(&p.load(Ordering::Relaxed)), StatusCode::OK)
+            let report = PidReport {
+                pid: p.load(Ordering::Relaxed),
+                capture: capture.report(),
+            };
+            reply::with_status(reply::json(&report), StatusCode::OK)
         })
 }
 
-fn rss_anon(p: Arc<AtomicU32>) -> Result<u64, Error> {
-    let pid = p.load(Ordering::Relaxed);
-    let f = File::open(format!("/proc/{}/status", pid))?;
-    let reader = BufReader::new(f);
-    let mut v = 0;
-    for line in reader.lines() {
-        let line = line?;
-        let mut words = line.split_whitespace();
-        if let Some("RssAnon:") = words.next() {
-            v = words.next().map(|s| s.parse().unwrap_or(0)).unwrap_or(0);
-        } else {
-            continue;
-        }
+fn get_config(
+    filter: Arc<EventFilter>,
+) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static {
+    warp::path!("v1" / "config")
+        .and(warp::query::query())
+        .map(move |()| -> WithStatus<Json> {
+            reply::with_status(reply::json(&filter.get()), StatusCode::OK)
+        })
+}
+
+/// Replace the filter of the allocations, the allocations tracked before stay in the tree
+fn set_config(
+    filter: Arc<EventFilter>,
+) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static {
+    warp::path!("v1" / "config")
+        .and(warp::body::content_length_limit(1 << 12))
+        .and(warp::body::json())
+        .map(move |config: FilterConfig| -> WithStatus<Json> {
+            if !config.is_valid() {
+                let r = "min_order is greater than max_order";
+                return reply::with_status(reply::json(&r), StatusCode::BAD_REQUEST);
+            }
+            filter.set(config.clone());
+            reply::with_status(reply::json(&config), StatusCode::OK)
+        })
+}
+
+/// The memory of the node and the messages captured by the recorder, in the same buckets
+fn get_correlation(
+    correlation: Arc<Correlation>,
+) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static {
+    // milliseconds, `[from, to)`, the same as `/v3/throughput` of the recorder has
+    #[derive(Deserialize)]
+    struct Params {
+        from: Option<u64>,
+        to: Option<u64>,
+        bucket: Option<u64>,
     }
 
-    Ok(v)
+    warp::path!("v1" / "correlation")
+        .and(warp::query::query())
+        .and_then(move |params: Params| {
+            let correlation = correlation.clone();
+            async move {
+                let width = params.bucket.unwrap_or(1000).max(1);
+                let to = params.to.unwrap_or_else(now_millis);
+                let from = params
+                    .from
+                    .unwrap_or_else(|| to.saturating_sub(width.saturating_mul(60)))
+                    .max(to.saturating_sub(MAX_BUCKETS.saturating_mul(width)));
+                let url = match &correlation.recorder_url {
+                    Some(url) => url,
+                    None => {
+                        let r = "the recorder url is not configured, see `--recorder-url`";
+                        let status = StatusCode::SERVICE_UNAVAILABLE;
+                        return Ok::<_, Rejection>(reply::with_status(reply::json(&r), status));
+                    },
+                };
+                let throughput = match correlation.fetch_throughput(url, from, to, width).await {
+                    Ok(throughput) => throughput,
+                    Err(error) => {
+                        let r = error.to_string();
+                        let status = StatusCode::BAD_GATEWAY;
+                        return Ok(reply::with_status(reply::json(&r), status));
+                    },
+                };
+                let samples = correlation.rss.samples(from, to);
+                let rss = rss_buckets(&samples, from, to, width);
+                let report = merge(&rss, &throughput);
+                Ok(reply::with_status(reply::json(&report), StatusCode::OK))
+            }
+        })
 }
 
 fn tree<T>(
@@ -101,7 +204,7 @@ where
             let history = history.lock().unwrap();
             if params.short.unwrap_or(false) {
                 let (total, cache) = history.short_report();
-                let system_report_anon = rss_anon(pid.clone()).unwrap_or(0);
+                let system_report_anon = rss_anon(pid.load(Ordering::Relaxed)).unwrap_or(0);
                 let report = ShortReport {
                     total,
                     cache,
@@ -132,3 +235,153 @@ pub fn openapi() -> impl Filter<Extract=(WithStatus<Json>, ), Error=Rejection> +
             )
         })
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex, RwLock, atomic::AtomicU32},
+        time::Duration,
+    };
+    use warp::Filter;
+    use http_common::auth;
+    use super::routes;
+    use crate::{
+        Aggregator, CaptureTime, StackResolver, EventFilter, Correlation, RssHistory, RssSample,
+    };
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn capture_window() {
+        let capture = Arc::new(CaptureTime::default());
+        let routes = routes(
+            Arc::new(Mutex::new(Aggregator::default())),
+            Arc::new(RwLock::new(StackResolver::mock())),
+            Arc::new(AtomicU32::new(1234)),
+            capture.clone(),
+            Arc::new(EventFilter::default()),
+            Arc::new(Correlation::default()),
+            None,
+        );
+        let routes = &routes;
+        let get = || async move {
+            let response = warp::test::request().path("/v1/pid").reply(routes).await;
+            assert_eq!(response.status(), 200);
+            serde_json::from_slice::<serde_json::Value>(response.body()).unwrap()
+        };
+
+        let first = get().await;
+        assert_eq!(first["pid"], 1234);
+        assert!(first["capture_start"].as_u64().unwrap() > 0);
+        assert!(first["first_event"].is_null() && first["last_event"].is_null());
+
+        capture.event();
+        std::thread::sleep(Duration::from_millis(1100));
+        capture.event();
+        let second = get().await;
+        assert_eq!(second["capture_start"], first["capture_start"]);
+        let field = |v: &serde_json::Value, name: &str| v[name].as_u64().unwrap();
+        assert!(field(&second, "uptime_seconds") > field(&first, "uptime_seconds"));
+        assert!(field(&second, "last_event") > field(&second, "first_event"));
+        assert!(field(&second, "first_event") >= field(&second, "capture_start"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn bearer_token() {
+        let new_routes = |api_token: Option<&str>| {
+            routes(
+                Arc::new(Mutex::new(Aggregator::default())),
+                Arc::new(RwLock::new(StackResolver::mock())),
+                Arc::new(AtomicU32::new(1234)),
+                Arc::new(CaptureTime::default()),
+                Arc::new(EventFilter::default()),
+                Arc::new(Correlation::default()),
+                api_token.map(str::to_string),
+            )
+        };
+        let get = |header: Option<&str>| {
+            let request = warp::test::request().path("/v1/pid");
+            match header {
+                Some(header) => request.header("authorization", header),
+                None => request,
+            }
+        };
+
+        let protected = new_routes(Some("secret"));
+        for header in &[None, Some("Bearer wrong"), Some("secret")] {
+            let response = get(*header).reply(&protected).await;
+            assert_eq!(response.status(), 401, "{:?}", header);
+            assert_eq!(response.headers()["www-authenticate"], "Bearer");
+        }
+        let response = get(Some("Bearer secret")).reply(&protected).await;
+        assert_eq!(response.status(), 200);
+
+        // without the token the access is open
+        let response = get(None).reply(&new_routes(None)).await;
+        assert_eq!(response.status(), 200);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn correlation() {
+        // serves `/v3/throughput` as the recorder with the token does
+        let recorder = warp::path!("v3" / "throughput")
+            .and(auth::bearer(Some("recorder-token".to_string())))
+            .map(|| {
+                warp::reply::json(&serde_json::json!([
+                    {"timestamp": 1000, "count": 3, "bytes": 300},
+                    {"timestamp": 2000, "count": 500, "bytes": 50000},
+                ]))
+            })
+            .recover(auth::recover);
+        let (addr, server) = warp::serve(recorder).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let recorder_url = format!("http://{}/", addr);
+
+        let rss = Arc::new(RssHistory::default());
+        for &(timestamp, rss_anon) in &[(1000, 100), (2000, 150), (2500, 400)] {
+            rss.record(RssSample { timestamp, rss_anon });
+        }
+        let new_routes = |recorder_url: Option<&str>, recorder_token: Option<&str>| {
+            routes(
+                Arc::new(Mutex::new(Aggregator::default())),
+                Arc::new(RwLock::new(StackResolver::mock())),
+                Arc::new(AtomicU32::new(1234)),
+                Arc::new(CaptureTime::default()),
+                Arc::new(EventFilter::default()),
+                Arc::new(Correlation::new(
+                    rss.clone(),
+                    recorder_url.map(str::to_string),
+                    recorder_token.map(str::to_string),
+                )),
+                None,
+            )
+        };
+        let get = |routes| async move {
+            warp::test::request()
+                .path("/v1/correlation?from=1000&to=3000&bucket=1000")
+                .reply(&routes)
+                .await
+        };
+
+        let response = get(new_routes(Some(&recorder_url), Some("recorder-token"))).await;
+        assert_eq!(response.status(), 200);
+        let report = serde_json::from_slice::<serde_json::Value>(response.body()).unwrap();
+        assert_eq!(
+            report,
+            serde_json::json!([
+                {"timestamp": 1000, "rss_anon": 100, "messages": 3, "bytes": 300},
+                {"timestamp": 2000, "rss_anon": 400, "messages": 500, "bytes": 50000},
+            ]),
+        );
+
+        // the recorder rejects the request without the token
+        let response = get(new_routes(Some(&recorder_url), None)).await;
+        assert_eq!(response.status(), 502);
+        let error = serde_json::from_slice::<String>(response.body()).unwrap();
+        assert!(error.contains("401"));
+
+        let response = get(new_routes(None, None)).await;
+        assert_eq!(response.status(), 503);
+        // nothing listens there
+        let response = get(new_routes(Some("http://127.0.0.1:1"), None)).await;
+        assert_eq!(response.status(), 502);
+    }
+}
@@ -3,29 +3,72 @@
 
 use std::{
     collections::{HashMap, HashSet},
-    sync::{Arc, RwLock, atomic::{AtomicU32, Ordering}}, 
+    sync::{Arc, RwLock, Mutex, atomic::{AtomicU32, Ordering}}, 
     path::PathBuf,
 };
 use bpf_memprof_common::Hex32;
 use serde::Serialize;
-use super::{memory_map::ProcessMap, table::SymbolTable};
+use super::{memory_map::ProcessMap, table::SymbolTable, lru::LruCache};
 
-#[derive(Serialize, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Serialize, Clone, PartialEq, Eq, PartialOrd, Ord)]
 #[serde(rename_all = "camelCase")]
 pub struct SymbolInfo {
     offset: Hex32,
     executable: String,
     function_name: Option<String>,
     function_category: String,
+    // the function is inlined into the function of the previous frame at the same offset
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    inlined: bool,
 }
 
 #[derive(Default)]
 pub struct StackResolver {
     files: HashMap<String, SymbolTable>,
     map: Option<ProcessMap>,
+    map_hash: u64,
+    cache: Mutex<SymbolCache>,
+    // the prefixes of the names of the frames stripped from the top of the stack,
+    // `None` means the default list
+    excluded_frames: Option<Vec<String>>,
     mock: Option<()>,
 }
 
+/// Resolved symbols by (filename, offset), valid while the process map is the same.
+struct SymbolCache {
+    map_hash: u64,
+    inner: LruCache<(String, usize), Option<Vec<SymbolInfo>>>,
+    hits: u64,
+    misses: u64,
+}
+
+impl SymbolInfo {
+    /// The demangled function name, or the offset in the executable if the name is unknown
+    pub fn display_name(&self) -> String {
+        match &self.function_name {
+            Some(name) => name.clone(),
+            None => format!("{}+0x{:?}", self.executable, self.offset),
+        }
+    }
+}
+
+impl Default for SymbolCache {
+    fn default() -> Self {
+        SymbolCache::new(StackResolver::DEFAULT_CACHE_CAPACITY)
+    }
+}
+
+impl SymbolCache {
+    fn new(capacity: usize) -> Self {
+        SymbolCache {
+            map_hash: 0,
+            inner: LruCache::new(capacity),
+            hits: 0,
+            misses: 0,
+        }
+    }
+}
+
 fn copy_binary(filename: &str) -> Result<PathBuf, ()> {
     use std::{env, process::Command, path::Path, fs};
 
@@ -65,10 +108,32 @@ fn copy_binary(filename: &str) -> Result<PathBuf, ()> {
 }
 
 impl StackResolver {
-    pub fn spawn(pid: Arc<AtomicU32>) -> Arc<RwLock<Self>> {
+    pub const DEFAULT_CACHE_CAPACITY: usize = 0x10000;
+
+    /// The allocator functions and the rust allocation shims, they are on top of every stack
+    pub const DEFAULT_EXCLUDED_FRAMES: &[&str] = &[
+        "malloc",
+        "calloc",
+        "realloc",
+        "_int_malloc",
+        "_int_realloc",
+        "__libc_",
+        "__rust_alloc",
+        "__rust_realloc",
+        "__rg_alloc",
+        "__rg_realloc",
+        "alloc::alloc::",
+        "<alloc::alloc::Global as core::alloc::Allocator>",
+        "std::alloc::",
+    ];
+
+    pub fn spawn(pid: Arc<AtomicU32>, cache_capacity: usize) -> Arc<RwLock<Self>> {
         use std::{time::Duration, thread};
 
-        let resolver = Arc::new(RwLock::new(StackResolver::default()));
+        let resolver = Arc::new(RwLock::new(StackResolver {
+            cache: Mutex::new(SymbolCache::new(cache_capacity)),
+            ..StackResolver::default()
+        }));
         let resolver_ref = resolver.clone();
         thread::spawn(move || {
             let mut last_map = None::<ProcessMap>;
@@ -112,7 +177,7 @@ impl StackResolver {
                                         files.insert(initial_filename);
                                     }
                                 }
-                                resolver_ref.write().unwrap().map = Some(map);
+                                resolver_ref.write().unwrap().set_map(map);
                             }
                         },
                         Err(error) => {
@@ -128,35 +193,94 @@ impl StackResolver {
         resolver
     }
 
+    /// Offline resolver, use saved `/proc/<pid>/maps` and load symbols from the local files.
+    /// Try the path inside `/tmp` if the binary was copied from the node container.
+    pub fn load(maps_path: &str) -> Result<Self, String> {
+        let map = ProcessMap::load(maps_path)
+            .map_err(|error| format!("cannot load process map {}: {}", maps_path, error))?;
+        let mut resolver = StackResolver::default();
+        for filename in map.files() {
+            if resolver.files.contains_key(&filename) {
+                continue;
+            }
+            let table = SymbolTable::load(&filename)
+                .or_else(|_| SymbolTable::load(format!("/tmp{}", filename)));
+            match table {
+                Ok(table) => {
+                    log::info!("loaded {} symbols from: {}", table.len(), filename);
+                    resolver.files.insert(filename, table);
+                },
+                Err(error) => {
+                    log::info!("failed to load symbols for: {:?}, {}", filename, error);
+                },
+            }
+        }
+        resolver.set_map(map);
+
+        Ok(resolver)
+    }
+
     pub fn mock() -> Self {
         StackResolver {
-            files: HashMap::new(),
-            map: None,
             mock: Some(()),
+            ..StackResolver::default()
         }
     }
 
-    fn try_resolve(&self, address: u64) -> Option<((usize, &str), Option<String>)> {
-        let map = self.map.as_ref()?;
-        let (filename, offset) = map.find(address as usize)?;
-        let table = self.files.get(&filename)?;
-        Some(((offset, table.name()), table.find(offset as u64)))
+    /// Replace the default list of the prefixes of the names of the frames
+    /// stripped from the top of the stack in the tree report
+    pub fn set_excluded_frames(&mut self, prefixes: Vec<String>) {
+        self.excluded_frames = Some(prefixes);
     }
 
-    fn try_mock(&self, address: u64) -> Option<((usize, &str), Option<String>)> {
-        self.mock.as_ref().map(|&()| ((0, "mock"), Some(format!("func_{}", address))))
+    /// The function at the address is one of the excluded frames, the unknown is not
+    pub fn is_excluded(&self, address: u64) -> bool {
+        let name = match self.resolve(address) {
+            Some(info) => info.display_name(),
+            None => return false,
+        };
+        match &self.excluded_frames {
+            Some(prefixes) => prefixes.iter().any(|p| name.starts_with(p.as_str())),
+            None => Self::DEFAULT_EXCLUDED_FRAMES.iter().any(|p| name.starts_with(p)),
+        }
     }
 
-    pub fn resolve(&self, address: u64) -> Option<SymbolInfo> {
-        let ((offset, filename), name) = self
-            .try_resolve(address)
-            .or_else(|| self.try_mock(address))?;
+    fn set_map(&mut self, map: ProcessMap) {
+        use std::{collections::hash_map::DefaultHasher, hash::{Hash, Hasher}};
+
+        let mut hasher = DefaultHasher::new();
+        map.hash(&mut hasher);
+        self.map_hash = hasher.finish();
+        self.map = Some(map);
+    }
+
+    /// Executable file and offset in it
+    fn locate(&self, address: u64) -> Option<(String, usize)> {
+        match &self.mock {
+            Some(()) => Some(("mock".to_string(), address as usize)),
+            None => self.map.as_ref()?.find(address as usize),
+        }
+    }
+
+    /// Lookup the symbol in the ELF symbol table, and the functions inlined there
+    /// in the debug info, if any, it is slow
+    fn lookup(&self, filename: &str, offset: usize) -> Option<Vec<SymbolInfo>> {
+        let (executable, name, inlined) = match &self.mock {
+            Some(()) => ("mock", Some(format!("func_{}", offset)), vec![]),
+            None => {
+                let table = self.files.get(filename)?;
+                // the return address points after the call instruction,
+                // which might be the first instruction of the next inlined function
+                let inlined = table.find_inlined((offset as u64).saturating_sub(1));
+                (table.name(), table.find(offset as u64), inlined)
+            },
+        };
 
         fn cpp_demangle(s: &str) -> Option<String> {
             cpp_demangle::Symbol::new(s).ok()?.demangle(&Default::default()).ok()
         }
 
-        let function_category = if filename == "light-node" {
+        let function_category = if executable == "light-node" {
             if name.as_ref().map(|n| is_rust(n)).unwrap_or(false) {
                 "nodeRust".to_string()
             } else {
@@ -166,19 +290,57 @@ impl StackResolver {
             "systemLib".to_string()
         };
 
-        Some(SymbolInfo {
+        let demangle = |n: String| {
+            if is_rust(&n) {
+                rustc_demangle::demangle(&n).to_string()
+            } else {
+                cpp_demangle(&n).unwrap_or(n)
+            }
+        };
+
+        let function = SymbolInfo {
             offset: Hex32(offset as _),
-            executable: filename.to_string(),
-            function_name: name
-                .map(|n| {
-                    if is_rust(&n) {
-                        rustc_demangle::demangle(&n).to_string()
-                    } else {
-                        cpp_demangle(&n).unwrap_or(n)
-                    }
-                }),
+            executable: executable.to_string(),
+            function_name: name.map(demangle),
             function_category,
-        })
+            inlined: false,
+        };
+        let inlined = inlined.into_iter().map(|name| SymbolInfo {
+            function_name: Some(demangle(name)),
+            inlined: true,
+            ..function.clone()
+        });
+        Some(Some(function.clone()).into_iter().chain(inlined).collect())
+    }
+
+    /// The function at the address, the outermost if some functions are inlined there
+    pub fn resolve(&self, address: u64) -> Option<SymbolInfo> {
+        self.resolve_inlined(address)?.into_iter().next()
+    }
+
+    /// The function at the address followed by the functions inlined there,
+    /// from the outermost to the innermost, never empty
+    pub fn resolve_inlined(&self, address: u64) -> Option<Vec<SymbolInfo>> {
+        let key = self.locate(address)?;
+
+        {
+            let mut cache = self.cache.lock().unwrap();
+            if cache.map_hash != self.map_hash {
+                cache.inner.clear();
+                cache.map_hash = self.map_hash;
+            }
+            if let Some(info) = cache.inner.get(&key) {
+                let info = info.clone();
+                cache.hits += 1;
+                return info;
+            }
+            cache.misses += 1;
+        }
+
+        // do not hold the lock while looking up the symbol
+        let info = self.lookup(&key.0, key.1);
+        self.cache.lock().unwrap().inner.insert(key, info.clone());
+        info
     }
 }
 
@@ -196,3 +358,29 @@ fn is_rust(s: &str) -> bool {
 
     s.split_whitespace().any(inner) || s.split(".llvm").any(inner)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{StackResolver, ProcessMap};
+
+    fn stats(resolver: &StackResolver) -> (u64, u64) {
+        let cache = resolver.cache.lock().unwrap();
+        (cache.hits, cache.misses)
+    }
+
+    #[test]
+    fn cache_hit() {
+        let mut resolver = StackResolver::mock();
+
+        let first = resolver.resolve(0x1234);
+        assert_eq!(stats(&resolver), (0, 1));
+        let second = resolver.resolve(0x1234);
+        assert_eq!(stats(&resolver), (1, 1));
+        assert!(first.is_some() && first == second);
+
+        // the process map changed, should lookup again
+        resolver.set_map(ProcessMap::default());
+        assert_eq!(resolver.resolve(0x1234), first);
+        assert_eq!(stats(&resolver), (1, 2));
+    }
+}
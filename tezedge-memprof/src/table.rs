@@ -1,12 +1,21 @@
 // Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
 // SPDX-License-Identifier: MIT
 
-use std::{ops::Range, path::Path};
+use std::{ops::Range, path::Path, sync::{Arc, Mutex}};
+use addr2line::{
+    Context,
+    gimli::{self, EndianArcSlice, RunTimeEndian},
+    object::{self, Object, ObjectSection},
+};
+
+type InlineContext = Context<EndianArcSlice<RunTimeEndian>>;
 
 pub struct SymbolTable {
     inner: Vec<Symbol>,
     name: String,
     strings: Vec<u8>,
+    // the inline information from the dwarf, if the binary has debug info
+    inlines: Option<Mutex<InlineContext>>,
 }
 
 struct Symbol {
@@ -73,13 +82,50 @@ impl SymbolTable {
         }
         symbols.sort_by(|a, b| a.range.start.cmp(&b.range.start));
 
+        let inlines = match load_inlines(&data) {
+            Ok(inlines) => inlines.map(Mutex::new),
+            Err(error) => {
+                log::warn!("failed to load debug info: {}", error);
+                None
+            },
+        };
+
         Ok(SymbolTable {
             inner: symbols,
             name: path.as_ref().file_name().and_then(|n| n.to_str()).unwrap_or("").to_string(),
             strings,
+            inlines,
         })
     }
 
+    pub fn has_debug_info(&self) -> bool {
+        self.inlines.is_some()
+    }
+
+    /// The functions inlined at the offset, from the outermost to the innermost,
+    /// the function containing them is not included. The names are mangled.
+    pub fn find_inlined(&self, offset: u64) -> Vec<String> {
+        let context = match &self.inlines {
+            Some(context) => context.lock().unwrap(),
+            None => return vec![],
+        };
+        let mut names = Vec::new();
+        let mut frames = match context.find_frames(offset) {
+            Ok(frames) => frames,
+            Err(_) => return vec![],
+        };
+        // the innermost comes first, the last is the function itself
+        while let Ok(Some(frame)) = frames.next() {
+            let name = frame
+                .function
+                .and_then(|f| f.raw_name().ok().map(|n| n.into_owned()));
+            names.push(name.unwrap_or_else(|| "<inlined>".to_string()));
+        }
+        names.pop();
+        names.reverse();
+        names
+    }
+
     pub fn name(&self) -> &str {
         &self.name
     }
@@ -125,3 +171,72 @@ impl SymbolTable {
         None
     }
 }
+
+/// `None` if the binary has no debug info
+fn load_inlines(data: &[u8]) -> Result<Option<InlineContext>, String> {
+    let file = object::File::parse(data).map_err(|e| e.to_string())?;
+    if file.section_by_name(".debug_info").is_none() {
+        return Ok(None);
+    }
+    let endian = if file.is_little_endian() {
+        RunTimeEndian::Little
+    } else {
+        RunTimeEndian::Big
+    };
+    let load = |id: gimli::SectionId| -> Result<_, gimli::Error> {
+        let data = file
+            .section_by_name(id.name())
+            .and_then(|section| section.uncompressed_data().ok())
+            .unwrap_or_default();
+        Ok(EndianArcSlice::new(Arc::from(&*data), endian))
+    };
+    let no_sup = |_| Ok(EndianArcSlice::new(Arc::from(&[][..]), endian));
+    let dwarf = gimli::Dwarf::load(load, no_sup).map_err(|e| e.to_string())?;
+    Context::from_dwarf(dwarf).map(Some).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use super::SymbolTable;
+
+    #[inline(never)]
+    fn inline_outer(x: u64) -> u64 {
+        inline_inner(x).wrapping_add(1)
+    }
+
+    #[inline(always)]
+    fn inline_inner(x: u64) -> u64 {
+        x.rotate_left(7) ^ 0x5555
+    }
+
+    #[test]
+    fn inlined() {
+        // keep the function in the binary
+        let f: fn(u64) -> u64 = inline_outer;
+        assert_eq!(f(1), 0x55d6);
+
+        // the test binary is built with debug info, `inline(always)` is inlined anyway
+        let table = SymbolTable::load(env::current_exe().unwrap()).unwrap();
+        assert!(table.has_debug_info());
+        let strtab = elf64::StringTable::new(&table.strings);
+        let symbol = table
+            .inner
+            .iter()
+            .find(|s| {
+                let name = strtab.pick(s.name_offset as usize);
+                name.map(|n| n.contains("inline_outer")).unwrap_or(false)
+            })
+            .unwrap();
+        let chains = symbol
+            .code()
+            .map(|offset| table.find_inlined(offset))
+            .collect::<Vec<_>>();
+        assert!(chains
+            .iter()
+            .any(|c| c.len() == 1 && c[0].contains("inline_inner")));
+        // the prologue of the function is not inlined code
+        assert!(chains[0].is_empty());
+        assert!(chains.iter().flatten().all(|name| !name.contains("inline_outer")));
+    }
+}
@@ -30,7 +30,7 @@ async fn get_usage_kib() -> Result<i64, E> {
 
 async fn get_pid() -> Result<u32, E> {
     let pid = get("/v1/pid", "").await.map_err(E::Serde)?;
-    let pid = pid.as_i64().ok_or(E::BadJson)?;
+    let pid = pid.get("pid").ok_or(E::BadJson)?.as_i64().ok_or(E::BadJson)?;
     Ok(pid as _)
 }
 
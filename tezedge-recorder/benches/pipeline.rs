@@ -0,0 +1,113 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Store and fetch a synthetic dataset in the in-memory database,
+//! so the cost of the pipeline is measured without disk I/O.
+
+use std::{convert::TryFrom, time::SystemTime};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use tezedge_recorder::{
+    common::{Initiator, Sender},
+    database::{rocks::Db, Database, DatabaseNew, DatabaseFetch, MessagesFilter},
+    tables::{connection, chunk, message::MessageBuilder},
+};
+
+const CONNECTIONS: u64 = 16;
+const MESSAGES: u64 = 10_000;
+
+fn open() -> Db {
+    Db::open_in_memory("bench", false, None, None, Default::default()).unwrap()
+}
+
+// bootstrap, get_current_branch and current_head of different length, one chunk each
+fn plain(i: u64) -> Vec<u8> {
+    let (tag, length) = match i % 3 {
+        0 => (0x02, 6),
+        1 => (0x10, 24),
+        _ => (0x14, 280),
+    };
+    let mut plain = ((length - 4) as u32).to_be_bytes().to_vec();
+    plain.extend_from_slice(&u16::to_be_bytes(tag));
+    plain.resize(length, (i % 256) as u8);
+    plain
+}
+
+fn store(db: &Db, messages: u64) {
+    let connections = (0..CONNECTIONS)
+        .map(|i| {
+            let remote_addr = format!("51.15.220.{}:9732", i).parse().unwrap();
+            let cn = connection::Item::new(
+                Initiator::new(i % 2 == 0),
+                remote_addr,
+                SystemTime::now(),
+            );
+            db.store_connection(cn.clone());
+            cn
+        })
+        .collect::<Vec<_>>();
+
+    for i in 0..messages {
+        let cn = &connections[(i % CONNECTIONS) as usize];
+        let sender = Sender::new(i % 5 == 0);
+        let counter = 3 + i / CONNECTIONS;
+        let plain = plain(i);
+        let six_bytes = <[u8; 6]>::try_from(&plain[..6]).unwrap();
+        let message = MessageBuilder::peer_message(six_bytes, counter)
+            .link_chunk(plain.len())
+            .ok()
+            .unwrap()
+            .build(&sender, cn, SystemTime::now());
+        let chunk = chunk::Item::new(
+            cn.key(),
+            sender,
+            counter,
+            plain.clone(),
+            plain,
+            SystemTime::now(),
+        );
+        db.store_chunk(chunk);
+        db.store_message(message);
+    }
+    db.flush();
+}
+
+fn store_messages(c: &mut Criterion) {
+    let mut group = c.benchmark_group("store");
+    group.throughput(Throughput::Elements(MESSAGES));
+    group.sample_size(10);
+    group.bench_function("messages", |b| {
+        b.iter_batched(open, |db| store(&db, MESSAGES), BatchSize::PerIteration)
+    });
+    group.finish();
+}
+
+fn fetch_messages(c: &mut Criterion) {
+    let db = open();
+    store(&db, MESSAGES);
+
+    let mut group = c.benchmark_group("fetch");
+    let types = MessagesFilter {
+        types: Some("current_head".to_string()),
+        ..Default::default()
+    };
+    let remote_addr = MessagesFilter {
+        remote_addr: Some("51.15.220.7:9732".to_string()),
+        incoming: Some(true),
+        ..Default::default()
+    };
+    let filters = [
+        ("latest", MessagesFilter::default()),
+        ("types", types),
+        ("remote_addr_incoming", remote_addr),
+    ];
+    for (name, filter) in &filters {
+        group.bench_function(*name, |b| b.iter(|| db.fetch_messages(filter).unwrap()));
+    }
+    group.bench_function("count", |b| {
+        b.iter(|| db.count_messages(&MessagesFilter::default()).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, store_messages, fetch_messages);
+criterion_main!(benches);
@@ -16,12 +16,52 @@ fn main() -> anyhow::Result<()> {
             atomic::{Ordering, AtomicBool},
         },
         io::ErrorKind,
+        fs::File,
+        process,
     };
-    use tezedge_recorder::{System, database::rocks::Db, main_loop};
+    use tezedge_recorder::{
+        System,
+        database::rocks::Db,
+        main_loop::{self, Stop},
+        telemetry::Telemetry,
+    };
+
+    // the value of the command line option
+    let arg = |name: &str| env::args().skip_while(|a| a != name).nth(1);
+
+    // `tezedge-recorder validate-config --path config.toml`, check and exit
+    if env::args().nth(1).as_deref() == Some("validate-config") {
+        let path = arg("--path").unwrap_or_else(|| "config.toml".to_string());
+        let report = tezedge_recorder::check_config(&path);
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        process::exit(if report.is_ok() { 0 } else { 1 });
+    }
+
+    // `tezedge-recorder export --db <path> --out <file> [--since <token>]`, append the records
+    // stored after the token to the file as JSON lines, print the token to resume from,
+    // the database is opened read only, the recorder may keep running
+    if env::args().nth(1).as_deref() == Some("export") {
+        use std::{fs::OpenOptions, io::BufWriter, path::Path};
+        use tezedge_recorder::database::export::Checkpoint;
 
-    tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::INFO)
-        .init();
+        let path = arg("--db").ok_or_else(|| anyhow::anyhow!("missing `--db <path>`"))?;
+        if !Path::new(&path).exists() {
+            anyhow::bail!("no database at {}", path);
+        }
+        let out = arg("--out").ok_or_else(|| anyhow::anyhow!("missing `--out <file>`"))?;
+        let since = match arg("--since") {
+            Some(token) => token.parse::<Checkpoint>()?,
+            None => Checkpoint::default(),
+        };
+        let db = Db::open_read_only(&path)?;
+        let out = OpenOptions::new().create(true).append(true).open(out)?;
+        let checkpoint = db.export(&since, BufWriter::new(out))?;
+        println!("{}", checkpoint);
+        return Ok(());
+    }
+
+    // flushes the spans when the recorder stops
+    let _telemetry = Telemetry::init(arg("--otlp-endpoint"))?;
 
     let running = Arc::new(AtomicBool::new(true));
     {
@@ -32,33 +72,64 @@ fn main() -> anyhow::Result<()> {
     let mut system = System::<Db>::load_config()?;
     system.run_dbs(running.clone());
 
-    if system.need_bpf() {
-        let bpf = if env::args().find(|a| a == "--run-bpf").is_some() {
-            let h = Command::new("bpf-recorder").spawn().or_else(|e| {
+    if let Some(path) = arg("--events-file") {
+        // replay the recorded events instead of the live ring buffer,
+        // `--replay-realtime [speed]` keeps the intervals between the events
+        let speed = if env::args().any(|a| a == "--replay-realtime") {
+            let speed = arg("--replay-realtime").and_then(|s| s.parse::<f64>().ok());
+            Some(speed.filter(|s| *s > 0.0).unwrap_or(1.0))
+        } else {
+            None
+        };
+        if let Err(error) = main_loop::run_file(&mut system, running, path, speed) {
+            log::error!("cannot replay events: {}", error)
+        }
+    } else if system.need_bpf() && env::args().any(|a| a == "--run-bpf") {
+        let spawn = || {
+            Command::new("bpf-recorder").spawn().or_else(|e| {
                 if e.kind() == ErrorKind::NotFound {
                     Command::new("./target/none/release/bpf-recorder").spawn()
                 } else {
                     Err(e)
                 }
-            });
-            match h {
-                Ok(h) => {
-                    thread::sleep(Duration::from_millis(500));
-                    if let Err(error) = main_loop::run(&mut system, running) {
-                        log::error!("cannot intercept p2p messages: {}", error)
-                    }
-                    Some(h)
-                },
+            })
+        };
+        let dump_events = arg("--dump-events");
+        if let Some(path) = &dump_events {
+            // the respawned bpf module appends to the file, start it empty
+            File::create(path)?;
+        }
+        // the watchdog, respawn the bpf module if it hangs up, until the recorder stops
+        while running.load(Ordering::Relaxed) {
+            let mut bpf = match spawn() {
+                Ok(h) => h,
                 Err(error) => {
                     log::error!("cannot run bpf: {:?}", error);
-                    None
+                    break;
+                },
+            };
+            thread::sleep(Duration::from_millis(500));
+            let stop = main_loop::run(
+                &mut system,
+                running.clone(),
+                dump_events.as_ref(),
+                Some(&mut bpf),
+            );
+            // reap the exited process, or stop the one which still runs, whatever the reason
+            let _ = bpf.kill();
+            let _ = bpf.wait();
+            match stop {
+                Ok(Stop::Hangup) => {
+                    log::warn!("bpf module hung up, respawning");
+                    thread::sleep(Duration::from_secs(1));
+                },
+                Ok(Stop::Shutdown) => break,
+                Err(error) => {
+                    log::error!("cannot intercept p2p messages: {}", error);
+                    break;
                 },
             }
-        } else {
-            None
-        };
-
-        let _ = bpf;
+        }
     }
     system.join();
 
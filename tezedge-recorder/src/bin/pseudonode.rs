@@ -19,6 +19,11 @@ enum Args {
         this: u16,
         peer: u16,
     },
+    // connects to the `peer` port where nobody listens
+    P2pRefused {
+        this: u16,
+        peer: u16,
+    },
 }
 
 fn main() {
@@ -41,9 +46,22 @@ fn main() {
         Args::P2pResponder { this, peer } => {
             generate_p2p(this, peer, false);
         },
+        Args::P2pRefused { this, peer } => {
+            generate_p2p_refused(this, peer);
+        },
     }
 }
 
+fn generate_p2p_refused(this: u16, peer: u16) {
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+
+    // the recorder knows the node by the port it listens on
+    let listener = TcpListener::bind(SocketAddr::from(([0, 0, 0, 0], this))).unwrap();
+    let addr = SocketAddr::from(([127, 0, 0, 1], peer));
+    assert!(TcpStream::connect(addr).is_err());
+    let _ = listener;
+}
+
 fn generate_p2p(this: u16, peer: u16, initiator: bool) {
     use std::net::{SocketAddr, TcpListener, TcpStream};
     use pseudonode::{handshake, Message, ChunkBuffer};
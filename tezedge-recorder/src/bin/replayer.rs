@@ -9,7 +9,7 @@ use std::{
 use tezedge_recorder::{
     common::MessageCategory,
     database::{DatabaseNew, DatabaseFetch, rocks::Db, MessagesFilter},
-    tables::message::{MessageFrontend, TezosMessage},
+    tables::message::{MessageFrontend, MessageId, TezosMessage},
 };
 use pseudonode::{ChunkBuffer, Message, handshake};
 use crypto::{
@@ -117,7 +117,7 @@ pub struct SimpleReplayer {
 
 impl Replayer for SimpleReplayer {
     fn replay_read(&mut self, id: u64) -> Option<()> {
-        let message = self.db.fetch_message(id).unwrap().unwrap();
+        let message = self.db.fetch_message(MessageId::Index(id)).unwrap().unwrap();
         let peer_message = match &message.message {
             &Some(TezosMessage::PeerMessage(ref v)) => v,
             _ => panic!(),
@@ -158,7 +158,7 @@ impl Replayer for SimpleReplayer {
     fn replay_write(&mut self, id: u64) {
         log::info!("replay write {}", id);
 
-        let message = self.db.fetch_message(id).unwrap().unwrap();
+        let message = self.db.fetch_message(MessageId::Index(id)).unwrap().unwrap();
         let peer_message = match message.message {
             Some(TezosMessage::PeerMessage(v)) => v,
             _ => panic!(),
@@ -178,7 +178,8 @@ fn main() {
         .with_max_level(tracing::Level::INFO)
         .init();
 
-    let db = Db::open("/volume/debugger_db/tezedge", false, None, None).unwrap();
+    let path = "/volume/debugger_db/tezedge";
+    let db = Db::open(path, false, None, None, Default::default()).unwrap();
 
     let mut filter = MessagesFilter::default();
     filter.cursor = Some(0);
@@ -207,7 +208,7 @@ fn main() {
         .set_read_timeout(Some(Duration::from_millis(1_000)))
         .unwrap();
 
-    let version = match db.fetch_message(0).unwrap().unwrap().message.unwrap() {
+    let version = match db.fetch_message(MessageId::Index(0)).unwrap().unwrap().message.unwrap() {
         TezosMessage::ConnectionMessage(cm) => Some(cm.version().clone()),
         _ => None,
     };
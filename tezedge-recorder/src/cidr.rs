@@ -0,0 +1,133 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::{
+    convert::TryFrom,
+    net::{IpAddr, Ipv6Addr, AddrParseError},
+    num::ParseIntError,
+    str::FromStr,
+};
+use serde::Deserialize;
+use thiserror::Error;
+
+/// Block of ip addresses, `10.0.0.0/8` or `fd00::/8`,
+/// single address without prefix length is also allowed.
+/// The ipv4 addresses also match their ipv4-mapped ipv6 form.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Cidr {
+    addr: Ipv6Addr,
+    // prefix length in terms of ipv6
+    prefix: u8,
+}
+
+#[derive(Error, Debug)]
+pub enum ParseCidrError {
+    #[error("cannot parse address: {}", _0)]
+    Addr(AddrParseError),
+    #[error("cannot parse prefix length: {}", _0)]
+    PrefixLength(ParseIntError),
+    #[error("prefix length is too big: {}", _0)]
+    PrefixTooBig(u8),
+}
+
+fn to_v6(ip: &IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => *ip,
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        if self.prefix == 0 {
+            return true;
+        }
+        let mask = u128::MAX << (128 - self.prefix as u32);
+        u128::from(to_v6(ip)) & mask == u128::from(self.addr) & mask
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = ParseCidrError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '/');
+        let ip = parts
+            .next()
+            .unwrap_or_default()
+            .parse::<IpAddr>()
+            .map_err(ParseCidrError::Addr)?;
+        let (max, shift) = match ip {
+            IpAddr::V4(_) => (32, 96),
+            IpAddr::V6(_) => (128, 0),
+        };
+        let prefix = match parts.next() {
+            Some(prefix) => prefix.parse::<u8>().map_err(ParseCidrError::PrefixLength)?,
+            None => max,
+        };
+        if prefix > max {
+            return Err(ParseCidrError::PrefixTooBig(prefix));
+        }
+        Ok(Cidr {
+            addr: to_v6(&ip),
+            prefix: prefix + shift,
+        })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = ParseCidrError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Loopback address, including ipv4-mapped ipv6 form of `127.0.0.0/8`
+pub fn is_loopback(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_loopback(),
+        IpAddr::V6(ip) => {
+            ip.is_loopback() || ip.to_ipv4().map(|ip| ip.is_loopback()).unwrap_or(false)
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use super::{Cidr, is_loopback};
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn contains() {
+        let cidr = "10.0.0.0/8".parse::<Cidr>().unwrap();
+        assert!(cidr.contains(&ip("10.1.2.3")));
+        assert!(cidr.contains(&ip("::ffff:10.1.2.3")));
+        assert!(!cidr.contains(&ip("11.0.0.1")));
+
+        let cidr = "fd00::/8".parse::<Cidr>().unwrap();
+        assert!(cidr.contains(&ip("fd12::1")));
+        assert!(!cidr.contains(&ip("fe80::1")));
+
+        let cidr = "192.168.1.7".parse::<Cidr>().unwrap();
+        assert!(cidr.contains(&ip("192.168.1.7")));
+        assert!(!cidr.contains(&ip("192.168.1.8")));
+
+        assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains(&ip("8.8.8.8")));
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn loopback() {
+        assert!(is_loopback(&ip("127.0.0.1")));
+        assert!(is_loopback(&ip("::1")));
+        assert!(is_loopback(&ip("::ffff:127.0.0.1")));
+        assert!(!is_loopback(&ip("51.15.220.7")));
+    }
+}
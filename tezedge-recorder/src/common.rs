@@ -174,7 +174,7 @@ pub enum MessageCategory {
     P2p,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum MessageType {
     Connection,
     Meta,
@@ -237,6 +237,13 @@ impl MessageType {
         }
     }
 
+    /// Each type once, in the order of its number
+    pub fn all() -> impl Iterator<Item = Self> {
+        (0..=u8::MAX)
+            .filter(|int| MessageType::from_int(*int).into_int() == *int)
+            .map(MessageType::from_int)
+    }
+
     pub fn from_int(v: u8) -> Self {
         match v {
             0x00 => MessageType::Connection,
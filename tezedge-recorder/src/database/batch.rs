@@ -0,0 +1,144 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::time::Duration;
+use serde::Deserialize;
+
+fn default_size() -> usize {
+    1
+}
+
+fn default_interval() -> u64 {
+    1000
+}
+
+/// How the store groups chunks and messages into a single write,
+/// the queue is committed when it holds `size` records or every `interval` milliseconds,
+/// so a crash loses at most one interval of the recording
+#[derive(Clone, Deserialize)]
+pub struct BatchConfig {
+    #[serde(default = "default_size")]
+    pub size: usize,
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        BatchConfig {
+            size: default_size(),
+            interval: default_interval(),
+        }
+    }
+}
+
+impl BatchConfig {
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval)
+    }
+}
+
+/// Encoded records waiting for the commit
+#[derive(Default)]
+pub struct Queue {
+    puts: Vec<(&'static str, Vec<u8>, Vec<u8>)>,
+    records: usize,
+    // the lowest index of the message in the queue
+    first_message: Option<u64>,
+}
+
+impl Queue {
+    pub fn put(&mut self, cf_name: &'static str, key: Vec<u8>, value: Vec<u8>) {
+        self.puts.push((cf_name, key, value));
+    }
+
+    /// Account the record which puts belong to
+    pub fn record(&mut self, message: Option<u64>) {
+        self.records += 1;
+        if let Some(index) = message {
+            self.first_message = Some(self.first_message.map_or(index, |first| first.min(index)));
+        }
+    }
+
+    pub fn is_full(&self, config: &BatchConfig) -> bool {
+        self.records >= config.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.puts.is_empty()
+    }
+
+    /// The message is not yet committed
+    pub fn holds_message(&self, index: u64) -> bool {
+        matches!(self.first_message, Some(first) if first <= index)
+    }
+
+    pub fn into_puts(self) -> Vec<(&'static str, Vec<u8>, Vec<u8>)> {
+        self.puts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+    use super::{
+        BatchConfig,
+        super::{
+            rocks::Db, temp::TempDb, Database, DatabaseNew, DatabaseFetch, connection, chunk,
+            common::Sender,
+        },
+    };
+
+    fn burst(db: &Db) -> Vec<chunk::Key> {
+        (0..1000)
+            .map(|counter| {
+                let cn_id = connection::Key::default();
+                let item = chunk::Item::new(
+                    cn_id,
+                    Sender::Remote,
+                    counter,
+                    vec![0; 64],
+                    vec![],
+                    SystemTime::now(),
+                );
+                let key = item.clone().split().0;
+                db.store_chunk(item);
+                key
+            })
+            .collect()
+    }
+
+    #[test]
+    fn burst_writes() {
+        // without batching every chunk is a separate write
+        let path = TempDb::new("batch");
+        let db = Db::open(&path, false, None, None, BatchConfig::default()).unwrap();
+        burst(&db);
+        assert_eq!(db.writes(), 1000);
+        drop(db);
+
+        let path = TempDb::new("batch-size");
+        let config = BatchConfig {
+            size: 300,
+            interval: 1000,
+        };
+        let db = Db::open(&path, false, None, None, config.clone()).unwrap();
+        let keys = burst(&db);
+        assert_eq!(db.writes(), 3);
+        // committed batches are visible, the rest is waiting in the queue
+        assert!(db.fetch_chunk(&keys[899]).unwrap().is_some());
+        assert!(db.fetch_chunk(&keys[900]).unwrap().is_none());
+        db.flush();
+        assert_eq!(db.writes(), 4);
+        assert!(db.fetch_chunk(&keys[999]).unwrap().is_some());
+        drop(db);
+
+        // the queue is committed on shutdown
+        let path = TempDb::new("batch-shutdown");
+        let db = Db::open(&path, false, None, None, config.clone()).unwrap();
+        let keys = burst(&db);
+        drop(db);
+        let db = Db::open(&path, false, None, None, config).unwrap();
+        assert!(db.fetch_chunk(&keys[999]).unwrap().is_some());
+    }
+}
@@ -0,0 +1,77 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// The source of time of the storage, the tests replace it to control when things happen
+pub trait Clock: Send + Sync {
+    /// The wall time, for the timestamps stored in the database
+    fn now(&self) -> SystemTime;
+
+    /// The monotonic time, for the intervals, like the throttling of the compaction
+    fn instant(&self) -> Instant;
+
+    fn now_millis(&self) -> u64 {
+        self.now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0)
+    }
+}
+
+/// The clock of the system, used unless the test sets another
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+#[cfg(test)]
+pub use self::mock::MockClock;
+
+#[cfg(test)]
+mod mock {
+    use std::{
+        sync::Mutex,
+        time::{Duration, Instant, SystemTime},
+    };
+    use super::Clock;
+
+    /// Stands still until the test advances it
+    pub struct MockClock {
+        system: SystemTime,
+        instant: Instant,
+        elapsed: Mutex<Duration>,
+    }
+
+    impl MockClock {
+        /// Starts at the wall time `system`
+        pub fn new(system: SystemTime) -> Self {
+            MockClock {
+                system,
+                instant: Instant::now(),
+                elapsed: Mutex::new(Duration::from_secs(0)),
+            }
+        }
+
+        pub fn advance(&self, duration: Duration) {
+            *self.elapsed.lock().unwrap() += duration;
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> SystemTime {
+            self.system + *self.elapsed.lock().unwrap()
+        }
+
+        fn instant(&self) -> Instant {
+            self.instant + *self.elapsed.lock().unwrap()
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Do not compact the column family more often
+pub const MIN_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Do not bother compacting the column family if there are few deletes
+pub const MIN_DELETES: u64 = 0x1000;
+
+/// The deletes and all the entries, including the deletes, of the column family,
+/// as rocksdb counts them in the memtables and in the sst files
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tombstones {
+    pub deletes: u64,
+    pub entries: u64,
+}
+
+impl Tombstones {
+    /// Parse the `rocksdb.aggregated-table-properties` of the column family,
+    /// like `# data blocks=1; # entries=100; # deletions=40; ...`
+    pub fn parse_table_properties(s: &str) -> Self {
+        let mut t = Tombstones::default();
+        for property in s.split(';') {
+            let mut kv = property.splitn(2, '=');
+            let (key, value) = match (kv.next(), kv.next()) {
+                (Some(key), Some(value)) => (key.trim(), value.trim()),
+                _ => continue,
+            };
+            let value = value.parse().unwrap_or(0);
+            match key {
+                "# entries" => t.entries = value,
+                "# deletions" => t.deletes = value,
+                _ => (),
+            }
+        }
+        t
+    }
+
+    pub fn ratio(&self) -> f64 {
+        if self.entries == 0 {
+            0.0
+        } else {
+            self.deletes as f64 / self.entries as f64
+        }
+    }
+}
+
+impl std::ops::Add for Tombstones {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self {
+        Tombstones {
+            deletes: self.deletes + rhs.deletes,
+            entries: self.entries + rhs.entries,
+        }
+    }
+}
+
+/// Decides when the ratio of deleted keys is high enough to compact the column family,
+/// throttles the compaction per column family.
+pub struct Tracker {
+    column_families: HashMap<&'static str, Mutex<Option<Instant>>>,
+}
+
+impl Tracker {
+    pub fn new<I>(names: I) -> Self
+    where
+        I: IntoIterator<Item = &'static str>,
+    {
+        Tracker {
+            column_families: names
+                .into_iter()
+                .map(|name| (name, Mutex::new(None)))
+                .collect(),
+        }
+    }
+
+    /// Column families where the ratio of deleted keys to all keys exceeds the threshold
+    /// and the last compaction was long enough ago.
+    /// `tombstones` reads the counts of the column family from rocksdb,
+    /// the compaction drops the deletes, so the counts fall after it.
+    pub fn due<F>(&self, tombstones: F, threshold: f64, now: Instant) -> Vec<&'static str>
+    where
+        F: Fn(&str) -> Tombstones,
+    {
+        let mut v = Vec::new();
+        for (name, last) in &self.column_families {
+            let t = tombstones(name);
+            if t.deletes < MIN_DELETES || t.ratio() < threshold {
+                continue;
+            }
+            let mut last = last.lock().unwrap();
+            if let Some(last) = &*last {
+                if now.duration_since(*last) < MIN_INTERVAL {
+                    continue;
+                }
+            }
+            *last = Some(now);
+            v.push(*name);
+        }
+        v
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant, SystemTime},
+    };
+    use crate::{
+        common::{Initiator, Sender},
+        tables::{connection, message::MessageBuilder},
+    };
+    use super::{
+        Tracker, Tombstones, MIN_DELETES, MIN_INTERVAL,
+        super::{rocks::Db, temp::TempDb, clock::MockClock, Database, DatabaseNew},
+    };
+
+    #[test]
+    fn table_properties() {
+        let s = "# data blocks=12; # entries=5000; # deletions=1200; # merge operands=0; \
+                 # range deletions=0; raw key size=40000; raw average key size=8.000000";
+        let t = Tombstones::parse_table_properties(s);
+        assert_eq!(t, Tombstones { deletes: 1200, entries: 5000 });
+        assert_eq!(Tombstones::parse_table_properties(""), Tombstones::default());
+        assert_eq!(Tombstones::default().ratio(), 0.0);
+    }
+
+    #[test]
+    fn many_deletes() {
+        let tracker = Tracker::new(vec!["message_storage", "log_storage"]);
+        let now = Instant::now();
+        let only = |deletes, entries| {
+            move |name: &str| match name {
+                "message_storage" => Tombstones { deletes, entries },
+                _ => Tombstones::default(),
+            }
+        };
+
+        // few deletes, even if the ratio is high
+        assert!(tracker.due(only(MIN_DELETES / 2, MIN_DELETES / 2), 0.5, now).is_empty());
+        // too many live keys, the ratio is low
+        let t = only(MIN_DELETES * 2, MIN_DELETES * 100);
+        assert!(tracker.due(t, 0.5, now).is_empty());
+        let t = only(MIN_DELETES * 2, MIN_DELETES * 3);
+        assert_eq!(tracker.due(t, 0.5, now), vec!["message_storage"]);
+
+        // the compaction is throttled
+        let t = only(MIN_DELETES * 4, MIN_DELETES * 4);
+        assert!(tracker.due(t, 0.5, now).is_empty());
+        assert_eq!(tracker.due(t, 0.5, now + MIN_INTERVAL), vec!["message_storage"]);
+    }
+
+    #[test]
+    fn retention_by_clock() {
+        let path = TempDb::new("compaction");
+        let clock = Arc::new(MockClock::new(SystemTime::now()));
+        let limit = 16;
+        let db = Db::open(&path, false, None, Some(limit), Default::default())
+            .unwrap()
+            .with_clock(clock.clone());
+
+        let cn = connection::Item::new(
+            Initiator::new(false),
+            ([51, 15, 220, 7], 9732).into(),
+            SystemTime::now(),
+        );
+        let store = |n| {
+            for _ in 0..n {
+                db.store_message(MessageBuilder::acknowledge_message().build(
+                    &Sender::Local,
+                    &cn,
+                    SystemTime::now(),
+                ));
+            }
+        };
+        // the retention removes the oldest messages, enough of them to compact
+        store(limit + MIN_DELETES);
+        assert!(db.compact(0.0).contains(&"message_storage"));
+
+        // as many removed again, but the clock stands still
+        store(MIN_DELETES);
+        assert!(db.compact(0.0).is_empty());
+        clock.advance(MIN_INTERVAL - Duration::from_secs(1));
+        assert!(db.compact(0.0).is_empty());
+        // the interval is over exactly now
+        clock.advance(Duration::from_secs(1));
+        assert!(db.compact(0.0).contains(&"message_storage"));
+    }
+}
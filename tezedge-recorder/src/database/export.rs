@@ -0,0 +1,195 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::{fmt, str::FromStr, num::ParseIntError};
+use thiserror::Error;
+use super::{connection, timestamp};
+
+/// The last exported key of each table, the next export resumes after it,
+/// `None` if nothing is exported from the table yet
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub connections: Option<connection::Key>,
+    // the last exported close, the connections closed after it are exported again
+    pub closes: Option<timestamp::CloseItem>,
+    pub messages: Option<u64>,
+    pub logs: Option<u64>,
+}
+
+#[derive(Error, Debug)]
+pub enum CheckpointFromStrError {
+    #[error("wrong formatted checkpoint token")]
+    Checkpoint,
+    #[error("unknown table {}", _0)]
+    Table(String),
+    #[error("cannot parse decimal: {}", _0)]
+    DecimalParse(ParseIntError),
+}
+
+impl FromStr for Checkpoint {
+    type Err = CheckpointFromStrError;
+
+    // format: [table]=[key],..., the tables not exported yet are omitted,
+    // the key of the close is [timestamp millis]:[connection key]
+    // example: connections=1617005682.953928051,closes=1617005690123:1617005682.953928051
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut checkpoint = Checkpoint::default();
+        for part in s.split(',').filter(|part| !part.is_empty()) {
+            let mut kv = part.splitn(2, '=');
+            let table = kv.next().ok_or(CheckpointFromStrError::Checkpoint)?;
+            let key = kv.next().ok_or(CheckpointFromStrError::Checkpoint)?;
+            match table {
+                "connections" => {
+                    let cn_id = key
+                        .parse::<connection::Key>()
+                        .map_err(|_| CheckpointFromStrError::Checkpoint)?;
+                    checkpoint.connections = Some(cn_id);
+                },
+                "closes" => {
+                    let mut parts = key.splitn(2, ':');
+                    let timestamp = parts
+                        .next()
+                        .ok_or(CheckpointFromStrError::Checkpoint)?
+                        .parse()
+                        .map_err(CheckpointFromStrError::DecimalParse)?;
+                    let cn_id = parts
+                        .next()
+                        .ok_or(CheckpointFromStrError::Checkpoint)?
+                        .parse::<connection::Key>()
+                        .map_err(|_| CheckpointFromStrError::Checkpoint)?;
+                    checkpoint.closes = Some(timestamp::CloseItem { timestamp, cn_id });
+                },
+                "messages" => {
+                    let index = key.parse().map_err(CheckpointFromStrError::DecimalParse)?;
+                    checkpoint.messages = Some(index);
+                },
+                "logs" => {
+                    let index = key.parse().map_err(CheckpointFromStrError::DecimalParse)?;
+                    checkpoint.logs = Some(index);
+                },
+                table => return Err(CheckpointFromStrError::Table(table.to_string())),
+            }
+        }
+        Ok(checkpoint)
+    }
+}
+
+impl fmt::Display for Checkpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parts = [
+            self.connections.as_ref().map(|k| format!("connections={}", k)),
+            self.closes
+                .as_ref()
+                .map(|k| format!("closes={}:{}", k.timestamp, k.cn_id)),
+            self.messages.map(|k| format!("messages={}", k)),
+            self.logs.map(|k| format!("logs={}", k)),
+        ];
+        let parts = parts.iter().flatten().cloned().collect::<Vec<_>>();
+        write!(f, "{}", parts.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, time::SystemTime};
+    use crate::{
+        common::{Initiator, Sender},
+        tables::{connection::{self, CloseReason}, message::MessageBuilder, node_log},
+    };
+    use super::{
+        Checkpoint,
+        super::{rocks::Db, temp::TempDb, Database},
+    };
+
+    #[test]
+    fn token() {
+        let checkpoint = "connections=1617005682.953928051,messages=1024"
+            .parse::<Checkpoint>()
+            .unwrap();
+        assert_eq!(checkpoint.connections.as_ref().map(|k| k.ts), Some(1617005682));
+        assert_eq!(checkpoint.messages, Some(1024));
+        assert_eq!(checkpoint.logs, None);
+        assert_eq!(
+            checkpoint.to_string(),
+            "connections=1617005682.953928051,messages=1024",
+        );
+        let token = "closes=1617005690123:1617005682.953928051";
+        let checkpoint = token.parse::<Checkpoint>().unwrap();
+        assert_eq!(checkpoint.closes.as_ref().map(|k| k.timestamp), Some(1617005690123));
+        assert_eq!(checkpoint.to_string(), token);
+        assert!("closes=1617005690123".parse::<Checkpoint>().is_err());
+        assert_eq!("".parse::<Checkpoint>().unwrap(), Checkpoint::default());
+        assert!("chunks=1".parse::<Checkpoint>().is_err());
+        assert!("messages".parse::<Checkpoint>().is_err());
+    }
+
+    #[test]
+    fn incremental() {
+        let path = TempDb::new("export");
+        let db = path.open();
+
+        let store = |port: u16| {
+            let addr = ([51, 15, 220, 7], port).into();
+            let mut cn = connection::Item::new(Initiator::new(false), addr, SystemTime::now());
+            cn.ts_nanos = u32::from(port);
+            db.store_connection(cn.clone());
+            for sender in &[Sender::Local, Sender::Remote] {
+                let message =
+                    MessageBuilder::acknowledge_message().build(sender, &cn, SystemTime::now());
+                db.store_message(message);
+            }
+            db.store_log(node_log::Item {
+                level: node_log::LogLevel::Info,
+                timestamp: 0,
+                section: String::new(),
+                message: port.to_string(),
+            });
+            cn
+        };
+        let close = |mut cn: connection::Item| {
+            cn.set_close_reason(CloseReason::Close);
+            db.update_connection(cn);
+        };
+        let export = |db: &Db, since: &Checkpoint| {
+            let mut out = Vec::new();
+            let checkpoint = db.export(since, &mut out).unwrap();
+            let lines = String::from_utf8(out)
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+                .map(|line| (line["table"].as_str().unwrap().to_string(), line["id"].to_string()))
+                .collect::<Vec<_>>();
+            (lines, checkpoint.to_string().parse::<Checkpoint>().unwrap())
+        };
+
+        let cn = store(9732);
+        store(9733);
+        let (first, checkpoint) = export(&db, &Checkpoint::default());
+        assert_eq!(first.len(), 8);
+        // the exported connection is closed, the new one is closed before the export
+        close(cn);
+        close(store(9734));
+        let (second, checkpoint) = export(&db, &checkpoint);
+        assert_eq!(second.len(), 5);
+        let connections = second.iter().filter(|(t, _)| t == "connections").count();
+        assert_eq!(connections, 2);
+        assert!(second.contains(&first[0]));
+        // nothing new
+        let (third, checkpoint) = export(&db, &checkpoint);
+        assert!(third.is_empty());
+
+        // the recorder keeps the database open
+        store(9735);
+        let read_only = Db::open_read_only(&path).unwrap();
+        let (fourth, _) = export(&read_only, &checkpoint);
+        assert_eq!(fourth.len(), 4);
+        drop(read_only);
+
+        let all = first.iter().chain(second.iter()).collect::<HashSet<_>>();
+        assert_eq!(all.len(), 12);
+        for (table, expected) in &[("connections", 3), ("messages", 6), ("logs", 3)] {
+            let n = all.iter().filter(|(t, _)| t == table).count();
+            assert_eq!(n, *expected, "{}", table);
+        }
+    }
+}
@@ -0,0 +1,168 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::time::Duration;
+use serde::{Serialize, Deserialize};
+
+#[derive(Clone, Deserialize)]
+pub struct IntegrityCheckConfig {
+    // seconds between the checks
+    pub interval: u64,
+    // the records of each table read at each check, the oldest, the newest and random ones
+    pub sample: Option<usize>,
+}
+
+impl IntegrityCheckConfig {
+    pub const DEFAULT_SAMPLE: usize = 64;
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval.max(1))
+    }
+
+    pub fn sample(&self) -> usize {
+        self.sample.unwrap_or(Self::DEFAULT_SAMPLE)
+    }
+}
+
+/// The result of reading a sample of the records back, see `Database::check_integrity`
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct Report {
+    // the records read and decoded
+    pub checked: u64,
+    pub failures: Vec<Failure>,
+    // the errors rocksdb met in the background, like the corruption found by the compaction
+    pub background_errors: u64,
+}
+
+/// The record which cannot be read or decoded
+#[derive(Debug, Clone, Serialize)]
+pub struct Failure {
+    pub table: &'static str,
+    pub error: String,
+}
+
+impl Report {
+    pub fn fail(&mut self, table: &'static str, error: String) {
+        self.failures.push(Failure { table, error });
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.failures.is_empty() && self.background_errors == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::SystemTime};
+    use rocksdb::{DB, Options};
+    use storage::persistent::{Encoder, database::RocksDbKeyValueSchema};
+    use crate::{
+        common::{Initiator, Sender},
+        tables::{connection, message::{self, MessageBuilder}},
+        system::{self, NodeStatus},
+        server,
+    };
+    use super::super::{temp::TempDb, Database};
+
+    #[test]
+    fn corrupted_message() {
+        let path = TempDb::new("intgr");
+
+        let db = path.open();
+        let cn = connection::Item::new(
+            Initiator::new(false),
+            ([51, 15, 220, 7], 9732).into(),
+            SystemTime::now(),
+        );
+        db.store_connection(cn.clone());
+        for sender in &[Sender::Local, Sender::Remote] {
+            let message =
+                MessageBuilder::acknowledge_message().build(sender, &cn, SystemTime::now());
+            db.store_message(message);
+        }
+        db.flush();
+        let report = db.check_integrity(16);
+        assert!(report.is_healthy(), "{:?}", report);
+        assert!(report.checked >= 3);
+        let status = NodeStatus::new(None, NodeStatus::DEFAULT_POW_TARGET);
+        assert!(status.integrity().is_none());
+        system::check_integrity("node", &db, &status, 16);
+        assert!(status.integrity().unwrap().is_healthy());
+        drop(db);
+
+        // the bad disk returns garbage instead of the newest message
+        {
+            let rocksdb_path = path.join("rocksdb");
+            let opts = Options::default();
+            let cfs = DB::list_cf(&opts, &rocksdb_path).unwrap();
+            let inner = DB::open_cf(&opts, &rocksdb_path, cfs).unwrap();
+            let cf = inner.cf_handle(message::Schema::name()).unwrap();
+            inner.put_cf(cf, 1u64.encode().unwrap(), [0xff; 3]).unwrap();
+        }
+
+        let db = path.open();
+        let report = db.check_integrity(16);
+        assert!(!report.is_healthy());
+        assert_eq!(report.failures.len(), 1);
+        assert_eq!(report.failures[0].table, message::Schema::name());
+        // the flag flips, the health tells the failure
+        system::check_integrity("node", &db, &status, 16);
+        let integrity = status.integrity().unwrap();
+        assert!(!integrity.is_healthy());
+        assert_eq!(integrity.failures.len(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn corrupted_in_the_middle() {
+        let path = TempDb::new("intgm");
+
+        let db = path.open();
+        let cn = connection::Item::new(
+            Initiator::new(false),
+            ([51, 15, 220, 7], 9732).into(),
+            SystemTime::now(),
+        );
+        db.store_connection(cn.clone());
+        for _ in 0..1000 {
+            let message =
+                MessageBuilder::acknowledge_message().build(&Sender::Local, &cn, SystemTime::now());
+            db.store_message(message);
+        }
+        db.flush();
+        drop(db);
+
+        // neither the oldest nor the newest messages are corrupted, but a half of the others
+        {
+            let rocksdb_path = path.join("rocksdb");
+            let opts = Options::default();
+            let cfs = DB::list_cf(&opts, &rocksdb_path).unwrap();
+            let inner = DB::open_cf(&opts, &rocksdb_path, cfs).unwrap();
+            let cf = inner.cf_handle(message::Schema::name()).unwrap();
+            for index in 100..600u64 {
+                inner.put_cf(cf, index.encode().unwrap(), [0xff; 3]).unwrap();
+            }
+        }
+
+        let db = Arc::new(path.open());
+        let status = Arc::new(NodeStatus::new(None, NodeStatus::DEFAULT_POW_TARGET));
+        let routes = server::routes(db.clone(), status.clone());
+        let response = warp::test::request().path("/v3/health").reply(&routes).await;
+        assert!(response.headers().get(server::DEGRADED_HEADER).is_none());
+
+        // the random records across the key range hit the corrupted ones
+        system::check_integrity("node", db.as_ref(), &status, 64);
+        let integrity = status.integrity().unwrap();
+        assert!(!integrity.is_healthy());
+        assert!(integrity
+            .failures
+            .iter()
+            .all(|failure| failure.table == message::Schema::name()));
+        let response = warp::test::request().path("/v3/health").reply(&routes).await;
+        assert_eq!(response.headers()[server::DEGRADED_HEADER], "true");
+        let response = warp::test::request()
+            .path("/v3/messages?limit=1")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.headers()[server::DEGRADED_HEADER], "true");
+    }
+}
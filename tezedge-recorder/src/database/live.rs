@@ -0,0 +1,71 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+use super::{connection, message};
+
+/// The record of the connection published as soon as it is stored
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub cn_id: connection::Key,
+    pub kind: EventKind,
+    // the connection is closed, nothing of it follows
+    pub last: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum EventKind {
+    Message(message::MessageFrontend),
+    // the connection as in `/v3/connections`, when it is stored and when it changes
+    Connection(serde_json::Value),
+}
+
+impl EventKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventKind::Message(_) => "message",
+            EventKind::Connection(_) => "connection",
+        }
+    }
+}
+
+/// The subscriber which is too slow misses the oldest events, see `RecvError::Lagged`
+pub struct Publisher {
+    sender: broadcast::Sender<Event>,
+}
+
+impl Default for Publisher {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(Self::CAPACITY);
+        Publisher { sender }
+    }
+}
+
+impl Publisher {
+    const CAPACITY: usize = 0x400;
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+
+    /// The event is built only if somebody listens
+    pub fn publish<F>(&self, cn_id: &connection::Key, last: bool, f: F)
+    where
+        F: FnOnce() -> Option<EventKind>,
+    {
+        if self.sender.receiver_count() == 0 {
+            return;
+        }
+        if let Some(kind) = f() {
+            let event = Event {
+                cn_id: cn_id.clone(),
+                kind,
+                last,
+            };
+            // the subscribers are gone meanwhile, nothing to do
+            let _ = self.sender.send(event);
+        }
+    }
+}
@@ -3,7 +3,7 @@
 
 use std::{
     path::Path,
-    sync::Mutex,
+    sync::{Arc, Mutex},
     fs::File,
     io::{self, Write},
 };
@@ -11,15 +11,18 @@ use anyhow::Result;
 #[rustfmt::skip]
 use super::{
     // core traits
-    Database, DatabaseNew, DatabaseFetch,
+    Database, DatabaseNew, DatabaseFetch, throughput, stats, batch, timeline, live, integrity,
+    clock::{Clock, SystemClock},
     // filters
-    ConnectionsFilter, ChunksFilter, MessagesFilter, LogsFilter,
+    ConnectionsFilter, ChunksFilter, MessagesFilter, LogsFilter, ThroughputFilter, TimelineFilter,
+    MessageTypesFilter,
     // tables
-    connection, chunk, message, node_log,
+    connection, connection_crypto, connection_geo, chunk, message, node_log,
 };
 
 pub struct Db {
-    file: Mutex<File>,
+    file: Mutex<Box<dyn Write + Send>>,
+    live: live::Publisher,
 }
 
 impl DatabaseNew for Db {
@@ -30,14 +33,34 @@ impl DatabaseNew for Db {
         log_full_text_index: bool,
         log_store_limit: Option<u64>,
         message_store_limit: Option<u64>,
+        batch: batch::BatchConfig,
     ) -> Result<Self, Self::Error>
     where
         P: AsRef<Path>,
     {
-        let _ = (log_full_text_index, log_store_limit, message_store_limit);
+        let _ = (log_full_text_index, log_store_limit, message_store_limit, batch);
 
         Ok(Db {
-            file: Mutex::new(File::create(path)?),
+            file: Mutex::new(Box::new(File::create(path)?)),
+            live: live::Publisher::default(),
+        })
+    }
+
+    fn open_in_memory<P>(
+        path: P,
+        log_full_text_index: bool,
+        log_store_limit: Option<u64>,
+        message_store_limit: Option<u64>,
+        batch: batch::BatchConfig,
+    ) -> Result<Self, Self::Error>
+    where
+        P: AsRef<Path>,
+    {
+        let _ = (path, log_full_text_index, log_store_limit, message_store_limit, batch);
+
+        Ok(Db {
+            file: Mutex::new(Box::new(io::sink())),
+            live: live::Publisher::default(),
         })
     }
 }
@@ -51,6 +74,14 @@ impl Database for Db {
             .unwrap();
     }
 
+    fn store_connection_crypto(&self, cn_id: connection::Key, value: connection_crypto::Value) {
+        let _ = (cn_id, value);
+    }
+
+    fn store_connection_geo(&self, cn_id: connection::Key, value: connection_geo::Value) {
+        let _ = (cn_id, value);
+    }
+
     fn update_connection(&self, item: connection::Item) {
         self.file
             .lock()
@@ -59,6 +90,10 @@ impl Database for Db {
             .unwrap();
     }
 
+    fn remove_chunk(&self, key: &chunk::Key) {
+        let _ = key;
+    }
+
     fn store_chunk(&self, item: chunk::Item) {
         let (key, value) = item.split();
         self.file
@@ -87,6 +122,40 @@ impl Database for Db {
             .write_fmt(format_args!("log: {:?}", item.level))
             .unwrap();
     }
+
+    fn set_session(&self, label: Option<String>) {
+        self.file
+            .lock()
+            .unwrap()
+            .write_fmt(format_args!("session: {:?}", label))
+            .unwrap();
+    }
+
+    fn set_tail_size(&self, size: usize) {
+        let _ = size;
+    }
+
+    fn compact(&self, tombstone_threshold: f64) -> Vec<&'static str> {
+        let _ = tombstone_threshold;
+        Vec::new()
+    }
+
+    fn flush(&self) {
+        self.file.lock().unwrap().flush().unwrap();
+    }
+
+    fn prune(&self, fraction: f64) {
+        let _ = fraction;
+    }
+
+    fn check_integrity(&self, sample: usize) -> integrity::Report {
+        let _ = sample;
+        integrity::Report::default()
+    }
+
+    fn clock(&self) -> Arc<dyn Clock> {
+        Arc::new(SystemClock)
+    }
 }
 
 impl DatabaseFetch for Db {
@@ -98,6 +167,23 @@ impl DatabaseFetch for Db {
         Ok(vec![])
     }
 
+    fn fetch_connections_after(
+        &self,
+        after: Option<&connection::Key>,
+        limit: usize,
+    ) -> Result<Vec<(connection::Key, connection::Value)>, Self::Error> {
+        let _ = (after, limit);
+        Ok(vec![])
+    }
+
+    fn fetch_connection(
+        &self,
+        cn_id: &connection::Key,
+    ) -> Result<Option<connection::Value>, Self::Error> {
+        let _ = cn_id;
+        Ok(None)
+    }
+
     fn fetch_chunks_truncated(
         &self,
         filter: &ChunksFilter,
@@ -111,6 +197,14 @@ impl DatabaseFetch for Db {
         Ok(None)
     }
 
+    fn fetch_connection_crypto(
+        &self,
+        cn_id: &connection::Key,
+    ) -> Result<Option<connection_crypto::Diagnostics>, Self::Error> {
+        let _ = cn_id;
+        Ok(None)
+    }
+
     fn fetch_messages(
         &self,
         filter: &MessagesFilter,
@@ -119,13 +213,76 @@ impl DatabaseFetch for Db {
         Ok(vec![])
     }
 
-    fn fetch_message(&self, id: u64) -> Result<Option<message::MessageDetails>, Self::Error> {
+    fn fetch_messages_tail(&self, n: u64) -> Result<Vec<message::MessageFrontend>, Self::Error> {
+        let _ = n;
+        Ok(vec![])
+    }
+
+    fn for_each_message<F>(&self, filter: &MessagesFilter, f: F) -> Result<(), Self::Error>
+    where
+        F: FnMut(message::MessageFrontend) -> bool,
+    {
+        let _ = (filter, f);
+        Ok(())
+    }
+
+    fn fetch_message(
+        &self,
+        id: message::MessageId,
+    ) -> Result<Option<message::MessageDetails>, Self::Error> {
         let _ = id;
         Ok(None)
     }
 
+    fn fetch_message_raw(&self, id: u64) -> Result<Option<message::MessageRaw>, Self::Error> {
+        let _ = id;
+        Ok(None)
+    }
+
+    fn count_messages(&self, filter: &MessagesFilter) -> Result<u64, Self::Error> {
+        let _ = filter;
+        Ok(0)
+    }
+
     fn fetch_log(&self, filter: &LogsFilter) -> Result<Vec<node_log::ItemWithId>, Self::Error> {
         let _ = filter;
         Ok(vec![])
     }
+
+    fn fetch_throughput(
+        &self,
+        filter: &ThroughputFilter,
+    ) -> Result<Vec<throughput::Bucket>, Self::Error> {
+        let _ = filter;
+        Ok(vec![])
+    }
+
+    fn fetch_stats(&self) -> Result<stats::Stats, Self::Error> {
+        Ok(stats::Stats::default())
+    }
+
+    fn fetch_message_types(
+        &self,
+        filter: &MessageTypesFilter,
+    ) -> Result<Vec<stats::MessageTypeStats>, Self::Error> {
+        let _ = filter;
+        Ok(vec![])
+    }
+
+    fn fetch_decode_coverage(
+        &self,
+        filter: &MessageTypesFilter,
+    ) -> Result<Vec<stats::DecodeCoverage>, Self::Error> {
+        let _ = filter;
+        Ok(vec![])
+    }
+
+    fn fetch_timeline(&self, filter: &TimelineFilter) -> Result<timeline::Timeline, Self::Error> {
+        let _ = filter;
+        Ok(timeline::Timeline::default())
+    }
+
+    fn subscribe(&self) -> tokio::sync::broadcast::Receiver<live::Event> {
+        self.live.subscribe()
+    }
 }
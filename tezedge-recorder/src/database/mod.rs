@@ -4,33 +4,81 @@
 pub mod rocks;
 pub mod mock;
 pub mod search;
+pub mod throughput;
+pub mod stats;
+pub mod batch;
+pub mod timeline;
+pub mod live;
+pub mod tail;
+pub mod verify;
+pub mod clock;
+pub mod export;
+pub mod integrity;
+#[cfg(test)]
+pub mod temp;
 
 mod sorted_intersect;
+mod compaction;
 
-use std::{error::Error, path::Path};
+use std::{error::Error, path::Path, sync::Arc};
 use serde::Deserialize;
 use super::{tables::*, common};
+use self::clock::Clock;
 
 pub trait Database {
     fn store_connection(&self, item: connection::Item);
+    /// The keys and the nonces of the connection, see `connection_crypto`
+    fn store_connection_crypto(&self, cn_id: connection::Key, value: connection_crypto::Value);
+    /// The country and the asn of the remote address, see `geoip::Enricher`
+    fn store_connection_geo(&self, cn_id: connection::Key, value: connection_geo::Value);
     fn update_connection(&self, item: connection::Item);
     fn store_chunk(&self, item: chunk::Item);
+    /// Remove the chunk with its event and gap, the chunks are stored again decoded,
+    /// see `processor::decode_stored`
+    fn remove_chunk(&self, key: &chunk::Key);
     fn store_message(&self, item: message::Item);
     fn store_log(&self, item: node_log::Item);
+    /// Label connections, messages and logs stored from now on, `None` stops labeling
+    fn set_session(&self, label: Option<String>);
+    /// Keep the `size` most recent messages in memory for `fetch_messages_tail`
+    fn set_tail_size(&self, size: usize);
+    /// Compact the storage where the ratio of deleted records exceeds the threshold,
+    /// return the names of the compacted column families
+    fn compact(&self, tombstone_threshold: f64) -> Vec<&'static str>;
+    /// Commit the queued chunks and messages
+    fn flush(&self);
+    /// Remove the oldest `fraction` of messages and logs to free the space
+    fn prune(&self, fraction: f64);
+    /// Read back and decode the newest `sample` records of each table
+    fn check_integrity(&self, sample: usize) -> integrity::Report;
+    /// The timestamps of the records and the timeouts of the connections are taken from it
+    fn clock(&self) -> Arc<dyn Clock>;
 }
 
-#[derive(Deserialize)]
+#[derive(Default, Deserialize)]
 pub struct ConnectionsFilter {
     pub limit: Option<u64>,
+    pub session: Option<String>,
+    pub close_reason: Option<connection::CloseReason>,
+    // `failed` selects the outgoing connects which failed, see `CloseReason::ConnectFailed`
+    pub status: Option<connection::ConnectionStatus>,
+    pub pow_valid: Option<bool>,
+    // the flags of the metadata message of the peer
+    pub private_node: Option<bool>,
+    pub disable_mempool: Option<bool>,
+    // resolved by `geoip`, the country code is case insensitive
+    pub country: Option<String>,
+    pub asn: Option<u32>,
 }
 
 #[derive(Deserialize)]
 pub struct ChunksFilter {
     pub limit: Option<u64>,
     pub cn: Option<String>,
+    pub preview: Option<u64>,
 }
 
-#[derive(Deserialize, Default)]
+#[derive(Deserialize, Default, Clone)]
 pub struct MessagesFilter {
     pub direction: Option<String>,
     pub limit: Option<u64>,
@@ -42,10 +90,48 @@ pub struct MessagesFilter {
     pub from: Option<u64>,
     pub to: Option<u64>,
     pub timestamp: Option<u64>,
+    pub session: Option<String>,
+    // hex of the blake2b of the decrypted bytes
+    pub hash: Option<String>,
+    // the range of message ids, both inclusive
+    pub id_from: Option<u64>,
+    pub id_to: Option<u64>,
+    // the level of the header of `block_header` and `current_head`, both inclusive
+    pub min_level: Option<i32>,
+    pub max_level: Option<i32>,
+    // the base58 chain id carried by `deactivate`
+    pub chain_id: Option<String>,
     // compatibility
     pub node_name: Option<String>,
 }
 
+#[derive(Deserialize)]
+pub struct ThroughputFilter {
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    pub bucket: Option<u64>,
+}
+
+#[derive(Deserialize, Default)]
+pub struct MessageTypesFilter {
+    // milliseconds, `[from, to)`
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    // sum the sizes of the messages, it reads the brief of each message, not only the index
+    pub bytes: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct TailFilter {
+    // the number of messages, default is `tail::Tail::DEFAULT_SIZE`
+    pub n: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct SessionFilter {
+    pub label: Option<String>,
+}
+
 #[derive(Deserialize)]
 pub struct LogsFilter {
     pub direction: Option<String>,
@@ -56,10 +142,29 @@ pub struct LogsFilter {
     pub to: Option<u64>,
     pub timestamp: Option<u64>,
     pub query: Option<String>,
+    pub session: Option<String>,
     // compatibility
     pub node_name: Option<String>,
 }
 
+/// The two ends of one session, the connection and the node which recorded it
+#[derive(Deserialize)]
+pub struct VerifyFilter {
+    pub initiator_node: String,
+    pub initiator: String,
+    pub responder_node: String,
+    pub responder: String,
+}
+
+#[derive(Deserialize, Default)]
+pub struct TimelineFilter {
+    pub limit: Option<u64>,
+    pub cursor: Option<String>,
+    // milliseconds, `[from, to)`
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+}
+
 pub trait DatabaseFetch
 where
     Self: DatabaseNew,
@@ -69,6 +174,19 @@ where
         filter: &ConnectionsFilter,
     ) -> Result<Vec<(connection::Key, connection::Value)>, Self::Error>;
 
+    /// At most `limit` connections in the order of the key after the `after` key,
+    /// to walk through all of them page by page
+    fn fetch_connections_after(
+        &self,
+        after: Option<&connection::Key>,
+        limit: usize,
+    ) -> Result<Vec<(connection::Key, connection::Value)>, Self::Error>;
+
+    fn fetch_connection(
+        &self,
+        cn_id: &connection::Key,
+    ) -> Result<Option<connection::Value>, Self::Error>;
+
     fn fetch_chunks_truncated(
         &self,
         filter: &ChunksFilter,
@@ -76,14 +194,65 @@ where
 
     fn fetch_chunk(&self, key: &chunk::Key) -> Result<Option<chunk::Value>, Self::Error>;
 
+    /// The keys and the nonces of the connection, if `debug_crypto` is configured
+    fn fetch_connection_crypto(
+        &self,
+        cn_id: &connection::Key,
+    ) -> Result<Option<connection_crypto::Diagnostics>, Self::Error>;
+
     fn fetch_messages(
         &self,
         filter: &MessagesFilter,
     ) -> Result<Vec<message::MessageFrontend>, Self::Error>;
 
-    fn fetch_message(&self, id: u64) -> Result<Option<message::MessageDetails>, Self::Error>;
+    /// The `n` most recent messages, the newest first, from memory if it holds so many,
+    /// otherwise from the storage, see `tail::Tail`
+    fn fetch_messages_tail(&self, n: u64) -> Result<Vec<message::MessageFrontend>, Self::Error>;
+
+    /// The same messages as `fetch_messages`, but each is passed to `f` as soon as it is loaded,
+    /// so they are never held together, stops when `f` returns `false`
+    fn for_each_message<F>(&self, filter: &MessagesFilter, f: F) -> Result<(), Self::Error>
+    where
+        F: FnMut(message::MessageFrontend) -> bool;
+
+    /// By the index, or by the stable id which survives rebuilding the database
+    fn fetch_message(
+        &self,
+        id: message::MessageId,
+    ) -> Result<Option<message::MessageDetails>, Self::Error>;
+
+    /// The chunks of the message with the bpf events they were captured from
+    fn fetch_message_raw(&self, id: u64) -> Result<Option<message::MessageRaw>, Self::Error>;
+
+    fn count_messages(&self, filter: &MessagesFilter) -> Result<u64, Self::Error>;
 
     fn fetch_log(&self, filter: &LogsFilter) -> Result<Vec<node_log::ItemWithId>, Self::Error>;
+
+    fn fetch_throughput(
+        &self,
+        filter: &ThroughputFilter,
+    ) -> Result<Vec<throughput::Bucket>, Self::Error>;
+
+    fn fetch_stats(&self) -> Result<stats::Stats, Self::Error>;
+
+    /// The number of messages of each type, the types without messages are omitted
+    fn fetch_message_types(
+        &self,
+        filter: &MessageTypesFilter,
+    ) -> Result<Vec<stats::MessageTypeStats>, Self::Error>;
+
+    /// The messages of each type decoded, stored raw and failed to decode,
+    /// it decodes each message in the range, the types without messages are omitted
+    fn fetch_decode_coverage(
+        &self,
+        filter: &MessageTypesFilter,
+    ) -> Result<Vec<stats::DecodeCoverage>, Self::Error>;
+
+    /// Messages, logs and connection events in timestamp order
+    fn fetch_timeline(&self, filter: &TimelineFilter) -> Result<timeline::Timeline, Self::Error>;
+
+    /// The connections and the messages stored from now on
+    fn subscribe(&self) -> tokio::sync::broadcast::Receiver<live::Event>;
 }
 
 pub trait DatabaseNew
@@ -97,6 +266,19 @@ where
         log_full_text_index: bool,
         log_store_limit: Option<u64>,
         message_store_limit: Option<u64>,
+        batch: batch::BatchConfig,
+    ) -> Result<Self, Self::Error>
+    where
+        P: AsRef<Path>;
+
+    /// The same storage with the same indexes, but nothing is written to the disk,
+    /// the content is lost when the database is dropped, useful for load testing
+    fn open_in_memory<P>(
+        path: P,
+        log_full_text_index: bool,
+        log_store_limit: Option<u64>,
+        message_store_limit: Option<u64>,
+        batch: batch::BatchConfig,
     ) -> Result<Self, Self::Error>
     where
         P: AsRef<Path>;
@@ -2,12 +2,17 @@
 // SPDX-License-Identifier: MIT
 
 use std::{
+    convert::TryFrom,
+    fmt, io,
     net::SocketAddr,
     ops::Add,
     path::{Path, PathBuf},
-    sync::atomic::{Ordering, AtomicU64},
+    sync::{
+        Arc, Mutex, RwLock,
+        atomic::{Ordering, AtomicU64},
+    },
 };
-use rocksdb::{Cache, DB, ReadOptions};
+use rocksdb::{Cache, DB, Env, ReadOptions, WriteBatch};
 use storage::{
     Direction, IteratorMode,
     persistent::{
@@ -20,17 +25,25 @@ use tantivy::TantivyError;
 use anyhow::Result;
 use thiserror::Error;
 use itertools::Itertools;
-use super::sorted_intersect::sorted_intersect;
+use super::{
+    sorted_intersect::{sorted_intersect, sorted_intersect_count},
+    compaction, export,
+    clock::{Clock, SystemClock},
+};
 #[rustfmt::skip]
 use super::{
     // core traits
-    Database, DatabaseNew, DatabaseFetch, search,
+    Database, DatabaseNew, DatabaseFetch, search, throughput, stats, batch, timeline, live, tail,
+    integrity,
     // filters
-    ConnectionsFilter, ChunksFilter, MessagesFilter, LogsFilter,
+    ConnectionsFilter, ChunksFilter, MessagesFilter, LogsFilter, ThroughputFilter, TimelineFilter,
+    MessageTypesFilter,
     // tables
-    common, connection, chunk, message, node_log,
+    common, connection, connection_crypto, connection_geo, chunk, chunk_event, chunk_gap, message,
+    node_log,
     // secondary indexes
-    message_ty, message_sender, message_initiator, message_addr, log_level, timestamp,
+    message_ty, message_sender, message_initiator, message_addr, log_level, timestamp, session,
+    message_hash, message_stable,
 };
 
 #[derive(Error, Debug)]
@@ -62,7 +75,22 @@ pub struct Db {
     log_store_limit: Option<u64>,
     log_counter: AtomicU64,
     log_indexer: Option<search::LogIndexer>,
+    compaction: compaction::Tracker,
+    session: RwLock<Option<String>>,
+    batch: batch::BatchConfig,
+    queue: Mutex<batch::Queue>,
+    // the number of writes committed to rocksdb
+    writes: AtomicU64,
+    // the chunks lost before storing, since the recorder started
+    missing_chunks: AtomicU64,
+    live: live::Publisher,
+    // the most recent messages for `fetch_messages_tail`
+    tail: tail::Tail,
+    // the source of the timestamps and the intervals, replaced by the tests
+    clock: Arc<dyn Clock>,
     inner: DB,
+    // the memory environment of the database opened in memory, must outlive `inner`
+    _env: Option<Env>,
 }
 
 impl Db {
@@ -80,6 +108,531 @@ impl Db {
     fn reserve_log_counter(&self) -> u64 {
         self.log_counter.fetch_add(1, Ordering::SeqCst)
     }
+
+    fn delete<S>(&self, key: &S::Key) -> Result<(), DBError>
+    where
+        S: KeyValueSchema + RocksDbKeyValueSchema,
+    {
+        self.as_kv::<S>().delete(key)?;
+        Ok(())
+    }
+
+    fn session(&self) -> Option<String> {
+        self.session.read().unwrap().clone()
+    }
+
+    pub fn writes(&self) -> u64 {
+        self.writes.load(Ordering::Relaxed)
+    }
+
+    fn enqueue<S>(queue: &mut batch::Queue, key: &S::Key, value: &S::Value) -> Result<(), DBError>
+    where
+        S: KeyValueSchema + RocksDbKeyValueSchema,
+    {
+        let key = key.encode().map_err(|error| DBError::SchemaError { error })?;
+        let value = value
+            .encode()
+            .map_err(|error| DBError::SchemaError { error })?;
+        queue.put(S::name(), key, value);
+        Ok(())
+    }
+
+    /// Commit the queue if it is full, the queue is bounded by the configured size
+    fn commit_full(&self, queue: &mut batch::Queue) -> Result<(), DBError> {
+        if queue.is_full(&self.batch) {
+            self.commit(queue)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn commit(&self, queue: &mut batch::Queue) -> Result<(), DBError> {
+        let queue = std::mem::take(queue);
+        if queue.is_empty() {
+            return Ok(());
+        }
+        let mut batch = WriteBatch::default();
+        for (name, key, value) in queue.into_puts() {
+            let cf = self
+                .inner
+                .cf_handle(name)
+                .ok_or(DBError::MissingColumnFamily { name })?;
+            batch.put_cf(cf, key, value);
+        }
+        self.inner
+            .write(batch)
+            .map_err(|error| DBError::RocksDBError { error })?;
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn session_iter<S>(
+        &self,
+        label: &str,
+        cursor: u64,
+        forward: bool,
+    ) -> Result<Box<dyn Iterator<Item = u64> + '_>, DBError>
+    where
+        S: KeyValueSchema<Key = session::Item> + RocksDbKeyValueSchema,
+    {
+        let key = session::Item {
+            session: session::hash(label),
+            index: cursor,
+        };
+        let key = key
+            .encode()
+            .map_err(|error| DBError::SchemaError { error })?;
+        let direction = if forward {
+            Direction::Forward
+        } else {
+            Direction::Reverse
+        };
+        let mode = rocksdb::IteratorMode::From(&key, direction.into());
+        let cf = self
+            .inner
+            .cf_handle(S::name())
+            .ok_or_else(|| DBError::MissingColumnFamily { name: S::name() })?;
+        let mut opts = ReadOptions::default();
+        opts.set_prefix_same_as_start(true);
+        let it = self
+            .inner
+            .iterator_cf_opt(cf, opts, mode)
+            .filter_map(|(k, _)| Some(session::Item::decode(&k).ok()?.index));
+        Ok(Box::new(it))
+    }
+
+    fn timeline_event(
+        &self,
+        position: &timeline::Cursor,
+    ) -> Result<Option<timeline::Event>, DBError> {
+        use self::timeline::{Kind, Event, ConnectionEvent};
+
+        let index = position.id.0;
+        let event = match position.kind {
+            Kind::Message => self
+                .as_kv::<message::Schema>()
+                .get(&index)?
+                .map(|value| Event::Message(self.frontend(value, index))),
+            Kind::Log => self
+                .as_kv::<node_log::Schema>()
+                .get(&index)?
+                .map(|value| Event::Log(node_log::ItemWithId::new(value, index))),
+            Kind::ConnectionOpen | Kind::ConnectionClose => {
+                let id = position.cn_id();
+                self.as_kv::<connection::Schema>()
+                    .get(&id)?
+                    .map(|value| {
+                        let event = ConnectionEvent {
+                            id,
+                            timestamp: (position.timestamp as u128) * 1_000_000,
+                            value,
+                        };
+                        if position.kind == Kind::ConnectionOpen {
+                            Event::ConnectionOpen(event)
+                        } else {
+                            Event::ConnectionClose(event)
+                        }
+                    })
+            },
+        };
+        Ok(event)
+    }
+
+    fn encoding_version(&self, item: &message::Item) -> Option<u16> {
+        self.as_kv::<connection::Schema>()
+            .get(&item.connection())
+            .ok()??
+            .version()
+    }
+
+    /// The message longer than the limit is not decoded, its placeholder is returned
+    fn details(
+        &self,
+        item: &message::Item,
+        index: u64,
+    ) -> Result<message::MessageDetails, DbError> {
+        let version = self.encoding_version(item);
+        if let Some(oversized) = self.as_kv::<message::OversizedSchema>().get(&index)? {
+            return Ok(message::MessageDetails::oversized(index, oversized, version));
+        }
+        details(item, index, version, self.as_kv())
+    }
+
+    fn frontend(&self, mut value: message::Item, index: u64) -> message::MessageFrontend {
+        let timestamp_index = timestamp::Item {
+            timestamp: value.timestamp,
+            index,
+        };
+        if let Ok(Some(v)) = self.as_kv::<timestamp::MessageSchema>().get(&timestamp_index) {
+            value.hash = v.hash;
+        }
+        match self.details(&value, index) {
+            Ok(details) => {
+                let preview = match details.json_string() {
+                    Ok(p) => p.map(|mut s| {
+                        utf8_truncate(&mut s, 100);
+                        s
+                    }),
+                    Err(error) => {
+                        log::error!(
+                            "Failed to deserialize message {:?}, error: {}",
+                            value,
+                            error
+                        );
+                        None
+                    },
+                };
+                message::MessageFrontend::new(value, index, preview, Some(&details))
+            },
+            Err(error) => {
+                log::error!("Failed to chunks for {:?}, error: {}", value, error);
+                message::MessageFrontend::new(value, index, None, None)
+            },
+        }
+    }
+
+    /// The index of the first message recorded at or after the timestamp
+    fn message_index_at(&self, timestamp: u64) -> Result<Option<u64>, DBError> {
+        let key = timestamp::Item {
+            timestamp,
+            index: 0,
+        };
+        let index = self
+            .as_kv::<timestamp::MessageSchema>()
+            .iterator(IteratorMode::From(&key, Direction::Forward))?
+            .filter_map(|(k, _)| k.ok())
+            .map(|k| k.index)
+            .next();
+        Ok(index)
+    }
+
+    /// Messages are indexed in the order they are recorded, so the time range `[from, to)`
+    /// in milliseconds is the range of indexes, `None` if nothing is recorded since `from`
+    fn message_index_range(
+        &self,
+        from: Option<u64>,
+        to: Option<u64>,
+    ) -> Result<Option<(u64, u64)>, DBError> {
+        let begin = match from {
+            Some(from) => match self.message_index_at(from)? {
+                Some(index) => index,
+                None => return Ok(None),
+            },
+            None => 0,
+        };
+        let end = match to {
+            Some(to) => self.message_index_at(to)?.unwrap_or(u64::MAX),
+            None => u64::MAX,
+        };
+        Ok(Some((begin, end)))
+    }
+
+    /// The indexes of the messages of the type in `[begin, end)`
+    fn message_type_iter(
+        &self,
+        ty: &common::MessageType,
+        begin: u64,
+        end: u64,
+    ) -> Result<impl Iterator<Item = u64> + '_, DBError> {
+        let cf = self
+            .inner
+            .cf_handle(message_ty::Schema::name())
+            .ok_or_else(|| DBError::MissingColumnFamily {
+                name: message_ty::Schema::name(),
+            })?;
+        let key = message_ty::Item {
+            ty: ty.clone(),
+            index: begin,
+        };
+        let key = key
+            .encode()
+            .map_err(|error| DBError::SchemaError { error })?;
+        let mode = rocksdb::IteratorMode::From(&key, Direction::Forward.into());
+        let mut opts = ReadOptions::default();
+        opts.set_prefix_same_as_start(true);
+        let it = self
+            .inner
+            .iterator_cf_opt(cf, opts, mode)
+            .filter_map(|(k, _)| Some(message_ty::Item::decode(&k).ok()?.index))
+            .take_while(move |index| *index < end);
+        Ok(it)
+    }
+
+    /// The size is in the timestamp index, the timestamp is in the message brief
+    fn message_size(&self, index: u64) -> Result<u64, DBError> {
+        let timestamp = match self.as_kv::<message::Schema>().get(&index)? {
+            Some(item) => item.timestamp,
+            None => return Ok(0),
+        };
+        let key = timestamp::Item { timestamp, index };
+        let size = self
+            .as_kv::<timestamp::MessageSchema>()
+            .get(&key)?
+            .map_or(0, |v| v.size as u64);
+        Ok(size)
+    }
+
+    /// Iterators over the secondary indexes for the filter, sorted by the message index
+    fn message_index_iters(
+        &self,
+        filter: &MessagesFilter,
+        forward: bool,
+    ) -> Result<Vec<Box<dyn Iterator<Item = u64> + '_>>, DbError> {
+        let direction = || {
+            if forward {
+                Direction::Forward
+            } else {
+                Direction::Reverse
+            }
+        };
+
+        let cursor = message_cursor(filter, forward).unwrap_or(if forward { 0 } else { u64::MAX });
+        let mut iters: Vec<Box<dyn Iterator<Item = u64>>> = Vec::with_capacity(5);
+        let types = match &filter.types {
+            Some(types) => Some(types.as_str()),
+            // only these messages carry the header
+            None if has_level_filter(filter) => Some("block_header,current_head"),
+            // only this message carries the chain id
+            None if filter.chain_id.is_some() => Some("deactivate"),
+            None => None,
+        };
+        if let Some(ty) = types {
+            let mut tys = Vec::new();
+            for ty in ty.split(',') {
+                let ty =
+                    ty.parse::<common::MessageType>()
+                        .map_err(|e| DBError::SchemaError {
+                            error: SchemaError::DecodeValidationError(e.to_string()),
+                        })?;
+                let key = message_ty::Item { ty, index: cursor };
+                let key = key
+                    .encode()
+                    .map_err(|error| DBError::SchemaError { error })?;
+                let mode = rocksdb::IteratorMode::From(&key, direction().into());
+                let cf = self
+                    .inner
+                    .cf_handle(message_ty::Schema::name())
+                    .ok_or_else(|| DBError::MissingColumnFamily {
+                        name: message_ty::Schema::name(),
+                    })?;
+                let mut opts = ReadOptions::default();
+                opts.set_prefix_same_as_start(true);
+                let it = self
+                    .inner
+                    .iterator_cf_opt(cf, opts, mode)
+                    .filter_map(|(k, _)| Some(message_ty::Item::decode(&k).ok()?.index));
+                tys.push(it);
+            }
+            iters.push(Box::new(tys.into_iter().kmerge_by(|x, y| x > y)));
+        }
+        if let Some(sender) = &filter.incoming {
+            let sender = common::Sender::new(*sender);
+            let key = message_sender::Item {
+                sender,
+                index: cursor,
+            };
+            let key = key
+                .encode()
+                .map_err(|error| DBError::SchemaError { error })?;
+            let mode = rocksdb::IteratorMode::From(&key, direction().into());
+            let cf = self
+                .inner
+                .cf_handle(message_sender::Schema::name())
+                .ok_or_else(|| DBError::MissingColumnFamily {
+                    name: message_sender::Schema::name(),
+                })?;
+            let mut opts = ReadOptions::default();
+            opts.set_prefix_same_as_start(true);
+            let it = self
+                .inner
+                .iterator_cf_opt(cf, opts, mode)
+                .filter_map(|(k, _)| Some(message_sender::Item::decode(&k).ok()?.index));
+            iters.push(Box::new(it));
+        }
+        if let Some(initiator) = &filter.source_type {
+            let key = message_initiator::Item {
+                initiator: initiator.clone(),
+                index: cursor,
+            };
+            let key = key
+                .encode()
+                .map_err(|error| DBError::SchemaError { error })?;
+            let mode = rocksdb::IteratorMode::From(&key, direction().into());
+            let cf = self
+                .inner
+                .cf_handle(message_initiator::Schema::name())
+                .ok_or_else(|| DBError::MissingColumnFamily {
+                    name: message_initiator::Schema::name(),
+                })?;
+            let mut opts = ReadOptions::default();
+            opts.set_prefix_same_as_start(true);
+            let it = self
+                .inner
+                .iterator_cf_opt(cf, opts, mode)
+                .filter_map(|(k, _)| Some(message_initiator::Item::decode(&k).ok()?.index));
+            iters.push(Box::new(it));
+        }
+        if let Some(addr) = &filter.remote_addr {
+            let addr = addr
+                .parse::<SocketAddr>()
+                .map_err(|e| DBError::SchemaError {
+                    error: SchemaError::DecodeValidationError(e.to_string()),
+                })?;
+            let key = message_addr::Item {
+                addr,
+                index: cursor,
+            };
+            let key = key
+                .encode()
+                .map_err(|error| DBError::SchemaError { error })?;
+            let mode = rocksdb::IteratorMode::From(&key, direction().into());
+            let cf = self
+                .inner
+                .cf_handle(message_addr::Schema::name())
+                .ok_or_else(|| DBError::MissingColumnFamily {
+                    name: message_addr::Schema::name(),
+                })?;
+            let mut opts = ReadOptions::default();
+            opts.set_prefix_same_as_start(true);
+            let it = self
+                .inner
+                .iterator_cf_opt(cf, opts, mode)
+                .filter_map(|(k, _)| Some(message_addr::Item::decode(&k).ok()?.index));
+            iters.push(Box::new(it));
+        }
+        if filter.from.is_some() || filter.to.is_some() {
+            let mut timestamp = timestamp::Item {
+                timestamp: u64::MAX,
+                index: u64::MAX,
+            };
+            let mode = if let Some(end) = filter.to {
+                timestamp.timestamp = end;
+                IteratorMode::From(&timestamp, direction())
+            } else {
+                if forward {
+                    IteratorMode::Start
+                } else {
+                    IteratorMode::End
+                }
+            };
+            let it = self
+                .as_kv::<timestamp::MessageSchema>()
+                .iterator(mode)?
+                .filter_map(|(k, _)| k.ok());
+            if let Some(begin) = filter.from {
+                let it = it
+                    .take_while(move |k| (k.timestamp >= begin) ^ forward)
+                    .map(|k| k.index);
+                iters.push(Box::new(it));
+            } else {
+                iters.push(Box::new(it.map(|k| k.index)));
+            }
+        }
+        if let Some(middle) = filter.timestamp {
+            let middle = timestamp::Item {
+                timestamp: middle,
+                index: u64::MAX,
+            };
+
+            let it = self
+                .as_kv::<timestamp::MessageSchema>()
+                .iterator(IteratorMode::From(&middle, direction()))?
+                .filter_map(|(k, _)| k.ok())
+                .map(|k| k.index);
+            iters.push(Box::new(it));
+        }
+        if let Some(label) = &filter.session {
+            iters.push(self.session_iter::<session::MessageSchema>(label, cursor, forward)?);
+        }
+        if let Some(hash) = &filter.hash {
+            let hash = hex::decode(hash)
+                .map_err(|e| e.to_string())
+                .and_then(|h| <[u8; 32]>::try_from(h.as_slice()).map_err(|e| e.to_string()))
+                .map_err(|e| DBError::SchemaError {
+                    error: SchemaError::DecodeValidationError(e),
+                })?;
+            let key = message_hash::Item {
+                hash,
+                index: cursor,
+            };
+            let key = key
+                .encode()
+                .map_err(|error| DBError::SchemaError { error })?;
+            let mode = rocksdb::IteratorMode::From(&key, direction().into());
+            let cf = self
+                .inner
+                .cf_handle(message_hash::Schema::name())
+                .ok_or_else(|| DBError::MissingColumnFamily {
+                    name: message_hash::Schema::name(),
+                })?;
+            let mut opts = ReadOptions::default();
+            opts.set_prefix_same_as_start(true);
+            let it = self
+                .inner
+                .iterator_cf_opt(cf, opts, mode)
+                .filter_map(|(k, _)| Some(message_hash::Item::decode(&k).ok()?.index));
+            iters.push(Box::new(it));
+        }
+        if filter.id_from.is_some() || filter.id_to.is_some() {
+            iters = iters
+                .into_iter()
+                .map(|it| message_id_range(it, filter, forward))
+                .collect();
+        }
+
+        Ok(iters)
+    }
+    fn column_families() -> Vec<&'static str> {
+        vec![
+            connection::Schema::name(),
+            chunk::Schema::name(),
+            chunk_event::Schema::name(),
+            chunk_gap::Schema::name(),
+            message::Schema::name(),
+            node_log::Schema::name(),
+            message_ty::Schema::name(),
+            message_sender::Schema::name(),
+            message_initiator::Schema::name(),
+            message_addr::Schema::name(),
+            timestamp::MessageSchema::name(),
+            log_level::Schema::name(),
+            timestamp::LogSchema::name(),
+            session::MessageSchema::name(),
+            session::LogSchema::name(),
+            message_hash::Schema::name(),
+            timestamp::ConnectionCloseSchema::name(),
+            message::OversizedSchema::name(),
+            connection_crypto::Schema::name(),
+            connection_geo::Schema::name(),
+            message_stable::Schema::name(),
+        ]
+    }
+
+    fn property(&self, name: &str, property: &str) -> Option<u64> {
+        let cf = self.inner.cf_handle(name)?;
+        self.inner.property_int_value_cf(cf, property).ok()?
+    }
+
+    // the deletes not compacted yet, in the memtables and in the sst files
+    fn tombstones(&self, name: &str) -> compaction::Tombstones {
+        let memtables = compaction::Tombstones {
+            deletes: self.property(name, "rocksdb.num-deletes-active-mem-table").unwrap_or(0)
+                + self.property(name, "rocksdb.num-deletes-imm-mem-tables").unwrap_or(0),
+            entries: self.property(name, "rocksdb.num-entries-active-mem-table").unwrap_or(0)
+                + self.property(name, "rocksdb.num-entries-imm-mem-tables").unwrap_or(0),
+        };
+        let tables = self
+            .inner
+            .cf_handle(name)
+            .and_then(|cf| {
+                self.inner
+                    .property_value_cf(cf, "rocksdb.aggregated-table-properties")
+                    .ok()?
+            })
+            .map(|s| compaction::Tombstones::parse_table_properties(&s))
+            .unwrap_or_default();
+        memtables + tables
+    }
 }
 
 impl DatabaseNew for Db {
@@ -90,7 +643,66 @@ impl DatabaseNew for Db {
         log_full_text_index: bool,
         log_store_limit: Option<u64>,
         message_store_limit: Option<u64>,
+        batch: batch::BatchConfig,
+    ) -> Result<Self, Self::Error>
+    where
+        P: AsRef<Path>,
+    {
+        Db::open_with(
+            path,
+            log_full_text_index,
+            log_store_limit,
+            message_store_limit,
+            batch,
+            None,
+            false,
+        )
+    }
+
+    fn open_in_memory<P>(
+        path: P,
+        log_full_text_index: bool,
+        log_store_limit: Option<u64>,
+        message_store_limit: Option<u64>,
+        batch: batch::BatchConfig,
     ) -> Result<Self, Self::Error>
+    where
+        P: AsRef<Path>,
+    {
+        let env = Env::mem_env().map_err(|error| DBError::RocksDBError { error })?;
+        Db::open_with(
+            path,
+            log_full_text_index,
+            log_store_limit,
+            message_store_limit,
+            batch,
+            Some(env),
+            false,
+        )
+    }
+}
+
+impl Db {
+    /// Open the database of the running recorder, it is not locked for reading,
+    /// sees the records written before the opening, and must not be written
+    pub fn open_read_only<P>(path: P) -> Result<Self, DbError>
+    where
+        P: AsRef<Path>,
+    {
+        Db::open_with(path, false, None, None, Default::default(), None, true)
+    }
+
+    /// The `env` is the memory environment if the database is opened in memory,
+    /// the `path` is only the name of the database then
+    fn open_with<P>(
+        path: P,
+        log_full_text_index: bool,
+        log_store_limit: Option<u64>,
+        message_store_limit: Option<u64>,
+        batch: batch::BatchConfig,
+        env: Option<Env>,
+        read_only: bool,
+    ) -> Result<Self, DbError>
     where
         P: AsRef<Path>,
     {
@@ -99,6 +711,8 @@ impl DatabaseNew for Db {
         let cfs = vec![
             connection::Schema::descriptor(&cache),
             chunk::Schema::descriptor(&cache),
+            chunk_event::Schema::descriptor(&cache),
+            chunk_gap::Schema::descriptor(&cache),
             message::Schema::descriptor(&cache),
             node_log::Schema::descriptor(&cache),
             message_ty::Schema::descriptor(&cache),
@@ -108,10 +722,32 @@ impl DatabaseNew for Db {
             timestamp::MessageSchema::descriptor(&cache),
             log_level::Schema::descriptor(&cache),
             timestamp::LogSchema::descriptor(&cache),
+            session::MessageSchema::descriptor(&cache),
+            session::LogSchema::descriptor(&cache),
+            message_hash::Schema::descriptor(&cache),
+            timestamp::ConnectionCloseSchema::descriptor(&cache),
+            message::OversizedSchema::descriptor(&cache),
+            connection_crypto::Schema::descriptor(&cache),
+            connection_geo::Schema::descriptor(&cache),
+            message_stable::Schema::descriptor(&cache),
         ];
         let path = PathBuf::from(path.as_ref());
-        let inner =
-            persistent::database::open_kv(path.join("rocksdb"), cfs, &DbConfiguration::default())?;
+        let inner = if let Some(env) = &env {
+            let mut opts = rocksdb::Options::default();
+            opts.create_if_missing(true);
+            opts.create_missing_column_families(true);
+            opts.set_env(env);
+            DB::open_cf_descriptors(&opts, path.join("rocksdb"), cfs)
+                .map_err(|error| DBError::RocksDBError { error })?
+        } else if read_only {
+            // the options of the column families only tune the writes and the prefix seeks,
+            // the plain reads of the export do not need them
+            let opts = rocksdb::Options::default();
+            DB::open_cf_for_read_only(&opts, path.join("rocksdb"), Self::column_families(), false)
+                .map_err(|error| DBError::RocksDBError { error })?
+        } else {
+            persistent::database::open_kv(path.join("rocksdb"), cfs, &DbConfiguration::default())?
+        };
 
         fn counter<S>(db: &DB) -> Option<S::Key>
         where
@@ -126,10 +762,10 @@ impl DatabaseNew for Db {
                 .map(|c| c + 1)
         }
 
-        let log_indexer = if log_full_text_index {
-            Some(search::LogIndexer::try_new(path.join("tantivy"))?)
-        } else {
-            None
+        let log_indexer = match (log_full_text_index, &env) {
+            (false, _) => None,
+            (true, None) => Some(search::LogIndexer::try_new(path.join("tantivy"))?),
+            (true, Some(_)) => Some(search::LogIndexer::in_ram()?),
         };
 
         Ok(Db {
@@ -138,12 +774,46 @@ impl DatabaseNew for Db {
             log_store_limit,
             log_counter: AtomicU64::new(counter::<node_log::Schema>(&inner).unwrap_or(0)),
             log_indexer,
+            compaction: compaction::Tracker::new(vec![
+                chunk::Schema::name(),
+                chunk_event::Schema::name(),
+                chunk_gap::Schema::name(),
+                message::Schema::name(),
+                node_log::Schema::name(),
+                message_ty::Schema::name(),
+                message_sender::Schema::name(),
+                message_initiator::Schema::name(),
+                message_addr::Schema::name(),
+                timestamp::MessageSchema::name(),
+                log_level::Schema::name(),
+                timestamp::LogSchema::name(),
+                session::MessageSchema::name(),
+                session::LogSchema::name(),
+                message_hash::Schema::name(),
+                message::OversizedSchema::name(),
+                message_stable::Schema::name(),
+            ]),
+            session: RwLock::new(None),
+            batch,
+            queue: Mutex::new(batch::Queue::default()),
+            writes: AtomicU64::new(0),
+            missing_chunks: AtomicU64::new(0),
+            live: live::Publisher::default(),
+            tail: tail::Tail::default(),
+            clock: Arc::new(SystemClock),
             inner,
+            _env: env,
         })
     }
 }
 
 impl Db {
+    /// Take the timestamps and the intervals from the `clock` instead of the system
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
     pub fn remove_message(&self, index: u64) -> Result<(), DbError> {
         if let Some(item) = self.as_kv::<message::Schema>().get(&index)? {
             let ty_index = message_ty::Item {
@@ -167,19 +837,31 @@ impl Db {
                 index,
             };
 
+            let timestamp_value = self
+                .as_kv::<timestamp::MessageSchema>()
+                .get(&timestamp_index)?
+                .unwrap_or_default();
+            if let Some(session) = timestamp_value.session {
+                self.delete::<session::MessageSchema>(&session::Item { session, index })?;
+            }
+            if let Some(hash) = timestamp_value.hash {
+                self.delete::<message_hash::Schema>(&message_hash::Item { hash, index })?;
+            }
+
             for chunk_key in item.chunks() {
-                self.as_kv::<chunk::Schema>().delete(&chunk_key)?;
+                self.delete::<chunk::Schema>(&chunk_key)?;
+                self.delete::<chunk_event::Schema>(&chunk_key)?;
+                self.delete::<chunk_gap::Schema>(&chunk_key)?;
             }
 
-            self.as_kv::<message_ty::Schema>().delete(&ty_index)?;
-            self.as_kv::<message_sender::Schema>()
-                .delete(&sender_index)?;
-            self.as_kv::<message_initiator::Schema>()
-                .delete(&initiator_index)?;
-            self.as_kv::<message_addr::Schema>().delete(&addr_index)?;
-            self.as_kv::<timestamp::MessageSchema>()
-                .delete(&timestamp_index)?;
-            self.as_kv::<message::Schema>().delete(&index)?;
+            self.delete::<message_ty::Schema>(&ty_index)?;
+            self.delete::<message_sender::Schema>(&sender_index)?;
+            self.delete::<message_initiator::Schema>(&initiator_index)?;
+            self.delete::<message_addr::Schema>(&addr_index)?;
+            self.delete::<timestamp::MessageSchema>(&timestamp_index)?;
+            self.delete::<message::OversizedSchema>(&index)?;
+            self.delete::<message_stable::Schema>(&item.stable_id())?;
+            self.delete::<message::Schema>(&index)?;
         }
         Ok(())
     }
@@ -195,45 +877,224 @@ impl Db {
                 index,
             };
 
-            self.as_kv::<log_level::Schema>().delete(&lv_index)?;
-            self.as_kv::<timestamp::LogSchema>()
-                .delete(&timestamp_index)?;
-            self.as_kv::<node_log::Schema>().delete(&index)?;
+            let timestamp_value = self.as_kv::<timestamp::LogSchema>().get(&timestamp_index)?;
+            if let Some(session) = timestamp_value.and_then(|v| v.session) {
+                self.delete::<session::LogSchema>(&session::Item { session, index })?;
+            }
+
+            self.delete::<log_level::Schema>(&lv_index)?;
+            self.delete::<timestamp::LogSchema>(&timestamp_index)?;
+            self.delete::<node_log::Schema>(&index)?;
         }
         Ok(())
     }
+
+    /// Write the connections, messages and logs stored after the checkpoint as JSON lines,
+    /// each table in the order of the keys, return the checkpoint to resume the next export.
+    /// The connection is exported as it is now, and again once it is closed, the consumer
+    /// replaces the earlier record with the same id.
+    pub fn export<W>(&self, since: &export::Checkpoint, mut out: W) -> Result<export::Checkpoint>
+    where
+        W: io::Write,
+    {
+        let mut write =
+            |table: &str, id: serde_json::Value, record: serde_json::Value| -> Result<()> {
+                let line = serde_json::json!({ "table": table, "id": id, "record": record });
+                serde_json::to_writer(&mut out, &line)?;
+                out.write_all(b"\n")?;
+                Ok(())
+            };
+
+        let mut write_connection = |key: &connection::Key, mut value: connection::Value| {
+            if let Ok(Some(geo)) = self.as_kv::<connection_geo::Schema>().get(key) {
+                value.set_geo(geo);
+            }
+            write("connections", serde_json::to_value(key)?, serde_json::to_value(value)?)
+        };
+
+        // the connections exported before and closed since, the new connections are skipped,
+        // they are exported below, the ones closed after that are exported by the next export
+        let closes = self.for_each_after::<timestamp::ConnectionCloseSchema, _>(
+            since.closes.as_ref(),
+            |close, _| {
+                if since.connections.as_ref().map_or(true, |last| close.cn_id > *last) {
+                    return Ok(());
+                }
+                match self.as_kv::<connection::Schema>().get(&close.cn_id)? {
+                    Some(value) => write_connection(&close.cn_id, value),
+                    // removed by the retention
+                    None => Ok(()),
+                }
+            },
+        )?;
+        let connections = self.for_each_after::<connection::Schema, _>(
+            since.connections.as_ref(),
+            |key, value| write_connection(key, value),
+        )?;
+        let messages =
+            self.for_each_after::<message::Schema, _>(since.messages.as_ref(), |key, value| {
+                let value = self.frontend(value, *key);
+                write("messages", (*key).into(), serde_json::to_value(value)?)
+            })?;
+        let logs = self.for_each_after::<node_log::Schema, _>(since.logs.as_ref(), |key, value| {
+            let value = node_log::ItemWithId::new(value, *key);
+            write("logs", (*key).into(), serde_json::to_value(value)?)
+        })?;
+        out.flush()?;
+
+        Ok(export::Checkpoint {
+            connections: connections.or_else(|| since.connections.clone()),
+            closes: closes.or_else(|| since.closes.clone()),
+            messages: messages.or(since.messages),
+            logs: logs.or(since.logs),
+        })
+    }
+
+    /// Call `f` on each record of the table after the `after` key, return the last key
+    fn for_each_after<S, F>(&self, after: Option<&S::Key>, mut f: F) -> Result<Option<S::Key>>
+    where
+        S: KeyValueSchema + RocksDbKeyValueSchema,
+        S::Key: PartialEq + fmt::Debug,
+        F: FnMut(&S::Key, S::Value) -> Result<()>,
+    {
+        let mode = match after {
+            Some(key) => IteratorMode::From(key, Direction::Forward),
+            None => IteratorMode::Start,
+        };
+        let mut last = None;
+        for (k, v) in self.as_kv::<S>().iterator(mode)? {
+            match (k, v) {
+                // the iterator starts at the checkpoint itself
+                (Ok(key), _) if after == Some(&key) => (),
+                (Ok(key), Ok(value)) => {
+                    f(&key, value)?;
+                    last = Some(key);
+                },
+                (Ok(key), Err(err)) => {
+                    log::warn!("Failed to load value at {:?}: {}", key, err);
+                    // skip it, do not stop the next export at the broken record
+                    last = Some(key);
+                },
+                (Err(err), _) => log::warn!("Failed to load index: {}", err),
+            }
+        }
+        Ok(last)
+    }
+}
+
+impl Db {
+    fn publish_connection(&self, key: &connection::Key, value: &connection::Value) {
+        self.live.publish(key, value.close_reason().is_some(), || {
+            match serde_json::to_value(value) {
+                Ok(json) => Some(live::EventKind::Connection(json)),
+                Err(error) => {
+                    log::error!("failed to serialize connection {}: {}", key, error);
+                    None
+                },
+            }
+        });
+    }
 }
 
 impl Database for Db {
-    fn store_connection(&self, item: connection::Item) {
+    fn store_connection(&self, mut item: connection::Item) {
+        item.set_session(self.session());
         let (key, value) = item.split();
         if let Err(error) = self.as_kv::<connection::Schema>().put(&key, &value) {
             log::error!("database error: {}", error);
         }
+        self.publish_connection(&key, &value);
     }
 
-    fn update_connection(&self, item: connection::Item) {
-        let (key, value) = item.split();
+    fn store_connection_crypto(&self, cn_id: connection::Key, value: connection_crypto::Value) {
+        if let Err(error) = self.as_kv::<connection_crypto::Schema>().put(&cn_id, &value) {
+            log::error!("database error: {}", error);
+        }
+    }
+
+    fn store_connection_geo(&self, cn_id: connection::Key, value: connection_geo::Value) {
+        if let Err(error) = self.as_kv::<connection_geo::Schema>().put(&cn_id, &value) {
+            log::error!("database error: {}", error);
+        }
+    }
+
+    fn update_connection(&self, mut item: connection::Item) {
         let kv = self.as_kv::<connection::Schema>();
+        let old = kv.get(&item.key()).ok().flatten();
+        // keep the session the connection was stored with
+        if let Some(old) = &old {
+            item.set_session(old.session().map(ToString::to_string));
+        }
+        // the connection is closed now, put it on the timeline
+        let closed = item.close_reason().is_some()
+            && old.map_or(true, |old| old.close_reason().is_none());
+        let (key, value) = item.split();
         if let Err(error) = kv.delete(&key).and_then(|()| kv.put(&key, &value)) {
             log::error!("database error: {}", error);
         }
+        self.publish_connection(&key, &value);
+        if closed {
+            let close_index = timestamp::CloseItem {
+                timestamp: self.clock.now_millis(),
+                cn_id: key,
+            };
+            if let Err(error) = self
+                .as_kv::<timestamp::ConnectionCloseSchema>()
+                .put(&close_index, &())
+            {
+                log::error!("database error: {}", error);
+            }
+        }
     }
 
     fn store_chunk(&self, item: chunk::Item) {
+        let event = item.event;
+        let gap = item.gap.map(|missing| chunk_gap::Value { missing });
         let (key, value) = item.split();
-        if let Err(error) = self.as_kv::<chunk::Schema>().put(&key, &value) {
+        let mut queue = self.queue.lock().unwrap();
+        let mut inner = || -> Result<(), DBError> {
+            if let Some(event) = &event {
+                Self::enqueue::<chunk_event::Schema>(&mut queue, &key, event)?;
+            }
+            if let Some(gap) = &gap {
+                self.missing_chunks.fetch_add(gap.missing, Ordering::Relaxed);
+                Self::enqueue::<chunk_gap::Schema>(&mut queue, &key, gap)?;
+            }
+            Self::enqueue::<chunk::Schema>(&mut queue, &key, &value)?;
+            queue.record(None);
+            self.commit_full(&mut queue)
+        };
+        if let Err(error) = inner() {
+            log::error!("database error: {}", error);
+        }
+    }
+
+    fn remove_chunk(&self, key: &chunk::Key) {
+        let inner = || -> Result<(), DBError> {
+            self.delete::<chunk_event::Schema>(key)?;
+            self.delete::<chunk_gap::Schema>(key)?;
+            self.delete::<chunk::Schema>(key)
+        };
+        if let Err(error) = inner() {
             log::error!("database error: {}", error);
         }
     }
 
     fn store_message(&self, item: message::Item) {
         let index = self.reserve_message_counter();
+        let mut queue = self.queue.lock().unwrap();
         if let Some(store_limit) = self.message_store_limit {
             if index >= store_limit {
+                // the message to remove must be committed first
+                if queue.holds_message(index - store_limit) {
+                    if let Err(error) = self.commit(&mut queue) {
+                        log::error!("database error: {}", error);
+                    }
+                }
                 if let Err(error) = self.remove_message(index - store_limit) {
                     log::error!("database error: {}", error);
                 }
+                self.tail.remove_before(index - store_limit + 1);
             }
         }
 
@@ -257,21 +1118,44 @@ impl Database for Db {
             timestamp: item.timestamp,
             index,
         };
-        let inner = || -> Result<(), DbError> {
-            self.as_kv::<message_ty::Schema>().put(&ty_index, &())?;
-            self.as_kv::<message_sender::Schema>()
-                .put(&sender_index, &())?;
-            self.as_kv::<message_initiator::Schema>()
-                .put(&initiator_index, &())?;
-            self.as_kv::<message_addr::Schema>().put(&addr_index, &())?;
-            self.as_kv::<timestamp::MessageSchema>()
-                .put(&timestamp_index, &())?;
-            self.as_kv::<message::Schema>().put(&index, &item)?;
-            Ok(())
+        let stable_id = item.stable_id();
+        let session = self.session().map(|label| session::hash(&label));
+        let timestamp_value = timestamp::MessageValue {
+            size: item.size,
+            session,
+            hash: item.hash,
+        };
+        let mut inner = || -> Result<(), DBError> {
+            let queue = &mut *queue;
+            if let Some(session) = session {
+                let key = session::Item { session, index };
+                Self::enqueue::<session::MessageSchema>(queue, &key, &())?;
+            }
+            if let Some(hash) = item.hash {
+                let key = message_hash::Item { hash, index };
+                Self::enqueue::<message_hash::Schema>(queue, &key, &())?;
+            }
+            if let Some(oversized) = &item.oversized {
+                Self::enqueue::<message::OversizedSchema>(queue, &index, oversized)?;
+            }
+            Self::enqueue::<message_ty::Schema>(queue, &ty_index, &())?;
+            Self::enqueue::<message_sender::Schema>(queue, &sender_index, &())?;
+            Self::enqueue::<message_initiator::Schema>(queue, &initiator_index, &())?;
+            Self::enqueue::<message_addr::Schema>(queue, &addr_index, &())?;
+            Self::enqueue::<timestamp::MessageSchema>(queue, &timestamp_index, &timestamp_value)?;
+            Self::enqueue::<message_stable::Schema>(queue, &stable_id, &index)?;
+            Self::enqueue::<message::Schema>(queue, &index, &item)?;
+            queue.record(Some(index));
+            self.commit_full(queue)
         };
         if let Err(error) = inner() {
             log::error!("database error: {}", error);
         }
+        drop(queue);
+        let cn_id = item.connection();
+        self.tail.push(index, item.clone());
+        let message = message::MessageFrontend::new(item, index, None, None);
+        self.live.publish(&cn_id, false, || Some(live::EventKind::Message(message)));
     }
 
     fn store_log(&self, item: node_log::Item) {
@@ -292,10 +1176,16 @@ impl Database for Db {
             timestamp: (item.timestamp / 1_000_000) as u64,
             index,
         };
+        let session = self.session().map(|label| session::hash(&label));
+        let timestamp_value = timestamp::LogValue { session };
         let inner = || -> Result<(), DbError> {
+            if let Some(session) = session {
+                self.as_kv::<session::LogSchema>()
+                    .put(&session::Item { session, index }, &())?;
+            }
             self.as_kv::<log_level::Schema>().put(&lv_index, &())?;
             self.as_kv::<timestamp::LogSchema>()
-                .put(&timestamp_index, &())?;
+                .put(&timestamp_index, &timestamp_value)?;
             self.as_kv::<node_log::Schema>().put(&index, &item)?;
             Ok(())
         };
@@ -306,6 +1196,184 @@ impl Database for Db {
             log::error!("database error: {}", error);
         }
     }
+
+    fn set_session(&self, label: Option<String>) {
+        log::info!("session: {:?}", label);
+        *self.session.write().unwrap() = label;
+    }
+
+    fn set_tail_size(&self, size: usize) {
+        self.tail.set_capacity(size);
+    }
+
+    fn flush(&self) {
+        if let Err(error) = self.commit(&mut self.queue.lock().unwrap()) {
+            log::error!("database error: {}", error);
+        }
+    }
+
+    fn prune(&self, fraction: f64) {
+        fn first<S>(db: &DB) -> Option<u64>
+        where
+            S: RocksDbKeyValueSchema + KeyValueSchema<Key = u64>,
+        {
+            KeyValueStoreWithSchemaIterator::<S>::iterator(db, IteratorMode::Start)
+                .ok()?
+                .next()?
+                .0
+                .ok()
+        }
+
+        // the range of the oldest records to remove
+        let oldest = |first: Option<u64>, counter: &AtomicU64| {
+            let first = first?;
+            let stored = counter.load(Ordering::SeqCst).saturating_sub(first);
+            Some(first..(first + (stored as f64 * fraction) as u64))
+        };
+
+        // the oldest messages might be still in the queue
+        self.flush();
+        if let Some(range) = oldest(first::<message::Schema>(&self.inner), &self.message_counter) {
+            log::info!("pruning {} messages", range.end - range.start);
+            self.tail.remove_before(range.end);
+            for index in range {
+                if let Err(error) = self.remove_message(index) {
+                    log::error!("database error: {}", error);
+                }
+            }
+        }
+        if let Some(range) = oldest(first::<node_log::Schema>(&self.inner), &self.log_counter) {
+            log::info!("pruning {} logs", range.end - range.start);
+            for index in range {
+                if let Err(error) = self.remove_log(index) {
+                    log::error!("database error: {}", error);
+                }
+            }
+        }
+        self.compact(0.0);
+    }
+
+    fn check_integrity(&self, sample: usize) -> integrity::Report {
+        // the oldest and the newest records, and the records at random positions between them,
+        // the blocks are read from the disk, not from the cache, and their checksums are verified
+        fn spread<S>(db: &DB, sample: usize, report: &mut integrity::Report)
+        where
+            S: KeyValueSchema + RocksDbKeyValueSchema,
+        {
+            use std::collections::BTreeSet;
+            use rand::Rng;
+
+            // the keys are big endian, so the first 8 bytes tell the position in the key range
+            fn position(key: &[u8]) -> u64 {
+                let mut bytes = [0; 8];
+                let len = key.len().min(8);
+                bytes[..len].copy_from_slice(&key[..len]);
+                u64::from_be_bytes(bytes)
+            }
+
+            if sample == 0 {
+                return;
+            }
+            let cf = match db.cf_handle(S::name()) {
+                Some(cf) => cf,
+                None => {
+                    let error = DBError::MissingColumnFamily { name: S::name() };
+                    report.fail(S::name(), error.to_string());
+                    return;
+                },
+            };
+            let mut opts = ReadOptions::default();
+            opts.set_verify_checksums(true);
+            opts.fill_cache(false);
+            let mut it = db.raw_iterator_cf_opt(cf, opts);
+
+            // the same record is checked once, the random positions might repeat
+            let mut checked = BTreeSet::new();
+            let mut check = |it: &rocksdb::DBRawIterator, report: &mut integrity::Report| {
+                match (it.key(), it.value()) {
+                    (Some(k), Some(v)) => {
+                        if !checked.insert(k.to_vec()) {
+                            return;
+                        }
+                        match (S::Key::decode(k), S::Value::decode(v)) {
+                            (Ok(_), Ok(_)) => report.checked += 1,
+                            (Err(error), _) | (_, Err(error)) => {
+                                report.fail(S::name(), error.to_string())
+                            },
+                        }
+                    },
+                    _ => {
+                        if let Err(error) = it.status() {
+                            report.fail(S::name(), error.to_string());
+                        }
+                    },
+                }
+            };
+
+            it.seek_to_last();
+            check(&it, report);
+            let last = it.key().map(position);
+            it.seek_to_first();
+            check(&it, report);
+            let first = it.key().map(position);
+            let (first, last) = match (first, last) {
+                (Some(first), Some(last)) => (first, last.max(first)),
+                // the table is empty or cannot be read at all, it is reported above
+                _ => return,
+            };
+            let mut rng = rand::thread_rng();
+            for _ in 2..sample {
+                it.seek(rng.gen_range(first..=last).to_be_bytes());
+                check(&it, report);
+            }
+        }
+
+        let mut report = integrity::Report::default();
+        spread::<connection::Schema>(&self.inner, sample, &mut report);
+        spread::<chunk::Schema>(&self.inner, sample, &mut report);
+        spread::<message::Schema>(&self.inner, sample, &mut report);
+        spread::<timestamp::MessageSchema>(&self.inner, sample, &mut report);
+        spread::<node_log::Schema>(&self.inner, sample, &mut report);
+        spread::<connection_crypto::Schema>(&self.inner, sample, &mut report);
+        // rocksdb checks the checksums of the blocks it reads, the compaction reads everything,
+        // the corruption it meets is counted here
+        report.background_errors = self
+            .inner
+            .property_int_value("rocksdb.background-errors")
+            .ok()
+            .flatten()
+            .unwrap_or(0);
+        report
+    }
+
+    fn compact(&self, tombstone_threshold: f64) -> Vec<&'static str> {
+        let tombstones = |name: &str| self.tombstones(name);
+        let mut compacted = Vec::new();
+        let now = self.clock.instant();
+        for name in self.compaction.due(tombstones, tombstone_threshold, now) {
+            let cf = match self.inner.cf_handle(name) {
+                Some(cf) => cf,
+                None => continue,
+            };
+            let size = || self.property(name, "rocksdb.total-sst-files-size").unwrap_or(0);
+            let before = size();
+            log::info!("compacting {}, size: {}", name, before);
+            self.inner.compact_range_cf(cf, None::<&[u8]>, None::<&[u8]>);
+            log::info!("compacted {}, size: {} -> {}", name, before, size());
+            compacted.push(name);
+        }
+        compacted
+    }
+
+    fn clock(&self) -> Arc<dyn Clock> {
+        self.clock.clone()
+    }
+}
+
+impl Drop for Db {
+    fn drop(&mut self) {
+        self.flush();
+    }
 }
 
 // TODO: duplicated code
@@ -330,26 +1398,73 @@ impl DatabaseFetch for Db {
                     None
                 },
             })
+            .filter(|(_, value)| match &filter.session {
+                Some(session) => value.session() == Some(session.as_str()),
+                None => true,
+            })
+            .filter(|(_, value)| match &filter.close_reason {
+                Some(close_reason) => value.close_reason() == Some(*close_reason),
+                None => true,
+            })
+            .filter(|(_, value)| match filter.status {
+                Some(status) => value.status() == status,
+                None => true,
+            })
+            .filter(|(_, value)| match filter.pow_valid {
+                Some(pow_valid) => value.pow_valid() == Some(pow_valid),
+                None => true,
+            })
+            .filter(|(_, value)| match filter.private_node {
+                Some(private_node) => {
+                    value.peer_metadata().map(|m| m.private_node) == Some(private_node)
+                },
+                None => true,
+            })
+            .filter(|(_, value)| match filter.disable_mempool {
+                Some(disable_mempool) => {
+                    value.peer_metadata().map(|m| m.disable_mempool) == Some(disable_mempool)
+                },
+                None => true,
+            })
+            .map(|(key, mut value)| {
+                if let Ok(Some(geo)) = self.as_kv::<connection_geo::Schema>().get(&key) {
+                    value.set_geo(geo);
+                }
+                (key, value)
+            })
+            .filter(|(_, value)| match &filter.country {
+                Some(country) => value
+                    .geo()
+                    .country
+                    .as_ref()
+                    .map_or(false, |c| c.eq_ignore_ascii_case(country)),
+                None => true,
+            })
+            .filter(|(_, value)| match filter.asn {
+                Some(asn) => value.geo().asn == Some(asn),
+                None => true,
+            })
             .take(limit)
             .collect();
         Ok(vec)
     }
 
-    fn fetch_chunks_truncated(
+    fn fetch_connections_after(
         &self,
-        filter: &ChunksFilter,
-    ) -> Result<Vec<(chunk::Key, chunk::ValueTruncated)>, Self::Error> {
-        type ItItem = (
-            Result<chunk::Key, SchemaError>,
-            Result<chunk::Value, SchemaError>,
-        );
-
-        fn collect_it(
-            it: impl Iterator<Item = ItItem>,
-            limit: usize,
-        ) -> Vec<(chunk::Key, chunk::ValueTruncated)> {
-            it.filter_map(|(k, v)| match (k, v) {
-                (Ok(key), Ok(value)) => Some((key, chunk::ValueTruncated(value))),
+        after: Option<&connection::Key>,
+        limit: usize,
+    ) -> Result<Vec<(connection::Key, connection::Value)>, Self::Error> {
+        let mode = match after {
+            Some(key) => IteratorMode::From(key, Direction::Forward),
+            None => IteratorMode::Start,
+        };
+        let vec = self
+            .as_kv::<connection::Schema>()
+            .iterator(mode)?
+            .filter_map(|(k, v)| match (k, v) {
+                // the iterator starts at the `after` key itself
+                (Ok(key), _) if after == Some(&key) => None,
+                (Ok(key), Ok(value)) => Some((key, value)),
                 (Ok(index), Err(err)) => {
                     log::warn!("Failed to load value at {:?}: {}", index, err);
                     None
@@ -360,11 +1475,33 @@ impl DatabaseFetch for Db {
                 },
             })
             .take(limit)
-            .collect()
-        }
+            .collect();
+        Ok(vec)
+    }
 
+    fn fetch_connection(
+        &self,
+        cn_id: &connection::Key,
+    ) -> Result<Option<connection::Value>, Self::Error> {
+        Ok(self.as_kv::<connection::Schema>().get(cn_id)?)
+    }
+
+    fn fetch_chunks_truncated(
+        &self,
+        filter: &ChunksFilter,
+    ) -> Result<Vec<(chunk::Key, chunk::ValueTruncated)>, Self::Error> {
         let limit = filter.limit.unwrap_or(100) as usize;
-        if let Some(connection_id) = &filter.cn {
+        let preview = chunk::ValueTruncated::preview(filter.preview);
+        let cf = self
+            .inner
+            .cf_handle(chunk::Schema::name())
+            .ok_or(DBError::MissingColumnFamily {
+                name: chunk::Schema::name(),
+            })?;
+        // the raw iterator lends the value from the pinned block, only the header
+        // and the preview are copied, unlike `iterator_cf`, which copies the whole value
+        let mut opts = ReadOptions::default();
+        let mut it = if let Some(connection_id) = &filter.cn {
             let cn_id = connection_id
                 .parse()
                 .map_err(|e: connection::KeyFromStrError| DBError::SchemaError {
@@ -372,35 +1509,130 @@ impl DatabaseFetch for Db {
                 })?;
             let k = chunk::Key::begin(cn_id);
             let k_bytes = k.encode().map_err(|error| DBError::SchemaError { error })?;
-            let mode = rocksdb::IteratorMode::From(&k_bytes, rocksdb::Direction::Forward);
-            let mut opts = ReadOptions::default();
             opts.set_prefix_same_as_start(true);
-            let cf = self.inner.cf_handle(chunk::Schema::name()).ok_or(
-                DBError::MissingColumnFamily {
-                    name: chunk::Schema::name(),
-                },
-            )?;
-            let it = self
-                .inner
-                .iterator_cf_opt(cf, opts, mode)
-                .map(|(k, v)| (chunk::Key::decode(&k), chunk::Value::decode(&v)));
-            Ok(collect_it(it, limit))
+            let mut it = self.inner.raw_iterator_cf_opt(cf, opts);
+            it.seek(&k_bytes);
+            it
         } else {
-            let it = self
-                .as_kv::<chunk::Schema>()
-                .iterator(IteratorMode::Start)?;
-            Ok(collect_it(it, limit))
+            let mut it = self.inner.raw_iterator_cf_opt(cf, opts);
+            it.seek_to_first();
+            it
+        };
+        let mut chunks = Vec::new();
+        while chunks.len() < limit {
+            let (k, v) = match (it.key(), it.value()) {
+                (Some(k), Some(v)) => (k, v),
+                _ => break,
+            };
+            match (chunk::Key::decode(k), chunk::ValueTruncated::decode(v, preview)) {
+                (Ok(key), Ok(value)) => chunks.push((key, value)),
+                (Ok(index), Err(err)) => {
+                    log::warn!("Failed to load value at {:?}: {}", index, err);
+                },
+                (Err(err), _) => {
+                    log::warn!("Failed to load index: {}", err);
+                },
+            }
+            it.next();
+        }
+        it.status().map_err(|error| DBError::RocksDBError { error })?;
+        for (key, value) in &mut chunks {
+            let gap = self.as_kv::<chunk_gap::Schema>().get(key)?;
+            value.set_gap(gap.map(|gap| gap.missing));
+        }
+        Ok(chunks)
+    }
+
+    fn fetch_chunk(&self, key: &chunk::Key) -> Result<Option<chunk::Value>, Self::Error> {
+        self.as_kv::<chunk::Schema>().get(&key).map_err(Into::into)
+    }
+
+    fn fetch_connection_crypto(
+        &self,
+        cn_id: &connection::Key,
+    ) -> Result<Option<connection_crypto::Diagnostics>, Self::Error> {
+        let value = match self.as_kv::<connection_crypto::Schema>().get(cn_id)? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        let comments = self
+            .as_kv::<connection::Schema>()
+            .get(cn_id)?
+            .map(|value| value.comments().clone())
+            .unwrap_or_default();
+
+        // the last chunk in each direction, the chunks are decrypted in order,
+        // one nonce per chunk, the connection message at counter 0 is not encrypted
+        let cf = self
+            .inner
+            .cf_handle(chunk::Schema::name())
+            .ok_or(DBError::MissingColumnFamily {
+                name: chunk::Schema::name(),
+            })?;
+        let k = chunk::Key::end(cn_id.clone());
+        let k_bytes = k.encode().map_err(|error| DBError::SchemaError { error })?;
+        let mode = rocksdb::IteratorMode::From(&k_bytes, rocksdb::Direction::Reverse);
+        let mut opts = ReadOptions::default();
+        opts.set_prefix_same_as_start(true);
+        let (mut local, mut remote) = (None, None);
+        for (k, _) in self.inner.iterator_cf_opt(cf, opts, mode) {
+            let key = chunk::Key::decode(&k).map_err(|error| DBError::SchemaError { error })?;
+            if key.cn_id.ts != cn_id.ts || key.cn_id.ts_nanos != cn_id.ts_nanos {
+                break;
+            }
+            let last = if key.sender.incoming() {
+                &mut remote
+            } else {
+                &mut local
+            };
+            last.get_or_insert(key.counter);
+            if local.is_some() && remote.is_some() {
+                break;
+            }
         }
-    }
-
-    fn fetch_chunk(&self, key: &chunk::Key) -> Result<Option<chunk::Value>, Self::Error> {
-        self.as_kv::<chunk::Schema>().get(&key).map_err(Into::into)
+        let decrypted = |last: Option<u64>, cannot_decrypt: Option<u64>| {
+            cannot_decrypt
+                .map(|position| position.saturating_sub(1))
+                .or(last)
+                .unwrap_or(0)
+        };
+        let decrypted = (
+            decrypted(local, comments.outgoing_cannot_decrypt),
+            decrypted(remote, comments.incoming_cannot_decrypt),
+        );
+        Ok(Some(connection_crypto::Diagnostics::new(&value, &comments, decrypted)))
     }
 
     fn fetch_messages(
         &self,
         filter: &MessagesFilter,
     ) -> Result<Vec<message::MessageFrontend>, Self::Error> {
+        let mut v = Vec::new();
+        self.for_each_message(filter, |message| {
+            v.push(message);
+            true
+        })?;
+        Ok(v)
+    }
+
+    fn fetch_messages_tail(&self, n: u64) -> Result<Vec<message::MessageFrontend>, Self::Error> {
+        match self.tail.last(n as usize) {
+            // the message still in the write batch is decoded partially, as in the live stream
+            Some(messages) => Ok(messages
+                .into_iter()
+                .map(|(index, item)| self.frontend(item, index))
+                .collect()),
+            None => self.fetch_messages(&MessagesFilter {
+                limit: Some(n),
+                ..Default::default()
+            }),
+        }
+    }
+
+    fn for_each_message<F>(&self, filter: &MessagesFilter, mut f: F) -> Result<(), Self::Error>
+    where
+        F: FnMut(message::MessageFrontend) -> bool,
+    {
         let limit = filter.limit.unwrap_or(100) as usize;
 
         let forward = filter.direction == Some("forward".to_string());
@@ -412,15 +1644,9 @@ impl DatabaseFetch for Db {
             }
         };
 
-        if filter.remote_addr.is_none()
-            && filter.source_type.is_none()
-            && filter.incoming.is_none()
-            && filter.types.is_none()
-            && filter.from.is_none()
-            && filter.to.is_none()
-            && filter.timestamp.is_none()
-        {
-            let mode = if let Some(cursor) = &filter.cursor {
+        if !has_index_filter(filter) {
+            let cursor = message_cursor(filter, forward);
+            let mode = if let Some(cursor) = &cursor {
                 IteratorMode::From(cursor, direction())
             } else {
                 if forward {
@@ -429,34 +1655,16 @@ impl DatabaseFetch for Db {
                     IteratorMode::End
                 }
             };
-            let v = self
+            let messages = self
                 .as_kv::<message::Schema>()
                 .iterator(mode)?
+                .take_while(|(k, _)| {
+                    k.as_ref()
+                        .map_or(true, |index| before_id_end(filter, forward, *index))
+                })
                 .take(limit)
                 .filter_map(|(k, v)| match (k, v) {
-                    (Ok(key), Ok(value)) => {
-                        let preview = match details(&value, key, self.as_kv()) {
-                            Ok(details) => match details.json_string() {
-                                Ok(p) => p.map(|mut s| {
-                                    utf8_truncate(&mut s, 100);
-                                    s
-                                }),
-                                Err(error) => {
-                                    log::error!(
-                                        "Failed to deserialize message {:?}, error: {}",
-                                        value,
-                                        error
-                                    );
-                                    None
-                                },
-                            },
-                            Err(error) => {
-                                log::error!("Failed to chunks for {:?}, error: {}", value, error);
-                                None
-                            },
-                        };
-                        Some(message::MessageFrontend::new(value, key, preview))
-                    },
+                    (Ok(key), Ok(value)) => Some(self.frontend(value, key)),
                     (Ok(index), Err(err)) => {
                         log::warn!("Failed to load value at {:?}: {}", index, err);
                         None
@@ -465,193 +1673,28 @@ impl DatabaseFetch for Db {
                         log::warn!("Failed to load index: {}", err);
                         None
                     },
-                })
-                .collect();
-
-            Ok(v)
-        } else {
-            let cursor = filter
-                .cursor
-                .clone()
-                .unwrap_or(if forward { 0 } else { u64::MAX });
-            let mut iters: Vec<Box<dyn Iterator<Item = u64>>> = Vec::with_capacity(5);
-            if let Some(ty) = &filter.types {
-                let mut tys = Vec::new();
-                for ty in ty.split(',') {
-                    let ty =
-                        ty.parse::<common::MessageType>()
-                            .map_err(|e| DBError::SchemaError {
-                                error: SchemaError::DecodeValidationError(e.to_string()),
-                            })?;
-                    let key = message_ty::Item { ty, index: cursor };
-                    let key = key
-                        .encode()
-                        .map_err(|error| DBError::SchemaError { error })?;
-                    let mode = rocksdb::IteratorMode::From(&key, direction().into());
-                    let cf = self
-                        .inner
-                        .cf_handle(message_ty::Schema::name())
-                        .ok_or_else(|| DBError::MissingColumnFamily {
-                            name: message_ty::Schema::name(),
-                        })?;
-                    let mut opts = ReadOptions::default();
-                    opts.set_prefix_same_as_start(true);
-                    let it = self
-                        .inner
-                        .iterator_cf_opt(cf, opts, mode)
-                        .filter_map(|(k, _)| Some(message_ty::Item::decode(&k).ok()?.index));
-                    tys.push(it);
-                }
-                iters.push(Box::new(tys.into_iter().kmerge_by(|x, y| x > y)));
-            }
-            if let Some(sender) = &filter.incoming {
-                let sender = common::Sender::new(*sender);
-                let key = message_sender::Item {
-                    sender,
-                    index: cursor,
-                };
-                let key = key
-                    .encode()
-                    .map_err(|error| DBError::SchemaError { error })?;
-                let mode = rocksdb::IteratorMode::From(&key, direction().into());
-                let cf = self
-                    .inner
-                    .cf_handle(message_sender::Schema::name())
-                    .ok_or_else(|| DBError::MissingColumnFamily {
-                        name: message_sender::Schema::name(),
-                    })?;
-                let mut opts = ReadOptions::default();
-                opts.set_prefix_same_as_start(true);
-                let it = self
-                    .inner
-                    .iterator_cf_opt(cf, opts, mode)
-                    .filter_map(|(k, _)| Some(message_sender::Item::decode(&k).ok()?.index));
-                iters.push(Box::new(it));
-            }
-            if let Some(initiator) = &filter.source_type {
-                let key = message_initiator::Item {
-                    initiator: initiator.clone(),
-                    index: cursor,
-                };
-                let key = key
-                    .encode()
-                    .map_err(|error| DBError::SchemaError { error })?;
-                let mode = rocksdb::IteratorMode::From(&key, direction().into());
-                let cf = self
-                    .inner
-                    .cf_handle(message_initiator::Schema::name())
-                    .ok_or_else(|| DBError::MissingColumnFamily {
-                        name: message_initiator::Schema::name(),
-                    })?;
-                let mut opts = ReadOptions::default();
-                opts.set_prefix_same_as_start(true);
-                let it = self
-                    .inner
-                    .iterator_cf_opt(cf, opts, mode)
-                    .filter_map(|(k, _)| Some(message_initiator::Item::decode(&k).ok()?.index));
-                iters.push(Box::new(it));
-            }
-            if let Some(addr) = &filter.remote_addr {
-                let addr = addr
-                    .parse::<SocketAddr>()
-                    .map_err(|e| DBError::SchemaError {
-                        error: SchemaError::DecodeValidationError(e.to_string()),
-                    })?;
-                let key = message_addr::Item {
-                    addr,
-                    index: cursor,
-                };
-                let key = key
-                    .encode()
-                    .map_err(|error| DBError::SchemaError { error })?;
-                let mode = rocksdb::IteratorMode::From(&key, direction().into());
-                let cf = self
-                    .inner
-                    .cf_handle(message_addr::Schema::name())
-                    .ok_or_else(|| DBError::MissingColumnFamily {
-                        name: message_addr::Schema::name(),
-                    })?;
-                let mut opts = ReadOptions::default();
-                opts.set_prefix_same_as_start(true);
-                let it = self
-                    .inner
-                    .iterator_cf_opt(cf, opts, mode)
-                    .filter_map(|(k, _)| Some(message_addr::Item::decode(&k).ok()?.index));
-                iters.push(Box::new(it));
-            }
-            if filter.from.is_some() || filter.to.is_some() {
-                let mut timestamp = timestamp::Item {
-                    timestamp: u64::MAX,
-                    index: u64::MAX,
-                };
-                let mode = if let Some(end) = filter.to {
-                    timestamp.timestamp = end;
-                    IteratorMode::From(&timestamp, direction())
-                } else {
-                    if forward {
-                        IteratorMode::Start
-                    } else {
-                        IteratorMode::End
-                    }
-                };
-                let it = self
-                    .as_kv::<timestamp::MessageSchema>()
-                    .iterator(mode)?
-                    .filter_map(|(k, _)| k.ok());
-                if let Some(begin) = filter.from {
-                    let it = it
-                        .take_while(move |k| (k.timestamp >= begin) ^ forward)
-                        .map(|k| k.index);
-                    iters.push(Box::new(it));
-                } else {
-                    iters.push(Box::new(it.map(|k| k.index)));
+                });
+            for message in messages {
+                if !f(message) {
+                    break;
                 }
             }
-            if let Some(middle) = filter.timestamp {
-                let middle = timestamp::Item {
-                    timestamp: middle,
-                    index: u64::MAX,
-                };
 
-                let it = self
-                    .as_kv::<timestamp::MessageSchema>()
-                    .iterator(IteratorMode::From(&middle, direction()))?
-                    .filter_map(|(k, _)| k.ok())
-                    .map(|k| k.index);
-                iters.push(Box::new(it));
-            }
+            Ok(())
+        } else {
+            let mut iters = self.message_index_iters(filter, forward)?;
 
-            let v = sorted_intersect(iters.as_mut_slice(), limit, forward)
+            // the level and the chain id are known only when the message is loaded
+            let index_limit = if has_content_filter(filter) {
+                usize::MAX
+            } else {
+                limit
+            };
+            let messages = sorted_intersect(iters.as_mut_slice(), index_limit, forward)
                 .into_iter()
                 .filter_map(
                     move |index| match self.as_kv::<message::Schema>().get(&index) {
-                        Ok(Some(value)) => {
-                            let preview = match details(&value, index, self.as_kv()) {
-                                Ok(details) => match details.json_string() {
-                                    Ok(p) => p.map(|mut s| {
-                                        utf8_truncate(&mut s, 100);
-                                        s
-                                    }),
-                                    Err(error) => {
-                                        log::error!(
-                                            "Failed to deserialize message {:?}, error: {}",
-                                            value,
-                                            error
-                                        );
-                                        None
-                                    },
-                                },
-                                Err(error) => {
-                                    log::error!(
-                                        "Failed to chunks for {:?}, error: {}",
-                                        value,
-                                        error
-                                    );
-                                    None
-                                },
-                            };
-                            Some(message::MessageFrontend::new(value, index, preview))
-                        },
+                        Ok(Some(value)) => Some(self.frontend(value, index)),
                         Ok(None) => {
                             log::info!("No value at index: {}", index);
                             None
@@ -662,19 +1705,114 @@ impl DatabaseFetch for Db {
                         },
                     },
                 )
-                .collect();
-            Ok(v)
+                .filter(|message| content_matches(filter, message))
+                .take(limit);
+            for message in messages {
+                if !f(message) {
+                    break;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    fn count_messages(&self, filter: &MessagesFilter) -> Result<u64, Self::Error> {
+        let forward = filter.direction == Some("forward".to_string());
+        let direction = || {
+            if forward {
+                Direction::Forward
+            } else {
+                Direction::Reverse
+            }
+        };
+
+        if !has_index_filter(filter) {
+            // only keys are visited, values are not loaded
+            let key;
+            let mode = if let Some(cursor) = &message_cursor(filter, forward) {
+                key = cursor
+                    .encode()
+                    .map_err(|error| DBError::SchemaError { error })?;
+                rocksdb::IteratorMode::From(&key, direction().into())
+            } else {
+                if forward {
+                    rocksdb::IteratorMode::Start
+                } else {
+                    rocksdb::IteratorMode::End
+                }
+            };
+            let cf = self
+                .inner
+                .cf_handle(message::Schema::name())
+                .ok_or_else(|| DBError::MissingColumnFamily {
+                    name: message::Schema::name(),
+                })?;
+            let count = self
+                .inner
+                .iterator_cf(cf, mode)
+                .take_while(|(k, _)| {
+                    u64::decode(k).map_or(true, |index| before_id_end(filter, forward, index))
+                })
+                .count();
+            Ok(count as u64)
+        } else if has_content_filter(filter) {
+            // the messages are loaded to know the level or the chain id
+            let mut count = 0;
+            self.for_each_message(
+                &MessagesFilter {
+                    limit: Some(u64::MAX),
+                    ..filter.clone()
+                },
+                |_| {
+                    count += 1;
+                    true
+                },
+            )?;
+            Ok(count)
+        } else {
+            let mut iters = self.message_index_iters(filter, forward)?;
+            Ok(sorted_intersect_count(iters.as_mut_slice(), forward) as u64)
         }
     }
 
-    fn fetch_message(&self, id: u64) -> Result<Option<message::MessageDetails>, Self::Error> {
-        if let Some(brief) = self.as_kv::<message::Schema>().get(&id)? {
-            details(&brief, id, self.as_kv()).map(Some)
+    fn fetch_message(
+        &self,
+        id: message::MessageId,
+    ) -> Result<Option<message::MessageDetails>, Self::Error> {
+        let index = match id {
+            message::MessageId::Index(index) => index,
+            message::MessageId::Stable(id) => {
+                match self.as_kv::<message_stable::Schema>().get(&id)? {
+                    Some(index) => index,
+                    None => return Ok(None),
+                }
+            },
+        };
+        if let Some(brief) = self.as_kv::<message::Schema>().get(&index)? {
+            let stable_id = brief.stable_id();
+            self.details(&brief, index)
+                .map(|details| Some(details.with_stable_id(stable_id)))
         } else {
             Ok(None)
         }
     }
 
+    fn fetch_message_raw(&self, id: u64) -> Result<Option<message::MessageRaw>, Self::Error> {
+        let brief = match self.as_kv::<message::Schema>().get(&id)? {
+            Some(brief) => brief,
+            None => return Ok(None),
+        };
+        let mut chunks = Vec::new();
+        for key in brief.chunks() {
+            // the chunk might be removed, or never stored
+            if let Some(value) = self.as_kv::<chunk::Schema>().get(&key)? {
+                let event = self.as_kv::<chunk_event::Schema>().get(&key)?;
+                chunks.push((key, value, event));
+            }
+        }
+        Ok(Some(message::MessageRaw::new(id, chunks)))
+    }
+
     fn fetch_log(&self, filter: &LogsFilter) -> Result<Vec<node_log::ItemWithId>, Self::Error> {
         let limit = filter.limit.unwrap_or(100) as usize;
 
@@ -714,6 +1852,7 @@ impl DatabaseFetch for Db {
             && filter.from.is_none()
             && filter.to.is_none()
             && filter.timestamp.is_none()
+            && filter.session.is_none()
         {
             let mode = if let Some(cursor) = &filter.cursor {
                 IteratorMode::From(cursor, direction())
@@ -818,6 +1957,13 @@ impl DatabaseFetch for Db {
                     .map(|k| k.index);
                 iters.push(Box::new(it));
             }
+            if let Some(label) = &filter.session {
+                let cursor = filter
+                    .cursor
+                    .clone()
+                    .unwrap_or(if forward { 0 } else { u64::MAX });
+                iters.push(self.session_iter::<session::LogSchema>(label, cursor, forward)?);
+            }
 
             let v = sorted_intersect(iters.as_mut_slice(), limit, forward)
                 .into_iter()
@@ -836,22 +1982,316 @@ impl DatabaseFetch for Db {
             Ok(v)
         }
     }
+
+    fn fetch_throughput(
+        &self,
+        filter: &ThroughputFilter,
+    ) -> Result<Vec<throughput::Bucket>, Self::Error> {
+        let width = filter.bucket.unwrap_or(1000).max(1);
+        let to = filter.to.unwrap_or_else(|| self.clock.now_millis());
+        let from = filter
+            .from
+            .unwrap_or_else(|| to.saturating_sub(width.saturating_mul(60)))
+            .max(to.saturating_sub(throughput::MAX_BUCKETS.saturating_mul(width)));
+
+        let begin = timestamp::Item {
+            timestamp: from,
+            index: 0,
+        };
+        let it = self
+            .as_kv::<timestamp::MessageSchema>()
+            .iterator(IteratorMode::From(&begin, Direction::Forward))?
+            .filter_map(|(k, v)| match (k, v) {
+                (Ok(key), Ok(value)) => Some((key.timestamp, value.size as u64)),
+                (Ok(key), Err(err)) => {
+                    log::warn!("Failed to load value at {:?}: {}", key.index, err);
+                    None
+                },
+                (Err(err), _) => {
+                    log::warn!("Failed to load index: {}", err);
+                    None
+                },
+            });
+        Ok(throughput::buckets(it, from, to, width))
+    }
+
+    fn fetch_stats(&self) -> Result<stats::Stats, Self::Error> {
+        // rocksdb default number of levels
+        const LEVELS: usize = 7;
+
+        let column_families = Self::column_families()
+            .into_iter()
+            .map(|name| stats::ColumnFamily {
+                name,
+                live_data_size: self
+                    .property(name, "rocksdb.estimate-live-data-size")
+                    .unwrap_or(0),
+                keys: self
+                    .property(name, "rocksdb.estimate-num-keys")
+                    .unwrap_or(0),
+                sst_files: (0..LEVELS)
+                    .filter_map(|level| {
+                        self.property(name, &format!("rocksdb.num-files-at-level{}", level))
+                    })
+                    .sum(),
+            })
+            .collect();
+        Ok(stats::Stats::new(
+            column_families,
+            self.message_store_limit,
+            self.log_store_limit,
+            self.missing_chunks.load(Ordering::Relaxed),
+        ))
+    }
+
+    fn fetch_message_types(
+        &self,
+        filter: &MessageTypesFilter,
+    ) -> Result<Vec<stats::MessageTypeStats>, Self::Error> {
+        let (begin, end) = match self.message_index_range(filter.from, filter.to)? {
+            Some(range) => range,
+            None => return Ok(vec![]),
+        };
+        let mut result = vec![];
+        for ty in common::MessageType::all() {
+            let (mut count, mut bytes) = (0, 0);
+            for index in self.message_type_iter(&ty, begin, end)? {
+                count += 1;
+                if filter.bytes == Some(true) {
+                    bytes += self.message_size(index)?;
+                }
+            }
+            if count != 0 {
+                let (category, kind) = ty.split();
+                result.push(stats::MessageTypeStats {
+                    category,
+                    kind,
+                    count,
+                    bytes: filter.bytes.filter(|b| *b).map(|_| bytes),
+                });
+            }
+        }
+        Ok(result)
+    }
+
+    fn fetch_decode_coverage(
+        &self,
+        filter: &MessageTypesFilter,
+    ) -> Result<Vec<stats::DecodeCoverage>, Self::Error> {
+        let (begin, end) = match self.message_index_range(filter.from, filter.to)? {
+            Some(range) => range,
+            None => return Ok(vec![]),
+        };
+        let mut result = vec![];
+        for ty in common::MessageType::all() {
+            let mut coverage = stats::DecodeCoverage::new(ty.clone());
+            for index in self.message_type_iter(&ty, begin, end)? {
+                // the message might be removed by the retention meanwhile
+                if let Some(brief) = self.as_kv::<message::Schema>().get(&index)? {
+                    coverage.add(&self.details(&brief, index)?);
+                }
+            }
+            if coverage.count != 0 {
+                result.push(coverage);
+            }
+        }
+        Ok(result)
+    }
+
+    fn subscribe(&self) -> tokio::sync::broadcast::Receiver<live::Event> {
+        self.live.subscribe()
+    }
+
+    fn fetch_timeline(&self, filter: &TimelineFilter) -> Result<timeline::Timeline, Self::Error> {
+        use self::timeline::{Cursor, Kind};
+
+        let limit = filter.limit.unwrap_or(100) as usize;
+        let to = filter.to.unwrap_or(u64::MAX);
+        let start = match &filter.cursor {
+            Some(cursor) => cursor.parse::<Cursor>().map_err(|e| DBError::SchemaError {
+                error: SchemaError::DecodeValidationError(e.to_string()),
+            })?,
+            None => Cursor::start(filter.from.unwrap_or(0)),
+        };
+
+        let begin = timestamp::Item {
+            timestamp: start.timestamp,
+            index: 0,
+        };
+        let messages = self
+            .as_kv::<timestamp::MessageSchema>()
+            .iterator(IteratorMode::From(&begin, Direction::Forward))?
+            .filter_map(|(k, _)| k.ok())
+            .map(|k| Cursor::record(k.timestamp, Kind::Message, k.index));
+        let logs = self
+            .as_kv::<timestamp::LogSchema>()
+            .iterator(IteratorMode::From(&begin, Direction::Forward))?
+            .filter_map(|(k, _)| k.ok())
+            .map(|k| Cursor::record(k.timestamp, Kind::Log, k.index));
+        // the connection key is the time when it was opened
+        let begin_cn = connection::Key {
+            ts: start.timestamp / 1000,
+            ts_nanos: ((start.timestamp % 1000) * 1_000_000) as u32,
+        };
+        let opened = self
+            .as_kv::<connection::Schema>()
+            .iterator(IteratorMode::From(&begin_cn, Direction::Forward))?
+            .filter_map(|(k, _)| k.ok())
+            .map(|k| {
+                let timestamp = k.ts * 1000 + (k.ts_nanos / 1_000_000) as u64;
+                Cursor::connection(timestamp, Kind::ConnectionOpen, &k)
+            });
+        let begin_close = timestamp::CloseItem {
+            timestamp: start.timestamp,
+            cn_id: connection::Key::default(),
+        };
+        let closed = self
+            .as_kv::<timestamp::ConnectionCloseSchema>()
+            .iterator(IteratorMode::From(&begin_close, Direction::Forward))?
+            .filter_map(|(k, _)| k.ok())
+            .map(|k| Cursor::connection(k.timestamp, Kind::ConnectionClose, &k.cn_id));
+
+        let sources: Vec<Box<dyn Iterator<Item = Cursor> + '_>> = vec![
+            Box::new(opened),
+            Box::new(messages),
+            Box::new(logs),
+            Box::new(closed),
+        ];
+        let positions = timeline::merge(sources)
+            .skip_while(|position| *position < start)
+            .take_while(|position| position.timestamp < to);
+
+        let mut result = timeline::Timeline::default();
+        for position in positions {
+            if result.events.len() == limit {
+                result.cursor = Some(position.to_string());
+                break;
+            }
+            // the record might be removed since the index was read
+            if let Some(event) = self.timeline_event(&position)? {
+                result.events.push(event);
+            }
+        }
+        Ok(result)
+    }
+}
+
+/// Where the iteration of messages starts, the cursor or the bound of the id range
+/// whichever is further in the direction
+fn message_cursor(filter: &MessagesFilter, forward: bool) -> Option<u64> {
+    let bound = if forward { filter.id_from } else { filter.id_to };
+    match (filter.cursor, bound) {
+        (Some(cursor), Some(bound)) if forward => Some(cursor.max(bound)),
+        (Some(cursor), Some(bound)) => Some(cursor.min(bound)),
+        (cursor, bound) => cursor.or(bound),
+    }
+}
+
+/// The message is not past the end of the id range in the direction
+fn before_id_end(filter: &MessagesFilter, forward: bool, index: u64) -> bool {
+    if forward {
+        filter.id_to.map_or(true, |id_to| index <= id_to)
+    } else {
+        filter.id_from.map_or(true, |id_from| index >= id_from)
+    }
+}
+
+/// The iterator over the secondary index restricted by the id range, the timestamp
+/// index does not start at the cursor, so the beginning is skipped as well
+fn message_id_range<'a, I>(
+    it: I,
+    filter: &MessagesFilter,
+    forward: bool,
+) -> Box<dyn Iterator<Item = u64> + 'a>
+where
+    I: Iterator<Item = u64> + 'a,
+{
+    let id_from = filter.id_from.unwrap_or(0);
+    let id_to = filter.id_to.unwrap_or(u64::MAX);
+    if forward {
+        Box::new(
+            it.skip_while(move |i| *i < id_from)
+                .take_while(move |i| *i <= id_to),
+        )
+    } else {
+        Box::new(
+            it.skip_while(move |i| *i > id_to)
+                .take_while(move |i| *i >= id_from),
+        )
+    }
+}
+
+fn has_level_filter(filter: &MessagesFilter) -> bool {
+    filter.min_level.is_some() || filter.max_level.is_some()
+}
+
+/// The level of the header carried by the message is in the range of the filter,
+/// the message without the header does not match if the range is given
+fn level_matches(filter: &MessagesFilter, message: &message::MessageFrontend) -> bool {
+    if !has_level_filter(filter) {
+        return true;
+    }
+    match &message.block_header {
+        Some(header) => {
+            filter.min_level.map_or(true, |min| header.level >= min)
+                && filter.max_level.map_or(true, |max| header.level <= max)
+        },
+        None => false,
+    }
+}
+
+/// The filter by the content, the messages are loaded to match it
+fn has_content_filter(filter: &MessagesFilter) -> bool {
+    has_level_filter(filter) || filter.chain_id.is_some()
+}
+
+/// The message matches the level and the chain id of the filter
+fn content_matches(filter: &MessagesFilter, message: &message::MessageFrontend) -> bool {
+    level_matches(filter, message)
+        && filter
+            .chain_id
+            .as_ref()
+            .map_or(true, |chain_id| message.chain_id.as_ref() == Some(chain_id))
+}
+
+/// The filter requires the secondary indexes, otherwise messages are iterated directly
+fn has_index_filter(filter: &MessagesFilter) -> bool {
+    filter.remote_addr.is_some()
+        || filter.source_type.is_some()
+        || filter.incoming.is_some()
+        || filter.types.is_some()
+        || filter.from.is_some()
+        || filter.to.is_some()
+        || filter.timestamp.is_some()
+        || filter.session.is_some()
+        || filter.hash.is_some()
+        || has_content_filter(filter)
 }
 
 fn details(
     message_item: &message::Item,
     id: u64,
+    encoding_version: Option<u16>,
     db: &(impl KeyValueStoreBackend<chunk::Schema> + KeyValueStoreWithSchemaIterator<chunk::Schema>),
 ) -> Result<message::MessageDetails, DbError> {
     let mut chunks = Vec::new();
+    let mut complete = true;
     for key in message_item.chunks() {
-        if let Some(c) = db.get(&key)? {
+        // the chunk stored without the bytes is as good as the missing one
+        if let Some(c) = db.get(&key)?.filter(chunk::Value::payload) {
             chunks.push(c);
         } else {
+            complete = false;
             break;
         }
     }
-    Ok(message::MessageDetails::new(id, &message_item.ty, &chunks))
+    Ok(message::MessageDetails::new(
+        id,
+        &message_item.ty,
+        &chunks,
+        complete,
+        encoding_version,
+    ))
 }
 
 fn utf8_truncate(input: &mut String, max_size: usize) {
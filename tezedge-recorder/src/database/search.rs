@@ -124,17 +124,31 @@ impl Drop for LogIndexer {
 impl LogIndexer {
     const HEAP_BYTES: usize = 32 * 1024 * 1024; // 32Mb
 
-    pub fn try_new<P>(path: P) -> Result<Self, TantivyError>
-    where
-        P: AsRef<Path>,
-    {
+    fn schema() -> schema::Schema {
         let mut schema_builder = schema::Schema::builder();
         schema_builder.add_text_field("message", schema::TEXT);
         schema_builder.add_text_field("id", schema::STORED);
-        let schema = schema_builder.build();
+        schema_builder.build()
+    }
 
+    pub fn try_new<P>(path: P) -> Result<Self, TantivyError>
+    where
+        P: AsRef<Path>,
+    {
+        let schema = Self::schema();
         let _ = fs::create_dir_all(&path);
         let index = Index::open_or_create(MmapDirectory::open(path)?, schema.clone())?;
+        Self::with_index(index, schema)
+    }
+
+    /// The index is not persisted, see `DatabaseNew::open_in_memory`
+    pub fn in_ram() -> Result<Self, TantivyError> {
+        let schema = Self::schema();
+        let index = Index::create_in_ram(schema.clone());
+        Self::with_index(index, schema)
+    }
+
+    fn with_index(index: Index, schema: schema::Schema) -> Result<Self, TantivyError> {
         let message_field = schema.get_field("message").unwrap();
         let id_field = schema.get_field("id").unwrap();
         let queue = Default::default();
@@ -60,37 +60,73 @@ where
     I: Iterator,
     I::Item: Ord,
 {
-    let mut ret = Default::default();
+    let mut ret = Vec::new();
+    if limit != 0 {
+        sorted_intersect_with(iters, forward, |item| {
+            ret.push(item);
+            ret.len() < limit
+        });
+    }
+    ret
+}
+
+/// Number of values present in *every* iterator, the values are not collected
+pub fn sorted_intersect_count<I>(iters: &mut [I], forward: bool) -> usize
+where
+    I: Iterator,
+    I::Item: Ord,
+{
+    let mut count = 0;
+    sorted_intersect_with(iters, forward, |_| {
+        count += 1;
+        true
+    });
+    count
+}
+
+/// Call `f` for each value present in *every* iterator, until `f` returns false
+fn sorted_intersect_with<I, F>(iters: &mut [I], forward: bool, mut f: F)
+where
+    I: Iterator,
+    I::Item: Ord,
+    F: FnMut(I::Item) -> bool,
+{
     if iters.is_empty() {
-        return ret;
+        return;
     } else if iters.len() == 1 {
         let iter = iters.iter_mut().next().unwrap();
-        ret.extend(iter.take(limit));
-        return ret;
+        for item in iter {
+            if !f(item) {
+                return;
+            }
+        }
+        return;
     }
     let mut heap = Vec::with_capacity(iters.len());
     // Fill the heap with values
     if !fill_heap(iters.iter_mut(), &mut heap, forward) {
         // Hit an exhausted iterator, finish
-        return ret;
+        return;
     }
 
-    while ret.len() < limit {
+    loop {
         if is_hit(&heap) {
             // We hit intersected item
             if let Some((item, _)) = heap.pop() {
-                // Push it into the intersect values
-                ret.push(item);
+                // Pass it to the consumer
+                if !f(item) {
+                    return;
+                }
                 // Clear the rest of the heap
                 heap.clear();
                 // Build a new heap from new values
                 if !fill_heap(iters.iter_mut(), &mut heap, forward) {
                     // Hit an exhausted iterator, finish
-                    return ret;
+                    return;
                 }
             } else {
                 // Hit an exhausted iterator, finish
-                return ret;
+                return;
             }
         } else {
             // Remove max element from the heap
@@ -101,16 +137,14 @@ where
                     heapify(&mut heap, forward);
                 } else {
                     // Hit an exhausted iterator, finish
-                    return ret;
+                    return;
                 }
             } else {
                 // Hit an exhausted iterator, finish
-                return ret;
+                return;
             }
         }
     }
-
-    ret
 }
 
 /// Create heap out of vector
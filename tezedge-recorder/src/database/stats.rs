@@ -0,0 +1,143 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use serde::Serialize;
+use super::{
+    common::{MessageCategory, MessageKind, MessageType},
+    message::MessageDetails,
+};
+
+/// Estimated size of a column family, taken from rocksdb properties
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct ColumnFamily {
+    pub name: &'static str,
+    pub live_data_size: u64,
+    pub keys: u64,
+    pub sst_files: u64,
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct Stats {
+    pub column_families: Vec<ColumnFamily>,
+    pub total_live_data_size: u64,
+    pub total_sst_files: u64,
+    // the retention configured for the node, `None` means unlimited
+    pub message_store_limit: Option<u64>,
+    pub log_store_limit: Option<u64>,
+    // the chunks lost before storing since the recorder started, see `/v3/chunks`
+    pub missing_chunks: u64,
+}
+
+impl Stats {
+    pub fn new(
+        column_families: Vec<ColumnFamily>,
+        message_store_limit: Option<u64>,
+        log_store_limit: Option<u64>,
+        missing_chunks: u64,
+    ) -> Self {
+        Stats {
+            total_live_data_size: column_families.iter().map(|cf| cf.live_data_size).sum(),
+            total_sst_files: column_families.iter().map(|cf| cf.sst_files).sum(),
+            column_families,
+            message_store_limit,
+            log_store_limit,
+            missing_chunks,
+        }
+    }
+}
+
+/// The messages of the type, see `/v3/stats/message_types`
+#[derive(Debug, Clone, Serialize)]
+pub struct MessageTypeStats {
+    pub category: MessageCategory,
+    pub kind: Option<MessageKind>,
+    pub count: u64,
+    // the sum of the sizes, only if requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<u64>,
+}
+
+/// How many messages of the type are decoded, see `/v3/stats/decode_coverage`,
+/// the low `rate` of some type tells its encoding has changed
+#[derive(Debug, Clone, Serialize)]
+pub struct DecodeCoverage {
+    pub category: MessageCategory,
+    pub kind: Option<MessageKind>,
+    pub count: u64,
+    pub decoded: u64,
+    // oversized, or of the version not supported, not decoded on purpose
+    pub raw: u64,
+    // the decoder failed, or some chunks are missing
+    pub failed: u64,
+    // `decoded / count`
+    pub rate: f64,
+}
+
+impl DecodeCoverage {
+    pub fn new(ty: MessageType) -> Self {
+        let (category, kind) = ty.split();
+        DecodeCoverage {
+            category,
+            kind,
+            count: 0,
+            decoded: 0,
+            raw: 0,
+            failed: 0,
+            rate: 0.0,
+        }
+    }
+
+    pub fn add(&mut self, details: &MessageDetails) {
+        self.count += 1;
+        if details.is_decoded() {
+            self.decoded += 1;
+        } else if details.is_raw() {
+            self.raw += 1;
+        } else {
+            self.failed += 1;
+        }
+        self.rate = self.decoded as f64 / self.count as f64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{rocks::Db, temp::TempDb, Database, DatabaseNew, DatabaseFetch, node_log};
+
+    #[test]
+    fn populated() {
+        let path = TempDb::new("stats");
+        let db = Db::open(&path, false, Some(100), None, Default::default()).unwrap();
+        for i in 0..10 {
+            db.store_log(node_log::Item {
+                level: node_log::LogLevel::Info,
+                timestamp: i * 1_000_000_000,
+                section: String::new(),
+                message: format!("message {}", i),
+            });
+        }
+
+        let stats = db.fetch_stats().unwrap();
+        let log = stats
+            .column_families
+            .iter()
+            .find(|cf| cf.name == "log_storage")
+            .unwrap();
+        assert!(log.keys > 0);
+        assert!(stats
+            .column_families
+            .iter()
+            .any(|cf| cf.name == "message_storage"));
+        assert_eq!(
+            stats.total_live_data_size,
+            stats.column_families.iter().map(|cf| cf.live_data_size).sum(),
+        );
+        assert_eq!(stats.log_store_limit, Some(100));
+        assert_eq!(stats.message_store_limit, None);
+
+        let json = serde_json::to_value(&stats).unwrap();
+        for field in &["live_data_size", "keys", "sst_files"] {
+            assert!(json["column_families"][0][field].is_u64());
+        }
+    }
+}
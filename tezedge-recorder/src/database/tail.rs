@@ -0,0 +1,67 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::{collections::VecDeque, sync::Mutex};
+use super::message;
+
+/// The most recent messages as they are stored with their ids, the database decodes them
+/// when they are read, so they are the same as the stored ones
+pub struct Tail(Mutex<Inner>);
+
+struct Inner {
+    capacity: usize,
+    // the oldest first
+    messages: VecDeque<(u64, message::Item)>,
+}
+
+impl Default for Tail {
+    fn default() -> Self {
+        Tail(Mutex::new(Inner {
+            capacity: Self::DEFAULT_SIZE,
+            messages: VecDeque::with_capacity(Self::DEFAULT_SIZE),
+        }))
+    }
+}
+
+impl Tail {
+    pub const DEFAULT_SIZE: usize = 100;
+
+    pub fn set_capacity(&self, capacity: usize) {
+        let mut inner = self.0.lock().unwrap();
+        inner.capacity = capacity;
+        while inner.messages.len() > capacity {
+            inner.messages.pop_front();
+        }
+    }
+
+    pub fn push(&self, id: u64, message: message::Item) {
+        let mut inner = self.0.lock().unwrap();
+        if inner.capacity == 0 {
+            return;
+        }
+        if inner.messages.len() == inner.capacity {
+            inner.messages.pop_front();
+        }
+        // the ids are reserved before the messages are stored, the order might differ slightly
+        let position = inner
+            .messages
+            .iter()
+            .rposition(|(m, _)| *m < id)
+            .map_or(0, |p| p + 1);
+        inner.messages.insert(position, (id, message));
+    }
+
+    /// The newest first, as in `/v3/messages`, `None` if there are not so many in memory
+    pub fn last(&self, n: usize) -> Option<Vec<(u64, message::Item)>> {
+        let inner = self.0.lock().unwrap();
+        if n > inner.messages.len() {
+            return None;
+        }
+        Some(inner.messages.iter().rev().take(n).cloned().collect())
+    }
+
+    /// Forget the messages older than `id`, they are removed from the database
+    pub fn remove_before(&self, id: u64) {
+        self.0.lock().unwrap().messages.retain(|(m, _)| *m >= id);
+    }
+}
@@ -0,0 +1,47 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::{
+    env, fs, process,
+    ops::Deref,
+    path::{Path, PathBuf},
+};
+use super::{rocks::Db, DatabaseNew};
+
+/// The directory of the database of the test, unique for the process and the `name`,
+/// left by the previous run is removed, removed when dropped, even if the test panics.
+/// Declare it before the database, so the database is closed first.
+pub struct TempDb(PathBuf);
+
+impl TempDb {
+    pub fn new(name: &str) -> Self {
+        let path = env::temp_dir().join(format!("tezedge-recorder-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&path);
+        TempDb(path)
+    }
+
+    /// The database without the limits and with the default options
+    pub fn open(&self) -> Db {
+        Db::open(&self.0, false, None, None, Default::default()).unwrap()
+    }
+}
+
+impl Deref for TempDb {
+    type Target = Path;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempDb {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDb {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
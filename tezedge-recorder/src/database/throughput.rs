@@ -0,0 +1,84 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use serde::Serialize;
+
+/// Number of messages and bytes captured in `[timestamp, timestamp + width)`
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct Bucket {
+    pub timestamp: u64,
+    pub count: u64,
+    pub bytes: u64,
+}
+
+/// Do not allow the client to request too many buckets
+pub const MAX_BUCKETS: u64 = 0x10000;
+
+/// For given iterator of `(timestamp, size)` sorted by timestamp,
+/// sum counts and sizes into buckets of `width` milliseconds in range `[from, to)`.
+/// Every bucket is present in the result, even if it is empty.
+pub fn buckets<I>(it: I, from: u64, to: u64, width: u64) -> Vec<Bucket>
+where
+    I: Iterator<Item = (u64, u64)>,
+{
+    let width = width.max(1);
+    let number = (to.saturating_sub(from).saturating_add(width - 1) / width).min(MAX_BUCKETS);
+    let mut v = (0..number)
+        .map(|i| Bucket {
+            timestamp: from + i * width,
+            ..Default::default()
+        })
+        .collect::<Vec<_>>();
+    for (timestamp, size) in it.take_while(|&(timestamp, _)| timestamp < to) {
+        if timestamp < from {
+            continue;
+        }
+        match v.get_mut(((timestamp - from) / width) as usize) {
+            Some(bucket) => {
+                bucket.count += 1;
+                bucket.bytes += size;
+            },
+            None => break,
+        }
+    }
+    v
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{buckets, Bucket};
+
+    #[test]
+    fn boundaries() {
+        let timestamps = [(999, 1), (1000, 2), (1999, 3), (2000, 4), (2500, 5), (4000, 6)];
+        let v = buckets(timestamps.iter().cloned(), 1000, 4000, 1000);
+        let expected = [
+            Bucket {
+                timestamp: 1000,
+                count: 2,
+                bytes: 5,
+            },
+            Bucket {
+                timestamp: 2000,
+                count: 2,
+                bytes: 9,
+            },
+            Bucket {
+                timestamp: 3000,
+                count: 0,
+                bytes: 0,
+            },
+        ];
+        assert_eq!(v, expected);
+    }
+
+    #[test]
+    fn partial_last_bucket() {
+        let timestamps = (0..100).map(|t| (t, 10));
+        let v = buckets(timestamps, 0, 25, 10);
+        assert_eq!(v.len(), 3);
+        assert_eq!(v[2].timestamp, 20);
+        assert_eq!(v[2].count, 5);
+        assert_eq!(v.iter().map(|b| b.bytes).sum::<u64>(), 250);
+    }
+}
@@ -0,0 +1,283 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::{fmt, str::FromStr, num::ParseIntError};
+use serde::Serialize;
+use thiserror::Error;
+use itertools::Itertools;
+use super::{connection, message, node_log};
+
+/// Events with the same timestamp are ordered by kind
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Kind {
+    ConnectionOpen,
+    Message,
+    Log,
+    ConnectionClose,
+}
+
+impl Kind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Kind::ConnectionOpen => "connection_open",
+            Kind::Message => "message",
+            Kind::Log => "log",
+            Kind::ConnectionClose => "connection_close",
+        }
+    }
+}
+
+/// The position of the event in the timeline, the `id` is the index of the message or log,
+/// or the seconds and nanoseconds of the connection key
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Cursor {
+    // milliseconds
+    pub timestamp: u64,
+    pub kind: Kind,
+    pub id: (u64, u32),
+}
+
+impl Cursor {
+    /// The position before any event at the timestamp
+    pub fn start(timestamp: u64) -> Self {
+        Cursor {
+            timestamp,
+            kind: Kind::ConnectionOpen,
+            id: (0, 0),
+        }
+    }
+
+    pub fn record(timestamp: u64, kind: Kind, index: u64) -> Self {
+        Cursor {
+            timestamp,
+            kind,
+            id: (index, 0),
+        }
+    }
+
+    pub fn connection(timestamp: u64, kind: Kind, cn_id: &connection::Key) -> Self {
+        Cursor {
+            timestamp,
+            kind,
+            id: (cn_id.ts, cn_id.ts_nanos),
+        }
+    }
+
+    pub fn cn_id(&self) -> connection::Key {
+        connection::Key {
+            ts: self.id.0,
+            ts_nanos: self.id.1,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum CursorFromStrError {
+    #[error("wrong formatted timeline cursor")]
+    Cursor,
+    #[error("unknown event kind {}", _0)]
+    Kind(String),
+    #[error("cannot parse decimal: {}", _0)]
+    DecimalParse(ParseIntError),
+}
+
+impl FromStr for Cursor {
+    type Err = CursorFromStrError;
+
+    // format: [timestamp]-[kind]-[id]
+    // example: 1617005682953-message-1024, 1617005682953-connection_open-1617005682.953928051
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(3, '-');
+        let mut next = || parts.next().ok_or(CursorFromStrError::Cursor);
+        let timestamp = next()?.parse().map_err(CursorFromStrError::DecimalParse)?;
+        let kind = match next()? {
+            "connection_open" => Kind::ConnectionOpen,
+            "message" => Kind::Message,
+            "log" => Kind::Log,
+            "connection_close" => Kind::ConnectionClose,
+            kind => return Err(CursorFromStrError::Kind(kind.to_string())),
+        };
+        let id = next()?;
+        match kind {
+            Kind::ConnectionOpen | Kind::ConnectionClose => {
+                let cn_id = id
+                    .parse::<connection::Key>()
+                    .map_err(|_| CursorFromStrError::Cursor)?;
+                Ok(Cursor::connection(timestamp, kind, &cn_id))
+            },
+            Kind::Message | Kind::Log => {
+                let index = id.parse().map_err(CursorFromStrError::DecimalParse)?;
+                Ok(Cursor::record(timestamp, kind, index))
+            },
+        }
+    }
+}
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            Kind::ConnectionOpen | Kind::ConnectionClose => {
+                write!(f, "{}-{}-{}", self.timestamp, self.kind.as_str(), self.cn_id())
+            },
+            Kind::Message | Kind::Log => {
+                write!(f, "{}-{}-{}", self.timestamp, self.kind.as_str(), self.id.0)
+            },
+        }
+    }
+}
+
+/// Merge the positions, each source is sorted, the result is sorted too
+pub fn merge<'a, I>(sources: I) -> impl Iterator<Item = Cursor> + 'a
+where
+    I: IntoIterator,
+    I::Item: Iterator<Item = Cursor> + 'a,
+{
+    sources.into_iter().kmerge()
+}
+
+#[derive(Serialize)]
+pub struct ConnectionEvent {
+    pub id: connection::Key,
+    // nanoseconds, like timestamps of messages and logs
+    pub timestamp: u128,
+    #[serde(flatten)]
+    pub value: connection::Value,
+}
+
+/// The message has its own `kind`, so the event is in `data`
+#[derive(Serialize)]
+#[serde(tag = "kind", content = "data", rename_all = "snake_case")]
+pub enum Event {
+    ConnectionOpen(ConnectionEvent),
+    Message(message::MessageFrontend),
+    Log(node_log::ItemWithId),
+    ConnectionClose(ConnectionEvent),
+}
+
+#[derive(Default, Serialize)]
+pub struct Timeline {
+    pub events: Vec<Event>,
+    // the position of the next event, `None` if there are no more events in the range
+    pub cursor: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+    use super::{
+        Cursor, Kind,
+        super::{
+            temp::TempDb,
+            Database, DatabaseFetch, TimelineFilter,
+            connection, message, node_log,
+            common::{Initiator, Sender},
+        },
+    };
+
+    #[test]
+    fn cursor() {
+        for s in &["1617005682953-message-1024", "1617005682953-connection_close-1617005682.95"] {
+            assert_eq!(s.parse::<Cursor>().unwrap().to_string(), *s);
+        }
+        assert!("1617005682953-chunk-1".parse::<Cursor>().is_err());
+        assert!("1617005682953-log".parse::<Cursor>().is_err());
+        let log = Cursor::record(1000, Kind::Log, 0);
+        assert!(Cursor::start(1000) < log);
+        assert!(log < Cursor::start(1001));
+    }
+
+    #[test]
+    fn interleaving() {
+        let path = TempDb::new("events");
+        let db = path.open();
+
+        let open = |ts: u64, addr: &str| {
+            let mut cn = connection::Item::new(
+                Initiator::new(false),
+                addr.parse().unwrap(),
+                SystemTime::now(),
+            );
+            cn.ts = ts;
+            cn.ts_nanos = 0;
+            db.store_connection(cn.clone());
+            cn
+        };
+        let send = |timestamp: u64, cn: &connection::Item| {
+            let mut item = message::MessageBuilder::acknowledge_message().build(
+                &Sender::Local,
+                cn,
+                SystemTime::now(),
+            );
+            item.timestamp = timestamp;
+            db.store_message(item);
+        };
+        let write = |timestamp: u64, text: &str| {
+            db.store_log(node_log::Item {
+                level: node_log::LogLevel::Info,
+                timestamp: (timestamp as u128) * 1_000_000,
+                section: String::new(),
+                message: text.to_string(),
+            })
+        };
+
+        write(500_000, "before");
+        let mut first = open(1000, "51.15.220.7:9732");
+        write(1_000_500, "first");
+        send(1_001_000, &first);
+        write(1_001_500, "second");
+        let second = open(1002, "51.15.220.8:9732");
+        send(1_002_500, &second);
+        // the close is recorded at the current time, it is the last
+        first.set_close_reason(connection::CloseReason::Close);
+        db.update_connection(first);
+
+        let fetch = |cursor: Option<String>, to: Option<u64>, limit: Option<u64>| {
+            let filter = TimelineFilter {
+                limit,
+                cursor,
+                from: Some(1_000_000),
+                to,
+            };
+            let timeline = db.fetch_timeline(&filter).unwrap();
+            let v = serde_json::to_value(&timeline.events).unwrap();
+            let kinds = v
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|e| e["kind"].as_str().unwrap().to_string())
+                .collect::<Vec<_>>();
+            (kinds, v, timeline.cursor)
+        };
+
+        let (kinds, events, cursor) = fetch(None, None, None);
+        let expected = [
+            "connection_open",
+            "log",
+            "message",
+            "log",
+            "connection_open",
+            "message",
+            "connection_close",
+        ];
+        assert_eq!(kinds, expected);
+        assert!(cursor.is_none());
+        assert_eq!(events[1]["data"]["message"], "first");
+        assert_eq!(events[2]["data"]["category"], "ack");
+        assert_eq!(events[4]["data"]["remote_addr"], "51.15.220.8:9732");
+        assert_eq!(events[6]["data"]["close_reason"], "close");
+
+        // paging, the cursor points to the next event
+        let (kinds, _, cursor) = fetch(None, None, Some(3));
+        assert_eq!(kinds, ["connection_open", "log", "message"]);
+        assert_eq!(cursor.as_deref(), Some("1001500-log-2"));
+        let (kinds, _, cursor) = fetch(cursor, None, Some(3));
+        assert_eq!(kinds, ["log", "connection_open", "message"]);
+        let (kinds, _, cursor) = fetch(cursor, None, Some(3));
+        assert_eq!(kinds, ["connection_close"]);
+        assert!(cursor.is_none());
+
+        // the end of the range is exclusive
+        let (kinds, _, _) = fetch(None, Some(1_002_000), None);
+        assert_eq!(kinds, ["connection_open", "log", "message", "log"]);
+    }
+}
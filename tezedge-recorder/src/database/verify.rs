@@ -0,0 +1,231 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use serde::Serialize;
+use super::{DatabaseFetch, ChunksFilter, connection, common::Sender};
+
+/// The two ends of one session recorded separately, compared chunk by chunk.
+/// The messages are built from the chunks, so equal chunks mean equal messages
+/// in the same order. The captured bytes are compared, they are the same at both ends
+/// even if one of the ends is recorded without the identity.
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub initiator_to_responder: DirectionReport,
+    pub responder_to_initiator: DirectionReport,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct DirectionReport {
+    // the chunks recorded at the sending end
+    pub sent: u64,
+    // the chunks recorded at the receiving end
+    pub received: u64,
+    // the chunks equal at both ends before the divergence
+    pub matched: u64,
+    pub divergence: Option<Divergence>,
+}
+
+/// The first difference in the direction, the chunks after it are not compared
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Divergence {
+    /// The chunk is recorded only at one end, the other end lost it
+    Missing { counter: u64, at: End },
+    /// The chunk is recorded at both ends, but its bytes differ
+    Content { counter: u64 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum End {
+    Initiator,
+    Responder,
+}
+
+impl Report {
+    pub fn is_consistent(&self) -> bool {
+        self.initiator_to_responder.divergence.is_none()
+            && self.responder_to_initiator.divergence.is_none()
+    }
+}
+
+/// Compare the connection `initiator` recorded by the initiator of the session
+/// with the connection `responder` recorded by the responder
+pub fn verify_session<Db>(
+    initiator_db: &Db,
+    initiator: &connection::Key,
+    responder_db: &Db,
+    responder: &connection::Key,
+) -> Result<Report, Db::Error>
+where
+    Db: DatabaseFetch,
+{
+    let i_sent = chunks(initiator_db, initiator, Sender::Local)?;
+    let i_received = chunks(initiator_db, initiator, Sender::Remote)?;
+    let r_sent = chunks(responder_db, responder, Sender::Local)?;
+    let r_received = chunks(responder_db, responder, Sender::Remote)?;
+
+    Ok(Report {
+        initiator_to_responder: compare(&i_sent, &r_received, End::Responder),
+        responder_to_initiator: compare(&r_sent, &i_received, End::Initiator),
+    })
+}
+
+/// The captured bytes of the chunks sent by `sender`, ordered by the counter
+fn chunks<Db>(
+    db: &Db,
+    cn_id: &connection::Key,
+    sender: Sender,
+) -> Result<Vec<(u64, Vec<u8>)>, Db::Error>
+where
+    Db: DatabaseFetch,
+{
+    let filter = ChunksFilter {
+        limit: Some(u64::MAX),
+        cn: Some(cn_id.to_string()),
+        preview: Some(0),
+    };
+    let local = matches!(sender, Sender::Local);
+    let mut chunks = Vec::new();
+    for (key, _) in db.fetch_chunks_truncated(&filter)? {
+        if matches!(key.sender, Sender::Local) != local {
+            continue;
+        }
+        if let Some(value) = db.fetch_chunk(&key)? {
+            chunks.push((key.counter, value.bytes));
+        }
+    }
+    chunks.sort_by_key(|(counter, _)| *counter);
+    Ok(chunks)
+}
+
+/// `receiver` is the end which received the chunks
+fn compare(
+    sent: &[(u64, Vec<u8>)],
+    received: &[(u64, Vec<u8>)],
+    receiver: End,
+) -> DirectionReport {
+    let sender = match receiver {
+        End::Initiator => End::Responder,
+        End::Responder => End::Initiator,
+    };
+    let mut report = DirectionReport {
+        sent: sent.len() as u64,
+        received: received.len() as u64,
+        ..DirectionReport::default()
+    };
+    let (mut sent, mut received) = (sent.iter().peekable(), received.iter().peekable());
+    loop {
+        let divergence = match (sent.peek(), received.peek()) {
+            (None, None) => break,
+            (Some((counter, _)), None) => Divergence::Missing {
+                counter: *counter,
+                at: receiver,
+            },
+            (None, Some((counter, _))) => Divergence::Missing {
+                counter: *counter,
+                at: sender,
+            },
+            (Some((s, _)), Some((r, _))) if s < r => Divergence::Missing {
+                counter: *s,
+                at: receiver,
+            },
+            (Some((s, _)), Some((r, _))) if s > r => Divergence::Missing {
+                counter: *r,
+                at: sender,
+            },
+            (Some((counter, s)), Some((_, r))) if s != r => {
+                Divergence::Content { counter: *counter }
+            },
+            (Some(_), Some(_)) => {
+                report.matched += 1;
+                sent.next();
+                received.next();
+                continue;
+            },
+        };
+        report.divergence = Some(divergence);
+        break;
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+    use crate::{
+        common::Sender,
+        database::{Database, DatabaseNew, rocks::Db, temp::TempDb},
+        tables::{chunk, connection},
+    };
+    use super::{verify_session, Divergence, End};
+
+    #[test]
+    fn two_ends() {
+        let dir = TempDb::new("verify");
+        let open = |name| Db::open(dir.join(name), false, None, None, Default::default()).unwrap();
+        let (i_db, r_db) = (open("initiator"), open("responder"));
+        let i_cn = connection::Key {
+            ts: 1,
+            ts_nanos: 0,
+        };
+        let r_cn = connection::Key {
+            ts: 2,
+            ts_nanos: 0,
+        };
+
+        // the initiator sends three chunks, the responder two, the bytes are the counters
+        let store = |db: &Db, cn_id: &connection::Key, sender, counter: u64| {
+            let bytes = vec![counter as u8; 4];
+            db.store_chunk(chunk::Item::new(
+                cn_id.clone(),
+                sender,
+                counter,
+                bytes,
+                vec![],
+                SystemTime::now(),
+            ));
+        };
+        for counter in 0..3 {
+            store(&i_db, &i_cn, Sender::Local, counter);
+            store(&r_db, &r_cn, Sender::Remote, counter);
+        }
+        for counter in 0..2 {
+            store(&r_db, &r_cn, Sender::Local, counter);
+            store(&i_db, &i_cn, Sender::Remote, counter);
+        }
+        i_db.flush();
+        r_db.flush();
+        let report = verify_session(&i_db, &i_cn, &r_db, &r_cn).unwrap();
+        assert!(report.is_consistent());
+        assert_eq!(report.initiator_to_responder.matched, 3);
+        assert_eq!(report.responder_to_initiator.matched, 2);
+
+        // the initiator lost the third chunk of the responder
+        store(&r_db, &r_cn, Sender::Local, 2);
+        store(&i_db, &i_cn, Sender::Remote, 3);
+        // the responder received another content of the third chunk
+        r_db.store_chunk(chunk::Item::new(
+            r_cn.clone(),
+            Sender::Remote,
+            2,
+            vec![0xff],
+            vec![],
+            SystemTime::now(),
+        ));
+        i_db.flush();
+        r_db.flush();
+        let report = verify_session(&i_db, &i_cn, &r_db, &r_cn).unwrap();
+        assert!(!report.is_consistent());
+        let expected = Divergence::Content { counter: 2 };
+        assert_eq!(report.initiator_to_responder.divergence, Some(expected));
+        let expected = Divergence::Missing {
+            counter: 2,
+            at: End::Initiator,
+        };
+        assert_eq!(report.responder_to_initiator.divergence, Some(expected));
+        assert_eq!(report.responder_to_initiator.matched, 2);
+
+        drop((i_db, r_db));
+    }
+}
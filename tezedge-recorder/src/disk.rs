@@ -0,0 +1,133 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::{io, path::Path};
+use serde::Deserialize;
+
+/// Free space of the file system containing the path
+pub trait SpaceProvider {
+    fn available(&self, path: &Path) -> io::Result<u64>;
+}
+
+pub struct FileSystem;
+
+impl SpaceProvider for FileSystem {
+    fn available(&self, path: &Path) -> io::Result<u64> {
+        fs2::available_space(path)
+    }
+}
+
+#[derive(Clone, Deserialize)]
+pub struct DiskGuardConfig {
+    // megabytes
+    pub min_free: u64,
+    // the fraction of the oldest messages and logs to remove when the space is low
+    pub prune: Option<f64>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Transition {
+    Low { available: u64 },
+    Recovered { available: u64 },
+}
+
+/// Tracks the free space of the database, the space is low when it drops below
+/// the threshold and recovered when it is 10% above, so the state does not flap
+pub struct DiskGuard<P> {
+    config: DiskGuardConfig,
+    provider: P,
+    low: bool,
+}
+
+impl<P> DiskGuard<P>
+where
+    P: SpaceProvider,
+{
+    pub fn new(config: DiskGuardConfig, provider: P) -> Self {
+        DiskGuard {
+            config,
+            provider,
+            low: false,
+        }
+    }
+
+    pub fn prune(&self) -> Option<f64> {
+        self.config.prune
+    }
+
+    /// Return the transition if the state changed since the last check
+    pub fn check(&mut self, path: &Path) -> Option<Transition> {
+        let available = match self.provider.available(path) {
+            Ok(available) => available,
+            Err(error) => {
+                log::warn!("cannot get free space of {}: {}", path.display(), error);
+                return None;
+            },
+        };
+        let threshold = self.config.min_free.saturating_mul(1 << 20);
+        if !self.low && available < threshold {
+            self.low = true;
+            Some(Transition::Low { available })
+        } else if self.low && available >= threshold.saturating_add(threshold / 10) {
+            self.low = false;
+            Some(Transition::Recovered { available })
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, io, path::Path};
+    use super::{DiskGuard, DiskGuardConfig, SpaceProvider, Transition};
+
+    struct Fake(Cell<Option<u64>>);
+
+    impl SpaceProvider for &Fake {
+        fn available(&self, path: &Path) -> io::Result<u64> {
+            let _ = path;
+            self.0
+                .get()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "unavailable"))
+        }
+    }
+
+    #[test]
+    fn low_space() {
+        const MB: u64 = 1 << 20;
+
+        let space = Fake(Cell::new(Some(500 * MB)));
+        let config = DiskGuardConfig {
+            min_free: 100,
+            prune: None,
+        };
+        let mut guard = DiskGuard::new(config, &space);
+        let path = Path::new("/volume/debugger_db");
+
+        assert_eq!(guard.check(path), None);
+        space.0.set(Some(99 * MB));
+        assert_eq!(
+            guard.check(path),
+            Some(Transition::Low {
+                available: 99 * MB
+            }),
+        );
+        // reported once
+        assert_eq!(guard.check(path), None);
+        // the provider failed, the state is kept
+        space.0.set(None);
+        assert_eq!(guard.check(path), None);
+        // above the threshold, but not enough to resume
+        space.0.set(Some(105 * MB));
+        assert_eq!(guard.check(path), None);
+        space.0.set(Some(110 * MB));
+        assert_eq!(
+            guard.check(path),
+            Some(Transition::Recovered {
+                available: 110 * MB
+            }),
+        );
+        assert_eq!(guard.check(path), None);
+    }
+}
@@ -0,0 +1,202 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::{
+    net::IpAddr,
+    path::Path,
+    sync::{
+        Arc,
+        atomic::{Ordering, AtomicBool},
+        mpsc::{self, RecvTimeoutError},
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+use maxminddb::{geoip2, MaxMindDBError, Reader};
+use super::{database::Database, tables::{connection, connection_geo}};
+
+/// Resolves the country and the autonomous system of the address
+pub trait GeoIp: Send + Sync {
+    /// `None` if the address is not known, like a private one
+    fn lookup(&self, ip: IpAddr) -> Option<connection_geo::Value>;
+}
+
+/// The MaxMind databases, like `GeoLite2-Country.mmdb` and `GeoLite2-ASN.mmdb`,
+/// the first database which knows the country or the asn of the address is taken
+pub struct MaxMind {
+    readers: Vec<Reader<Vec<u8>>>,
+}
+
+impl MaxMind {
+    pub fn open<P>(paths: &[P]) -> Result<Self, MaxMindDBError>
+    where
+        P: AsRef<Path>,
+    {
+        let readers = paths
+            .iter()
+            .map(Reader::open_readfile)
+            .collect::<Result<_, _>>()?;
+        Ok(MaxMind { readers })
+    }
+}
+
+impl GeoIp for MaxMind {
+    fn lookup(&self, ip: IpAddr) -> Option<connection_geo::Value> {
+        let mut value = connection_geo::Value::default();
+        for reader in &self.readers {
+            if value.country.is_none() {
+                value.country = reader
+                    .lookup::<geoip2::Country>(ip)
+                    .ok()
+                    .and_then(|c| c.country)
+                    .and_then(|c| c.iso_code)
+                    .map(str::to_string);
+            }
+            if value.asn.is_none() {
+                value.asn = reader
+                    .lookup::<geoip2::Asn>(ip)
+                    .ok()
+                    .and_then(|a| a.autonomous_system_number);
+            }
+        }
+        if value.is_empty() {
+            None
+        } else {
+            Some(value)
+        }
+    }
+}
+
+/// Resolves the addresses of the connections on its own thread, the capture only queues them,
+/// the result is stored apart from the connection, see `Database::store_connection_geo`
+#[derive(Clone)]
+pub struct Enricher {
+    tx: mpsc::Sender<(connection::Key, IpAddr)>,
+}
+
+impl Enricher {
+    pub fn spawn<Db>(
+        geoip: Arc<dyn GeoIp>,
+        db: Arc<Db>,
+        running: Arc<AtomicBool>,
+    ) -> (Self, JoinHandle<()>)
+    where
+        Db: Database + Sync + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel::<(connection::Key, IpAddr)>();
+        let handle = thread::spawn(move || {
+            // wake up from time to time to see if the recorder stops
+            while running.load(Ordering::Relaxed) {
+                let (cn_id, ip) = match rx.recv_timeout(Duration::from_millis(100)) {
+                    Ok(v) => v,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                };
+                if let Some(value) = geoip.lookup(ip) {
+                    db.store_connection_geo(cn_id, value);
+                }
+            }
+        });
+        (Enricher { tx }, handle)
+    }
+
+    pub fn enqueue(&self, cn_id: connection::Key, ip: IpAddr) {
+        // the thread is gone only when the recorder stops
+        let _ = self.tx.send((cn_id, ip));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::IpAddr,
+        sync::{
+            Arc,
+            atomic::{Ordering, AtomicBool},
+        },
+        thread,
+        time::{Duration, SystemTime},
+    };
+    use crate::{
+        common::Initiator,
+        database::{temp::TempDb, Database, DatabaseFetch, ConnectionsFilter},
+        tables::{connection, connection_geo},
+    };
+    use super::{GeoIp, Enricher};
+
+    struct Stub;
+
+    impl GeoIp for Stub {
+        fn lookup(&self, ip: IpAddr) -> Option<connection_geo::Value> {
+            match ip {
+                IpAddr::V4(ip) if ip.octets()[0] == 51 => Some(connection_geo::Value {
+                    country: Some("FR".to_string()),
+                    asn: Some(12876),
+                }),
+                IpAddr::V4(ip) if ip.octets()[0] == 95 => Some(connection_geo::Value {
+                    country: Some("DE".to_string()),
+                    asn: Some(24940),
+                }),
+                _ => None,
+            }
+        }
+    }
+
+    #[test]
+    fn enrich() {
+        let path = TempDb::new("geoip");
+        let db = Arc::new(path.open());
+        let running = Arc::new(AtomicBool::new(true));
+        let (enricher, handle) = Enricher::spawn(Arc::new(Stub), db.clone(), running.clone());
+
+        let addrs = [[51, 15, 220, 7], [95, 217, 1, 2], [10, 0, 0, 1]];
+        for (i, ip) in addrs.iter().enumerate() {
+            let mut item = connection::Item::new(
+                Initiator::new(true),
+                (*ip, 9732).into(),
+                SystemTime::now(),
+            );
+            item.ts_nanos = i as u32;
+            let (key, ip) = (item.key(), item.remote_addr.ip());
+            db.store_connection(item);
+            enricher.enqueue(key, ip);
+        }
+
+        let filter = |country: Option<&str>, asn: Option<u32>| ConnectionsFilter {
+            country: country.map(str::to_string),
+            asn,
+            ..Default::default()
+        };
+        // resolved on the thread of the enricher
+        for _ in 0..100 {
+            if db.fetch_connections(&filter(None, Some(24940))).unwrap().len() == 1 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let connections = db.fetch_connections(&filter(None, None)).unwrap();
+        assert_eq!(connections.len(), 3);
+        let geo = connections
+            .iter()
+            .map(|(_, value)| value.geo().clone())
+            .collect::<Vec<_>>();
+        assert_eq!(geo[0].country.as_deref(), Some("FR"));
+        assert_eq!(geo[0].asn, Some(12876));
+        assert_eq!(geo[1].country.as_deref(), Some("DE"));
+        // the private address is not annotated
+        assert!(geo[2].is_empty());
+        let json = serde_json::to_value(&connections[1].1).unwrap();
+        assert_eq!(json["country"], "DE");
+        assert_eq!(json["asn"], 24940);
+
+        let by_country = db.fetch_connections(&filter(Some("fr"), None)).unwrap();
+        assert_eq!(by_country.len(), 1);
+        assert_eq!(by_country[0].1.geo().asn, Some(12876));
+        assert_eq!(db.fetch_connections(&filter(Some("DE"), Some(24940))).unwrap().len(), 1);
+        assert!(db.fetch_connections(&filter(Some("DE"), Some(12876))).unwrap().is_empty());
+
+        running.store(false, Ordering::Relaxed);
+        handle.join().unwrap();
+    }
+}
@@ -1,7 +1,8 @@
 // Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
 // SPDX-License-Identifier: MIT
 
-#![forbid(unsafe_code)]
+// the tests emulate the bpf module with raw file descriptors
+#![cfg_attr(not(test), forbid(unsafe_code))]
 
 pub use crypto;
 pub use tezos_messages;
@@ -14,5 +15,12 @@ mod processor;
 pub mod main_loop;
 pub mod database;
 mod server;
+mod limit;
+mod cidr;
+mod disk;
+mod node_port;
+mod proc_net;
+mod geoip;
+pub mod telemetry;
 
-pub use self::system::System;
+pub use self::system::{System, check_config, ConfigReport, ConfigProblem};
@@ -0,0 +1,90 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::sync::{
+    Arc,
+    atomic::{Ordering, AtomicUsize},
+};
+use warp::{
+    Filter, Rejection,
+    reject::{self, Reject},
+    reply::{self, WithHeader, WithStatus, Json},
+    http::StatusCode,
+};
+
+/// The client should retry the rejected request after this many seconds
+pub const RETRY_AFTER_SECONDS: u64 = 1;
+
+/// Counts the expensive reads served at once by all http servers. The capture writes
+/// to the database on its own threads and is never limited, so the rejected reads
+/// leave the database to the capture.
+pub struct Limiter {
+    max: usize,
+    in_flight: AtomicUsize,
+}
+
+/// The read is in flight until the permit is dropped
+pub struct Permit(Option<Arc<Limiter>>);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some(limiter) = &self.0 {
+            limiter.in_flight.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+impl Limiter {
+    pub fn new(max: usize) -> Self {
+        Limiter {
+            max,
+            in_flight: AtomicUsize::new(0),
+        }
+    }
+
+    /// `None` if there are `max` reads in flight already
+    pub fn try_acquire(self: &Arc<Self>) -> Option<Permit> {
+        let max = self.max;
+        self.in_flight
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                if n < max {
+                    Some(n + 1)
+                } else {
+                    None
+                }
+            })
+            .ok()
+            .map(|_| Permit(Some(self.clone())))
+    }
+}
+
+#[derive(Debug)]
+struct Overloaded;
+
+impl Reject for Overloaded {}
+
+/// Takes the permit for the request, without the limiter passes any request
+pub fn permit(
+    limiter: Option<Arc<Limiter>>,
+) -> impl Filter<Extract = (Permit,), Error = Rejection> + Clone + Sync + Send + 'static {
+    warp::any().and_then(move || {
+        let limiter = limiter.clone();
+        async move {
+            match limiter {
+                None => Ok(Permit(None)),
+                Some(limiter) => limiter.try_acquire().ok_or_else(|| reject::custom(Overloaded)),
+            }
+        }
+    })
+}
+
+/// 503 for the request rejected by `permit`, other rejections are handled as usual
+pub async fn recover(rejection: Rejection) -> Result<WithHeader<WithStatus<Json>>, Rejection> {
+    if rejection.find::<Overloaded>().is_some() {
+        let r = "too many concurrent requests, retry later";
+        let reply = reply::with_status(reply::json(&r), StatusCode::SERVICE_UNAVAILABLE);
+        Ok(reply::with_header(reply, "Retry-After", RETRY_AFTER_SECONDS.to_string()))
+    } else {
+        Err(rejection)
+    }
+}
@@ -12,7 +12,10 @@ use std::{
 };
 use super::{database::Database, tables::node_log};
 
+/// Receive the syslog of the node at its own port, each node has its own socket
+/// and its own database, so the log is attributed to the node by the port
 pub fn spawn<Db>(
+    name: String,
     port: u16,
     db: Arc<Db>,
     running: Arc<AtomicBool>,
@@ -20,9 +23,13 @@ pub fn spawn<Db>(
 where
     Db: Database + Sync + Send + 'static,
 {
-    let socket = UdpSocket::bind(("0.0.0.0", port))?;
+    let socket = UdpSocket::bind(("0.0.0.0", port)).map_err(|error| {
+        let msg = format!("node: {}, cannot bind syslog port {}: {}", name, port, error);
+        io::Error::new(error.kind(), msg)
+    })?;
     socket.set_read_timeout(Some(Duration::from_secs(5)))?;
-    Ok(thread::spawn(move || {
+    log::info!("node: {}, receiving syslog at port {}", name, port);
+    thread::Builder::new().name(format!("syslog-{}", name)).spawn(move || {
         let mut buffer = [0u8; 0x10000];
         while running.load(Ordering::Relaxed) {
             match socket.recv(&mut buffer) {
@@ -35,12 +42,92 @@ where
                 },
                 Err(error) => {
                     if error.kind() == io::ErrorKind::WouldBlock {
-                        log::trace!("receiving log timeout");
+                        log::trace!("node: {}, receiving log timeout", name);
                     } else {
-                        log::error!("receiving log error: {}", error)
+                        log::error!("node: {}, receiving log error: {}", name, error)
                     }
                 },
             }
         }
-    }))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env, fs, thread,
+        net::UdpSocket,
+        sync::{
+            Arc,
+            atomic::{AtomicBool, Ordering},
+        },
+        time::Duration,
+    };
+    use crate::database::{rocks::Db, DatabaseNew, DatabaseFetch, LogsFilter};
+    use super::spawn;
+
+    fn free_port() -> u16 {
+        UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    #[test]
+    fn two_nodes() {
+        let dir = env::temp_dir().join(format!("tezedge-recorder-syslog-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let running = Arc::new(AtomicBool::new(true));
+
+        let nodes = ["tezedge", "ocaml"]
+            .iter()
+            .map(|name| {
+                let path = dir.join(name);
+                let db = Arc::new(Db::open(path, false, None, None, Default::default()).unwrap());
+                let port = free_port();
+                let handle = spawn(name.to_string(), port, db.clone(), running.clone()).unwrap();
+                (*name, port, db, handle)
+            })
+            .collect::<Vec<_>>();
+
+        // the port is already taken by the first node
+        let (_, port, db, _) = &nodes[0];
+        let error = spawn("copy".to_string(), *port, db.clone(), running.clone()).unwrap_err();
+        assert!(error.to_string().starts_with("node: copy, cannot bind syslog port"));
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        for (name, port, ..) in &nodes {
+            let msg = format!(
+                "<27>1 2021-07-14T12:00:00+00:00 host app 665 app - \
+                 Jul 14 12:00:00.000 INFO log of {}",
+                name,
+            );
+            socket.send_to(msg.as_bytes(), ("127.0.0.1", *port)).unwrap();
+        }
+        thread::sleep(Duration::from_millis(500));
+        running.store(false, Ordering::Relaxed);
+
+        let filter = LogsFilter {
+            direction: None,
+            limit: None,
+            cursor: None,
+            log_level: None,
+            from: None,
+            to: None,
+            timestamp: None,
+            query: None,
+            session: None,
+            node_name: None,
+        };
+        for (name, _, db, handle) in nodes {
+            // each node receives only its own log
+            let logs = db.fetch_log(&filter).unwrap();
+            assert_eq!(logs.len(), 1, "{}", name);
+            assert!(logs[0].message.ends_with(&format!("log of {}", name)), "{}", name);
+            handle.join().unwrap();
+        }
+
+        let _ = fs::remove_dir_all(&dir);
+    }
 }
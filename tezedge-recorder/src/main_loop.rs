@@ -3,124 +3,691 @@
 
 use std::{
     collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{self, BufReader, BufWriter},
     net::SocketAddr,
+    path::Path,
+    process::Child,
+    thread,
+    time::{Duration, Instant},
     sync::{
         Arc,
         atomic::{Ordering, AtomicBool},
     },
 };
 use anyhow::Result;
-use bpf_recorder::{BpfModuleClient, SnifferEvent, Command, EventId, SocketId};
+use bpf_recorder::{
+    BpfModuleClient, SnifferEvent, Command, EventId, SocketId, RawEvent, EventsFileWriter,
+    EventsFileReader, PeerAddress, ContextStats,
+};
+use bpf_ring_buffer::{RingBufferSync, RingBufferData};
 
 use super::{
-    processor::Connection,
-    database::{Database, DatabaseNew, DatabaseFetch},
-    system::System,
+    processor::{Connection, Candidate},
+    database::{Database, DatabaseNew, DatabaseFetch, clock::{Clock, SystemClock}},
+    system::{System, ConnectionStats},
+    tables::{
+        connection::{self, CloseReason},
+        chunk_event,
+    },
+    common::Initiator,
+    proc_net::{self, Listener, CmdlineWatcher},
 };
 
-pub fn run<Db>(system: &mut System<Db>, running: Arc<AtomicBool>) -> Result<()>
-where
-    Db: Database + DatabaseNew + DatabaseFetch + Sync + Send + 'static,
-{
-    let (client, mut rb) = BpfModuleClient::new_sync(system.sniffer_path())?;
-    let mut list = ConnectionList::new(client, system);
-    list.watching()?;
+/// The longest the main loop waits for the events, so the periodic work, like finalizing
+/// the idle connections or rescanning the processes, is done while no event arrives
+const TICK: Duration = Duration::from_millis(200);
 
-    while running.load(Ordering::Relaxed) {
-        let events = rb.read_blocking::<SnifferEvent>(&running)?;
-        for event in events {
-            match event {
-                SnifferEvent::Bind { id, address } => {
-                    // TODO: remove old connections on this port
-                    if let Err(error) = list.system.handle_bind(id.socket_id.pid, address.port()) {
-                        log::error!("failed to handle bind syscall: {}", error);
-                    }
-                },
-                SnifferEvent::Listen { id } => {
-                    let _ = id;
-                },
-                SnifferEvent::Connect { id, address } => {
-                    list.handle_connection(id, address, false);
-                },
-                SnifferEvent::Accept {
-                    id,
-                    address,
-                    listen_on_fd,
-                } => {
-                    let _ = listen_on_fd;
-                    list.handle_connection(id, address, true);
-                },
-                SnifferEvent::Data {
-                    id,
-                    data,
-                    net,
-                    incoming,
-                } => {
-                    if !data.is_empty() {
-                        list.handle_data(id, data, net, incoming);
-                    }
-                },
-                SnifferEvent::Close { id } => {
-                    list.handle_close(id);
-                },
-                SnifferEvent::GetFd { id } => {
-                    list.handle_get_fd(id);
-                },
-                SnifferEvent::Debug { id, msg } => {
-                    log::warn!("{} {}", id, msg);
-                },
+/// Where the events come from
+enum Source {
+    Live(RingBufferSync),
+    // the live stream, also written to the file
+    Dump(RingBufferSync, EventsFileWriter<BufWriter<File>>),
+    // if the pacer is set, an event at a time, at the pace it was recorded
+    File(EventsFileReader<BufReader<File>>, Option<Pacer>),
+}
+
+/// Reproduces the intervals between the recorded events, divided by `speed`
+struct Pacer {
+    speed: f64,
+    // the timestamp of the first event, nanoseconds, and when it was replayed
+    start: Option<(u64, Instant)>,
+}
+
+impl Pacer {
+    fn new(speed: f64) -> Self {
+        Pacer { speed, start: None }
+    }
+
+    /// Sleep until the event at `ts` is due, the event out of order is due at once
+    fn wait(&mut self, ts: u64, running: &AtomicBool) {
+        let (first, started) = *self.start.get_or_insert((ts, Instant::now()));
+        let offset = ts.saturating_sub(first) as f64 / self.speed;
+        let due = started + Duration::from_nanos(offset as u64);
+        while running.load(Ordering::Relaxed) {
+            let now = Instant::now();
+            if now >= due {
+                break;
             }
+            // wake up from time to time to see if the recorder stops
+            thread::sleep((due - now).min(Duration::from_millis(100)));
         }
     }
+}
+
+impl Source {
+    /// The events come from the bpf module, it might hang up
+    fn is_live(&self) -> bool {
+        !matches!(self, Source::File(..))
+    }
 
-    Ok(())
+    /// `None` when the recorded stream is over, the live stream gives the empty batch
+    /// if nothing arrived for the `TICK`
+    fn read(&mut self, running: &AtomicBool) -> io::Result<Option<Vec<SnifferEvent>>> {
+        match self {
+            Source::Live(rb) => {
+                let events = rb.read_timeout::<SnifferEvent>(TICK)?;
+                Ok(Some(events.into_iter().collect()))
+            },
+            Source::Dump(rb, writer) => {
+                let raw = rb.read_timeout::<RawEvent>(TICK)?;
+                if raw.is_empty() {
+                    return Ok(Some(Vec::new()));
+                }
+                let mut events = Vec::with_capacity(raw.len());
+                for event in raw {
+                    writer.write(&event)?;
+                    match SnifferEvent::from_rb_slice(&event.0) {
+                        Ok(event) => events.push(event),
+                        Err(error) => log::error!("rb parse data: {:?}", error),
+                    }
+                }
+                writer.flush()?;
+                Ok(Some(events))
+            },
+            Source::File(reader, pacer) => {
+                let limit = if pacer.is_some() { 1 } else { 64 };
+                let events = reader.read::<SnifferEvent>(limit)?;
+                if let (Some(pacer), Some(event)) = (pacer, events.first()) {
+                    pacer.wait(event.id().ts_finish(), running);
+                }
+                if events.is_empty() {
+                    Ok(None)
+                } else {
+                    Ok(Some(events))
+                }
+            },
+        }
+    }
+}
+
+/// Why the capture stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    /// The recorder stops, or the recorded stream is over
+    Shutdown,
+    /// The bpf module exited or hung up, the capture resumes when it is respawned
+    Hangup,
+}
+
+/// The bpf module exited, its end of the command socket is gone
+fn is_hangup(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::BrokenPipe | io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset
+    )
+}
+
+/// Intercept the live stream of events from the bpf module,
+/// if `dump_events` is set, append the stream to the file,
+/// if the recorder spawned the bpf module, `bpf` is its process, its exit is the hangup
+pub fn run<Db, P>(
+    system: &mut System<Db>,
+    running: Arc<AtomicBool>,
+    dump_events: Option<P>,
+    bpf: Option<&mut Child>,
+) -> Result<Stop>
+where
+    Db: Database + DatabaseNew + DatabaseFetch + Sync + Send + 'static,
+    P: AsRef<Path>,
+{
+    let (client, rb) = BpfModuleClient::new_sync(system.sniffer_path())?;
+    let source = match dump_events {
+        Some(path) => {
+            // the stream continues in the same file after the bpf module is respawned
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            let writer = if file.metadata()?.len() == 0 {
+                EventsFileWriter::new(BufWriter::new(file))?
+            } else {
+                EventsFileWriter::append(BufWriter::new(file))
+            };
+            Source::Dump(rb, writer)
+        },
+        None => Source::Live(rb),
+    };
+    let mut list = ConnectionList::new(Some(client), system);
+    list.watching()?;
+    list.attach_running();
+    list.scan_cmdline();
+    list.run(source, running, bpf)
+}
+
+/// Replay the events recorded by `--dump-events`, no bpf module needed,
+/// if `speed` is set, at the pace they were recorded, `2.0` is twice as fast
+pub fn run_file<Db, P>(
+    system: &mut System<Db>,
+    running: Arc<AtomicBool>,
+    path: P,
+    speed: Option<f64>,
+) -> Result<()>
+where
+    Db: Database + DatabaseNew + DatabaseFetch + Sync + Send + 'static,
+    P: AsRef<Path>,
+{
+    let reader = EventsFileReader::new(BufReader::new(File::open(path)?))?;
+    let list = ConnectionList::new(None, system);
+    list.run(Source::File(reader, speed.map(Pacer::new)), running, None)
+        .map(|_| ())
 }
 
 struct ConnectionList<'a, Db> {
-    client: BpfModuleClient,
+    client: Option<BpfModuleClient>,
     system: &'a mut System<Db>,
     connections: HashMap<SocketId, Connection<Db>>,
+    // the listening sockets of the nodes and their ports
+    listeners: HashMap<SocketId, u16>,
+    max_connections: Option<usize>,
+    // the connections not recorded because of `max_connections`
+    rejected: u64,
+    saturated: bool,
+    // the processes matching `watch_cmdline`, rescanned at the interval
+    cmdline: Option<(CmdlineWatcher, Duration)>,
+    // the intervals of the periodic work
+    clock: Arc<dyn Clock>,
 }
 
 impl<'a, Db> ConnectionList<'a, Db>
 where
     Db: Database + DatabaseNew + DatabaseFetch + Sync + Send + 'static,
 {
-    fn new(client: BpfModuleClient, system: &'a mut System<Db>) -> Self {
+    fn run(
+        mut self,
+        mut source: Source,
+        running: Arc<AtomicBool>,
+        mut bpf: Option<&mut Child>,
+    ) -> Result<Stop> {
+        let clock = self.clock.clone();
+        let elapsed = |since: Instant| clock.instant().saturating_duration_since(since);
+        let mut last_check = clock.instant();
+        let mut last_stats = clock.instant();
+        let mut last_expire = clock.instant();
+        let mut last_scan = clock.instant();
+        let mut stats = ContextStats::default();
+        let mut evicted = 0;
+        let mut stop = Stop::Shutdown;
+        while running.load(Ordering::Relaxed) {
+            let events = match source.read(&running) {
+                Ok(Some(events)) => events,
+                Ok(None) => break,
+                Err(error) if source.is_live() && is_hangup(&error) => {
+                    log::error!("bpf module hung up the ring buffer: {}", error);
+                    stop = Stop::Hangup;
+                    break;
+                },
+                Err(error) => return Err(error.into()),
+            };
+            for event in events {
+                self.handle_event(event);
+            }
+            self.finalize_requested();
+            if elapsed(last_expire) > Duration::from_secs(1) {
+                last_expire = clock.instant();
+                self.expire();
+                self.expire_idle();
+                self.check_limit();
+                self.publish_active();
+                // the map of the ring buffer outlives the bpf module, its fd never hangs up
+                if let Some(bpf) = &mut bpf {
+                    match bpf.try_wait() {
+                        Ok(Some(status)) => {
+                            log::error!("bpf module exited: {}", status);
+                            stop = Stop::Hangup;
+                            break;
+                        },
+                        Ok(None) => (),
+                        Err(error) => log::error!("cannot check bpf module: {}", error),
+                    }
+                }
+            }
+            let scan_interval = self.cmdline.as_ref().map(|(_, interval)| *interval);
+            if scan_interval.map_or(false, |interval| elapsed(last_scan) > interval) {
+                last_scan = clock.instant();
+                self.scan_cmdline();
+            }
+            if elapsed(last_stats) > Duration::from_secs(5) {
+                last_stats = clock.instant();
+                if let Some(client) = &mut self.client {
+                    match client.fetch_context_stats() {
+                        Ok(v) => {
+                            stats = v;
+                            self.system.set_context_stats(stats);
+                        },
+                        Err(error) if is_hangup(&error) => {
+                            log::error!("bpf module hung up the command socket: {}", error);
+                            stop = Stop::Hangup;
+                            break;
+                        },
+                        Err(error) => log::error!("failed to fetch context stats: {}", error),
+                    }
+                }
+            }
+            if elapsed(last_check) > Duration::from_secs(60) {
+                last_check = clock.instant();
+                if stats.evicted != evicted {
+                    log::warn!(
+                        "bpf module evicted {} syscall contexts, some syscall exits were missed",
+                        stats.evicted.wrapping_sub(evicted),
+                    );
+                    evicted = stats.evicted;
+                }
+            }
+        }
+        // the respawned bpf module does not know the open sockets, close them either way
+        self.close_all(CloseReason::RecorderShutdown);
+        self.publish_active();
+
+        Ok(stop)
+    }
+
+    /// Drop the connections which failed the required handshake, or did not complete it in time
+    fn expire(&mut self) {
+        let expired = self
+            .connections
+            .iter()
+            .filter(|(_, connection)| connection.is_expired())
+            .map(|(socket_id, _)| *socket_id)
+            .collect::<Vec<_>>();
+        for socket_id in expired {
+            if let Some(connection) = self.connections.remove(&socket_id) {
+                connection.join();
+            }
+            self.ignore(socket_id);
+        }
+    }
+
+    /// Finalize the connections which got no data for `idle_timeout`, store what they hold
+    fn expire_idle(&mut self) {
+        let idle = self
+            .connections
+            .iter()
+            .filter(|(_, connection)| connection.is_idle())
+            .map(|(socket_id, _)| *socket_id)
+            .collect::<Vec<_>>();
+        for socket_id in idle {
+            if let Some(mut connection) = self.connections.remove(&socket_id) {
+                log::info!("connection: {} is idle, finalize it", connection.key());
+                connection.set_close_reason(CloseReason::Timeout);
+                connection.finalize();
+                connection.join();
+            }
+            self.ignore(socket_id);
+        }
+    }
+
+    /// Finalize the connections requested by `/v3/connection/{id}/finalize`,
+    /// the socket might still be open, the bpf module stops reporting its data
+    fn finalize_requested(&mut self) {
+        for cn_id in self.system.take_finalize_requests() {
+            let socket_id = self
+                .connections
+                .iter()
+                .find(|(_, connection)| connection.key() == cn_id)
+                .map(|(socket_id, _)| *socket_id);
+            let socket_id = match socket_id {
+                Some(socket_id) => socket_id,
+                None => {
+                    log::debug!("cannot finalize connection: {}, it is not open", cn_id);
+                    continue;
+                },
+            };
+            if let Some(mut connection) = self.connections.remove(&socket_id) {
+                connection.finalize();
+                connection.join();
+            }
+            self.ignore(socket_id);
+        }
+    }
+
+    /// The connections closed, so the new ones are recorded again, report the stats
+    fn check_limit(&mut self) {
+        let below = self
+            .max_connections
+            .map_or(true, |max| self.connections.len() < max);
+        if self.saturated && below {
+            log::info!("tracking {} connections, recording new ones", self.connections.len());
+            self.saturated = false;
+        }
+        self.system.set_connection_stats(ConnectionStats {
+            tracked: self.connections.len(),
+            max: self.max_connections,
+            rejected: self.rejected,
+            saturated: self.saturated,
+        });
+    }
+
+    /// Share the live counters of the open connections with the server
+    fn publish_active(&mut self) {
+        let mut active = self
+            .connections
+            .values()
+            .map(Connection::active)
+            .collect::<Vec<_>>();
+        active.sort_by_key(|a| (a.id.ts, a.id.ts_nanos));
+        self.system.set_active_connections(active);
+    }
+
+    /// Too many connections are tracked, the new one is not recorded
+    fn reject(&mut self) {
+        self.rejected += 1;
+        if !self.saturated {
+            log::warn!(
+                "tracking {} connections, the limit, new connections are not recorded",
+                self.connections.len(),
+            );
+            self.saturated = true;
+        }
+    }
+
+    fn handle_event(&mut self, event: SnifferEvent) {
+        match event {
+            SnifferEvent::Bind { id, address } => {
+                let port = address.port();
+                if self.system.detect_port(port) {
+                    self.watch_detected(port);
+                }
+                // reported while detecting the port of some node, but not a node
+                if !self.system.is_p2p_port(port) {
+                    return;
+                }
+                // TODO: remove old connections on this port
+                if let Err(error) = self.system.handle_bind(id.socket_id.pid, port) {
+                    log::error!("failed to handle bind syscall: {}", error);
+                }
+                self.listeners.insert(id.socket_id, port);
+            },
+            SnifferEvent::Listen { id } => {
+                let _ = id;
+            },
+            SnifferEvent::Connect { id, address } => {
+                self.handle_connection(id, address, false, None);
+            },
+            SnifferEvent::ConnectFailed { id, address, code } => {
+                self.handle_connect_failed(id, address, code);
+            },
+            SnifferEvent::Accept {
+                id,
+                address,
+                listen_on_fd,
+            } => {
+                let listener = SocketId {
+                    pid: id.socket_id.pid,
+                    fd: listen_on_fd,
+                };
+                let listen_port = self.listeners.get(&listener).cloned();
+                self.handle_connection(id, address, true, listen_port);
+            },
+            SnifferEvent::Data {
+                id,
+                data,
+                net,
+                incoming,
+            } => {
+                if !data.is_empty() {
+                    self.handle_data(id, data, net, incoming);
+                } else if incoming {
+                    // end of stream
+                    self.set_close_reason(id, CloseReason::PeerClose);
+                }
+            },
+            SnifferEvent::Shutdown { id } => {
+                self.set_close_reason(id, CloseReason::Shutdown);
+            },
+            SnifferEvent::Error { id, code } => {
+                self.set_close_reason(id, CloseReason::from_error_code(code));
+            },
+            SnifferEvent::Close { id } => {
+                self.set_close_reason(id.clone(), CloseReason::Close);
+                self.handle_close(id);
+            },
+            SnifferEvent::GetFd { id } => {
+                self.handle_get_fd(id);
+            },
+            SnifferEvent::Debug { id, msg } => {
+                log::warn!("{} {}", id, msg);
+            },
+        }
+    }
+
+    fn new(client: Option<BpfModuleClient>, system: &'a mut System<Db>) -> Self {
+        let max_connections = system.max_connections();
+        // only the live capture can watch new ports
+        let cmdline = system
+            .watch_cmdline()
+            .filter(|_| client.is_some())
+            .and_then(|c| Some((CmdlineWatcher::new("/proc", c.regex().ok()?), c.interval())));
         ConnectionList {
             client,
             system,
             connections: HashMap::new(),
+            listeners: HashMap::new(),
+            max_connections,
+            rejected: 0,
+            saturated: false,
+            cmdline,
+            clock: Arc::new(SystemClock),
         }
     }
 
+    /// The test drives the periodic work by the mock clock
+    #[cfg(test)]
+    fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
     fn watching(&mut self) -> Result<()> {
-        for p2p_config in self.system.p2p_configs() {
-            self.client.send_command(Command::WatchPort {
-                port: p2p_config.port,
-            })?;
+        if let Some(client) = &mut self.client {
+            for port in self.system.p2p_configs().filter_map(|c| c.port) {
+                client.send_command(Command::WatchPort { port })?;
+            }
+            if self.system.detecting_port() {
+                // any port, until the ports of all nodes are detected
+                client.send_command(Command::WatchPort { port: 0 })?;
+            }
         }
 
         Ok(())
     }
 
-    fn handle_connection(&mut self, event_id: EventId, address: SocketAddr, incoming: bool) {
+    /// The node started before the recorder, so its bind was never reported,
+    /// attach to its listening sockets found in `/proc`
+    fn attach_running(&mut self) {
+        let ports = self
+            .system
+            .p2p_configs()
+            .filter_map(|c| c.port)
+            .collect::<Vec<_>>();
+        self.attach(proc_net::scan("/proc", &ports));
+    }
+
+    /// Watch the listening ports of the processes matching `watch_cmdline`,
+    /// the port might become the port of the node whose port is not known,
+    /// attach to the processes listening on the ports of the nodes
+    fn scan_cmdline(&mut self) {
+        let refresh = match &mut self.cmdline {
+            Some((watcher, _)) => watcher.refresh(),
+            None => return,
+        };
+        for port in refresh.watch {
+            if self.system.detect_port(port) {
+                self.watch_detected(port);
+            } else if let Some(client) = &mut self.client {
+                log::info!("watching port: {} of the matching process", port);
+                if let Err(error) = client.send_command(Command::WatchPort { port }) {
+                    log::error!("cannot watch port: {}, error: {}", port, error);
+                }
+            }
+        }
+        for port in refresh.unwatch {
+            // the node keeps its port, the restarted node listens there again
+            if self.system.is_p2p_port(port) {
+                continue;
+            }
+            if let Some(client) = &mut self.client {
+                if let Err(error) = client.send_command(Command::UnwatchPort { port }) {
+                    log::error!("cannot unwatch port: {}, error: {}", port, error);
+                }
+            }
+        }
+        let listeners = refresh
+            .listeners
+            .into_iter()
+            .filter(|l| self.system.is_p2p_port(l.port))
+            .collect();
+        self.attach(listeners);
+    }
+
+    fn attach(&mut self, listeners: Vec<Listener>) {
+        for Listener { pid, fd, port } in listeners {
+            if self.listeners.contains_key(&SocketId { pid, fd }) {
+                continue;
+            }
+            // the node might listen on both ipv4 and ipv6 sockets
+            let attached = self.listeners.iter().any(|(id, p)| id.pid == pid && *p == port);
+            if !attached {
+                if let Err(error) = self.system.handle_bind(pid, port) {
+                    log::error!("failed to attach to running node: {}", error);
+                    continue;
+                }
+            }
+            self.listeners.insert(SocketId { pid, fd }, port);
+            if let Some(client) = &mut self.client {
+                if let Err(error) = client.send_command(Command::WatchProcess { pid, port }) {
+                    log::error!("cannot watch process: {}, error: {}", pid, error);
+                }
+            }
+        }
+    }
+
+    fn watch_detected(&mut self, port: u16) {
+        let detecting = self.system.detecting_port();
+        if let Some(client) = &mut self.client {
+            let mut commands = vec![Command::WatchPort { port }];
+            if !detecting {
+                commands.push(Command::UnwatchPort { port: 0 });
+            }
+            for command in commands {
+                if let Err(error) = client.send_command(command) {
+                    log::error!("cannot watch detected port: {}, error: {}", port, error);
+                }
+            }
+        }
+    }
+
+    /// `listen_port` is the port of the listening socket which accepted the connection, if known
+    fn handle_connection(
+        &mut self,
+        event_id: EventId,
+        address: PeerAddress,
+        incoming: bool,
+        listen_port: Option<u16>,
+    ) {
         let socket_id = event_id.socket_id;
         let pid = socket_id.pid;
-        let fd = socket_id.fd;
-        if !self.system.should_ignore(&address) {
-            if let Some((info, db)) = self.system.get_mut(pid) {
-                let connection = Connection::new(address, incoming, info.identity(), db);
+        let node_port = self.system.node_port(pid, listen_port);
+        // the unix socket has no ip address, the path identifies the peer
+        let inet = address.inet();
+        let ignore = inet.map_or(false, |inet| self.system.should_ignore(&inet));
+        let full = self
+            .max_connections
+            .map_or(false, |max| self.connections.len() >= max);
+        if !ignore {
+            let node = node_port
+                .and_then(|port| self.system.get_mut(port))
+                // the capture is paused, do not record new connections
+                .filter(|(info, _)| !info.low_disk());
+            if let Some((info, db)) = node {
+                if full {
+                    self.reject();
+                    self.ignore(socket_id);
+                    return;
+                }
+                let pow_target = info.pow_target();
+                // the peers of unix sockets would share the placeholder address
+                let rate_monitor = info.rate_monitor().filter(|_| inet.is_some());
+                let options = info.capture_options();
+                let geoip = info.geoip().filter(|_| inet.is_some());
+                let precomputed_key = inet.and_then(|inet| info.precomputed_key(&inet));
+                let identities = self.system.identities(pid, listen_port);
+                let candidates = self
+                    .system
+                    .candidates(pid, listen_port)
+                    .into_iter()
+                    .map(|c| Candidate {
+                        geoip: c.geoip.filter(|_| inet.is_some()),
+                        ..c
+                    })
+                    .collect();
+                let remote_addr = inet.unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0)));
+                let mut connection =
+                    Connection::new(remote_addr, incoming, identities, pow_target, options, db)
+                        .with_rate_monitor(rate_monitor)
+                        .with_geoip(geoip)
+                        .with_precomputed_key(precomputed_key)
+                        .with_candidates(candidates);
+                if let Some(port) = listen_port {
+                    connection.set_listen_port(port);
+                }
+                if inet.is_none() {
+                    connection.set_unix_path(address.to_string());
+                }
                 if let Some(old) = self.connections.insert(socket_id, connection) {
                     old.join();
                 }
                 return;
             }
         }
-        match self
-            .client
-            .send_command(Command::IgnoreConnection { pid, fd })
-        {
+        self.ignore(socket_id);
+    }
+
+    /// The node could not reach the peer, store the attempt, there is no data to record
+    fn handle_connect_failed(&mut self, event_id: EventId, address: PeerAddress, code: i32) {
+        let inet = match address.inet() {
+            Some(inet) if !self.system.should_ignore(&inet) => inet,
+            _ => return,
+        };
+        let node = self
+            .system
+            .node_port(event_id.socket_id.pid, None)
+            .and_then(|port| self.system.get_mut(port))
+            .filter(|(info, _)| !info.low_disk());
+        if let Some((_, db)) = node {
+            log::info!("connect to {} failed, errno: {}", inet, -code);
+            let mut item = connection::Item::new(Initiator::new(false), inet, db.clock().now());
+            item.set_connect_error(-code);
+            db.store_connection(item);
+        }
+    }
+
+    /// The bpf module stops reporting the data of the socket
+    fn ignore(&mut self, socket_id: SocketId) {
+        let SocketId { pid, fd } = socket_id;
+        // replaying the recorded stream, nothing to ignore
+        let client = match &mut self.client {
+            Some(client) => client,
+            None => return,
+        };
+        match client.send_command(Command::IgnoreConnection { pid, fd }) {
             Ok(()) => (),
             Err(error) => {
                 log::error!(
@@ -137,7 +704,14 @@ where
             log::warn!("received from ring buffer big payload {}", payload.len());
         }
         if let Some(connection) = self.connections.get_mut(&id.socket_id) {
-            connection.handle_data(&payload, net, incoming);
+            let event = chunk_event::Value {
+                pid: id.socket_id.pid,
+                fd: id.socket_id.fd,
+                ts: id.ts_finish(),
+                net,
+                incoming,
+            };
+            connection.handle_data(&payload, net, incoming, Some(event));
         } else {
             log::debug!("failed to handle data, connection does not exist: {}", id);
         }
@@ -151,10 +725,414 @@ where
         }
     }
 
+    fn set_close_reason(&mut self, id: EventId, reason: CloseReason) {
+        if let Some(connection) = self.connections.get_mut(&id.socket_id) {
+            connection.set_close_reason(reason);
+        }
+    }
+
+    fn close_all(&mut self, reason: CloseReason) {
+        for (_, mut connection) in self.connections.drain() {
+            connection.set_close_reason(reason);
+            connection.join();
+        }
+    }
+
     fn handle_close(&mut self, id: EventId) {
         let socket_id = id.socket_id;
+        self.listeners.remove(&socket_id);
         if let Some(old) = self.connections.remove(&socket_id) {
             old.join();
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        process::Command,
+        sync::{Arc, atomic::{Ordering, AtomicBool}},
+        thread,
+        time::{Duration, Instant, SystemTime},
+    };
+    use bpf_recorder::{SnifferEvent, EventId, SocketId, PeerAddress};
+    use bpf_ring_buffer::RingBufferSync;
+    use crate::{
+        database::{mock, rocks, DatabaseNew, DatabaseFetch, ConnectionsFilter, clock::MockClock},
+        processor::fixture::chunk,
+        system::System,
+        tables::connection::{CloseReason, ConnectionStatus},
+    };
+    use super::{ConnectionList, Pacer, Source, Stop};
+
+    #[test]
+    fn pacer() {
+        let running = AtomicBool::new(true);
+        // milliseconds, replayed five times faster
+        let recorded = [1_000, 1_000, 1_100, 1_350, 1_300, 1_400u64];
+        let mut pacer = Pacer::new(5.0);
+        let start = Instant::now();
+        let mut replayed = vec![];
+        for ts in &recorded {
+            pacer.wait(ts * 1_000_000, &running);
+            replayed.push(start.elapsed());
+        }
+        assert!(replayed.windows(2).all(|w| w[0] <= w[1]));
+        // the event out of order is replayed at once
+        for (i, expected) in [0, 0, 20, 70, 70, 80].iter().enumerate() {
+            let expected = Duration::from_millis(*expected);
+            assert!(replayed[i] >= expected, "{}: {:?}", i, replayed[i]);
+            assert!(replayed[i] < expected + Duration::from_millis(50), "{}: {:?}", i, replayed[i]);
+        }
+
+        // stops waiting when the recorder stops
+        running.store(false, Ordering::Relaxed);
+        let start = Instant::now();
+        pacer.wait(100_000 * 1_000_000, &running);
+        assert!(start.elapsed() < Duration::from_millis(50));
+    }
+
+    #[test]
+    fn max_connections() {
+        let config = r#"
+            max_connections = 2
+            [[nodes]]
+            name = "tezedge"
+            db = "target/debugger_db/max-connections"
+            in_memory = true
+            [nodes.p2p]
+            identity = "target/missing-identity.json"
+            port = 29735
+        "#;
+        let mut system = System::<mock::Db>::from_toml(config).unwrap();
+        let running = Arc::new(AtomicBool::new(true));
+        system.run_dbs(running.clone());
+        system.handle_bind(100, 29735).unwrap();
+
+        let id = |fd| EventId::new(SocketId { pid: 100, fd }, 0, 0);
+        let connect = |fd| SnifferEvent::Connect {
+            id: id(fd),
+            address: PeerAddress::Inet(([51, 15, 220, 7], 9732 + fd as u16).into()),
+        };
+        let mut list = ConnectionList::new(None, &mut system);
+        for fd in 1..=3 {
+            list.handle_event(connect(fd));
+        }
+        // the third connection is over the limit
+        assert_eq!(list.connections.len(), 2);
+        assert!(list.connections.contains_key(&SocketId { pid: 100, fd: 2 }));
+        assert_eq!(list.rejected, 1);
+        list.check_limit();
+        assert!(list.saturated);
+
+        // some connection is closed, the new one is recorded
+        list.handle_event(SnifferEvent::Close { id: id(1) });
+        list.check_limit();
+        assert!(!list.saturated);
+        list.handle_event(connect(4));
+        assert_eq!(list.connections.len(), 2);
+        assert_eq!(list.rejected, 1);
+        drop(list);
+
+        running.store(false, Ordering::Relaxed);
+        system.join();
+    }
+
+    #[test]
+    fn idle_timeout() {
+        let config = r#"
+            [[nodes]]
+            name = "tezedge"
+            db = "target/debugger_db/idle-timeout"
+            in_memory = true
+            [nodes.p2p]
+            identity = "target/missing-identity.json"
+            port = 29737
+            idle_timeout = 1
+        "#;
+        let mut system = System::<rocks::Db>::from_toml(config).unwrap();
+        let clock = Arc::new(MockClock::new(SystemTime::now()));
+        system.insert_db("tezedge", mock_clock_db("target/debugger_db/idle-timeout", &clock));
+        system.handle_bind(100, 29737).unwrap();
+        let (_, db) = system.get_mut(29737).unwrap();
+
+        let id = |fd| EventId::new(SocketId { pid: 100, fd }, 0, 0);
+        // without identity the handshake is done as soon as both connection messages arrive
+        let data = |fd, b: u8, incoming| {
+            SnifferEvent::Data {
+                id: id(fd),
+                data: chunk(b),
+                net: true,
+                incoming,
+            }
+        };
+        let mut list = ConnectionList::new(None, &mut system);
+        for fd in 1..=2 {
+            list.handle_event(SnifferEvent::Connect {
+                id: id(fd),
+                address: PeerAddress::Inet(([51, 15, 220, 7], 9732 + fd as u16).into()),
+            });
+            list.handle_event(data(fd, 1, false));
+            list.handle_event(data(fd, 2, true));
+        }
+
+        clock.advance(Duration::from_millis(600));
+        // the second connection resumes before the timeout
+        list.handle_event(data(2, 3, true));
+        clock.advance(Duration::from_millis(600));
+        list.expire_idle();
+        assert_eq!(list.connections.len(), 1);
+        assert!(list.connections.contains_key(&SocketId { pid: 100, fd: 2 }));
+        drop(list);
+
+        let filter = ConnectionsFilter {
+            close_reason: Some(CloseReason::Timeout),
+            ..Default::default()
+        };
+        let connections = db.fetch_connections(&filter).unwrap();
+        assert_eq!(connections.len(), 1);
+        let json = serde_json::to_value(&connections[0].1).unwrap();
+        assert_eq!(json["remote_addr"], "51.15.220.7:9733");
+        assert_eq!(json["close_reason"], "timeout");
+        drop(db);
+
+        system.join();
+    }
+
+    #[test]
+    fn active_connections() {
+        let config = r#"
+            [[nodes]]
+            name = "tezedge"
+            db = "target/debugger_db/active-connections"
+            in_memory = true
+            [nodes.p2p]
+            identity = "target/missing-identity.json"
+            port = 29738
+        "#;
+        let mut system = System::<mock::Db>::from_toml(config).unwrap();
+        let running = Arc::new(AtomicBool::new(true));
+        system.run_dbs(running.clone());
+        system.handle_bind(100, 29738).unwrap();
+        let status = system.get_mut(29738).unwrap().0.status();
+
+        let id = |fd| EventId::new(SocketId { pid: 100, fd }, 0, 0);
+        let mut list = ConnectionList::new(None, &mut system);
+        list.handle_event(SnifferEvent::Connect {
+            id: id(1),
+            address: PeerAddress::Inet(([51, 15, 220, 7], 9732).into()),
+        });
+        list.handle_event(SnifferEvent::Data {
+            id: id(1),
+            data: vec![0, 3, 1, 2, 3],
+            net: true,
+            incoming: true,
+        });
+        list.publish_active();
+        let active = status.active_connections();
+        assert_eq!(active.len(), 1);
+        assert_eq!(active[0].remote_addr, ([51, 15, 220, 7], 9732).into());
+        assert!(!active[0].incoming);
+        assert_eq!(active[0].bytes_incoming, 5);
+        assert_eq!(active[0].bytes_outgoing, 0);
+        assert!(active[0].last_activity > 0);
+        let json = serde_json::to_value(&active[0]).unwrap();
+        assert_eq!(json["remote_addr"], "51.15.220.7:9732");
+
+        // closed, it is no longer tracked by the parser
+        list.handle_event(SnifferEvent::Close { id: id(1) });
+        list.publish_active();
+        assert!(status.active_connections().is_empty());
+        drop(list);
+
+        running.store(false, Ordering::Relaxed);
+        system.join();
+    }
+
+    #[test]
+    fn connect_failed() {
+        let config = r#"
+            [[nodes]]
+            name = "tezedge"
+            db = "target/debugger_db/connect-failed"
+            in_memory = true
+            [nodes.p2p]
+            identity = "target/missing-identity.json"
+            port = 29739
+        "#;
+        let mut system = System::<rocks::Db>::from_toml(config).unwrap();
+        let running = Arc::new(AtomicBool::new(true));
+        system.run_dbs(running.clone());
+        system.handle_bind(100, 29739).unwrap();
+        let (_, db) = system.get_mut(29739).unwrap();
+
+        let id = |fd| EventId::new(SocketId { pid: 100, fd }, 0, 0);
+        let mut list = ConnectionList::new(None, &mut system);
+        list.handle_event(SnifferEvent::Connect {
+            id: id(1),
+            address: PeerAddress::Inet(([51, 15, 220, 7], 9732).into()),
+        });
+        // ECONNREFUSED, the kernel returns the negated errno
+        list.handle_event(SnifferEvent::ConnectFailed {
+            id: id(2),
+            address: PeerAddress::Inet(([51, 15, 220, 7], 9733).into()),
+            code: -111,
+        });
+        // the attempt is stored, not tracked
+        assert_eq!(list.connections.len(), 1);
+        drop(list);
+
+        let filter = |status| ConnectionsFilter { status: Some(status), ..Default::default() };
+        let connections = db.fetch_connections(&filter(ConnectionStatus::Failed)).unwrap();
+        assert_eq!(connections.len(), 1);
+        let json = serde_json::to_value(&connections[0].1).unwrap();
+        assert_eq!(json["remote_addr"], "51.15.220.7:9733");
+        assert_eq!(json["close_reason"], "connect_failed");
+        assert_eq!(json["connect_error"], 111);
+        assert_eq!(json["initiator"], "local");
+        let connections = db.fetch_connections(&filter(ConnectionStatus::Closed)).unwrap();
+        assert!(connections.is_empty());
+        drop(db);
+
+        running.store(false, Ordering::Relaxed);
+        system.join();
+    }
+
+    /// The database of the node, the test controls its time
+    fn mock_clock_db(path: &str, clock: &Arc<MockClock>) -> Arc<rocks::Db> {
+        let db = rocks::Db::open_in_memory(path, false, None, None, Default::default()).unwrap();
+        Arc::new(db.with_clock(clock.clone()))
+    }
+
+    /// The memory file stands for the map of the bpf module, no event ever arrives,
+    /// it is always readable, so the loop does not wait, but finds nothing
+    fn idle_ring_buffer() -> (RingBufferSync, i32) {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let max_length = 0x1000;
+        let fd = unsafe { libc::memfd_create(b"ring-buffer\0".as_ptr() as *const _, 0) };
+        assert!(fd >= 0);
+        let size = page_size * 2 + max_length * 2;
+        assert_eq!(unsafe { libc::ftruncate(fd, size as i64) }, 0);
+        (RingBufferSync::new(fd, max_length).unwrap(), fd)
+    }
+
+    #[test]
+    fn bpf_module_exit() {
+        let config = r#"
+            [[nodes]]
+            name = "tezedge"
+            db = "target/debugger_db/bpf-module-exit"
+            in_memory = true
+            [nodes.p2p]
+            identity = "target/missing-identity.json"
+            port = 29736
+        "#;
+        let mut system = System::<mock::Db>::from_toml(config).unwrap();
+        let running = Arc::new(AtomicBool::new(true));
+        system.run_dbs(running.clone());
+
+        // stands for the bpf module, it exits at once, the ring buffer stays as it is
+        let mut bpf = Command::new("true").spawn().unwrap();
+        let (rb, fd) = idle_ring_buffer();
+        let list = ConnectionList::new(None, &mut system);
+        let started = Instant::now();
+        let stop = list
+            .run(Source::Live(rb), running.clone(), Some(&mut bpf))
+            .unwrap();
+        // the capture is to be respawned, the recorder keeps running
+        assert_eq!(stop, Stop::Hangup);
+        assert!(started.elapsed() < Duration::from_secs(3));
+        assert!(running.load(Ordering::Relaxed));
+        unsafe { libc::close(fd) };
+
+        running.store(false, Ordering::Relaxed);
+        system.join();
+    }
+
+    #[test]
+    fn housekeeping_without_events() {
+        let config = r#"
+            [[nodes]]
+            name = "tezedge"
+            db = "target/debugger_db/housekeeping"
+            in_memory = true
+            [nodes.p2p]
+            identity = "target/missing-identity.json"
+            port = 29740
+            idle_timeout = 1
+        "#;
+        let mut system = System::<rocks::Db>::from_toml(config).unwrap();
+        let clock = Arc::new(MockClock::new(SystemTime::now()));
+        system.insert_db("tezedge", mock_clock_db("target/debugger_db/housekeeping", &clock));
+        system.handle_bind(100, 29740).unwrap();
+        let (info, db) = system.get_mut(29740).unwrap();
+        let status = info.status();
+
+        let id = |fd| EventId::new(SocketId { pid: 100, fd }, 0, 0);
+        // without identity the handshake is done as soon as both connection messages arrive
+        let data = |fd, b: u8, incoming| {
+            SnifferEvent::Data {
+                id: id(fd),
+                data: chunk(b),
+                net: true,
+                incoming,
+            }
+        };
+        let mut list = ConnectionList::new(None, &mut system);
+        for fd in 1..=2 {
+            list.handle_event(SnifferEvent::Connect {
+                id: id(fd),
+                address: PeerAddress::Inet(([51, 15, 220, 7], 9732 + fd as u16).into()),
+            });
+            list.handle_event(data(fd, 1, false));
+            list.handle_event(data(fd, 2, true));
+        }
+        // the server asks to finalize the first, then the traffic stops completely
+        let cn_id = list.connections[&SocketId { pid: 100, fd: 1 }].key();
+        status.request_finalize(cn_id);
+        // both are active, the housekeeping publishes none once they are gone
+        list.publish_active();
+        let list = list.with_clock(clock.clone());
+
+        let capture = Arc::new(AtomicBool::new(true));
+        let stopper = {
+            let capture = capture.clone();
+            thread::spawn(move || {
+                // no event arrives, only the time goes
+                while !status.active_connections().is_empty() {
+                    clock.advance(Duration::from_millis(100));
+                    thread::yield_now();
+                }
+                capture.store(false, Ordering::Relaxed);
+            })
+        };
+        let (rb, fd) = idle_ring_buffer();
+        let stop = list.run(Source::Live(rb), capture, None).unwrap();
+        stopper.join().unwrap();
+        assert_eq!(stop, Stop::Shutdown);
+        unsafe { libc::close(fd) };
+
+        let filter = ConnectionsFilter::default();
+        let mut reasons = db
+            .fetch_connections(&filter)
+            .unwrap()
+            .into_iter()
+            .map(|(_, value)| {
+                let json = serde_json::to_value(&value).unwrap();
+                (json["remote_addr"].clone(), json["close_reason"].clone())
+            })
+            .collect::<Vec<_>>();
+        reasons.sort_by_key(|(addr, _)| addr.to_string());
+        // finalized and expired while no event arrived, not at the shutdown
+        assert_eq!(
+            reasons,
+            [
+                ("51.15.220.7:9733".into(), "manual".into()),
+                ("51.15.220.7:9734".into(), "timeout".into()),
+            ],
+        );
+        drop(db);
+
+        system.join();
+    }
+}
@@ -0,0 +1,46 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::{fs, io, path::Path};
+
+/// The p2p port from the config file of the node
+pub fn from_config_file<P>(path: P) -> io::Result<Option<u16>>
+where
+    P: AsRef<Path>,
+{
+    fs::read_to_string(path).map(|text| parse(&text))
+}
+
+/// Either the json config of the tezos node, `{ "p2p": { "listen-addr": "[::]:9732" } }`,
+/// or the config of the tezedge node, with the line `--p2p-port=9732`
+pub fn parse(text: &str) -> Option<u16> {
+    if let Ok(json) = serde_json::from_str::<serde_json::Value>(text) {
+        let addr = json.get("p2p")?.get("listen-addr")?.as_str()?;
+        return addr.rsplit(':').next()?.parse().ok();
+    }
+    text.lines()
+        .filter_map(|line| line.trim().strip_prefix("--p2p-port"))
+        .find_map(|value| {
+            value
+                .trim_start_matches(|c: char| c == '=' || c.is_whitespace())
+                .parse()
+                .ok()
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse;
+
+    #[test]
+    fn config_formats() {
+        let tezos = r#"{ "data-dir": "/var/tezos/node", "p2p": { "listen-addr": "[::]:9733" } }"#;
+        assert_eq!(parse(tezos), Some(9733));
+        assert_eq!(parse(r#"{ "p2p": { "bootstrap-peers": [] } }"#), None);
+
+        let tezedge = "--tezos-data-dir=/tmp/tezedge\n--p2p-port=19732\n--rpc-port=18732\n";
+        assert_eq!(parse(tezedge), Some(19732));
+        assert_eq!(parse("--p2p-port 9734"), Some(9734));
+        assert_eq!(parse("--rpc-port=18732"), None);
+    }
+}
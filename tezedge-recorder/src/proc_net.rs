@@ -0,0 +1,321 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::{
+    collections::BTreeSet,
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use regex::Regex;
+use serde::Deserialize;
+
+/// The listening socket the process created before the recorder started,
+/// the bpf module never reported its bind
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Listener {
+    pub pid: u32,
+    pub fd: u32,
+    pub port: u16,
+}
+
+// the state of the listening socket in `/proc/<pid>/net/tcp`
+const TCP_LISTEN: &str = "0A";
+
+/// The ports and the inodes of the listening sockets in the content of `/proc/<pid>/net/tcp`
+/// or `/proc/<pid>/net/tcp6`, the line looks like:
+/// `0: 00000000:2604 00000000:0000 0A 00000000:00000000 00:00000000 00000000 0 0 12345 ...`
+pub fn parse_listening(text: &str) -> Vec<(u16, u64)> {
+    text.lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            if fields.get(3) != Some(&TCP_LISTEN) {
+                return None;
+            }
+            let port = fields.get(1)?.rsplit(':').next()?;
+            let port = u16::from_str_radix(port, 16).ok()?;
+            let inode = fields.get(9)?.parse().ok()?;
+            Some((port, inode))
+        })
+        .collect()
+}
+
+/// Scan the processes in `proc_root`, normally `/proc`, for the sockets
+/// listening on any of the `ports`
+pub fn scan<P>(proc_root: P, ports: &[u16]) -> Vec<Listener>
+where
+    P: AsRef<Path>,
+{
+    scan_processes(proc_root, |_, _| true, |port| ports.contains(&port))
+}
+
+/// Scan the processes in `proc_root` whose command line matches the `regex`,
+/// for the sockets listening on any port
+pub fn scan_cmdline<P>(proc_root: P, regex: &Regex) -> Vec<Listener>
+where
+    P: AsRef<Path>,
+{
+    // the process is gone, or it is a kernel thread without the command line
+    let matches = |_: u32, path: &Path| cmdline(path).map_or(false, |c| regex.is_match(&c));
+    scan_processes(proc_root, matches, |_| true)
+}
+
+/// The arguments in `/proc/<pid>/cmdline` are separated by zeros, join them by spaces,
+/// `None` if the command line is empty
+fn cmdline(path: &Path) -> Option<String> {
+    let bytes = fs::read(path.join("cmdline")).ok()?;
+    let args = bytes
+        .split(|b| *b == 0)
+        .filter(|arg| !arg.is_empty())
+        .map(String::from_utf8_lossy)
+        .collect::<Vec<_>>();
+    if args.is_empty() {
+        None
+    } else {
+        Some(args.join(" "))
+    }
+}
+
+fn scan_processes<P, F, G>(proc_root: P, process: F, port: G) -> Vec<Listener>
+where
+    P: AsRef<Path>,
+    F: Fn(u32, &Path) -> bool,
+    G: Fn(u16) -> bool,
+{
+    let entries = match fs::read_dir(proc_root) {
+        Ok(entries) => entries,
+        Err(error) => {
+            log::warn!("cannot scan processes: {}", error);
+            return vec![];
+        },
+    };
+    let mut listeners = entries
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let pid = entry.file_name().to_str()?.parse::<u32>().ok()?;
+            Some((pid, entry.path()))
+        })
+        .filter(|(pid, path)| process(*pid, path.as_path()))
+        .flat_map(|(pid, path)| scan_process(pid, &path, &port))
+        .collect::<Vec<_>>();
+    listeners.sort_unstable_by_key(|l| (l.pid, l.fd));
+    listeners
+}
+
+fn scan_process<G>(pid: u32, path: &Path, port: G) -> Vec<Listener>
+where
+    G: Fn(u16) -> bool,
+{
+    // the listening sockets of the namespace of the process on the interesting ports
+    let listening = ["tcp", "tcp6"]
+        .iter()
+        .filter_map(|name| fs::read_to_string(path.join("net").join(name)).ok())
+        .flat_map(|text| parse_listening(&text))
+        .filter(|(p, _)| port(*p))
+        .collect::<Vec<_>>();
+    if listening.is_empty() {
+        return vec![];
+    }
+    let fds = match fs::read_dir(path.join("fd")) {
+        Ok(fds) => fds,
+        // the process is gone, or belongs to other user
+        Err(_) => return vec![],
+    };
+    fds.filter_map(Result::ok)
+        .filter_map(|entry| {
+            let fd = entry.file_name().to_str()?.parse().ok()?;
+            // the link looks like `socket:[12345]`
+            let target = fs::read_link(entry.path()).ok()?;
+            let inode = target
+                .to_str()?
+                .strip_prefix("socket:[")?
+                .strip_suffix(']')?
+                .parse::<u64>()
+                .ok()?;
+            let &(port, _) = listening.iter().find(|(_, i)| *i == inode)?;
+            Some(Listener { pid, fd, port })
+        })
+        .collect()
+}
+
+#[derive(Clone, Deserialize)]
+pub struct CmdlineConfig {
+    // the regex of the command line, like `tezos-node`
+    pub regex: String,
+    // seconds between the scans, 5 by default
+    pub interval: Option<u64>,
+}
+
+impl CmdlineConfig {
+    pub const DEFAULT_INTERVAL: u64 = 5;
+
+    pub fn regex(&self) -> Result<Regex, regex::Error> {
+        Regex::new(&self.regex)
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval.unwrap_or(Self::DEFAULT_INTERVAL).max(1))
+    }
+}
+
+/// The change of the listening ports of the matching processes since the previous scan
+#[derive(Debug, Default)]
+pub struct Refresh {
+    // the ports to watch, not watched before
+    pub watch: Vec<u16>,
+    // the ports nobody of the matching processes listens on anymore
+    pub unwatch: Vec<u16>,
+    // all listening sockets of the matching processes
+    pub listeners: Vec<Listener>,
+}
+
+/// Tracks the listening ports of the processes whose command line matches the regex,
+/// so the short-lived nodes are watched without configuring their ports
+pub struct CmdlineWatcher {
+    proc_root: PathBuf,
+    regex: Regex,
+    ports: BTreeSet<u16>,
+}
+
+impl CmdlineWatcher {
+    pub fn new<P>(proc_root: P, regex: Regex) -> Self
+    where
+        P: AsRef<Path>,
+    {
+        CmdlineWatcher {
+            proc_root: proc_root.as_ref().to_path_buf(),
+            regex,
+            ports: BTreeSet::new(),
+        }
+    }
+
+    pub fn refresh(&mut self) -> Refresh {
+        let listeners = scan_cmdline(&self.proc_root, &self.regex);
+        let ports = listeners.iter().map(|l| l.port).collect::<BTreeSet<_>>();
+        let refresh = Refresh {
+            watch: ports.difference(&self.ports).cloned().collect(),
+            unwatch: self.ports.difference(&ports).cloned().collect(),
+            listeners,
+        };
+        self.ports = ports;
+        refresh
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, os::unix::fs::symlink, path::Path};
+    use regex::Regex;
+    use super::{scan, Listener, CmdlineWatcher};
+
+    const HEADER: &str = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when \
+                          retrnsmt   uid  timeout inode\n";
+
+    #[test]
+    fn listening_before_start() {
+        let root = env::temp_dir().join(format!("tezedge-recorder-proc-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+
+        // the node listens on 9732 (0x2604) over ipv6 and on the rpc port 18732 (0x492c),
+        // also it has the connection from the port 9732
+        let node = root.join("1234");
+        fs::create_dir_all(node.join("net")).unwrap();
+        fs::create_dir_all(node.join("fd")).unwrap();
+        let tcp = format!(
+            "{}{}{}",
+            HEADER,
+            "   0: 00000000:492C 00000000:0000 0A 00000000:00000000 00:00000000 00000000 \
+             1000        0 111 1 0000000000000000 100 0 0 10 0\n",
+            "   1: 0100007F:2604 0700000A:D431 01 00000000:00000000 00:00000000 00000000 \
+             1000        0 333 1 0000000000000000 20 4 30 10 -1\n",
+        );
+        fs::write(node.join("net/tcp"), tcp).unwrap();
+        let tcp6 = format!(
+            "{}{}",
+            HEADER,
+            "   0: 00000000000000000000000000000000:2604 00000000000000000000000000000000:0000 \
+             0A 00000000:00000000 00:00000000 00000000  1000        0 222 1 0000000000000000 \
+             100 0 0 10 0\n",
+        );
+        fs::write(node.join("net/tcp6"), tcp6).unwrap();
+        symlink("socket:[111]", node.join("fd/7")).unwrap();
+        symlink("socket:[222]", node.join("fd/8")).unwrap();
+        symlink("socket:[333]", node.join("fd/9")).unwrap();
+        symlink("/dev/null", node.join("fd/0")).unwrap();
+        // not a process
+        fs::create_dir_all(root.join("self/net")).unwrap();
+
+        let listeners = scan(&root, &[9732]);
+        assert_eq!(
+            listeners,
+            [Listener {
+                pid: 1234,
+                fd: 8,
+                port: 9732,
+            }],
+        );
+        assert!(scan(&root, &[9733]).is_empty());
+        assert!(scan(root.join("missing"), &[9732]).is_empty());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    /// The process `pid` with the command line `args` listening on the `ports` over ipv4
+    fn stub_process(root: &Path, pid: u32, args: &[&str], ports: &[u16]) {
+        let path = root.join(pid.to_string());
+        fs::create_dir_all(path.join("net")).unwrap();
+        fs::create_dir_all(path.join("fd")).unwrap();
+        let cmdline = args.iter().map(|arg| format!("{}\0", arg)).collect::<String>();
+        fs::write(path.join("cmdline"), cmdline).unwrap();
+        let mut tcp = HEADER.to_string();
+        for (i, port) in ports.iter().enumerate() {
+            let inode = u64::from(pid) * 100 + i as u64;
+            tcp.push_str(&format!(
+                "   {}: 00000000:{:04X} 00000000:0000 0A 00000000:00000000 00:00000000 00000000 \
+                 1000        0 {} 1 0000000000000000 100 0 0 10 0\n",
+                i, port, inode,
+            ));
+            let fd = path.join("fd").join((i + 3).to_string());
+            symlink(format!("socket:[{}]", inode), fd).unwrap();
+        }
+        fs::write(path.join("net/tcp"), tcp).unwrap();
+    }
+
+    #[test]
+    fn watch_cmdline() {
+        let root = env::temp_dir().join(format!("tezedge-recorder-cmdline-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+
+        let node = ["/usr/local/bin/tezos-node", "run", "--net-addr", "[::]:9732"];
+        stub_process(&root, 1234, &node, &[9732, 8732]);
+        // not a node, though listens
+        stub_process(&root, 2000, &["nginx", "-g", "daemon off;"], &[9999]);
+        // the node which does not listen yet
+        stub_process(&root, 3000, &["tezos-node", "run"], &[]);
+        // the kernel thread has empty command line
+        stub_process(&root, 4000, &[], &[9733]);
+
+        let mut watcher = CmdlineWatcher::new(&root, Regex::new("tezos-node").unwrap());
+        let refresh = watcher.refresh();
+        assert_eq!(refresh.watch, [8732, 9732]);
+        assert!(refresh.unwatch.is_empty());
+        assert_eq!(refresh.listeners.len(), 2);
+        assert!(refresh.listeners.iter().all(|l| l.pid == 1234));
+        // nothing changed
+        let refresh = watcher.refresh();
+        assert!(refresh.watch.is_empty() && refresh.unwatch.is_empty());
+        assert_eq!(refresh.listeners.len(), 2);
+
+        // the node exits, another starts on other ports
+        fs::remove_dir_all(root.join("1234")).unwrap();
+        stub_process(&root, 1300, &["tezos-node", "run"], &[19732, 8732]);
+        let refresh = watcher.refresh();
+        assert_eq!(refresh.watch, [19732]);
+        assert_eq!(refresh.unwatch, [9732]);
+        let listener = refresh.listeners.iter().find(|l| l.port == 19732).unwrap();
+        assert_eq!((listener.pid, listener.fd), (1300, 3));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
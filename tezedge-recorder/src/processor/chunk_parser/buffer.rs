@@ -52,6 +52,28 @@ impl Buffer {
         }
     }
 
+    /// Remove the `prefix` if the buffer starts with it, `None` if there is not enough data
+    /// to tell, the buffer is shorter than the prefix, but matches it so far
+    pub fn strip_prefix(&mut self, prefix: &[u8]) -> Option<bool> {
+        if self.buffer.len() < prefix.len() {
+            if prefix.starts_with(&self.buffer) {
+                None
+            } else {
+                Some(false)
+            }
+        } else if self.buffer.starts_with(prefix) {
+            self.buffer.drain(..prefix.len());
+            Some(true)
+        } else {
+            Some(false)
+        }
+    }
+
+    /// The chunks are lost, the next chunk has the counter after them
+    pub fn skip(&mut self, lost: u64) {
+        self.counter += lost;
+    }
+
     pub fn cleanup(&mut self) -> Option<(u64, Vec<u8>)> {
         use std::mem;
 
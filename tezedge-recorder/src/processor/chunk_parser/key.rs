@@ -18,6 +18,8 @@ pub struct Keys {
 pub struct Key {
     key: PrecomputedKey,
     nonce: Nonce,
+    // the chunks decrypted with the key so far
+    decrypted: u64,
 }
 
 impl Keys {
@@ -29,6 +31,35 @@ impl Keys {
     ) -> Result<Self, CryptoError> {
         use crypto::crypto_box::CryptoKey;
 
+        Self::check_len(local, remote)?;
+
+        // check if the identity belong to one of the parties
+        if identity.public_key.as_ref() != local[4..36].as_ref() {
+            return Err(CryptoError::InvalidKey {
+                reason: "The communication does not belong to the local node".to_string(),
+            });
+        };
+
+        let pk = CryptoKey::from_bytes(&remote[4..36]).unwrap();
+        let sk = CryptoKey::from_bytes(&identity.secret_key).unwrap();
+
+        let key = PrecomputedKey::precompute(&pk, &sk);
+        Ok(Self::with_key(key, local, remote, initiator))
+    }
+
+    /// The key is known in advance, the identity is not needed, only the nonces are derived
+    pub fn precomputed(
+        key: &[u8; 32],
+        local: &[u8],
+        remote: &[u8],
+        initiator: Initiator,
+    ) -> Result<Self, CryptoError> {
+        Self::check_len(local, remote)?;
+        let key = PrecomputedKey::from_bytes(*key);
+        Ok(Self::with_key(key, local, remote, initiator))
+    }
+
+    fn check_len(local: &[u8], remote: &[u8]) -> Result<(), CryptoError> {
         if local.len() < 36 {
             return Err(CryptoError::InvalidKeySize {
                 expected: 32,
@@ -43,33 +74,60 @@ impl Keys {
             });
         }
 
-        // check if the identity belong to one of the parties
-        if identity.public_key.as_ref() != local[4..36].as_ref() {
-            return Err(CryptoError::InvalidKey {
-                reason: "The communication does not belong to the local node".to_string(),
-            });
-        };
-
-        let pk = CryptoKey::from_bytes(&remote[4..36]).unwrap();
-        let sk = CryptoKey::from_bytes(&identity.secret_key).unwrap();
+        Ok(())
+    }
 
+    fn with_key(key: PrecomputedKey, local: &[u8], remote: &[u8], initiator: Initiator) -> Self {
         let NoncePair { local, remote } =
             generate_nonces(local, remote, initiator.incoming()).unwrap();
-        let key = PrecomputedKey::precompute(&pk, &sk);
-        Ok(Keys {
+        Keys {
             local: Key {
                 key: key.clone(),
                 nonce: local,
+                decrypted: 0,
             },
-            remote: Key { key, nonce: remote },
-        })
+            remote: Key {
+                key,
+                nonce: remote,
+                decrypted: 0,
+            },
+        }
     }
 }
 
 impl Key {
+    /// How many chunks after the expected one might be lost in the capture,
+    /// the peer has used their nonces, see `resync`
+    const RESYNC_WINDOW: u64 = 16;
+
+    /// The nonce for the next chunk
+    pub fn nonce_bytes(&self) -> Option<[u8; 24]> {
+        self.nonce.get_bytes().ok()
+    }
+
     pub fn decrypt(&mut self, payload: &[u8]) -> Result<Vec<u8>, CryptoError> {
         let plain = self.key.decrypt(&payload[2..], &self.nonce)?;
         self.nonce = self.nonce.increment();
+        self.decrypted += 1;
         Ok(plain)
     }
+
+    /// Try the nonces of the next chunks, the chunk which failed to decrypt is fine
+    /// if the chunks before it are lost, returns the plain and how many chunks are lost
+    pub fn resync(&mut self, payload: &[u8]) -> Option<(Vec<u8>, u64)> {
+        let mut nonce = self.nonce.increment();
+        for lost in 1..=Self::RESYNC_WINDOW {
+            if let Ok(plain) = self.key.decrypt(&payload[2..], &nonce) {
+                self.nonce = nonce.increment();
+                self.decrypted += 1;
+                return Some((plain, lost));
+            }
+            nonce = nonce.increment();
+        }
+        None
+    }
+
+    pub fn decrypted(&self) -> u64 {
+        self.decrypted
+    }
 }
@@ -1,7 +1,7 @@
 // Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
 // SPDX-License-Identifier: MIT
 
-use super::{common, tables, Identity};
+use super::{common, tables, telemetry, Identity, database::clock::Clock};
 
 mod buffer;
 mod key;
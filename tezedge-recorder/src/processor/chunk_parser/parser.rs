@@ -1,13 +1,14 @@
 // Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
 // SPDX-License-Identifier: MIT
 
+use std::sync::Arc;
 use typenum::Bit;
 use either::Either;
 use super::{
     state::{Initial, HaveCm, Uncertain, HaveKey, HaveNotKey, CannotDecrypt, MakeKeyOutput},
-    tables::{connection, chunk},
+    tables::{connection, connection_crypto, chunk},
     common::{Local, Remote},
-    Identity,
+    Identity, Clock,
 };
 
 pub struct Handshake {
@@ -25,6 +26,8 @@ pub struct HandshakeOutput {
     pub l_chunk: Option<chunk::Item>,
     pub remote: HandshakeDone<Remote>,
     pub r_chunk: Option<chunk::Item>,
+    // the keys and the nonces, if the handshake made the keys
+    pub crypto: Option<connection_crypto::Value>,
 }
 
 impl From<MakeKeyOutput> for HandshakeOutput {
@@ -34,17 +37,57 @@ impl From<MakeKeyOutput> for HandshakeOutput {
             l_chunk: v.l_chunk,
             remote: v.remote.into(),
             r_chunk: v.r_chunk,
+            crypto: v.crypto,
         }
     }
 }
 
 impl Handshake {
-    pub fn new(cn_id: &connection::Key, id: Identity) -> Self {
-        let local = Half::Initial(Initial::new(&cn_id, id.clone()));
-        let remote = Half::Initial(Initial::new(&cn_id, id));
+    /// Each of `ids` is tried until one matches the local connection message,
+    /// without identity the chunks are stored, but not decrypted,
+    /// the proof-of-work of connection messages is checked against `pow_target` anyway,
+    /// the chunks are stamped by the `clock`
+    pub fn new(
+        cn_id: &connection::Key,
+        ids: Vec<Identity>,
+        pow_target: f64,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        let local = Half::Initial(Initial::new(&cn_id, ids.clone(), pow_target, clock.clone()));
+        let remote = Half::Initial(Initial::new(&cn_id, ids, pow_target, clock));
         Handshake { local, remote }
     }
 
+    /// The key is known in advance, decrypt the connection without the identity
+    pub fn with_precomputed_key(self, key: Option<[u8; 32]>) -> Self {
+        match self {
+            Handshake {
+                local: Half::Initial(l),
+                remote,
+            } => Handshake {
+                local: Half::Initial(l.with_precomputed_key(key)),
+                remote,
+            },
+            // too late, the connection message is already here
+            handshake => handshake,
+        }
+    }
+
+    /// Drop the copy of the connection message, if it is seen twice, enabled by default,
+    /// must be called before any data
+    pub fn with_drop_duplicate(self, drop_duplicate: bool) -> Self {
+        match self {
+            Handshake {
+                local: Half::Initial(l),
+                remote: Half::Initial(r),
+            } => Handshake::initial(
+                l.with_drop_duplicate(drop_duplicate),
+                r.with_drop_duplicate(drop_duplicate),
+            ),
+            handshake => handshake,
+        }
+    }
+
     pub fn is_empty(&self) -> bool {
         match self {
             Handshake {
@@ -118,6 +161,7 @@ impl Handshake {
                                 l_chunk,
                                 remote: HandshakeDone::Uncertain(r),
                                 r_chunk,
+                                crypto: None,
                             })
                         },
                     }
@@ -146,6 +190,7 @@ impl Handshake {
                                 l_chunk,
                                 remote: HandshakeDone::Uncertain(r),
                                 r_chunk,
+                                crypto: None,
                             })
                         },
                     }
@@ -212,8 +257,8 @@ where
                 }
                 match temp_state.over() {
                     Ok(state) => HandshakeDone::HaveKey(state),
-                    Err((mut state, position)) => {
-                        cn.mark_cannot_decrypt::<S>(position);
+                    Err((mut state, position, decrypted)) => {
+                        cn.mark_cannot_decrypt::<S>(position, decrypted);
                         handler.update_cn(cn);
                         for mut chunk in &mut state {
                             chunk.net(net);
@@ -239,6 +284,22 @@ where
             },
         }
     }
+
+    /// Store the incomplete chunk as it is, the connection is over before it is complete
+    pub fn flush<H>(&mut self, cn: &mut connection::Item, handler: &mut H)
+    where
+        H: ChunkHandler,
+    {
+        let chunk = match self {
+            HandshakeDone::HaveKey(state) => state.flush(),
+            HandshakeDone::CannotDecrypt(state) => state.flush(),
+            // each payload is the chunk, nothing is buffered
+            HandshakeDone::Uncertain(_) | HandshakeDone::HaveNotKey(_) => None,
+        };
+        if let Some(chunk) = chunk {
+            handler.handle_chunk(chunk, cn);
+        }
+    }
 }
 
 pub trait ChunkHandler {
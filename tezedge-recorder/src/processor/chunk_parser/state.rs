@@ -1,22 +1,33 @@
 // Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
 // SPDX-License-Identifier: MIT
 
-use std::marker::PhantomData;
+use std::{convert::TryFrom, marker::PhantomData, sync::Arc};
 use either::Either;
 use thiserror::Error;
 use typenum::{self, Bit};
+use tezos_messages::p2p::{encoding::connection::ConnectionMessage, binary_message::BinaryRead};
 use super::{
     buffer::Buffer,
     key::{Keys, Key},
-    tables::{connection, chunk},
+    tables::{connection, connection_crypto, chunk},
     common::{Sender, Local, Remote},
-    Identity,
+    telemetry, Identity, Clock,
 };
 
 struct Inner<S> {
     cn_id: connection::Key,
-    id: Identity,
+    // the candidates, the one whose public key is in the local connection message is used
+    ids: Vec<Identity>,
+    // the key supplied for this connection, used instead of the identity
+    precomputed_key: Option<[u8; 32]>,
+    pow_target: f64,
+    // drop the copy of the connection message, see `drop_duplicate`
+    drop_duplicate: bool,
+    // the connection message, kept until the next chunk shows whether it is the copy
+    cm: Option<Vec<u8>>,
     buffer: Buffer,
+    // the timestamps of the chunks
+    clock: Arc<dyn Clock>,
     incoming: PhantomData<S>,
 }
 
@@ -31,6 +42,7 @@ where
             counter,
             bytes,
             plain,
+            self.clock.now(),
         )
     }
 
@@ -43,6 +55,26 @@ where
             .cleanup()
             .map(|(counter, bytes)| self.chunk(counter, bytes, Vec::new()))
     }
+
+    /// On some captures the connection message is seen twice, for example, echoed by a bridge,
+    /// the copy would be taken for the first encrypted chunk and break the decryption
+    fn drop_duplicate(&mut self) {
+        let cm = match &self.cm {
+            Some(cm) => cm,
+            None => return,
+        };
+        match self.buffer.strip_prefix(cm) {
+            Some(true) => {
+                log::warn!(
+                    "connection {}, dropped the duplicated connection message",
+                    self.cn_id,
+                );
+                self.cm = None;
+            },
+            Some(false) => self.cm = None,
+            None => (),
+        }
+    }
 }
 
 /// State machine:
@@ -89,17 +121,37 @@ impl<S> Initial<S>
 where
     S: Bit,
 {
-    pub fn new(cn_id: &connection::Key, id: Identity) -> Self {
+    pub fn new(
+        cn_id: &connection::Key,
+        ids: Vec<Identity>,
+        pow_target: f64,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
         Initial {
             inner: Inner {
                 cn_id: cn_id.clone(),
-                id,
+                ids,
+                precomputed_key: None,
+                pow_target,
+                drop_duplicate: true,
+                cm: None,
                 buffer: Buffer::default(),
+                clock,
                 incoming: PhantomData,
             },
         }
     }
 
+    pub fn with_precomputed_key(mut self, key: Option<[u8; 32]>) -> Self {
+        self.inner.precomputed_key = key;
+        self
+    }
+
+    pub fn with_drop_duplicate(mut self, drop_duplicate: bool) -> Self {
+        self.inner.drop_duplicate = drop_duplicate;
+        self
+    }
+
     pub fn uncertain(self) -> (Uncertain<S>, Option<chunk::Item>) {
         Uncertain::new(self.inner)
     }
@@ -138,6 +190,10 @@ where
 
     fn have_key(mut self, key: Key) -> (HaveKey<S>, chunk::Item) {
         let (counter, bytes) = self.inner.buffer.next().unwrap();
+        if self.inner.drop_duplicate {
+            self.inner.cm = Some(bytes.clone());
+            self.inner.drop_duplicate();
+        }
         let remaining = self.inner.buffer.remaining();
         if remaining > 0 {
             log::info!(
@@ -167,6 +223,7 @@ pub struct MakeKeyOutput {
     pub l_chunk: Option<chunk::Item>,
     pub remote: Result<HaveKey<Remote>, HaveNotKey<Remote>>,
     pub r_chunk: Option<chunk::Item>,
+    pub crypto: Option<connection_crypto::Value>,
 }
 
 impl HaveCm<Local> {
@@ -181,12 +238,11 @@ impl HaveCm<Local> {
             PowInvalid(f64),
         }
 
+        let target = self.inner.pow_target;
         let check = |payload: &[u8]| -> Result<[u8; 32], HandshakeWarning> {
             if payload.len() <= 88 {
                 return Err(HandshakeWarning::ConnectionMessageTooShort(payload.len()));
             }
-            // TODO: move to config
-            let target = 26.0;
             if proof_of_work::check_proof_of_work(&payload[4..60], target).is_err() {
                 return Err(HandshakeWarning::PowInvalid(target));
             }
@@ -196,52 +252,94 @@ impl HaveCm<Local> {
             Ok(pk)
         };
 
+        // the connection messages are not encrypted, check them even without identity
         let local_chunk = self.inner.buffer.have_chunk().unwrap();
         let remote_chunk = peer.inner.buffer.have_chunk().unwrap();
-        let identity = &self.inner.id;
+        match check(local_chunk) {
+            Ok(_) => (),
+            Err(HandshakeWarning::ConnectionMessageTooShort(size)) => {
+                cn.add_comment().outgoing_too_short = Some(size);
+            },
+            Err(HandshakeWarning::PowInvalid(target)) => {
+                cn.add_comment().outgoing_wrong_pow = Some(target);
+            },
+        }
+        match check(remote_chunk) {
+            Ok(peer_pk) => {
+                cn.set_peer_pk(peer_pk);
+                cn.set_pow_valid(true);
+            },
+            Err(HandshakeWarning::ConnectionMessageTooShort(size)) => {
+                cn.add_comment().incoming_too_short = Some(size);
+            },
+            Err(HandshakeWarning::PowInvalid(target)) => {
+                cn.add_comment().incoming_wrong_pow = Some(target);
+                cn.set_pow_valid(false);
+            },
+        }
+
         let initiator = cn.initiator.clone();
-        match Keys::new(identity, local_chunk, remote_chunk, initiator) {
-            Ok(Keys { local, remote }) => {
+        let keys = match self.inner.precomputed_key {
+            Some(key) => Keys::precomputed(&key, local_chunk, remote_chunk, initiator).ok(),
+            None if self.inner.ids.is_empty() => {
+                cn.add_comment().outgoing_no_identity = true;
+                return self.have_not_keys(peer);
+            },
+            None => self
+                .inner
+                .ids
+                .iter()
+                .find_map(|id| Keys::new(id, local_chunk, remote_chunk, initiator.clone()).ok()),
+        };
+        match keys {
+            Some(Keys { local, remote }) => {
+                // the chunks are at least 36 bytes, otherwise the keys are not made
+                let pk = |chunk: &[u8]| <[u8; 32]>::try_from(&chunk[4..36]).unwrap();
+                let crypto = match (local.nonce_bytes(), remote.nonce_bytes()) {
+                    (Some(local_nonce), Some(remote_nonce)) => Some(connection_crypto::Value {
+                        local_pk: pk(local_chunk),
+                        remote_pk: pk(remote_chunk),
+                        local_nonce,
+                        remote_nonce,
+                    }),
+                    _ => None,
+                };
                 let (l, l_chunk) = self.have_key(local);
                 let (r, r_chunk) = peer.have_key(remote);
-                match check(&l_chunk.bytes) {
-                    Ok(_) => (),
-                    Err(HandshakeWarning::ConnectionMessageTooShort(size)) => {
-                        cn.add_comment().outgoing_too_short = Some(size);
-                    },
-                    Err(HandshakeWarning::PowInvalid(target)) => {
-                        cn.add_comment().outgoing_wrong_pow = Some(target);
-                    },
-                }
-                match check(&r_chunk.bytes) {
-                    Ok(peer_pk) => cn.set_peer_pk(peer_pk),
-                    Err(HandshakeWarning::ConnectionMessageTooShort(size)) => {
-                        cn.add_comment().incoming_too_short = Some(size);
-                    },
-                    Err(HandshakeWarning::PowInvalid(target)) => {
-                        cn.add_comment().incoming_wrong_pow = Some(target);
-                    },
+                // the peers use the lowest of their distributed db versions
+                let version = |bytes: &[u8]| {
+                    let message = ConnectionMessage::from_bytes(bytes.get(2..)?).ok()?;
+                    Some(*message.version().distributed_db_version())
+                };
+                if let (Some(l), Some(r)) = (version(&l_chunk.bytes), version(&r_chunk.bytes)) {
+                    cn.set_version(l.min(r));
                 }
                 MakeKeyOutput {
                     local: Ok(l),
                     l_chunk: Some(l_chunk),
                     remote: Ok(r),
                     r_chunk: Some(r_chunk),
+                    crypto,
                 }
             },
-            Err(_) => {
+            None => {
                 cn.add_comment().outgoing_wrong_pk = true;
-                let (l, l_chunk) = self.have_not_key();
-                let (r, r_chunk) = peer.have_not_key();
-                MakeKeyOutput {
-                    local: Err(l),
-                    l_chunk,
-                    remote: Err(r),
-                    r_chunk,
-                }
+                self.have_not_keys(peer)
             },
         }
     }
+
+    fn have_not_keys(self, peer: HaveCm<Remote>) -> MakeKeyOutput {
+        let (l, l_chunk) = self.have_not_key();
+        let (r, r_chunk) = peer.have_not_key();
+        MakeKeyOutput {
+            local: Err(l),
+            l_chunk,
+            remote: Err(r),
+            r_chunk,
+            crypto: None,
+        }
+    }
 }
 
 impl<S> Uncertain<S>
@@ -278,12 +376,18 @@ where
 {
     pub fn handle_data(mut self, payload: &[u8]) -> HaveData<S> {
         self.inner.handle_data(payload);
+        self.inner.drop_duplicate();
         HaveData {
             inner: self.inner,
             key: self.key,
             error: None,
         }
     }
+
+    /// The incomplete chunk, it cannot be decrypted
+    pub fn flush(&mut self) -> Option<chunk::Item> {
+        self.inner.cleanup()
+    }
 }
 
 impl<S> Iterator for HaveData<S>
@@ -297,11 +401,31 @@ where
             return None;
         }
         let (counter, bytes) = self.inner.buffer.next()?;
+        let _span = tracing::debug_span!(
+            target: telemetry::TARGET,
+            "decrypt",
+            connection = %self.inner.cn_id,
+            counter,
+        )
+        .entered();
         match self.key.decrypt(&bytes) {
             Ok(plain) => Some(self.inner.chunk(counter, bytes, plain)),
-            Err(_) => {
-                self.error = Some(counter);
-                None
+            Err(_) => match self.key.resync(&bytes) {
+                Some((plain, lost)) => {
+                    log::warn!(
+                        "connection: {}, {} chunks lost before chunk: {}, the nonce is resynced",
+                        self.inner.cn_id,
+                        lost,
+                        counter + lost,
+                    );
+                    // the message parser sees the gap in the counters
+                    self.inner.buffer.skip(lost);
+                    Some(self.inner.chunk(counter + lost, bytes, plain))
+                },
+                None => {
+                    self.error = Some(counter);
+                    None
+                },
             },
         }
     }
@@ -311,9 +435,12 @@ impl<S> HaveData<S>
 where
     S: Bit,
 {
-    pub fn over(self) -> Result<HaveKey<S>, (CannotDecrypt<S>, u64)> {
+    /// The position of the chunk which cannot be decrypted,
+    /// and how many chunks were decrypted before it
+    pub fn over(self) -> Result<HaveKey<S>, (CannotDecrypt<S>, u64, u64)> {
         if let Some(position) = self.error {
-            Err((CannotDecrypt { inner: self.inner }, position))
+            let decrypted = self.key.decrypted();
+            Err((CannotDecrypt { inner: self.inner }, position, decrypted))
         } else {
             Ok(HaveKey {
                 inner: self.inner,
@@ -331,6 +458,10 @@ where
         debug_assert!(!payload.is_empty());
         self.inner.handle_data(payload);
     }
+
+    pub fn flush(&mut self) -> Option<chunk::Item> {
+        self.inner.cleanup()
+    }
 }
 
 impl<'a, S> Iterator for &'a mut CannotDecrypt<S>
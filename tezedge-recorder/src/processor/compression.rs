@@ -0,0 +1,38 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::borrow::Cow;
+
+/// How the payloads of the peer messages are compressed. No tezos protocol negotiates
+/// the compression yet, the peers announce zero or nothing and the payloads are plain.
+/// A future protocol would announce the flag after the fields of the metadata message,
+/// the peers compress the payloads only if both announced the same flag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    /// The flag the recorder does not know how to decompress
+    Unknown(u8),
+}
+
+impl Compression {
+    /// The flag announced in the decrypted metadata message, `None` if it is absent or zero
+    pub fn announced(metadata: &[u8]) -> Option<u8> {
+        metadata.get(2).cloned().filter(|flag| *flag != 0)
+    }
+
+    /// The compression for the flag negotiated on the connection
+    pub fn from_flag(flag: Option<u8>) -> Self {
+        match flag {
+            None => Compression::None,
+            Some(flag) => Compression::Unknown(flag),
+        }
+    }
+
+    /// The plain payload of the peer message chunk, `None` if it cannot be decompressed
+    pub fn decompress<'a>(&self, payload: &'a [u8]) -> Option<Cow<'a, [u8]>> {
+        match self {
+            Compression::None => Some(Cow::Borrowed(payload)),
+            Compression::Unknown(_) => None,
+        }
+    }
+}
@@ -1,22 +1,124 @@
 // Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
 // SPDX-License-Identifier: MIT
 
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use either::Either;
+use serde::Serialize;
 use super::{
     chunk_parser::{Handshake, HandshakeOutput, HandshakeDone, ChunkHandler},
-    message_parser::MessageParser,
-    Identity, Database,
-    common::{Local, Remote, Initiator},
-    tables::connection,
+    message_parser::{MessageParser, ChunkStorage, ChunkSample},
+    rate::RateMonitor,
+    Identity, Database, Enricher,
+    database::clock::Clock,
+    common::{Local, Remote, Initiator, MessageType},
+    tables::{connection, chunk_event},
+    telemetry,
 };
 
+/// The other node the connection might belong to, if the attribution is ambiguous,
+/// see `Connection::with_candidates`
+pub struct Candidate<Db> {
+    pub public_key: [u8; 32],
+    pub db: Arc<Db>,
+    pub geoip: Option<Enricher>,
+}
+
+/// How the connections of the node are recorded, built once from the config of the node
+#[derive(Clone)]
+pub struct CaptureOptions {
+    /// Store the blake2b of the decrypted bytes of each message
+    pub message_hash: bool,
+    /// Store a placeholder instead of the messages longer than the limit
+    pub max_message_size: Option<u32>,
+    /// Which chunks are stored, see `ChunkStorage`
+    pub chunk_storage: ChunkStorage,
+    /// Which chunks are stored with the bytes, see `ChunkSample`
+    pub chunk_sample: Option<ChunkSample>,
+    /// Store only the messages of these types, see `MessageParser::with_capture_types`
+    pub capture_types: Option<Vec<MessageType>>,
+    /// Store the public keys and the nonces derived by the handshake, see `connection_crypto`
+    pub debug_crypto: bool,
+    /// Store the connection only when the connection messages are valid,
+    /// the connection which did not exchange them within the timeout is expired
+    pub handshake_timeout: Option<Duration>,
+    /// Finalize the connection which got no data within the timeout, see `is_idle`
+    pub idle_timeout: Option<Duration>,
+    /// The peer messages are decoded by the encoding of this version,
+    /// unless the connection messages negotiate another one
+    pub ddb_version: Option<u16>,
+    /// Drop the copy of the connection message seen twice
+    pub drop_duplicate_cm: bool,
+}
+
+impl Default for CaptureOptions {
+    fn default() -> Self {
+        CaptureOptions {
+            message_hash: false,
+            max_message_size: None,
+            chunk_storage: ChunkStorage::All,
+            chunk_sample: None,
+            capture_types: None,
+            debug_crypto: false,
+            handshake_timeout: None,
+            idle_timeout: None,
+            ddb_version: None,
+            drop_duplicate_cm: true,
+        }
+    }
+}
+
 pub struct Connection<Db> {
     state: Option<ConnectionState<Db>>,
     item: connection::Item,
+    rate: Option<RateMonitor>,
+    options: CaptureOptions,
+    geoip: Option<Enricher>,
+    stage: HandshakeStage,
+    created: Instant,
+    last_data: Instant,
+    // the payload bytes seen so far, and when the last data came, milliseconds since the epoch
+    bytes_incoming: u64,
+    bytes_outgoing: u64,
+    last_activity: u64,
+    // finalized on request, the later data is ignored
+    finalized: bool,
+    // the connection is already stored, it is decoded again, see `resume`
+    resumed: bool,
+    // the other nodes the connection might belong to, until the handshake tells which
+    candidates: Vec<Candidate<Db>>,
+    // the timestamps and the timeouts, the clock of the database
+    clock: Arc<dyn Clock>,
     db: Arc<Db>,
 }
 
+/// The connection the parser tracks, see `/v3/connections/active`
+#[derive(Debug, Clone, Serialize)]
+pub struct ActiveConnection {
+    pub id: connection::Key,
+    pub remote_addr: SocketAddr,
+    pub incoming: bool,
+    pub bytes_incoming: u64,
+    pub bytes_outgoing: u64,
+    // milliseconds since the unix epoch
+    pub last_activity: u64,
+}
+
+/// How far the peers got in the handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeStage {
+    /// Some of the connection messages did not arrive yet
+    Initial,
+    /// The connection messages arrived, but some is too short, or lacks the proof-of-work,
+    /// or the peer sent too much data without the message, likely a scan or a probe
+    Invalid,
+    /// Both connection messages are valid
+    ConnectionMessages,
+}
+
 #[allow(clippy::large_enum_variant)]
 enum ConnectionState<Db> {
     Handshake(Handshake),
@@ -26,40 +128,244 @@ enum ConnectionState<Db> {
         remote: HandshakeDone<Remote>,
         remote_mp: MessageParser<Db>,
     },
+    // the handshake is invalid and the valid one is required, the data is ignored
+    Discarded,
 }
 
 impl<Db> Connection<Db>
 where
     Db: Database,
 {
-    pub fn new(remote_addr: SocketAddr, incoming: bool, identity: Identity, db: Arc<Db>) -> Self {
-        let item = connection::Item::new(Initiator::new(incoming), remote_addr);
-        let state = ConnectionState::Handshake(Handshake::new(&item.key(), identity));
+    /// The `identities` of the nodes the connection might belong to,
+    /// usually one, several if the attribution is ambiguous
+    pub fn new(
+        remote_addr: SocketAddr,
+        incoming: bool,
+        identities: Vec<Identity>,
+        pow_target: f64,
+        options: CaptureOptions,
+        db: Arc<Db>,
+    ) -> Self {
+        let now = db.clock().now();
+        let item = connection::Item::new(Initiator::new(incoming), remote_addr, now);
+        Self::with_item(item, identities, pow_target, options, db)
+    }
+
+    /// The connection already stored, its data is fed again and decoded under the same key,
+    /// the stored record is updated rather than stored anew, see `decode_stored`
+    pub fn resume(
+        item: connection::Item,
+        identities: Vec<Identity>,
+        pow_target: f64,
+        db: Arc<Db>,
+    ) -> Self {
+        let options = CaptureOptions::default();
+        Connection {
+            resumed: true,
+            ..Self::with_item(item, identities, pow_target, options, db)
+        }
+    }
+
+    fn with_item(
+        item: connection::Item,
+        identities: Vec<Identity>,
+        pow_target: f64,
+        options: CaptureOptions,
+        db: Arc<Db>,
+    ) -> Self {
+        let clock = db.clock();
+        let handshake = Handshake::new(&item.key(), identities, pow_target, clock.clone())
+            .with_drop_duplicate(options.drop_duplicate_cm);
+        let state = ConnectionState::Handshake(handshake);
         Connection {
             state: Some(state),
             item,
+            rate: None,
+            options,
+            geoip: None,
+            stage: HandshakeStage::Initial,
+            created: clock.instant(),
+            last_data: clock.instant(),
+            bytes_incoming: 0,
+            bytes_outgoing: 0,
+            last_activity: clock.now_millis(),
+            finalized: false,
+            resumed: false,
+            candidates: vec![],
+            clock,
             db,
         }
     }
 
-    pub fn handle_data(&mut self, payload: &[u8], net: bool, incoming: bool) {
+    /// The connection accepted on the listening socket at the port
+    pub fn set_listen_port(&mut self, port: u16) {
+        self.item.set_listen_port(port);
+    }
+
+    pub fn set_unix_path(&mut self, path: String) {
+        self.item.set_unix_path(path);
+    }
+
+    /// Report the peer sending too many messages, see `RateLimit`
+    pub fn with_rate_monitor(self, rate: Option<RateMonitor>) -> Self {
+        Connection { rate, ..self }
+    }
+
+    /// Resolve the country and the asn of the peer once the connection is stored
+    pub fn with_geoip(self, geoip: Option<Enricher>) -> Self {
+        Connection { geoip, ..self }
+    }
+
+    /// Decrypt the connection with the `key` instead of the identity,
+    /// must be called before any data
+    pub fn with_precomputed_key(self, key: Option<[u8; 32]>) -> Self {
+        let state = match self.state {
+            Some(ConnectionState::Handshake(h)) if key.is_some() => {
+                Some(ConnectionState::Handshake(h.with_precomputed_key(key)))
+            },
+            state => state,
+        };
+        Connection { state, ..self }
+    }
+
+    /// The nodes other than the one of the database the connection might belong to,
+    /// the connection is stored by the node whose identity makes the keys
+    pub fn with_candidates(self, candidates: Vec<Candidate<Db>>) -> Self {
+        Connection { candidates, ..self }
+    }
+
+    pub fn key(&self) -> connection::Key {
+        self.item.key()
+    }
+
+    pub fn stage(&self) -> HandshakeStage {
+        self.stage
+    }
+
+    /// The live counters of the connection
+    pub fn active(&self) -> ActiveConnection {
+        ActiveConnection {
+            id: self.item.key(),
+            remote_addr: self.item.remote_addr,
+            incoming: self.item.initiator.incoming(),
+            bytes_incoming: self.bytes_incoming,
+            bytes_outgoing: self.bytes_outgoing,
+            last_activity: self.last_activity,
+        }
+    }
+
+    /// The valid handshake is required, but it is invalid, or it did not complete in time,
+    /// the connection should be dropped, nothing of it is stored
+    pub fn is_expired(&self) -> bool {
+        let timeout = match self.options.handshake_timeout {
+            Some(timeout) => timeout,
+            None => return false,
+        };
+        match &self.state {
+            Some(ConnectionState::Discarded) => true,
+            Some(ConnectionState::Handshake(_)) => self.elapsed(self.created) >= timeout,
+            _ => false,
+        }
+    }
+
+    /// No data within the `idle_timeout`, the close of the socket was likely lost,
+    /// the connection should be finalized to free its buffers
+    pub fn is_idle(&self) -> bool {
+        self.options.idle_timeout
+            .is_some_and(|timeout| self.elapsed(self.last_data) >= timeout)
+    }
+
+    fn elapsed(&self, since: Instant) -> Duration {
+        self.clock.instant().saturating_duration_since(since)
+    }
+
+    /// The `event` is stored along with the chunks completed by the payload
+    pub fn handle_data(
+        &mut self,
+        payload: &[u8],
+        net: bool,
+        incoming: bool,
+        event: Option<chunk_event::Value>,
+    ) {
+        if self.finalized {
+            log::debug!("connection: {} is finalized, ignore data", self.item.key());
+            return;
+        }
+        self.last_data = self.clock.instant();
+        self.last_activity = self.clock.now_millis();
+        if incoming {
+            self.bytes_incoming += payload.len() as u64;
+        } else {
+            self.bytes_outgoing += payload.len() as u64;
+        }
+        let _span = tracing::debug_span!(
+            target: telemetry::TARGET,
+            "handle_data",
+            connection = %self.item.key(),
+            incoming,
+        )
+        .entered();
         let state = match self.state.take().unwrap() {
             ConnectionState::Handshake(h) => {
                 match h.handle_data(payload, net, incoming, &mut self.item) {
                     Either::Left(h) => ConnectionState::Handshake(h),
+                    Either::Right(_) if !self.check_handshake() => {
+                        log::debug!(
+                            "discard connection: {}, invalid handshake",
+                            self.item.remote_addr,
+                        );
+                        ConnectionState::Discarded
+                    },
                     Either::Right(HandshakeOutput {
                         local,
                         l_chunk,
                         remote,
                         r_chunk,
+                        crypto,
                     }) => {
-                        let mut local_mp = MessageParser::new(self.db.clone());
-                        let mut remote_mp = MessageParser::new(self.db.clone());
-                        self.db.store_connection(self.item.clone());
+                        // the keys are made by the identity of the local node
+                        let candidates = std::mem::take(&mut self.candidates);
+                        let local_pk = crypto.as_ref().map(|crypto| crypto.local_pk);
+                        let node = candidates
+                            .into_iter()
+                            .find(|c| Some(c.public_key) == local_pk);
+                        if let Some(Candidate { db, geoip, .. }) = node {
+                            self.db = db;
+                            self.geoip = geoip;
+                        }
+                        let mut local_mp = MessageParser::new(self.db.clone())
+                            .with_hash(self.options.message_hash)
+                            .with_max_size(self.options.max_message_size)
+                            .with_chunk_storage(self.options.chunk_storage)
+                            .with_chunk_sample(self.options.chunk_sample)
+                            .with_capture_types(self.options.capture_types.clone());
+                        let mut remote_mp = MessageParser::new(self.db.clone())
+                            .with_hash(self.options.message_hash)
+                            .with_max_size(self.options.max_message_size)
+                            .with_chunk_storage(self.options.chunk_storage)
+                            .with_chunk_sample(self.options.chunk_sample)
+                            .with_capture_types(self.options.capture_types.clone());
+                        let ddb_version = self.options.ddb_version;
+                        if let (None, Some(version)) = (self.item.version(), ddb_version) {
+                            self.item.set_version(version);
+                        }
+                        if self.resumed {
+                            self.db.update_connection(self.item.clone());
+                        } else {
+                            self.db.store_connection(self.item.clone());
+                        }
+                        if let Some(geoip) = &self.geoip {
+                            geoip.enqueue(self.item.key(), self.item.remote_addr.ip());
+                        }
+                        if let Some(crypto) = crypto.filter(|_| self.options.debug_crypto) {
+                            self.db.store_connection_crypto(self.item.key(), crypto);
+                        }
                         if let Some(chunk) = l_chunk {
+                            local_mp.set_event(event.filter(|_| !incoming));
                             local_mp.handle_chunk(chunk, &mut self.item);
                         }
                         if let Some(chunk) = r_chunk {
+                            remote_mp.set_event(event.filter(|_| incoming));
                             remote_mp.handle_chunk(chunk, &mut self.item);
                         }
                         ConnectionState::HandshakeDone {
@@ -78,6 +384,7 @@ where
                 mut remote_mp,
             } => {
                 if !incoming {
+                    local_mp.set_event(event);
                     ConnectionState::HandshakeDone {
                         local: local.handle_data(payload, net, &mut self.item, &mut local_mp),
                         local_mp,
@@ -85,18 +392,54 @@ where
                         remote_mp,
                     }
                 } else {
+                    remote_mp.set_event(event);
+                    let remote = remote.handle_data(payload, net, &mut self.item, &mut remote_mp);
+                    self.check_rate(remote_mp.take_messages());
                     ConnectionState::HandshakeDone {
                         local,
                         local_mp,
-                        remote: remote.handle_data(payload, net, &mut self.item, &mut remote_mp),
+                        remote,
                         remote_mp,
                     }
                 }
             },
+            ConnectionState::Discarded => ConnectionState::Discarded,
         };
         self.state = Some(state);
     }
 
+    /// Update the stage when the connection messages arrived,
+    /// `false` if the connection should be discarded
+    fn check_handshake(&mut self) -> bool {
+        let comments = self.item.comments();
+        let valid = !comments.incoming_uncertain
+            && !comments.outgoing_uncertain
+            && comments.incoming_too_short.is_none()
+            && comments.incoming_wrong_pow.is_none()
+            && comments.outgoing_too_short.is_none();
+        self.stage = if valid {
+            HandshakeStage::ConnectionMessages
+        } else {
+            HandshakeStage::Invalid
+        };
+        valid || self.options.handshake_timeout.is_none()
+    }
+
+    fn check_rate(&mut self, messages: u32) {
+        let rate = match &mut self.rate {
+            Some(rate) if messages != 0 => rate,
+            _ => return,
+        };
+        let now = self.clock.instant();
+        if let Some(alert) = rate.record(now, messages, self.item.remote_addr) {
+            alert.report(rate.ban_list());
+            let comments = self.item.add_comment();
+            let max = comments.incoming_rate_exceeded.unwrap_or(0).max(alert.messages);
+            comments.incoming_rate_exceeded = Some(max);
+            self.db.update_connection(self.item.clone());
+        }
+    }
+
     pub fn warn_fd_changed(&self) {
         if !matches!(&self.state, &Some(ConnectionState::Handshake(ref h)) if h.is_empty()) {
             log::warn!(
@@ -106,5 +449,558 @@ where
         }
     }
 
-    pub fn join(self) {}
+    /// The first reason is kept, it is stored when the connection is joined
+    pub fn set_close_reason(&mut self, reason: connection::CloseReason) {
+        self.item.set_close_reason(reason);
+    }
+
+    /// Close the connection though the socket is still open, store the incomplete chunks,
+    /// the data after it is ignored, see `CloseReason::Manual`, unless the reason is already set
+    pub fn finalize(&mut self) {
+        self.item.set_close_reason(connection::CloseReason::Manual);
+        if let Some(ConnectionState::HandshakeDone {
+            local,
+            local_mp,
+            remote,
+            remote_mp,
+        }) = &mut self.state
+        {
+            local.flush(&mut self.item, local_mp);
+            remote.flush(&mut self.item, remote_mp);
+        }
+        self.finalized = true;
+    }
+
+    pub fn join(self) {
+        // the connection is stored when the handshake is done, update it with the close reason
+        if let Some(ConnectionState::HandshakeDone { .. }) = &self.state {
+            if self.item.close_reason().is_some() {
+                self.db.update_connection(self.item);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+    use crate::{
+        database::{rocks::Db, temp::TempDb, DatabaseNew, DatabaseFetch, ConnectionsFilter},
+        tables::connection::CloseReason,
+        system::NodeStatus,
+    };
+    use super::{
+        Connection, CaptureOptions, HandshakeStage,
+        super::fixture::{self, chunk, connection_message},
+    };
+
+    #[test]
+    fn close_reason() {
+        let path = TempDb::new("close");
+        let db = Arc::new(path.open());
+
+        // without identity the handshake is done as soon as both connection messages arrive
+        let address = "51.15.220.7:9732".parse().unwrap();
+        let target = NodeStatus::DEFAULT_POW_TARGET;
+        let mut connection =
+            Connection::new(address, false, vec![], target, Default::default(), db.clone());
+        connection.handle_data(&chunk(1), true, false, None);
+        connection.handle_data(&chunk(2), true, true, None);
+        // the peer resets the connection, then the node closes the socket
+        connection.set_close_reason(CloseReason::from_error_code(-104));
+        connection.set_close_reason(CloseReason::Close);
+        connection.join();
+
+        let filter = |close_reason| ConnectionsFilter { close_reason, ..Default::default() };
+        let connections = db.fetch_connections(&filter(None)).unwrap();
+        assert_eq!(connections.len(), 1);
+        assert_eq!(connections[0].1.close_reason(), Some(CloseReason::Reset));
+        let json = serde_json::to_value(&connections[0].1).unwrap();
+        assert_eq!(json["close_reason"], "reset");
+
+        let reset = db.fetch_connections(&filter(Some(CloseReason::Reset))).unwrap();
+        assert_eq!(reset.len(), 1);
+        let closed = db.fetch_connections(&filter(Some(CloseReason::Close))).unwrap();
+        assert!(closed.is_empty());
+    }
+
+    #[test]
+    fn finalize() {
+        use crate::database::{Database, ChunksFilter};
+
+        let path = TempDb::new("final");
+        let db = Arc::new(path.open());
+
+        let address = "51.15.220.7:9732".parse().unwrap();
+        let target = NodeStatus::DEFAULT_POW_TARGET;
+        let mut connection =
+            Connection::new(address, false, vec![], target, Default::default(), db.clone());
+        connection.handle_data(&chunk(1), true, false, None);
+        connection.handle_data(&chunk(2), true, true, None);
+        connection.finalize();
+        // the socket is still open, but the data is ignored
+        connection.handle_data(&chunk(3), true, false, None);
+        connection.handle_data(&chunk(4), true, true, None);
+        // the node closes the socket later, the first reason is kept
+        connection.set_close_reason(CloseReason::Close);
+        let cn_id = connection.key();
+        connection.join();
+        db.flush();
+
+        let filter = ConnectionsFilter {
+            close_reason: Some(CloseReason::Manual),
+            ..Default::default()
+        };
+        let connections = db.fetch_connections(&filter).unwrap();
+        assert_eq!(connections.len(), 1);
+        let json = serde_json::to_value(&connections[0].1).unwrap();
+        assert_eq!(json["close_reason"], "manual");
+
+        let filter = ChunksFilter {
+            limit: None,
+            cn: Some(cn_id.to_string()),
+            preview: None,
+        };
+        let chunks = db.fetch_chunks_truncated(&filter).unwrap();
+        assert_eq!(chunks.len(), 2);
+    }
+
+    #[test]
+    fn unix_socket() {
+        let path = TempDb::new("unix");
+        let db = Arc::new(path.open());
+
+        // the connection is stored when both connection messages arrive
+        let target = NodeStatus::DEFAULT_POW_TARGET;
+        for (unix_path, session) in &[("/run/tezos/rpc.ipc", None), ("@tezos-node", Some("ipc"))] {
+            let address = "0.0.0.0:0".parse().unwrap();
+            let mut connection =
+                Connection::new(address, true, vec![], target, Default::default(), db.clone());
+            connection.set_unix_path(unix_path.to_string());
+            connection.item.set_session(session.map(str::to_string));
+            connection.handle_data(&chunk(1), true, false, None);
+            connection.handle_data(&chunk(2), true, true, None);
+            connection.join();
+        }
+
+        let filter = ConnectionsFilter::default();
+        let mut connections = db.fetch_connections(&filter).unwrap();
+        connections.sort_by_key(|(key, _)| (key.ts, key.ts_nanos));
+        assert_eq!(connections.len(), 2);
+        assert_eq!(connections[0].1.unix_path(), Some("/run/tezos/rpc.ipc"));
+        assert_eq!(connections[0].1.session(), None);
+        assert_eq!(connections[1].1.unix_path(), Some("@tezos-node"));
+        assert_eq!(connections[1].1.session(), Some("ipc"));
+        let json = serde_json::to_value(&connections[1].1).unwrap();
+        assert_eq!(json["remote_addr"], "@tezos-node");
+    }
+
+    #[test]
+    fn pow_valid() {
+        let path = TempDb::new("pow");
+        let db = Arc::new(path.open());
+
+        let valid_stamp = fixture::VALID_STAMP;
+        let invalid_stamp = "000000000000000000000000000000000000000000000000";
+        let connection_message = |stamp: &str| {
+            let pk = hex::decode(fixture::PK_I).unwrap();
+            fixture::connection_message_pow(&pk, &hex::decode(stamp).unwrap())
+        };
+
+        let address = "51.15.220.7:9732".parse().unwrap();
+        for stamp in &[valid_stamp, invalid_stamp] {
+            // the connection messages are checked even without identity
+            let mut connection =
+                Connection::new(address, false, vec![], 16.0, Default::default(), db.clone());
+            connection.handle_data(&connection_message(valid_stamp), true, false, None);
+            connection.handle_data(&connection_message(stamp), true, true, None);
+            connection.join();
+        }
+
+        let filter = |pow_valid| ConnectionsFilter { pow_valid, ..Default::default() };
+        let valid = db.fetch_connections(&filter(Some(true))).unwrap();
+        assert_eq!(valid.len(), 1);
+        assert!(valid[0].1.comments().incoming_wrong_pow.is_none());
+        let json = serde_json::to_value(&valid[0].1).unwrap();
+        assert_eq!(json["pow_valid"], true);
+        assert_eq!(json["peer_id"], "idssJHDL1z8fkryZaYVF9fQRMktoWg");
+
+        let invalid = db.fetch_connections(&filter(Some(false))).unwrap();
+        assert_eq!(invalid.len(), 1);
+        assert_eq!(invalid[0].1.comments().incoming_wrong_pow, Some(16.0));
+        assert_eq!(db.fetch_connections(&filter(None)).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn metadata_flags() {
+        use crypto::{crypto_box::PrecomputedKey, nonce::generate_nonces};
+
+        let path = TempDb::new("meta");
+        let db = Arc::new(path.open());
+
+        let (l_cm, r_cm) = (connection_message(&[1; 32]), connection_message(&[2; 32]));
+        let nonces = generate_nonces(&l_cm, &r_cm, false).unwrap();
+        let key = [0x5a; 32];
+        // the metadata message is two booleans, `disable_mempool` and `private_node`
+        let metadata = |flags: [u8; 2], remote: bool| {
+            let nonce = if remote { &nonces.remote } else { &nonces.local };
+            fixture::encrypted(&PrecomputedKey::from_bytes(key), &flags, nonce)
+        };
+
+        // a private node, and a peer with the mempool disabled
+        for (addr, flags) in &[("51.15.220.7:9732", [0, 0xff]), ("51.15.220.8:9732", [0xff, 0])] {
+            let address = addr.parse().unwrap();
+            let mut connection =
+                Connection::new(address, false, vec![], 0.0, Default::default(), db.clone())
+                    .with_precomputed_key(Some(key));
+            connection.handle_data(&l_cm, true, false, None);
+            connection.handle_data(&r_cm, true, true, None);
+            connection.handle_data(&metadata([0, 0], false), true, false, None);
+            connection.handle_data(&metadata(*flags, true), true, true, None);
+            connection.join();
+        }
+
+        let filter = |private_node, disable_mempool| ConnectionsFilter {
+            private_node,
+            disable_mempool,
+            ..Default::default()
+        };
+        let private = db.fetch_connections(&filter(Some(true), None)).unwrap();
+        assert_eq!(private.len(), 1);
+        let json = serde_json::to_value(&private[0].1).unwrap();
+        assert_eq!(json["remote_addr"], "51.15.220.7:9732");
+        assert_eq!(json["peer_metadata"]["private_node"], true);
+        assert_eq!(json["peer_metadata"]["disable_mempool"], false);
+        assert_eq!(json["local_metadata"]["private_node"], false);
+        // the flags share the byte with the initiator
+        assert_eq!(json["initiator"], "local");
+
+        let no_mempool = db.fetch_connections(&filter(None, Some(true))).unwrap();
+        assert_eq!(no_mempool.len(), 1);
+        assert_eq!(no_mempool[0].1.peer_metadata().map(|m| m.private_node), Some(false));
+        assert_eq!(db.fetch_connections(&filter(Some(false), Some(false))).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn duplicate_connection_message() {
+        use crypto::{crypto_box::PrecomputedKey, nonce::generate_nonces};
+
+        let path = TempDb::new("dup");
+        let db = Arc::new(path.open());
+
+        let (l_cm, r_cm) = (connection_message(&[1; 32]), connection_message(&[2; 32]));
+        let nonces = generate_nonces(&l_cm, &r_cm, false).unwrap();
+        let key = [0x5a; 32];
+        let metadata = |flags: [u8; 2], remote: bool| {
+            let nonce = if remote { &nonces.remote } else { &nonces.local };
+            fixture::encrypted(&PrecomputedKey::from_bytes(key), &flags, nonce)
+        };
+
+        // the local connection message is seen twice, before or after the peer answers,
+        // the last connection keeps the copy
+        let cases = [
+            ("51.15.220.7:9732", true, true),
+            ("51.15.220.8:9732", false, true),
+            ("51.15.220.9:9732", true, false),
+        ];
+        for &(addr, early, drop_duplicate) in &cases {
+            let address = addr.parse().unwrap();
+            let options = CaptureOptions {
+                drop_duplicate_cm: drop_duplicate,
+                ..Default::default()
+            };
+            let mut connection = Connection::new(address, false, vec![], 0.0, options, db.clone())
+                .with_precomputed_key(Some(key));
+            connection.handle_data(&l_cm, true, false, None);
+            if early {
+                connection.handle_data(&l_cm, true, false, None);
+            }
+            connection.handle_data(&r_cm, true, true, None);
+            if !early {
+                connection.handle_data(&l_cm, true, false, None);
+            }
+            connection.handle_data(&metadata([0, 0xff], false), true, false, None);
+            connection.handle_data(&metadata([0, 0], true), true, true, None);
+            connection.join();
+        }
+
+        let filter = ConnectionsFilter::default();
+        let connections = db
+            .fetch_connections(&filter)
+            .unwrap()
+            .into_iter()
+            .map(|(_, c)| serde_json::to_value(&c).unwrap())
+            .map(|json| (json["remote_addr"].as_str().unwrap().to_string(), json))
+            .collect::<HashMap<_, _>>();
+        assert_eq!(connections.len(), 3);
+        for addr in &["51.15.220.7:9732", "51.15.220.8:9732"] {
+            let json = &connections[*addr];
+            assert_eq!(json["local_metadata"]["private_node"], true);
+            assert_eq!(json["peer_metadata"]["private_node"], false);
+        }
+        // the copy is taken for the first encrypted chunk, it cannot be decrypted
+        let json = &connections["51.15.220.9:9732"];
+        assert!(json["local_metadata"].is_null());
+        assert_eq!(json["peer_metadata"]["private_node"], false);
+    }
+
+    #[test]
+    fn key_changed() {
+        use crypto::{crypto_box::PrecomputedKey, nonce::generate_nonces};
+        use crate::{common::Sender, database::{Database, ChunksFilter}};
+
+        let path = TempDb::new("rekey");
+        let db = Arc::new(path.open());
+
+        let (l_cm, r_cm) = (connection_message(&[1; 32]), connection_message(&[2; 32]));
+        let nonces = generate_nonces(&l_cm, &r_cm, false).unwrap();
+        let (key, other_key) = ([0x5a; 32], [0xa5; 32]);
+        // the chunk of the peer with the nonce of its `index`-th encrypted chunk
+        let encrypted = |plain: &[u8], key: [u8; 32], index: usize| {
+            let mut nonce = nonces.remote.clone();
+            for _ in 0..index {
+                nonce = nonce.increment();
+            }
+            fixture::encrypted(&PrecomputedKey::from_bytes(key), plain, &nonce)
+        };
+        // metadata, ack, get_current_branch
+        let mut branch = vec![0, 0, 0, 20, 0, 0x10];
+        branch.resize(24, 0xab);
+        let plain = [vec![0, 0], vec![0], branch.clone(), branch.clone(), branch];
+
+        // the key changes at the last chunk, the chunk is lost, the key is wrong from the start
+        let cases = [
+            ("51.15.220.7:9732", vec![key, key, key, key, other_key], vec![0, 1, 2, 3, 4]),
+            ("51.15.220.8:9732", vec![key, key, key, key], vec![0, 1, 2, 4]),
+            ("51.15.220.9:9732", vec![other_key, other_key], vec![0, 1]),
+        ];
+        let mut keys = HashMap::new();
+        for (addr, chunk_keys, indexes) in &cases {
+            let address = addr.parse().unwrap();
+            let mut connection =
+                Connection::new(address, false, vec![], 0.0, Default::default(), db.clone())
+                    .with_precomputed_key(Some(key));
+            connection.handle_data(&l_cm, true, false, None);
+            connection.handle_data(&r_cm, true, true, None);
+            for (chunk_key, &index) in chunk_keys.iter().zip(indexes) {
+                let chunk = encrypted(&plain[index], *chunk_key, index);
+                connection.handle_data(&chunk, true, true, None);
+            }
+            keys.insert(addr.to_string(), connection.key());
+            connection.join();
+        }
+        db.flush();
+
+        let filter = ConnectionsFilter::default();
+        let connections = db
+            .fetch_connections(&filter)
+            .unwrap()
+            .into_iter()
+            .map(|(_, c)| {
+                let json = serde_json::to_value(&c).unwrap();
+                (json["remote_addr"].as_str().unwrap().to_string(), c)
+            })
+            .collect::<HashMap<_, _>>();
+        assert_eq!(connections.len(), 3);
+
+        // decrypted four chunks, then broke at the chunk 5, the connection message is the chunk 0
+        let comments = connections["51.15.220.7:9732"].comments();
+        assert_eq!(comments.incoming_cannot_decrypt, Some(5));
+        assert!(comments.incoming_key_changed);
+        let text = serde_json::to_string(comments).unwrap();
+        assert!(text.contains("the key might have changed"));
+
+        // the nonce is resynced, the counters show the lost chunk
+        let c = &connections["51.15.220.8:9732"];
+        assert_eq!(c.comments().incoming_cannot_decrypt, None);
+        assert!(!c.comments().incoming_key_changed);
+        assert_eq!(c.close_reason(), None);
+        let filter = ChunksFilter {
+            limit: None,
+            cn: Some(keys["51.15.220.8:9732"].to_string()),
+            preview: None,
+        };
+        let mut counters = db
+            .fetch_chunks_truncated(&filter)
+            .unwrap()
+            .into_iter()
+            .filter(|(k, _)| matches!(k.sender, Sender::Remote))
+            .map(|(k, _)| k.counter)
+            .collect::<Vec<_>>();
+        counters.sort_unstable();
+        assert_eq!(counters, [0, 1, 2, 3, 5]);
+
+        // nothing was decrypted, the key is wrong rather than changed
+        let comments = connections["51.15.220.9:9732"].comments();
+        assert_eq!(comments.incoming_cannot_decrypt, Some(1));
+        assert!(!comments.incoming_key_changed);
+    }
+
+    #[test]
+    fn handshake_timeout() {
+        use std::time::{Duration, UNIX_EPOCH};
+        use crate::database::{Database, clock::MockClock};
+
+        let path = TempDb::new("stage");
+        let start = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let clock = Arc::new(MockClock::new(start));
+        let db = Db::open(&path, false, None, None, Default::default())
+            .unwrap()
+            .with_clock(clock.clone());
+        let db = Arc::new(db);
+
+        // the public key from `identity_i.json` with the stamp valid for the target 16
+        let connection_message = fixture::connection_message_pow(
+            &hex::decode(fixture::PK_I).unwrap(),
+            &hex::decode(fixture::VALID_STAMP).unwrap(),
+        );
+        let garbage = chunk(2);
+
+        let address = "51.15.220.7:9732".parse().unwrap();
+        let options = CaptureOptions {
+            handshake_timeout: Some(Duration::from_secs(10)),
+            ..Default::default()
+        };
+        let new_connection = || {
+            Connection::new(address, false, vec![], 16.0, options.clone(), db.clone())
+        };
+
+        // the peer answers with garbage, the connection is discarded at once
+        let mut scan = new_connection();
+        scan.handle_data(&connection_message, true, false, None);
+        scan.handle_data(&garbage, true, true, None);
+        assert_eq!(scan.stage(), HandshakeStage::Invalid);
+        assert!(scan.is_expired());
+        scan.join();
+
+        // the peer sends nothing, the connection expires after the timeout
+        let mut silent = new_connection();
+        silent.handle_data(&connection_message, true, false, None);
+        assert_eq!(silent.stage(), HandshakeStage::Initial);
+        clock.advance(Duration::from_secs(9));
+        assert!(!silent.is_expired());
+        clock.advance(Duration::from_secs(1));
+        assert!(silent.is_expired());
+        silent.join();
+
+        // both connection messages are valid, the connection is stored
+        let mut valid = new_connection();
+        valid.handle_data(&connection_message, true, false, None);
+        valid.handle_data(&connection_message, true, true, None);
+        assert_eq!(valid.stage(), HandshakeStage::ConnectionMessages);
+        clock.advance(Duration::from_secs(10));
+        assert!(!valid.is_expired());
+        valid.join();
+
+        let filter = ConnectionsFilter::default();
+        let connections = db.fetch_connections(&filter).unwrap();
+        assert_eq!(connections.len(), 1);
+        assert_eq!(connections[0].1.comments().incoming_wrong_pow, None);
+    }
+
+    #[test]
+    fn idle_timeout() {
+        use std::time::{Duration, UNIX_EPOCH};
+        use crate::database::{Database, clock::MockClock};
+
+        let path = TempDb::new("idle");
+        let start = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let clock = Arc::new(MockClock::new(start));
+        let db = Db::open(&path, false, None, None, Default::default())
+            .unwrap()
+            .with_clock(clock.clone());
+        let db = Arc::new(db);
+
+        let address = "51.15.220.7:9732".parse().unwrap();
+        let target = NodeStatus::DEFAULT_POW_TARGET;
+        let options = CaptureOptions {
+            idle_timeout: Some(Duration::from_secs(10)),
+            ..Default::default()
+        };
+        let mut connection = Connection::new(address, false, vec![], target, options, db.clone());
+        connection.handle_data(&chunk(1), true, false, None);
+        clock.advance(Duration::from_secs(5));
+        connection.handle_data(&chunk(2), true, true, None);
+
+        // the timeout counts from the last data, not from the start of the connection
+        clock.advance(Duration::from_secs(9));
+        assert!(!connection.is_idle());
+        clock.advance(Duration::from_secs(1));
+        assert!(connection.is_idle());
+        connection.finalize();
+        connection.join();
+        db.flush();
+
+        let filter = ConnectionsFilter::default();
+        let connections = db.fetch_connections(&filter).unwrap();
+        assert_eq!(connections.len(), 1);
+        // the record is stamped by the clock of the database
+        assert_eq!(connections[0].1.ts, 1_600_000_000);
+    }
+
+    #[test]
+    fn pipeline_spans() {
+        use std::{fmt::{self, Write}, sync::Mutex};
+        use tracing::{
+            span::{Attributes, Id},
+            field::{Field, Visit},
+            Subscriber,
+        };
+        use tracing_subscriber::{layer::{Context, SubscriberExt}, Layer};
+        use crate::telemetry::TARGET;
+
+        // the mock exporter, records the name and the fields of each span
+        #[derive(Clone, Default)]
+        struct Exporter(Arc<Mutex<Vec<String>>>);
+
+        struct Fields(String);
+
+        impl Visit for Fields {
+            fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+                let _ = write!(self.0, " {}={:?}", field.name(), value);
+            }
+        }
+
+        impl<S> Layer<S> for Exporter
+        where
+            S: Subscriber,
+        {
+            fn new_span(&self, attrs: &Attributes<'_>, _: &Id, _: Context<'_, S>) {
+                if attrs.metadata().target() == TARGET {
+                    let mut fields = Fields(attrs.metadata().name().to_string());
+                    attrs.record(&mut fields);
+                    self.0.lock().unwrap().push(fields.0);
+                }
+            }
+        }
+
+        let path = TempDb::new("spans");
+        let db = Arc::new(path.open());
+
+        let address = "51.15.220.7:9732".parse().unwrap();
+        let target = NodeStatus::DEFAULT_POW_TARGET;
+        let mut connection =
+            Connection::new(address, false, vec![], target, Default::default(), db.clone());
+        let id = connection.item.key().to_string();
+
+        let exporter = Exporter::default();
+        let subscriber = tracing_subscriber::registry().with(exporter.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            // the handshake is done, each connection message is stored
+            connection.handle_data(&chunk(1), true, false, None);
+            connection.handle_data(&chunk(2), true, true, None);
+        });
+        connection.join();
+
+        let message = format!("store_message connection={} message_type=Connection", id);
+        assert_eq!(
+            *exporter.0.lock().unwrap(),
+            [
+                format!("handle_data connection={} incoming=false", id),
+                format!("handle_data connection={} incoming=true", id),
+                message.clone(),
+                message,
+            ],
+        );
+    }
 }
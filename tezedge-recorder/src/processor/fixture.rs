@@ -0,0 +1,56 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use crypto::{
+    crypto_box::{CryptoKey, PrecomputedKey, PublicKey, SecretKey},
+    nonce::{Nonce, generate_nonces},
+};
+use crate::system::Identity;
+
+/// The public key from `identity_i.json`
+pub const PK_I: &str = "d8246d13d0270cbfff4046b6d94b05ab19920bc5ad9fb77f3e945c40b340e874";
+
+/// The stamp of `PK_I`, gives 21 leading zero bits of the hash, valid for the target 16
+pub const VALID_STAMP: &str = "0000000000000000000000000000000000000000001dc43f";
+
+/// The connection message with the public key `pk` and the proof of work `stamp`,
+/// the nonce is zero, the chain is `TEZOS_MAINNET`
+pub fn connection_message_pow(pk: &[u8], stamp: &[u8]) -> Vec<u8> {
+    let mut v = vec![0, 103, 0x26, 0x04];
+    v.extend_from_slice(pk);
+    v.extend_from_slice(stamp);
+    v.extend_from_slice(&[0; 24]);
+    v.extend_from_slice(&13u32.to_be_bytes());
+    v.extend_from_slice(b"TEZOS_MAINNET");
+    v.extend_from_slice(&[0, 1, 0, 1]);
+    v
+}
+
+/// The connection message with the zero stamp
+pub fn connection_message(pk: &[u8]) -> Vec<u8> {
+    connection_message_pow(pk, &[0; 24])
+}
+
+/// The chunk of 100 bytes `b`, without identity two of them complete the handshake
+pub fn chunk(b: u8) -> Vec<u8> {
+    let mut v = vec![0, 100];
+    v.extend_from_slice(&[b; 100]);
+    v
+}
+
+/// The chunk of the `plain` bytes encrypted by the `key` with the `nonce`
+pub fn encrypted(key: &PrecomputedKey, plain: &[u8], nonce: &Nonce) -> Vec<u8> {
+    let encrypted = key.encrypt(plain, nonce).unwrap();
+    let mut v = (encrypted.len() as u16).to_be_bytes().to_vec();
+    v.extend_from_slice(&encrypted);
+    v
+}
+
+/// The metadata the remote peer sends encrypted for the `local` node, both flags are false
+pub fn metadata(local: &Identity, l_cm: &[u8], r_cm: &[u8], incoming: bool) -> Vec<u8> {
+    let pk = PublicKey::from_bytes(&r_cm[4..36]).unwrap();
+    let sk = SecretKey::from_bytes(&local.secret_key).unwrap();
+    let key = PrecomputedKey::precompute(&pk, &sk);
+    let nonces = generate_nonces(l_cm, r_cm, incoming).unwrap();
+    encrypted(&key, &[0, 0], &nonces.remote)
+}
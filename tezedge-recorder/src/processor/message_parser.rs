@@ -1,16 +1,81 @@
 // Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
 // SPDX-License-Identifier: MIT
 
-use std::sync::Arc;
+use std::{borrow::Cow, sync::Arc};
+use serde::{Serialize, Deserialize};
 use super::{
     chunk_parser::ChunkHandler,
+    compression::Compression,
     Database,
-    tables::{connection, chunk, message},
+    database::clock::Clock,
+    tables::{connection, chunk, chunk_event, message, message_hash},
+    common::MessageType,
+    telemetry,
 };
 
+/// Which chunks are stored, the messages are stored anyway, but they are decoded
+/// from the chunks when requested, so without the chunks only the brief is known:
+/// the type, the direction, the size and the hash
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChunkStorage {
+    All,
+    /// Only the chunks the recorder cannot parse into messages
+    Failed,
+    None,
+}
+
+impl Default for ChunkStorage {
+    fn default() -> Self {
+        ChunkStorage::All
+    }
+}
+
+/// With `ChunkStorage::All`, which chunks are stored with the bytes, the rest are stored
+/// with the metadata only, the counters of each direction of the connection are sampled,
+/// the handshake chunks are needed to decrypt the connection, they are kept anyway
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkSample {
+    // the first chunks of each direction
+    pub first: Option<u64>,
+    // 1-in-N of the chunks after the first
+    pub every: Option<u64>,
+}
+
+impl ChunkSample {
+    pub const HANDSHAKE: u64 = 3;
+
+    pub fn payload(&self, counter: u64) -> bool {
+        let first = self.first.unwrap_or(0).max(Self::HANDSHAKE);
+        counter < first || self.every.filter(|&n| n != 0).map_or(false, |n| counter % n == 0)
+    }
+}
+
 pub struct MessageParser<Db> {
     builder: Option<message::MessageBuilder>,
+    // bytes of all chunks of the message being built
+    size: u32,
+    // messages built since the last `take_messages`
+    messages: u32,
     error: bool,
+    // the bpf event which delivered the data being parsed
+    event: Option<chunk_event::Value>,
+    // decrypted bytes of the message being built, if the hash is configured
+    plain: Option<Vec<u8>>,
+    // the peer messages longer than the limit are not decoded
+    max_size: Option<u32>,
+    // the message being built exceeds the limit
+    oversized: Option<message::Oversized>,
+    // the counter of the chunk expected next, a greater one means the chunks are lost
+    next_counter: u64,
+    chunk_storage: ChunkStorage,
+    chunk_sample: Option<ChunkSample>,
+    // if set, the messages of other types are parsed, but neither they nor their chunks are stored
+    capture_types: Option<Vec<MessageType>>,
+    // the message being built is not in `capture_types`
+    skip: bool,
+    // the timestamps of the messages, the clock of the database
+    clock: Arc<dyn Clock>,
     db: Arc<Db>,
 }
 
@@ -21,21 +86,138 @@ where
     pub fn new(db: Arc<Db>) -> Self {
         MessageParser {
             builder: None,
+            size: 0,
+            messages: 0,
             error: false,
+            event: None,
+            plain: None,
+            max_size: None,
+            oversized: None,
+            next_counter: 0,
+            chunk_storage: ChunkStorage::All,
+            chunk_sample: None,
+            capture_types: None,
+            skip: false,
+            clock: db.clock(),
             db,
         }
     }
+
+    /// Compute the blake2b of the decrypted bytes of each message
+    pub fn with_hash(self, hash: bool) -> Self {
+        MessageParser {
+            plain: if hash { Some(Vec::new()) } else { None },
+            ..self
+        }
+    }
+
+    /// Store a placeholder with the first `max_size` bytes instead of a longer message
+    pub fn with_max_size(self, max_size: Option<u32>) -> Self {
+        MessageParser { max_size, ..self }
+    }
+
+    pub fn with_chunk_storage(self, chunk_storage: ChunkStorage) -> Self {
+        MessageParser {
+            chunk_storage,
+            ..self
+        }
+    }
+
+    /// Store the bytes only of the sampled chunks, without the sample all are stored
+    pub fn with_chunk_sample(self, chunk_sample: Option<ChunkSample>) -> Self {
+        MessageParser {
+            chunk_sample,
+            ..self
+        }
+    }
+
+    /// Store only the messages of these types, the parser still follows all messages
+    pub fn with_capture_types(self, capture_types: Option<Vec<MessageType>>) -> Self {
+        MessageParser {
+            capture_types,
+            ..self
+        }
+    }
+
+    pub fn take_messages(&mut self) -> u32 {
+        std::mem::take(&mut self.messages)
+    }
+
+    pub fn set_event(&mut self, event: Option<chunk_event::Value>) {
+        self.event = event;
+    }
+
+    /// Store the flags of the metadata message on the connection, it is the second chunk
+    fn handle_metadata(&self, chunk: &chunk::Item, cn: &mut connection::Item) {
+        use tezos_messages::p2p::{binary_message::BinaryRead, encoding::metadata::MetadataMessage};
+
+        cn.set_compression(&chunk.sender, Compression::announced(&chunk.plain));
+        // the flags are the first two bytes, the decoder does not know the compression flag
+        match MetadataMessage::from_bytes(&chunk.plain[..2]) {
+            Ok(m) => {
+                let metadata = connection::Metadata {
+                    disable_mempool: m.disable_mempool(),
+                    private_node: m.private_node(),
+                };
+                cn.set_metadata(&chunk.sender, metadata);
+            },
+            Err(error) => {
+                log::debug!("connection: {}, cannot decode metadata: {}", cn.key(), error);
+            },
+        }
+        self.db.update_connection(cn.clone());
+    }
+
+    fn store_chunk(&self, mut chunk: chunk::Item) {
+        let sample = self.chunk_sample.filter(|_| self.chunk_storage == ChunkStorage::All);
+        if let Some(sample) = sample {
+            if !sample.payload(chunk.counter) {
+                chunk.strip_payload();
+            }
+        }
+        self.db.store_chunk(chunk);
+    }
 }
 
 impl<Db> ChunkHandler for MessageParser<Db>
 where
     Db: Database,
 {
-    fn handle_chunk(&mut self, chunk: chunk::Item, cn: &mut connection::Item) {
+    fn handle_chunk(&mut self, mut chunk: chunk::Item, cn: &mut connection::Item) {
         use std::convert::TryFrom;
         use self::message::MessageBuilder;
         use super::common::MessageKind;
 
+        chunk.event = self.event;
+        if chunk.counter > self.next_counter {
+            let missing = chunk.counter - self.next_counter;
+            log::warn!(
+                "connection: {}, {} chunks lost before chunk: {}",
+                cn.key(),
+                missing,
+                chunk.counter,
+            );
+            chunk.gap = Some(missing);
+        }
+        self.next_counter = self.next_counter.max(chunk.counter + 1);
+
+        let compression = Compression::from_flag(cn.compression());
+        if chunk.counter >= 3 && compression != Compression::None && !self.error {
+            match compression.decompress(&chunk.plain).map(Cow::into_owned) {
+                Some(plain) => chunk.plain = plain,
+                None => {
+                    // store the chunks as they are, the messages cannot be decoded
+                    log::warn!(
+                        "connection: {}, cannot decompress chunk: {}, compression: {:?}",
+                        cn.key(),
+                        chunk.counter,
+                        compression,
+                    );
+                    self.error = true;
+                },
+            }
+        }
+
         let too_small = match chunk.counter {
             0 => chunk.plain.len() < 82,
             1 => chunk.plain.len() < 2,
@@ -51,18 +233,65 @@ where
 
         if self.error || too_small {
             self.error = true;
-            if !chunk.bytes.is_empty() {
-                self.db.store_chunk(chunk);
+            if !chunk.bytes.is_empty() && self.chunk_storage != ChunkStorage::None {
+                self.store_chunk(chunk);
             }
             return;
         }
 
+        if let (Some(types), None) = (&self.capture_types, &self.builder) {
+            // the first chunk of the message tells its type
+            let ty = match chunk.counter {
+                0 => MessageType::Connection,
+                1 => MessageType::Meta,
+                2 => MessageType::Ack,
+                _ => {
+                    let tag = u16::from_be_bytes(<[u8; 2]>::try_from(&chunk.plain[4..6]).unwrap());
+                    MessageType::P2p(MessageKind::from_tag(tag))
+                },
+            };
+            self.skip = !types.contains(&ty);
+        }
+
         let sender = &chunk.sender;
+        self.size += chunk.bytes.len() as u32;
+        if let Some(max_size) = self.max_size.filter(|_| chunk.counter >= 3) {
+            if self.builder.is_none() {
+                // a new peer message, starts with 4 bytes length of the rest
+                let length = <[u8; 4]>::try_from(&chunk.plain[..4]).unwrap();
+                let size = u32::from_be_bytes(length).saturating_add(4);
+                self.oversized = Some(message::Oversized {
+                    size,
+                    head: Vec::new(),
+                })
+                .filter(|_| size > max_size);
+            }
+            if let Some(oversized) = &mut self.oversized {
+                let remaining = (max_size as usize).saturating_sub(oversized.head.len());
+                let head = &chunk.plain[..remaining.min(chunk.plain.len())];
+                oversized.head.extend_from_slice(head);
+            }
+        }
+        if let Some(plain) = &mut self.plain {
+            // a new message starts at the handshake chunk or when there is no builder
+            if chunk.counter < 3 || self.builder.is_none() {
+                plain.clear();
+            }
+            // do not buffer the message longer than the limit, it is not hashed
+            if self.oversized.is_none() && !self.skip {
+                plain.extend_from_slice(&chunk.plain);
+            }
+        }
 
+        if chunk.counter == 1 {
+            self.handle_metadata(&chunk, cn);
+        }
+
+        let now = self.clock.now();
         let message = match chunk.counter {
-            0 => Some(MessageBuilder::connection_message().build(&sender, &cn)),
-            1 => Some(MessageBuilder::metadata_message().build(&sender, &cn)),
-            2 => Some(MessageBuilder::acknowledge_message().build(&sender, &cn)),
+            0 => Some(MessageBuilder::connection_message().build(&sender, &cn, now)),
+            1 => Some(MessageBuilder::metadata_message().build(&sender, &cn, now)),
+            2 => Some(MessageBuilder::acknowledge_message().build(&sender, &cn, now)),
             c => {
                 let building_result = self
                     .builder
@@ -92,7 +321,7 @@ where
                     })
                     .link_chunk(chunk.plain.len());
                 match building_result {
-                    Ok(builder_full) => Some(builder_full.build(&sender, &cn)),
+                    Ok(builder_full) => Some(builder_full.build(&sender, &cn, now)),
                     Err(builder) => {
                         self.builder = builder;
                         None
@@ -101,8 +330,33 @@ where
             },
         };
 
-        self.db.store_chunk(chunk);
-        if let Some(message) = message {
+        // the handshake chunks are needed to decrypt the connection later, keep them anyway
+        let skip_chunk = self.skip && chunk.counter >= 3;
+        if self.chunk_storage == ChunkStorage::All && !skip_chunk {
+            self.store_chunk(chunk);
+        }
+        if let Some(mut message) = message {
+            message.size = self.size;
+            self.size = 0;
+            message.oversized = self.oversized.take();
+            if let Some(plain) = &mut self.plain {
+                if message.oversized.is_none() && !self.skip {
+                    message.hash = message_hash::hash(plain);
+                }
+                plain.clear();
+            }
+            // the rate limit counts all messages
+            self.messages += 1;
+            if self.skip {
+                return;
+            }
+            let _span = tracing::debug_span!(
+                target: telemetry::TARGET,
+                "store_message",
+                connection = %cn.key(),
+                message_type = ?message.ty,
+            )
+            .entered();
             self.db.store_message(message);
         }
     }
@@ -111,3 +365,925 @@ where
         self.db.update_connection(cn.clone());
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::SystemTime};
+    use super::{MessageParser, ChunkHandler, ChunkStorage, ChunkSample};
+    use crate::{
+        common::{Initiator, Sender, MessageKind, MessageType},
+        database::{
+            rocks::Db, Database, DatabaseNew, DatabaseFetch, MessagesFilter, ChunksFilter,
+            MessageTypesFilter, export::Checkpoint, temp::TempDb,
+        },
+        tables::{connection, chunk, chunk_event, message::MessageId},
+    };
+
+    // level 1, the level is the four bytes at offset 10
+    const BLOCK_HEADER: &str = "\
+        0000008b00210000008500000001011111111111111111111111111111111111111111111111111111111111\
+        111111000000005c8c4e50042222222222222222222222222222222222222222222222222222222222222222\
+        0000001100000001000000000800000000000000013333333333333333333333333333333333333333333333\
+        333333333333333333abcd";
+
+    #[test]
+    fn raw() {
+        let path = TempDb::new("raw");
+        let db = Arc::new(path.open());
+        let remote_addr = "51.15.220.7:9732".parse().unwrap();
+        let mut cn = connection::Item::new(Initiator::new(true), remote_addr, SystemTime::now());
+        let mut parser = MessageParser::new(db.clone());
+
+        // get_current_branch of 24 bytes split in three chunks
+        let mut plain = vec![0, 0, 0, 20, 0, 0x10];
+        plain.resize(24, 0xab);
+        for (i, piece) in plain.chunks(10).enumerate() {
+            let counter = 3 + i as u64;
+            parser.set_event(Some(chunk_event::Value {
+                pid: 7,
+                fd: 11,
+                ts: 1000 + counter,
+                net: true,
+                incoming: true,
+            }));
+            let bytes = piece.to_vec();
+            let chunk = chunk::Item::new(
+                cn.key(),
+                Sender::Remote,
+                counter,
+                bytes,
+                piece.to_vec(),
+                SystemTime::now(),
+            );
+            parser.handle_chunk(chunk, &mut cn);
+        }
+
+        let raw = db.fetch_message_raw(0).unwrap().unwrap();
+        let raw = serde_json::to_value(&raw).unwrap();
+        let chunks = raw["chunks"].as_array().unwrap();
+        let counters = chunks
+            .iter()
+            .map(|c| c["counter"].as_u64().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(counters, [3, 4, 5]);
+        assert_eq!(chunks[1]["plain"], hex::encode(&plain[10..20]));
+        assert_eq!(chunks[2]["event"]["ts"], 1005);
+        assert_eq!(chunks[2]["event"]["pid"], 7);
+        assert_eq!(chunks[2]["event"]["syscall"], "recv");
+        assert!(db.fetch_message_raw(1).unwrap().is_none());
+    }
+
+    #[test]
+    fn hash() {
+        let path = TempDb::new("hash");
+        let db = Arc::new(path.open());
+
+        // get_current_branch
+        let mut same = vec![0, 0, 0, 20, 0, 0x10];
+        same.resize(24, 0xab);
+        let mut other = same.clone();
+        other[23] = 0xcd;
+
+        let mut parsers = vec![];
+        for (addr, payloads) in [
+            ("51.15.220.7:9732", vec![&same]),
+            ("51.15.220.8:9732", vec![&other, &same]),
+        ] {
+            let mut cn = connection::Item::new(
+                Initiator::new(true),
+                addr.parse().unwrap(),
+                SystemTime::now(),
+            );
+            let mut parser = MessageParser::new(db.clone()).with_hash(true);
+            for (i, p) in payloads.into_iter().enumerate() {
+                let (counter, sender) = (3 + i as u64, Sender::Remote);
+                let chunk = chunk::Item::new(
+                    cn.key(),
+                    sender,
+                    counter,
+                    p.clone(),
+                    p.clone(),
+                    SystemTime::now(),
+                );
+                parser.handle_chunk(chunk, &mut cn);
+            }
+            parsers.push(parser);
+        }
+
+        let messages = db.fetch_messages(&MessagesFilter::default()).unwrap();
+        let hash_of = |id| {
+            messages
+                .iter()
+                .find(|m| m.id == id)
+                .and_then(|m| m.hash.clone())
+                .unwrap()
+        };
+        assert_eq!(hash_of(0), hash_of(2));
+        assert_ne!(hash_of(0), hash_of(1));
+
+        let filter = MessagesFilter {
+            hash: Some(hash_of(0)),
+            ..Default::default()
+        };
+        let mut ids = db
+            .fetch_messages(&filter)
+            .unwrap()
+            .into_iter()
+            .map(|m| m.id)
+            .collect::<Vec<_>>();
+        ids.sort_unstable();
+        assert_eq!(ids, [0, 2]);
+
+        let filter = MessagesFilter {
+            hash: Some("zz".to_string()),
+            ..Default::default()
+        };
+        assert!(db.fetch_messages(&filter).is_err());
+    }
+
+    #[test]
+    fn id_range() {
+        let path = TempDb::new("range");
+        let db = Arc::new(path.open());
+        let remote_addr = "51.15.220.7:9732".parse().unwrap();
+        let mut cn = connection::Item::new(Initiator::new(true), remote_addr, SystemTime::now());
+        let mut parser = MessageParser::new(db.clone());
+
+        // six get_current_branch messages
+        let mut plain = vec![0, 0, 0, 20, 0, 0x10];
+        plain.resize(24, 0xab);
+        for counter in 3..9 {
+            let bytes = plain.clone();
+            let chunk = chunk::Item::new(
+                cn.key(),
+                Sender::Remote,
+                counter,
+                bytes,
+                plain.clone(),
+                SystemTime::now(),
+            );
+            parser.handle_chunk(chunk, &mut cn);
+        }
+
+        let ids = |filter: MessagesFilter| {
+            db.fetch_messages(&filter)
+                .unwrap()
+                .into_iter()
+                .map(|m| m.id)
+                .collect::<Vec<_>>()
+        };
+        let range = |direction: &str| MessagesFilter {
+            direction: Some(direction.to_string()),
+            id_from: Some(2),
+            id_to: Some(4),
+            ..Default::default()
+        };
+        assert_eq!(ids(range("forward")), [2, 3, 4]);
+        assert_eq!(ids(range("backward")), [4, 3, 2]);
+        assert_eq!(db.count_messages(&range("forward")).unwrap(), 3);
+
+        // the limit and the cursor apply within the range
+        let filter = MessagesFilter {
+            limit: Some(2),
+            ..range("forward")
+        };
+        assert_eq!(ids(filter), [2, 3]);
+        let filter = MessagesFilter {
+            cursor: Some(3),
+            ..range("backward")
+        };
+        assert_eq!(ids(filter), [3, 2]);
+
+        // the secondary index is restricted as well
+        let filter = MessagesFilter {
+            remote_addr: Some(remote_addr.to_string()),
+            ..range("backward")
+        };
+        assert_eq!(ids(filter), [4, 3, 2]);
+        let filter = MessagesFilter {
+            incoming: Some(true),
+            ..range("forward")
+        };
+        assert_eq!(db.count_messages(&filter).unwrap(), 3);
+    }
+
+    #[test]
+    fn level() {
+        let path = TempDb::new("level");
+        let db = Arc::new(path.open());
+        let remote_addr = "51.15.220.7:9732".parse().unwrap();
+        let mut cn = connection::Item::new(Initiator::new(true), remote_addr, SystemTime::now());
+        let mut parser = MessageParser::new(db.clone());
+
+        // block_header at levels 1, 5 and 9, and get_current_branch without the header
+        let header = hex::decode(BLOCK_HEADER).unwrap();
+        let mut payloads = vec![];
+        for level in [1u32, 5, 9] {
+            let mut payload = header.clone();
+            payload[10..14].clone_from_slice(&level.to_be_bytes());
+            payloads.push(payload);
+        }
+        let mut other = vec![0, 0, 0, 20, 0, 0x10];
+        other.resize(24, 0xab);
+        payloads.insert(2, other);
+        for (i, p) in payloads.into_iter().enumerate() {
+            let counter = 3 + i as u64;
+            let chunk = chunk::Item::new(
+                cn.key(),
+                Sender::Remote,
+                counter,
+                p.clone(),
+                p,
+                SystemTime::now(),
+            );
+            parser.handle_chunk(chunk, &mut cn);
+        }
+
+        let levels = |filter: MessagesFilter| {
+            db.fetch_messages(&filter)
+                .unwrap()
+                .into_iter()
+                .map(|m| m.block_header.unwrap().level)
+                .collect::<Vec<_>>()
+        };
+        let range = |min_level, max_level| MessagesFilter {
+            direction: Some("forward".to_string()),
+            min_level,
+            max_level,
+            ..Default::default()
+        };
+        assert_eq!(levels(range(None, Some(9))), [1, 5, 9]);
+        assert_eq!(levels(range(Some(2), None)), [5, 9]);
+        assert_eq!(levels(range(Some(1), Some(5))), [1, 5]);
+        let filter = MessagesFilter {
+            limit: Some(1),
+            ..range(Some(2), None)
+        };
+        assert_eq!(levels(filter), [5]);
+        assert_eq!(db.count_messages(&range(Some(2), None)).unwrap(), 2);
+    }
+
+    #[test]
+    fn chain_id() {
+        let path = TempDb::new("chain");
+        let db = Arc::new(path.open());
+        let remote_addr = "51.15.220.7:9732".parse().unwrap();
+        let mut cn = connection::Item::new(Initiator::new(true), remote_addr, SystemTime::now());
+        let mut parser = MessageParser::new(db.clone());
+
+        // deactivate the mainnet, block_header, deactivate some other chain
+        let mainnet = hex::decode("0000000600127a06a770").unwrap();
+        let other = hex::decode("00000006001201020304").unwrap();
+        let payloads = vec![mainnet, hex::decode(BLOCK_HEADER).unwrap(), other];
+        for (i, p) in payloads.into_iter().enumerate() {
+            let counter = 3 + i as u64;
+            let chunk = chunk::Item::new(
+                cn.key(),
+                Sender::Remote,
+                counter,
+                p.clone(),
+                p,
+                SystemTime::now(),
+            );
+            parser.handle_chunk(chunk, &mut cn);
+        }
+
+        let filter = |chain_id: &str| MessagesFilter {
+            direction: Some("forward".to_string()),
+            chain_id: Some(chain_id.to_string()),
+            ..Default::default()
+        };
+        let messages = db.fetch_messages(&filter("NetXdQprcVkpaWU")).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].chain_id.as_deref(), Some("NetXdQprcVkpaWU"));
+        assert_eq!(db.count_messages(&filter("NetXdQprcVkpaWU")).unwrap(), 1);
+        let all = MessagesFilter {
+            direction: Some("forward".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(db.fetch_messages(&all).unwrap().len(), 3);
+        assert!(db.fetch_messages(&filter("NetXdppxzUbZxbM")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn message_types() {
+        let path = TempDb::new("types");
+        let db = Arc::new(path.open());
+        let remote_addr = "51.15.220.7:9732".parse().unwrap();
+        let mut cn = connection::Item::new(Initiator::new(true), remote_addr, SystemTime::now());
+        let mut parser = MessageParser::new(db.clone());
+
+        // get_current_branch, block_header and get_current_branch again
+        let header = hex::decode(BLOCK_HEADER).unwrap();
+        let mut other = vec![0, 0, 0, 20, 0, 0x10];
+        other.resize(24, 0xab);
+        let payloads = vec![other.clone(), header.clone(), other];
+        for (i, p) in payloads.into_iter().enumerate() {
+            let counter = 3 + i as u64;
+            let chunk = chunk::Item::new(
+                cn.key(),
+                Sender::Remote,
+                counter,
+                p.clone(),
+                p,
+                SystemTime::now(),
+            );
+            parser.handle_chunk(chunk, &mut cn);
+        }
+
+        let filter = MessageTypesFilter {
+            bytes: Some(true),
+            ..Default::default()
+        };
+        let stats = db.fetch_message_types(&filter).unwrap();
+        let stats = stats
+            .into_iter()
+            .map(|s| (s.kind.unwrap(), s.count, s.bytes))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            stats,
+            [
+                (MessageKind::GetCurrentBranch, 2, Some(48)),
+                (MessageKind::BlockHeader, 1, Some(header.len() as u64)),
+            ],
+        );
+
+        // the sizes are summed only on request
+        let stats = db.fetch_message_types(&Default::default()).unwrap();
+        assert!(stats.iter().all(|s| s.bytes.is_none()));
+        assert_eq!(stats.iter().map(|s| s.count).sum::<u64>(), 3);
+
+        // nothing is recorded before the epoch or in the future
+        let filter = MessageTypesFilter {
+            to: Some(0),
+            ..Default::default()
+        };
+        assert!(db.fetch_message_types(&filter).unwrap().is_empty());
+        let filter = MessageTypesFilter {
+            from: Some(u64::MAX),
+            ..Default::default()
+        };
+        assert!(db.fetch_message_types(&filter).unwrap().is_empty());
+    }
+
+    #[test]
+    fn decode_coverage() {
+        let path = TempDb::new("cover");
+        let db = Arc::new(path.open());
+        let remote_addr = "51.15.220.7:9732".parse().unwrap();
+        let mut cn = connection::Item::new(Initiator::new(true), remote_addr, SystemTime::now());
+        let mut parser = MessageParser::new(db.clone()).with_max_size(Some(32));
+
+        // get_current_branch decodes twice and fails once, the chain id is truncated,
+        // the block header exceeds the limit, it is stored raw
+        let good = vec![0, 0, 0, 6, 0, 0x10, 1, 2, 3, 4];
+        let truncated = vec![0, 0, 0, 3, 0, 0x10, 0xab];
+        let header = hex::decode(BLOCK_HEADER).unwrap();
+        let payloads = vec![good.clone(), truncated, header, good];
+        for (i, p) in payloads.into_iter().enumerate() {
+            let counter = 3 + i as u64;
+            let chunk = chunk::Item::new(
+                cn.key(),
+                Sender::Remote,
+                counter,
+                p.clone(),
+                p,
+                SystemTime::now(),
+            );
+            parser.handle_chunk(chunk, &mut cn);
+        }
+
+        let coverage = db.fetch_decode_coverage(&Default::default()).unwrap();
+        let coverage = coverage
+            .into_iter()
+            .map(|c| (c.kind.unwrap(), c.count, c.decoded, c.raw, c.failed, c.rate))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            coverage,
+            [
+                (MessageKind::GetCurrentBranch, 3, 2, 0, 1, 2.0 / 3.0),
+                (MessageKind::BlockHeader, 1, 0, 1, 0, 0.0),
+            ],
+        );
+        // nothing is recorded in the future
+        let filter = MessageTypesFilter {
+            from: Some(u64::MAX),
+            ..Default::default()
+        };
+        assert!(db.fetch_decode_coverage(&filter).unwrap().is_empty());
+    }
+
+    #[test]
+    fn oversized() {
+        let path = TempDb::new("size");
+        let db = Arc::new(path.open());
+        let remote_addr = "51.15.220.7:9732".parse().unwrap();
+        let mut cn = connection::Item::new(Initiator::new(true), remote_addr, SystemTime::now());
+        let mut parser = MessageParser::new(db.clone())
+            .with_hash(true)
+            .with_max_size(Some(32));
+
+        // protocol of 100 bytes split in three chunks, then bootstrap
+        let mut large = vec![0, 0, 0, 96, 0, 0x41];
+        large.resize(100, 0xab);
+        let mut payloads = large.chunks(40).collect::<Vec<_>>();
+        let bootstrap = [0, 0, 0, 2, 0, 2];
+        payloads.push(&bootstrap);
+        for (i, piece) in payloads.into_iter().enumerate() {
+            let counter = 3 + i as u64;
+            let bytes = piece.to_vec();
+            let chunk = chunk::Item::new(
+                cn.key(),
+                Sender::Remote,
+                counter,
+                bytes,
+                piece.to_vec(),
+                SystemTime::now(),
+            );
+            parser.handle_chunk(chunk, &mut cn);
+        }
+        assert_eq!(parser.take_messages(), 2);
+
+        let placeholder = db.fetch_message(MessageId::Index(0)).unwrap().unwrap();
+        let placeholder = serde_json::to_value(&placeholder).unwrap();
+        assert_eq!(placeholder["oversized"], 100);
+        assert!(placeholder["message"].is_null());
+        let head = placeholder["decrypted_bytes"].as_array().unwrap();
+        assert_eq!(head.len(), 32);
+        assert_eq!(head[3], "60");
+
+        // the connection survives, the next message is decoded
+        let next = db.fetch_message(MessageId::Index(1)).unwrap().unwrap();
+        let next = serde_json::to_value(&next).unwrap();
+        assert!(next["oversized"].is_null());
+        assert!(!next["message"].is_null());
+
+        let messages = db.fetch_messages(&MessagesFilter::default()).unwrap();
+        let oversized = |id| messages.iter().find(|m| m.id == id).unwrap().oversized;
+        assert_eq!(oversized(0), Some(100));
+        assert_eq!(oversized(1), None);
+        // the message longer than the limit is neither decoded, nor buffered to compute the hash
+        let placeholder = messages.iter().find(|m| m.id == 0).unwrap();
+        assert!(placeholder.protocol.is_none());
+        assert!(placeholder.hash.is_none());
+    }
+
+    #[test]
+    fn stable_id() {
+        let path = TempDb::new("stable");
+        let rebuilt = TempDb::new("rebuilt");
+        let cns = ["51.15.220.7:9732", "51.15.220.8:9732"]
+            .iter()
+            .map(|addr| connection::Item::new(
+                Initiator::new(true),
+                addr.parse().unwrap(),
+                SystemTime::now(),
+            ))
+            .collect::<Vec<_>>();
+
+        // store a bootstrap in each direction of the connections in the given order,
+        // the rebuilt database gets the same records in the other order
+        let bootstrap = [0, 0, 0, 2, 0, 2];
+        let store = |path: &TempDb, order: [usize; 2]| {
+            let db = Arc::new(path.open());
+            for i in order.iter() {
+                let mut cn = cns[*i].clone();
+                let mut parser = MessageParser::new(db.clone());
+                for sender in [Sender::Remote, Sender::Local] {
+                    let bytes = bootstrap.to_vec();
+                    let chunk = chunk::Item::new(
+                        cn.key(),
+                        sender,
+                        3,
+                        bytes.clone(),
+                        bytes,
+                        SystemTime::now(),
+                    );
+                    parser.handle_chunk(chunk, &mut cn);
+                }
+            }
+            db
+        };
+        let db = store(&path, [0, 1]);
+        let rebuilt_db = store(&rebuilt, [1, 0]);
+
+        let messages = db.fetch_messages(&MessagesFilter::default()).unwrap();
+        let mut stable_ids = messages.iter().map(|m| &m.stable_id).collect::<Vec<_>>();
+        stable_ids.sort_unstable();
+        stable_ids.dedup();
+        assert_eq!(stable_ids.len(), 4);
+        for m in &messages {
+            let stable_id = m.stable_id.parse::<MessageId>().unwrap();
+            assert_eq!(stable_id.to_string(), m.stable_id);
+            assert!(matches!(stable_id, MessageId::Stable(_)));
+
+            let original = db.fetch_message(MessageId::Index(m.id)).unwrap().unwrap();
+            let original = serde_json::to_value(&original).unwrap();
+            let restored = rebuilt_db.fetch_message(stable_id).unwrap().unwrap();
+            let restored = serde_json::to_value(&restored).unwrap();
+            assert_eq!(restored["stable_id"], m.stable_id.as_str());
+            assert_eq!(restored["decrypted_bytes"], original["decrypted_bytes"]);
+            // the index depends on the order the messages are stored
+            assert_ne!(restored["id"], original["id"]);
+        }
+        let unknown = MessageId::Stable(0);
+        assert!(rebuilt_db.fetch_message(unknown).unwrap().is_none());
+        assert_eq!("42".parse::<MessageId>().unwrap(), MessageId::Index(42));
+        // the index of 16 digits is not taken for the stable id
+        let index = "1000000000000000".parse::<MessageId>().unwrap();
+        assert_eq!(index, MessageId::Index(1_000_000_000_000_000));
+        assert!("3f2a9c01d4e5b607".parse::<MessageId>().is_err());
+
+        // the stable id taken from the archive finds the message in the rebuilt database
+        let mut archive = Vec::new();
+        db.export(&Checkpoint::default(), &mut archive).unwrap();
+        let exported = String::from_utf8(archive)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .filter(|line| line["table"] == "messages")
+            .collect::<Vec<_>>();
+        assert_eq!(exported.len(), 4);
+        for line in exported {
+            let stable_id = line["record"]["stable_id"].as_str().unwrap();
+            let id = stable_id.parse::<MessageId>().unwrap();
+            assert!(matches!(id, MessageId::Stable(_)));
+            let restored = rebuilt_db.fetch_message(id).unwrap().unwrap();
+            let restored = serde_json::to_value(&restored).unwrap();
+            assert_eq!(restored["stable_id"], stable_id);
+            let original = db.fetch_message(MessageId::Index(line["id"].as_u64().unwrap()));
+            let original = serde_json::to_value(&original.unwrap().unwrap()).unwrap();
+            assert_eq!(restored["decrypted_bytes"], original["decrypted_bytes"]);
+        }
+    }
+
+    #[test]
+    fn gap() {
+        let path = TempDb::new("gap");
+        let db = Arc::new(path.open());
+        let remote_addr = "51.15.220.7:9732".parse().unwrap();
+        let mut cn = connection::Item::new(Initiator::new(true), remote_addr, SystemTime::now());
+        let mut parser = MessageParser::new(db.clone());
+
+        // the chunk #3 is lost, the rest is stored as is
+        for counter in [0, 1, 2, 4, 5] {
+            let bytes = vec![counter as u8; 10];
+            let chunk = chunk::Item::new(
+                cn.key(),
+                Sender::Remote,
+                counter,
+                bytes,
+                vec![],
+                SystemTime::now(),
+            );
+            parser.handle_chunk(chunk, &mut cn);
+        }
+
+        let filter = ChunksFilter {
+            limit: None,
+            cn: Some(cn.key().to_string()),
+            preview: None,
+        };
+        let chunks = db.fetch_chunks_truncated(&filter).unwrap();
+        let chunks = serde_json::to_value(&chunks).unwrap();
+        let gaps = chunks
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|c| c[1]["gap"].as_u64())
+            .collect::<Vec<_>>();
+        assert_eq!(gaps, [None, None, None, Some(1), None]);
+        assert_eq!(chunks[3][0], format!("{}-remote-4", cn.key()));
+        assert_eq!(db.fetch_stats().unwrap().missing_chunks, 1);
+
+        // only the preview of the payload is read
+        let filter = ChunksFilter {
+            limit: Some(4),
+            cn: Some(cn.key().to_string()),
+            preview: Some(4),
+        };
+        let chunks = db.fetch_chunks_truncated(&filter).unwrap();
+        let chunks = serde_json::to_value(&chunks).unwrap();
+        assert_eq!(chunks.as_array().unwrap().len(), 4);
+        assert_eq!(chunks[3][1]["bytes"], "04040404...truncated 6 bytes");
+    }
+
+    #[test]
+    fn chunk_storage() {
+        let path = TempDb::new("nochunk");
+        let db = Arc::new(path.open());
+        let chunks_of = |cn: &connection::Item| {
+            let filter = ChunksFilter {
+                limit: None,
+                cn: Some(cn.key().to_string()),
+                preview: None,
+            };
+            db.fetch_chunks_truncated(&filter).unwrap().len()
+        };
+
+        // get_current_branch
+        let mut plain = vec![0, 0, 0, 20, 0, 0x10];
+        plain.resize(24, 0xab);
+
+        let remote_addr = "51.15.220.7:9732".parse().unwrap();
+        let mut cn = connection::Item::new(Initiator::new(true), remote_addr, SystemTime::now());
+        let mut parser = MessageParser::new(db.clone())
+            .with_hash(true)
+            .with_chunk_storage(ChunkStorage::None);
+        for counter in 3..5 {
+            let bytes = plain.clone();
+            let chunk = chunk::Item::new(
+                cn.key(),
+                Sender::Remote,
+                counter,
+                bytes,
+                plain.clone(),
+                SystemTime::now(),
+            );
+            parser.handle_chunk(chunk, &mut cn);
+        }
+        let messages = db.fetch_messages(&MessagesFilter::default()).unwrap();
+        assert_eq!(messages.len(), 2);
+        for m in &messages {
+            assert_eq!(m.kind, Some(MessageKind::GetCurrentBranch));
+            assert!(m.hash.is_some());
+        }
+        assert_eq!(chunks_of(&cn), 0);
+
+        // the message, then the chunks which cannot be parsed
+        let remote_addr = "51.15.220.8:9732".parse().unwrap();
+        let mut cn = connection::Item::new(Initiator::new(true), remote_addr, SystemTime::now());
+        let mut parser = MessageParser::new(db.clone()).with_chunk_storage(ChunkStorage::Failed);
+        for (counter, p) in [(3, plain.clone()), (4, vec![0xab; 3]), (5, plain.clone())] {
+            let chunk = chunk::Item::new(
+                cn.key(),
+                Sender::Remote,
+                counter,
+                p.clone(),
+                p,
+                SystemTime::now(),
+            );
+            parser.handle_chunk(chunk, &mut cn);
+        }
+        assert_eq!(db.fetch_messages(&MessagesFilter::default()).unwrap().len(), 3);
+        assert_eq!(chunks_of(&cn), 2);
+    }
+
+    #[test]
+    fn chunk_sample() {
+        let path = TempDb::new("sample");
+        let db = Arc::new(path.open());
+
+        // get_current_branch
+        let mut plain = vec![0, 0, 0, 20, 0, 0x10];
+        plain.resize(24, 0xab);
+
+        let remote_addr = "51.15.220.7:9732".parse().unwrap();
+        let mut cn = connection::Item::new(Initiator::new(true), remote_addr, SystemTime::now());
+        let sample = ChunkSample {
+            first: Some(5),
+            every: None,
+        };
+        let mut parser = MessageParser::new(db.clone()).with_chunk_sample(Some(sample));
+        for counter in 3..8 {
+            let bytes = plain.clone();
+            let chunk = chunk::Item::new(
+                cn.key(),
+                Sender::Remote,
+                counter,
+                bytes,
+                plain.clone(),
+                SystemTime::now(),
+            );
+            parser.handle_chunk(chunk, &mut cn);
+        }
+
+        // all chunks are stored, the first have the bytes
+        let filter = ChunksFilter {
+            limit: None,
+            cn: Some(cn.key().to_string()),
+            preview: None,
+        };
+        let mut chunks = db
+            .fetch_chunks_truncated(&filter)
+            .unwrap()
+            .into_iter()
+            .map(|(key, value)| (key.counter, serde_json::to_value(&value).unwrap()))
+            .collect::<Vec<_>>();
+        chunks.sort_by_key(|(counter, _)| *counter);
+        assert_eq!(chunks.len(), 5);
+        for (counter, chunk) in &chunks {
+            let payload = *counter < 5;
+            assert_eq!(chunk["payload"], payload, "{}", counter);
+            let expected = if payload { hex::encode(&plain) } else { String::new() };
+            assert_eq!(chunk["plain"], expected, "{}", counter);
+        }
+
+        // the messages are stored anyway, only those of the sampled chunks are decoded
+        let messages = db.fetch_messages(&MessagesFilter::default()).unwrap();
+        assert_eq!(messages.len(), 5);
+        for m in &messages {
+            assert_eq!(m.kind, Some(MessageKind::GetCurrentBranch));
+            assert_eq!(m.partial, m.id >= 2, "{}", m.id);
+        }
+
+        // 1-in-N after the first, the handshake is kept anyway
+        let sample = ChunkSample {
+            first: None,
+            every: Some(4),
+        };
+        let sampled = (0..10).filter(|&c| sample.payload(c)).collect::<Vec<_>>();
+        assert_eq!(sampled, [0, 1, 2, 4, 8]);
+    }
+
+    #[test]
+    fn compression() {
+        let path = TempDb::new("compr");
+        let db = Arc::new(path.open());
+
+        // get_current_branch
+        let mut plain = vec![0, 0, 0, 20, 0, 0x10];
+        plain.resize(24, 0xab);
+
+        // the flags announced by the local and the remote peer, the messages parsed
+        let cases = [(0, 0, None, 3), (7, 7, Some(7), 2), (0, 7, None, 3)];
+        for (i, &(local, remote, negotiated, messages)) in cases.iter().enumerate() {
+            let remote_addr = ([51, 15, 220, 7 + i as u8], 9732).into();
+            let mut cn = connection::Item::new(
+                Initiator::new(true),
+                remote_addr,
+                SystemTime::now(),
+            );
+            let mut local_mp = MessageParser::new(db.clone());
+            let mut remote_mp = MessageParser::new(db.clone());
+            let parsers = vec![
+                (&mut local_mp, Sender::Local, local),
+                (&mut remote_mp, Sender::Remote, remote),
+            ];
+            for (parser, sender, flag) in parsers {
+                let metadata = vec![0, 0, flag];
+                let chunk =
+                    chunk::Item::new(
+                        cn.key(),
+                        sender.clone(),
+                        1,
+                        metadata.clone(),
+                        metadata,
+                        SystemTime::now(),
+                    );
+                parser.handle_chunk(chunk, &mut cn);
+                let chunk = chunk::Item::new(
+                    cn.key(),
+                    sender,
+                    2,
+                    vec![0],
+                    vec![0],
+                    SystemTime::now(),
+                );
+                parser.handle_chunk(chunk, &mut cn);
+            }
+            assert_eq!(cn.compression(), negotiated);
+
+            let chunk = chunk::Item::new(
+                cn.key(),
+                Sender::Remote,
+                3,
+                plain.clone(),
+                plain.clone(),
+                SystemTime::now(),
+            );
+            remote_mp.handle_chunk(chunk, &mut cn);
+            // the payload compressed by the unknown method is stored, but not parsed
+            assert_eq!(remote_mp.take_messages(), messages);
+
+            let (_, value) = cn.split();
+            let json = serde_json::to_value(&value).unwrap();
+            assert_eq!(json["compression"], serde_json::json!(negotiated));
+        }
+    }
+
+    #[test]
+    fn capture_types() {
+        let path = TempDb::new("capture-types");
+        let db = Arc::new(path.open());
+        let remote_addr = "51.15.220.7:9732".parse().unwrap();
+        let mut cn = connection::Item::new(Initiator::new(true), remote_addr, SystemTime::now());
+        let types = vec![MessageType::P2p(MessageKind::BlockHeader)];
+        let mut parser = MessageParser::new(db.clone()).with_capture_types(Some(types));
+
+        // get_current_branch split in two chunks, block_header, get_current_branch
+        let mut other = vec![0, 0, 0, 20, 0, 0x10];
+        other.resize(24, 0xab);
+        let header = hex::decode(BLOCK_HEADER).unwrap();
+        let payloads = [&other[..10], &other[10..], &header, &other];
+        for (i, p) in payloads.iter().enumerate() {
+            let counter = 3 + i as u64;
+            let chunk = chunk::Item::new(
+                cn.key(),
+                Sender::Remote,
+                counter,
+                p.to_vec(),
+                p.to_vec(),
+                SystemTime::now(),
+            );
+            parser.handle_chunk(chunk, &mut cn);
+        }
+        assert_eq!(parser.take_messages(), 3);
+
+        let messages = db.fetch_messages(&MessagesFilter::default()).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].kind, Some(MessageKind::BlockHeader));
+        assert_eq!(messages[0].block_header.as_ref().unwrap().level, 1);
+        let filter = ChunksFilter {
+            limit: None,
+            cn: Some(cn.key().to_string()),
+            preview: None,
+        };
+        assert_eq!(db.fetch_chunks_truncated(&filter).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn tail() {
+        let path = TempDb::new("tail");
+        let db = Arc::new(path.open());
+        db.set_tail_size(3);
+        let remote_addr = "51.15.220.7:9732".parse().unwrap();
+        let mut cn = connection::Item::new(Initiator::new(true), remote_addr, SystemTime::now());
+        let mut parser = MessageParser::new(db.clone());
+
+        // five get_current_branch
+        let mut plain = vec![0, 0, 0, 20, 0, 0x10];
+        plain.resize(24, 0xab);
+        for counter in 3..8 {
+            let bytes = plain.clone();
+            let chunk = chunk::Item::new(
+                cn.key(),
+                Sender::Remote,
+                counter,
+                bytes,
+                plain.clone(),
+                SystemTime::now(),
+            );
+            parser.handle_chunk(chunk, &mut cn);
+        }
+
+        let ids = |n| {
+            db.fetch_messages_tail(n)
+                .unwrap()
+                .into_iter()
+                .map(|m| m.id)
+                .collect::<Vec<_>>()
+        };
+        // from memory, the newest first
+        assert_eq!(ids(2), [4, 3]);
+        assert_eq!(ids(3), [4, 3, 2]);
+        // more than memory holds, from the database, in the same order
+        assert_eq!(ids(5), [4, 3, 2, 1, 0]);
+        let filter = MessagesFilter {
+            limit: Some(3),
+            ..Default::default()
+        };
+        let stored = db.fetch_messages(&filter).unwrap();
+        assert_eq!(ids(3), stored.iter().map(|m| m.id).collect::<Vec<_>>());
+        // decoded the same way as the stored messages
+        let tail = db.fetch_messages_tail(3).unwrap();
+        assert_eq!(
+            serde_json::to_value(&tail).unwrap(),
+            serde_json::to_value(&stored).unwrap(),
+        );
+    }
+
+    #[test]
+    fn tail_store_limit() {
+        let path = TempDb::new("tlim");
+        let db = Arc::new(Db::open(&path, false, None, Some(2), Default::default()).unwrap());
+        db.set_tail_size(3);
+        let remote_addr = "51.15.220.7:9732".parse().unwrap();
+        let mut cn = connection::Item::new(Initiator::new(true), remote_addr, SystemTime::now());
+        let mut parser = MessageParser::new(db.clone());
+
+        let mut plain = vec![0, 0, 0, 20, 0, 0x10];
+        plain.resize(24, 0xab);
+        for counter in 3..8 {
+            let bytes = plain.clone();
+            let chunk = chunk::Item::new(
+                cn.key(),
+                Sender::Remote,
+                counter,
+                bytes,
+                plain.clone(),
+                SystemTime::now(),
+            );
+            parser.handle_chunk(chunk, &mut cn);
+        }
+
+        // the memory does not hold the messages removed by the limit
+        let ids = db
+            .fetch_messages_tail(3)
+            .unwrap()
+            .into_iter()
+            .map(|m| m.id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, [4, 3]);
+    }
+}
@@ -1,10 +1,25 @@
 // Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
 // SPDX-License-Identifier: MIT
 
-use super::{system::Identity, database::Database, tables, common};
+use super::{
+    system::Identity,
+    database::{self, Database},
+    tables, common, telemetry,
+    geoip::Enricher,
+};
 
 mod chunk_parser;
 mod message_parser;
 mod connection;
+mod stored;
+mod rate;
+mod compression;
+#[cfg(test)]
+pub mod fixture;
 
-pub use self::connection::Connection;
+pub use self::{
+    connection::{Connection, CaptureOptions, ActiveConnection, Candidate},
+    message_parser::{ChunkStorage, ChunkSample},
+    stored::decode_stored,
+    rate::{RateLimit, RateMonitor},
+};
@@ -0,0 +1,181 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::{
+    fs::OpenOptions,
+    io::{self, Write},
+    net::SocketAddr,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use serde::{Serialize, Deserialize};
+
+fn default_window() -> u64 {
+    1
+}
+
+/// The threshold of incoming messages per connection, the recorder only reports
+/// the peer exceeding it, the connection is not affected
+#[derive(Clone, Deserialize)]
+pub struct RateLimit {
+    // messages per window
+    pub messages: u32,
+    // the window in seconds
+    #[serde(default = "default_window")]
+    pub window: u64,
+    // append the address of the peer to this file
+    pub ban_list: Option<String>,
+}
+
+/// Emitted once per window when the peer exceeds the threshold
+#[derive(Serialize)]
+pub struct RateAlert {
+    pub node_name: String,
+    pub remote_addr: SocketAddr,
+    pub messages: u32,
+    pub window: u64,
+}
+
+impl RateAlert {
+    pub fn report(&self, ban_list: Option<&str>) {
+        match serde_json::to_string(self) {
+            Ok(alert) => log::warn!("message rate exceeded: {}", alert),
+            Err(error) => log::error!("failed to serialize rate alert: {}", error),
+        }
+        if let Some(path) = ban_list {
+            if let Err(error) = self.append(path) {
+                log::error!("failed to write ban list {}: {}", path, error);
+            }
+        }
+    }
+
+    fn append<P>(&self, path: P) -> io::Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        writeln!(file, "{}", self.remote_addr.ip())
+    }
+}
+
+/// Counts the messages in a fixed window
+pub struct RateMonitor {
+    node_name: String,
+    limit: Arc<RateLimit>,
+    window_start: Option<Instant>,
+    count: u32,
+    alerted: bool,
+}
+
+impl RateMonitor {
+    pub fn new(node_name: String, limit: Arc<RateLimit>) -> Self {
+        RateMonitor {
+            node_name,
+            limit,
+            window_start: None,
+            count: 0,
+            alerted: false,
+        }
+    }
+
+    pub fn ban_list(&self) -> Option<&str> {
+        self.limit.ban_list.as_deref()
+    }
+
+    /// Account `messages` received at `now`, return the alert if the threshold
+    /// is exceeded for the first time in the current window
+    pub fn record(
+        &mut self,
+        now: Instant,
+        messages: u32,
+        remote_addr: SocketAddr,
+    ) -> Option<RateAlert> {
+        let window = Duration::from_secs(self.limit.window);
+        match self.window_start {
+            Some(start) if now.saturating_duration_since(start) < window => (),
+            _ => {
+                self.window_start = Some(now);
+                self.count = 0;
+                self.alerted = false;
+            },
+        }
+        self.count = self.count.saturating_add(messages);
+        if self.count > self.limit.messages && !self.alerted {
+            self.alerted = true;
+            Some(RateAlert {
+                node_name: self.node_name.clone(),
+                remote_addr,
+                messages: self.count,
+                window: self.limit.window,
+            })
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env, fs,
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+    use super::{RateLimit, RateMonitor};
+
+    #[test]
+    fn flood() {
+        let limit = RateLimit {
+            messages: 100,
+            window: 1,
+            ban_list: None,
+        };
+        let mut monitor = RateMonitor::new("test".to_string(), Arc::new(limit));
+        let address = "51.15.220.7:9732".parse().unwrap();
+        let start = Instant::now();
+
+        // 1000 messages per second, the alert happens once per window
+        let mut alerts = vec![];
+        for ms in 0..3000 {
+            let now = start + Duration::from_millis(ms);
+            if let Some(alert) = monitor.record(now, 1, address) {
+                alerts.push((ms, alert));
+            }
+        }
+        assert_eq!(alerts.len(), 3);
+        for (i, (ms, alert)) in alerts.iter().enumerate() {
+            assert_eq!(*ms, i as u64 * 1000 + 100);
+            assert_eq!(alert.messages, 101);
+            assert_eq!(alert.remote_addr, address);
+        }
+
+        // below the threshold, no alert
+        let start = start + Duration::from_secs(10);
+        for ms in 0..3000 {
+            let now = start + Duration::from_millis(ms);
+            if ms % 20 == 0 {
+                assert!(monitor.record(now, 1, address).is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn ban_list() {
+        let path = env::temp_dir().join(format!("tezedge-recorder-ban-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let limit = RateLimit {
+            messages: 0,
+            window: 1,
+            ban_list: Some(path.to_str().unwrap().to_string()),
+        };
+        let mut monitor = RateMonitor::new("test".to_string(), Arc::new(limit));
+        let alert = monitor
+            .record(Instant::now(), 1, "51.15.220.7:9732".parse().unwrap())
+            .unwrap();
+        alert.report(monitor.ban_list());
+        alert.report(monitor.ban_list());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "51.15.220.7\n51.15.220.7\n");
+        fs::remove_file(&path).unwrap();
+    }
+}
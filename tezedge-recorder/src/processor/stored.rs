@@ -0,0 +1,96 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::sync::Arc;
+use super::{
+    Connection, Identity, Database,
+    database::DatabaseFetch,
+    common::Sender,
+    tables::{connection, chunk},
+};
+
+/// How many connections are loaded at once
+const PAGE: usize = 0x100;
+
+/// Decode the connections recorded in capture-only mode, when the identity became available.
+/// The raw chunks are replayed through the parser under the key of the original connection,
+/// the chunks are replaced by the decrypted ones, the messages are stored for the connection,
+/// and it is not marked anymore. Returns the number of decoded connections.
+pub fn decode_stored<Db>(
+    db: &Arc<Db>,
+    identity: &Identity,
+    pow_target: f64,
+) -> Result<usize, Db::Error>
+where
+    Db: Database + DatabaseFetch,
+{
+    // the chunks still queued are read back
+    db.flush();
+    let mut decoded = 0;
+    let mut after = None;
+    loop {
+        let page = db.fetch_connections_after(after.as_ref(), PAGE)?;
+        let last_page = page.len() < PAGE;
+        after = page.last().map(|(cn_id, _)| cn_id.clone());
+        for (cn_id, value) in page {
+            if value.comments().outgoing_no_identity {
+                decode_connection(db, identity, pow_target, cn_id, value)?;
+                decoded += 1;
+            }
+        }
+        if last_page {
+            break;
+        }
+    }
+
+    Ok(decoded)
+}
+
+fn decode_connection<Db>(
+    db: &Arc<Db>,
+    identity: &Identity,
+    pow_target: f64,
+    cn_id: connection::Key,
+    value: connection::Value,
+) -> Result<(), Db::Error>
+where
+    Db: Database + DatabaseFetch,
+{
+    // load the raw chunks first, the decoded ones replace them, the counters might differ
+    let mut raw = Vec::new();
+    for counter in 0.. {
+        let mut exhausted = true;
+        for &incoming in &[false, true] {
+            let key = chunk::Key {
+                cn_id: cn_id.clone(),
+                counter,
+                sender: Sender::new(incoming),
+            };
+            if let Some(value) = db.fetch_chunk(&key)? {
+                exhausted = false;
+                raw.push((key, value));
+            }
+        }
+        if exhausted {
+            break;
+        }
+    }
+    for (key, _) in &raw {
+        db.remove_chunk(key);
+    }
+
+    let mut item = connection::Item::unite(cn_id, value);
+    item.add_comment().outgoing_no_identity = false;
+    let mut connection = Connection::resume(item, vec![identity.clone()], pow_target, db.clone());
+    // the chunks of each direction are consecutive pieces of the stream,
+    // they are interleaved, so both connection messages arrive before the rest
+    for (key, value) in raw {
+        if !value.bytes.is_empty() {
+            let incoming = key.sender.incoming();
+            connection.handle_data(&value.bytes, value.net(), incoming, None);
+        }
+    }
+    connection.join();
+
+    Ok(())
+}
@@ -5,23 +5,35 @@ use std::{sync::Arc, collections::HashMap};
 use anyhow::Result;
 use warp::{
     Filter, Rejection, Reply,
-    reply::{WithStatus, Json, self},
-    http::StatusCode,
+    reply::{WithStatus, Json, Response, self},
+    http::{StatusCode, header},
+    hyper::Body,
 };
+use http_common::auth;
 use super::{
-    database::{DatabaseFetch, ConnectionsFilter, ChunksFilter, MessagesFilter, LogsFilter},
-    tables::chunk,
+    database::{
+        Database, DatabaseFetch, ConnectionsFilter, ChunksFilter, MessagesFilter, LogsFilter,
+        ThroughputFilter, SessionFilter, TimelineFilter, VerifyFilter, MessageTypesFilter,
+        TailFilter, verify, tail::Tail,
+    },
+    tables::{chunk, connection, connection_crypto, message::{MessageId, MessageDetails}},
+    common::MessageType,
+    system::NodeStatus,
+    limit::{self, Limiter, Permit},
+    processor,
 };
 
 fn connections<Db>(
     db: Arc<Db>,
+    limiter: Option<Arc<Limiter>>,
 ) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static
 where
     Db: DatabaseFetch + Sync + Send + 'static,
 {
     warp::path!("v3" / "connections")
+        .and(limit::permit(limiter))
         .and(warp::query::query())
-        .map(move |filter: ConnectionsFilter| -> WithStatus<Json> {
+        .map(move |_permit: Permit, filter: ConnectionsFilter| -> WithStatus<Json> {
             match db.fetch_connections(&filter) {
                 Ok(connections) => reply::with_status(reply::json(&connections), StatusCode::OK),
                 Err(err) => {
@@ -32,14 +44,37 @@ where
         })
 }
 
+/// The connections the parser tracks now, read from memory, not from the database
+fn connections_active(
+    status: Arc<NodeStatus>,
+) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static {
+    warp::path!("v3" / "connections" / "active").map(move || -> WithStatus<Json> {
+        reply::with_status(reply::json(&status.active_connections()), StatusCode::OK)
+    })
+}
+
+/// The empty result would look like no traffic, tell the chunks are not stored by the config
+fn chunks_not_stored(status: &NodeStatus) -> Option<WithStatus<Json>> {
+    if status.chunk_storage() == processor::ChunkStorage::None {
+        let r = "chunks are not stored, `chunk_storage` is `none`";
+        Some(reply::with_status(reply::json(&r), StatusCode::NOT_FOUND))
+    } else {
+        None
+    }
+}
+
 fn chunks<Db>(
     db: Arc<Db>,
+    status: Arc<NodeStatus>,
 ) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static
 where
     Db: DatabaseFetch + Sync + Send + 'static,
 {
     warp::path!("v3" / "chunks").and(warp::query::query()).map(
         move |filter: ChunksFilter| -> WithStatus<Json> {
+            if let Some(r) = chunks_not_stored(&status) {
+                return r;
+            }
             match db.fetch_chunks_truncated(&filter) {
                 Ok(chunks) => reply::with_status(reply::json(&chunks), StatusCode::OK),
                 Err(err) => {
@@ -53,6 +88,7 @@ where
 
 fn chunk<Db>(
     db: Arc<Db>,
+    status: Arc<NodeStatus>,
 ) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static
 where
     Db: DatabaseFetch + Sync + Send + 'static,
@@ -66,6 +102,9 @@ where
     }
 
     warp::path!("v3" / "chunk" / String).map(move |chunk_id: String| -> WithStatus<Json> {
+        if let Some(r) = chunks_not_stored(&status) {
+            return r;
+        }
         match inner(&db, chunk_id) {
             Ok(v) => reply::with_status(reply::json(&v), StatusCode::OK),
             Err(err) => {
@@ -76,15 +115,63 @@ where
     })
 }
 
+fn connection_crypto<Db>(
+    db: Arc<Db>,
+) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static
+where
+    Db: DatabaseFetch + Sync + Send + 'static,
+{
+    fn inner<Db>(db: &Arc<Db>, cn_id: String) -> Result<Option<connection_crypto::Diagnostics>>
+    where
+        Db: DatabaseFetch + Sync + Send + 'static,
+    {
+        let key = cn_id.parse::<connection::Key>()?;
+        db.fetch_connection_crypto(&key).map_err(Into::into)
+    }
+
+    warp::path!("v3" / "connection" / String / "crypto").map(
+        move |cn_id: String| -> WithStatus<Json> {
+            match inner(&db, cn_id) {
+                Ok(v) => reply::with_status(reply::json(&v), StatusCode::OK),
+                Err(err) => {
+                    let r = format!("database error: {}", err);
+                    reply::with_status(reply::json(&r), StatusCode::INTERNAL_SERVER_ERROR)
+                },
+            }
+        },
+    )
+}
+
+fn connection_finalize(
+    status: Arc<NodeStatus>,
+) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static {
+    warp::path!("v3" / "connection" / String / "finalize").map(
+        move |cn_id: String| -> WithStatus<Json> {
+            match cn_id.parse::<connection::Key>() {
+                Ok(key) => {
+                    status.request_finalize(key);
+                    reply::with_status(reply::json(&cn_id), StatusCode::ACCEPTED)
+                },
+                Err(err) => {
+                    let r = format!("bad connection id: {}", err);
+                    reply::with_status(reply::json(&r), StatusCode::BAD_REQUEST)
+                },
+            }
+        },
+    )
+}
+
 fn messages<Db>(
     db: Arc<Db>,
+    limiter: Option<Arc<Limiter>>,
 ) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static
 where
     Db: DatabaseFetch + Sync + Send + 'static,
 {
     warp::path!("v3" / "messages")
+        .and(limit::permit(limiter))
         .and(warp::query::query())
-        .map(move |filter: MessagesFilter| -> reply::WithStatus<Json> {
+        .map(move |_permit: Permit, filter: MessagesFilter| -> reply::WithStatus<Json> {
             match db.fetch_messages(&filter) {
                 Ok(messages) => reply::with_status(reply::json(&messages), StatusCode::OK),
                 Err(err) => {
@@ -95,13 +182,206 @@ where
         })
 }
 
+/// The same as `messages`, but each message is a line of json sent as soon as it is loaded,
+/// so the whole list is held neither by the server, nor by the client
+fn messages_ndjson<Db>(
+    db: Arc<Db>,
+    limiter: Option<Arc<Limiter>>,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone + Sync + Send + 'static
+where
+    Db: DatabaseFetch + Sync + Send + 'static,
+{
+    warp::path!("v3" / "messages.ndjson")
+        .and(limit::permit(limiter))
+        .and(warp::query::query())
+        .map(move |permit: Permit, filter: MessagesFilter| -> Response {
+            let (mut sender, body) = Body::channel();
+            let db = db.clone();
+            let rt = tokio::runtime::Handle::current();
+            tokio::task::spawn_blocking(move || {
+                // the read is in flight until the stream ends
+                let _permit = permit;
+                let result = db.for_each_message(&filter, |message| {
+                    let mut line = match serde_json::to_vec(&message) {
+                        Ok(line) => line,
+                        Err(error) => {
+                            log::error!("failed to serialize message {}: {}", message.id, error);
+                            return true;
+                        },
+                    };
+                    line.push(b'\n');
+                    // the client is gone, stop loading
+                    rt.block_on(sender.send_data(line.into())).is_ok()
+                });
+                if let Err(err) = result {
+                    log::error!("database error: {}", err);
+                    sender.abort();
+                }
+            });
+            let mut response = Response::new(body);
+            let content_type = header::HeaderValue::from_static("application/x-ndjson");
+            response.headers_mut().insert(header::CONTENT_TYPE, content_type);
+            response
+        })
+}
+
+/// Server-sent events of one connection, each message stored from now on, and each change
+/// of the connection, the stream ends when the connection closes
+fn connection_events<Db>(
+    db: Arc<Db>,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone + Sync + Send + 'static
+where
+    Db: DatabaseFetch + Sync + Send + 'static,
+{
+    use std::time::Duration;
+    use tokio::sync::broadcast::error::RecvError;
+
+    // the comment line keeps the idle stream open, and detects the client is gone
+    const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+    warp::path!("v3" / "connection" / String / "events").map(move |cn_id: String| -> Response {
+        let cn_id = match cn_id.parse::<connection::Key>() {
+            Ok(cn_id) => cn_id,
+            Err(err) => {
+                let r = format!("bad connection id: {}", err);
+                let reply = reply::with_status(reply::json(&r), StatusCode::BAD_REQUEST);
+                return reply.into_response();
+            },
+        };
+        // subscribe first, the connection closed after the lookup is in the stream
+        let mut events = db.subscribe();
+        let value = match db.fetch_connection(&cn_id) {
+            Ok(Some(value)) => value,
+            Ok(None) => {
+                let r = format!("no such connection: {}", cn_id);
+                let reply = reply::with_status(reply::json(&r), StatusCode::NOT_FOUND);
+                return reply.into_response();
+            },
+            Err(err) => {
+                let r = format!("database error: {}", err);
+                let status = StatusCode::INTERNAL_SERVER_ERROR;
+                return reply::with_status(reply::json(&r), status).into_response();
+            },
+        };
+        let stream = |body: Body| {
+            let mut response = Response::new(body);
+            let headers = response.headers_mut();
+            let content_type = header::HeaderValue::from_static("text/event-stream");
+            headers.insert(header::CONTENT_TYPE, content_type);
+            headers.insert(header::CACHE_CONTROL, header::HeaderValue::from_static("no-cache"));
+            response
+        };
+        // closed already, nothing follows its final state
+        if value.close_reason().is_some() {
+            let data = serde_json::to_string(&value).unwrap_or_default();
+            return stream(format!("event: connection\ndata: {}\n\n", data).into());
+        }
+        let (mut sender, body) = Body::channel();
+        let db = db.clone();
+        tokio::spawn(async move {
+            loop {
+                let text = match tokio::time::timeout(KEEP_ALIVE, events.recv()).await {
+                    Ok(Ok(event)) if event.cn_id == cn_id => {
+                        let data = match serde_json::to_string(&event.kind) {
+                            Ok(data) => data,
+                            Err(error) => {
+                                log::error!("failed to serialize event: {}", error);
+                                continue;
+                            },
+                        };
+                        let text = format!("event: {}\ndata: {}\n\n", event.kind.name(), data);
+                        if event.last {
+                            let _ = sender.send_data(text.into()).await;
+                            break;
+                        }
+                        text
+                    },
+                    Ok(Ok(_)) => continue,
+                    // the client is too slow, it missed some events, which of them are
+                    // of this connection is unknown, tell the connection as it is now
+                    Ok(Err(RecvError::Lagged(_))) => {
+                        let value = match db.fetch_connection(&cn_id) {
+                            Ok(Some(value)) => value,
+                            Ok(None) => break,
+                            Err(error) => {
+                                log::error!("database error: {}", error);
+                                break;
+                            },
+                        };
+                        let data = serde_json::to_string(&value).unwrap_or_default();
+                        let text = format!("event: lagged\ndata: {}\n\n", data);
+                        // the close is among the missed events
+                        if value.close_reason().is_some() {
+                            let _ = sender.send_data(text.into()).await;
+                            break;
+                        }
+                        text
+                    },
+                    Ok(Err(RecvError::Closed)) => break,
+                    Err(_) => ": keep-alive\n\n".to_string(),
+                };
+                // the client is gone
+                if sender.send_data(text.into()).await.is_err() {
+                    break;
+                }
+            }
+        });
+        stream(body)
+    })
+}
+
+fn messages_count<Db>(
+    db: Arc<Db>,
+    limiter: Option<Arc<Limiter>>,
+) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static
+where
+    Db: DatabaseFetch + Sync + Send + 'static,
+{
+    warp::path!("v3" / "messages" / "count")
+        .and(limit::permit(limiter))
+        .and(warp::query::query())
+        .map(move |_permit: Permit, filter: MessagesFilter| -> reply::WithStatus<Json> {
+            match db.count_messages(&filter) {
+                Ok(count) => reply::with_status(
+                    reply::json(&serde_json::json!({ "count": count })),
+                    StatusCode::OK,
+                ),
+                Err(err) => {
+                    let r = &format!("database error: {}", err);
+                    reply::with_status(reply::json(&r), StatusCode::INTERNAL_SERVER_ERROR)
+                },
+            }
+        })
+}
+
+/// The most recent messages, from memory if it holds so many
+fn messages_tail<Db>(
+    db: Arc<Db>,
+) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static
+where
+    Db: DatabaseFetch + Sync + Send + 'static,
+{
+    warp::path!("v3" / "messages" / "tail")
+        .and(warp::query::query())
+        .map(move |filter: TailFilter| -> reply::WithStatus<Json> {
+            let n = filter.n.unwrap_or(Tail::DEFAULT_SIZE as u64);
+            match db.fetch_messages_tail(n) {
+                Ok(messages) => reply::with_status(reply::json(&messages), StatusCode::OK),
+                Err(err) => {
+                    let r = &format!("database error: {}", err);
+                    reply::with_status(reply::json(&r), StatusCode::INTERNAL_SERVER_ERROR)
+                },
+            }
+        })
+}
+
 fn message<Db>(
     db: Arc<Db>,
 ) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static
 where
     Db: DatabaseFetch + Sync + Send + 'static,
 {
-    warp::path!("v3" / "message" / u64).map(move |id: u64| -> reply::WithStatus<Json> {
+    warp::path!("v3" / "message" / MessageId).map(move |id: MessageId| -> WithStatus<Json> {
         match db.fetch_message(id) {
             Ok(message) => reply::with_status(reply::json(&message), StatusCode::OK),
             Err(err) => {
@@ -112,14 +392,69 @@ where
     })
 }
 
+fn message_encoded<Db>(
+    db: Arc<Db>,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone + Sync + Send + 'static
+where
+    Db: DatabaseFetch + Sync + Send + 'static,
+{
+    warp::path!("v3" / "message" / MessageId / "encoded").map(move |id: MessageId| -> Response {
+        let error = |r: String, status: StatusCode| {
+            reply::with_status(reply::json(&r), status).into_response()
+        };
+        match db.fetch_message(id) {
+            Ok(Some(message)) => match message.encoded() {
+                Some(Ok((bytes, round_trip))) => {
+                    let mut response = Response::new(bytes.into());
+                    let headers = response.headers_mut();
+                    let content_type = header::HeaderValue::from_static("application/octet-stream");
+                    headers.insert(header::CONTENT_TYPE, content_type);
+                    // `false` means the decoder lost or altered something
+                    let round_trip = if round_trip { "true" } else { "false" };
+                    headers.insert("X-Round-Trip", header::HeaderValue::from_static(round_trip));
+                    response
+                },
+                Some(Err(err)) => {
+                    error(format!("failed to encode: {}", err), StatusCode::INTERNAL_SERVER_ERROR)
+                },
+                None => error(format!("message {} is not decoded", id), StatusCode::NOT_FOUND),
+            },
+            Ok(None) => error(format!("no such message: {}", id), StatusCode::NOT_FOUND),
+            Err(err) => {
+                error(format!("database error: {}", err), StatusCode::INTERNAL_SERVER_ERROR)
+            },
+        }
+    })
+}
+
+fn message_raw<Db>(
+    db: Arc<Db>,
+) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static
+where
+    Db: DatabaseFetch + Sync + Send + 'static,
+{
+    warp::path!("v3" / "message" / u64 / "raw").map(move |id: u64| -> reply::WithStatus<Json> {
+        match db.fetch_message_raw(id) {
+            Ok(raw) => reply::with_status(reply::json(&raw), StatusCode::OK),
+            Err(err) => {
+                let r = &format!("database error: {}", err);
+                reply::with_status(reply::json(&r), StatusCode::INTERNAL_SERVER_ERROR)
+            },
+        }
+    })
+}
+
 fn logs<Db>(
     db: Arc<Db>,
+    limiter: Option<Arc<Limiter>>,
 ) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static
 where
     Db: DatabaseFetch + Sync + Send + 'static,
 {
-    warp::path!("v3" / "logs").and(warp::query::query()).map(
-        move |filter: LogsFilter| -> reply::WithStatus<Json> {
+    warp::path!("v3" / "logs")
+        .and(limit::permit(limiter))
+        .and(warp::query::query())
+        .map(move |_permit: Permit, filter: LogsFilter| -> reply::WithStatus<Json> {
             match db.fetch_log(&filter) {
                 Ok(v) => reply::with_status(reply::json(&v), StatusCode::OK),
                 Err(err) => {
@@ -127,10 +462,254 @@ where
                     reply::with_status(reply::json(&r), StatusCode::INTERNAL_SERVER_ERROR)
                 },
             }
+        })
+}
+
+fn throughput<Db>(
+    db: Arc<Db>,
+    limiter: Option<Arc<Limiter>>,
+) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static
+where
+    Db: DatabaseFetch + Sync + Send + 'static,
+{
+    warp::path!("v3" / "throughput")
+        .and(limit::permit(limiter))
+        .and(warp::query::query())
+        .map(move |_permit: Permit, filter: ThroughputFilter| -> reply::WithStatus<Json> {
+            match db.fetch_throughput(&filter) {
+                Ok(v) => reply::with_status(reply::json(&v), StatusCode::OK),
+                Err(err) => {
+                    let r = &format!("database error: {}", err);
+                    reply::with_status(reply::json(&r), StatusCode::INTERNAL_SERVER_ERROR)
+                },
+            }
+        })
+}
+
+fn message_types<Db>(
+    db: Arc<Db>,
+    limiter: Option<Arc<Limiter>>,
+) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static
+where
+    Db: DatabaseFetch + Sync + Send + 'static,
+{
+    warp::path!("v3" / "stats" / "message_types")
+        .and(limit::permit(limiter))
+        .and(warp::query::query())
+        .map(move |_permit: Permit, filter: MessageTypesFilter| -> reply::WithStatus<Json> {
+            match db.fetch_message_types(&filter) {
+                Ok(v) => reply::with_status(reply::json(&v), StatusCode::OK),
+                Err(err) => {
+                    let r = &format!("database error: {}", err);
+                    reply::with_status(reply::json(&r), StatusCode::INTERNAL_SERVER_ERROR)
+                },
+            }
+        })
+}
+
+fn decode_coverage<Db>(
+    db: Arc<Db>,
+    limiter: Option<Arc<Limiter>>,
+) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static
+where
+    Db: DatabaseFetch + Sync + Send + 'static,
+{
+    warp::path!("v3" / "stats" / "decode_coverage")
+        .and(limit::permit(limiter))
+        .and(warp::query::query())
+        .map(move |_permit: Permit, filter: MessageTypesFilter| -> reply::WithStatus<Json> {
+            match db.fetch_decode_coverage(&filter) {
+                Ok(v) => reply::with_status(reply::json(&v), StatusCode::OK),
+                Err(err) => {
+                    let r = &format!("database error: {}", err);
+                    reply::with_status(reply::json(&r), StatusCode::INTERNAL_SERVER_ERROR)
+                },
+            }
+        })
+}
+
+fn timeline<Db>(
+    db: Arc<Db>,
+    limiter: Option<Arc<Limiter>>,
+) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static
+where
+    Db: DatabaseFetch + Sync + Send + 'static,
+{
+    warp::path!("v3" / "timeline")
+        .and(limit::permit(limiter))
+        .and(warp::query::query())
+        .map(move |_permit: Permit, filter: TimelineFilter| -> reply::WithStatus<Json> {
+            match db.fetch_timeline(&filter) {
+                Ok(v) => reply::with_status(reply::json(&v), StatusCode::OK),
+                Err(err) => {
+                    let r = &format!("database error: {}", err);
+                    reply::with_status(reply::json(&r), StatusCode::INTERNAL_SERVER_ERROR)
+                },
+            }
+        })
+}
+
+fn session<Db>(
+    db: Arc<Db>,
+) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static
+where
+    Db: Database + Sync + Send + 'static,
+{
+    warp::path!("v3" / "session").and(warp::query::query()).map(
+        move |filter: SessionFilter| -> reply::WithStatus<Json> {
+            db.set_session(filter.label.clone());
+            reply::with_status(reply::json(&filter.label), StatusCode::OK)
         },
     )
 }
 
+fn health(
+    status: Arc<NodeStatus>,
+) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static {
+    warp::path!("v3" / "health").map(move || -> reply::WithStatus<Json> {
+        let syscall_contexts = status.context_stats().map(|stats| {
+            // the fraction of syscalls whose exit was missed
+            let missed_exit_rate = if stats.pushed == 0 {
+                0.0
+            } else {
+                stats.evicted as f64 / stats.pushed as f64
+            };
+            serde_json::json!({
+                "pushed": stats.pushed,
+                "popped": stats.popped,
+                "evicted": stats.evicted,
+                "missed_exit_rate": missed_exit_rate,
+            })
+        });
+        let connections = status.connection_stats();
+        let integrity = status.integrity();
+        let v = serde_json::json!({
+            "healthy": integrity.as_ref().map_or(true, |report| report.is_healthy()),
+            "integrity": integrity,
+            "capture_only": status.capture_only(),
+            "low_disk": status.low_disk(),
+            "capture_start": status.capture_start(),
+            "uptime_seconds": status.uptime_seconds(),
+            "syscall_contexts": syscall_contexts,
+            "chunk_storage": status.chunk_storage(),
+            "connections": {
+                "tracked": connections.tracked,
+                "max": connections.max,
+                "rejected": connections.rejected,
+                "saturated": connections.saturated,
+            },
+        });
+        reply::with_status(reply::json(&v), StatusCode::OK)
+    })
+}
+
+fn identity_reload<Db>(
+    db: Arc<Db>,
+    status: Arc<NodeStatus>,
+) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static
+where
+    Db: Database + DatabaseFetch + Sync + Send + 'static,
+{
+    warp::path!("v3" / "identity" / "reload").and_then(move || {
+        let db = db.clone();
+        let status = status.clone();
+        async move {
+            let identity = match status.load_identity() {
+                Ok(identity) => identity,
+                Err(err) => {
+                    let r = &format!("identity error: {}", err);
+                    return Ok(reply::with_status(reply::json(&r), StatusCode::BAD_REQUEST));
+                },
+            };
+            // replays every capture-only connection, do not hold the executor
+            let pow_target = status.pow_target();
+            let decoded = tokio::task::spawn_blocking(move || {
+                processor::decode_stored(&db, &identity, pow_target)
+                    .map_err(|err| format!("database error: {}", err))
+            })
+            .await
+            .unwrap_or_else(|err| Err(format!("decode error: {}", err)));
+            Ok::<_, Rejection>(match decoded {
+                Ok(decoded) => {
+                    let v = serde_json::json!({ "decoded_connections": decoded });
+                    reply::with_status(reply::json(&v), StatusCode::OK)
+                },
+                Err(r) => reply::with_status(reply::json(&r), StatusCode::INTERNAL_SERVER_ERROR),
+            })
+        }
+    })
+}
+
+fn decode(
+) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static {
+    #[derive(serde::Deserialize)]
+    struct DecodeRequest {
+        // recognized if missing
+        #[serde(rename = "type")]
+        ty: Option<String>,
+        // the decrypted bytes of the message
+        hex: String,
+    }
+
+    warp::path!("v3" / "decode")
+        .and(warp::body::content_length_limit(1 << 24))
+        .and(warp::body::json())
+        .map(move |request: DecodeRequest| -> reply::WithStatus<Json> {
+            let ty = match request.ty.as_deref().map(str::parse::<MessageType>).transpose() {
+                Ok(ty) => ty,
+                Err(err) => {
+                    let r = &err.to_string();
+                    return reply::with_status(reply::json(&r), StatusCode::BAD_REQUEST);
+                },
+            };
+            let bytes = match hex::decode(request.hex.trim()) {
+                Ok(bytes) => bytes,
+                Err(err) => {
+                    let r = &format!("invalid hex: {}", err);
+                    return reply::with_status(reply::json(&r), StatusCode::BAD_REQUEST);
+                },
+            };
+            let (ty, details) = MessageDetails::decode_plain(ty, bytes);
+            let (category, kind) = ty.split();
+            // the message is not stored, it has no id and no encrypted bytes
+            let mut v = serde_json::to_value(&details).unwrap_or_default();
+            if let Some(v) = v.as_object_mut() {
+                v.remove("id");
+                v.remove("stable_id");
+                v.remove("original_bytes");
+                v.insert("category".to_string(), serde_json::json!(category));
+                v.insert("kind".to_string(), serde_json::json!(kind));
+            }
+            reply::with_status(reply::json(&v), StatusCode::OK)
+        })
+}
+
+fn db_stats<Db>(
+    db: Arc<Db>,
+    status: Arc<NodeStatus>,
+) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static
+where
+    Db: DatabaseFetch + Sync + Send + 'static,
+{
+    warp::path!("v3" / "db_stats").map(move || -> reply::WithStatus<Json> {
+        match db.fetch_stats() {
+            Ok(v) => {
+                // the stats are the object, extend it with the capture window
+                let mut v = serde_json::to_value(&v).unwrap_or_default();
+                if let Some(v) = v.as_object_mut() {
+                    v.insert("capture_start".to_string(), status.capture_start().into());
+                    v.insert("uptime_seconds".to_string(), status.uptime_seconds().into());
+                }
+                reply::with_status(reply::json(&v), StatusCode::OK)
+            },
+            Err(err) => {
+                let r = &format!("database error: {}", err);
+                reply::with_status(reply::json(&r), StatusCode::INTERNAL_SERVER_ERROR)
+            },
+        }
+    })
+}
+
 pub fn version(
 ) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static {
     warp::path!("v2" / "version").and(warp::query::query()).map(
@@ -140,36 +719,86 @@ pub fn version(
     )
 }
 
+const OPENAPI: &str = include_str!("../openapi.json");
+
 pub fn openapi(
 ) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static {
     warp::path!("openapi" / "network-recorder-openapi.json")
+        .or(warp::path!("openapi.json"))
+        .unify()
         .and(warp::query::query())
         .map(move |()| -> reply::WithStatus<Json> {
-            let s = include_str!("../openapi.json");
-            let d = serde_json::from_str::<serde_json::Value>(s).unwrap();
+            let d = serde_json::from_str::<serde_json::Value>(OPENAPI).unwrap();
             reply::with_status(reply::json(&d), StatusCode::OK)
         })
 }
 
+/// Set on every response while the last integrity check of the database fails
+pub const DEGRADED_HEADER: &str = "x-database-degraded";
+
 pub fn routes<Db>(
     db: Arc<Db>,
+    status: Arc<NodeStatus>,
 ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone + Sync + Send + 'static
 where
-    Db: DatabaseFetch + Sync + Send + 'static,
+    Db: Database + DatabaseFetch + Sync + Send + 'static,
 {
     use warp::reply::with;
 
-    warp::get()
+    let api_token = status.api_token();
+    let limiter = status.read_limiter();
+    // the records might be missing or wrong, the client should know, see `integrity_check`
+    let degraded = {
+        let status = status.clone();
+        warp::any().map(move || status.degraded())
+    };
+    // not json, so the content type is not overridden
+    let streaming = warp::get().and(
+        messages_ndjson(db.clone(), limiter.clone())
+            .or(connection_events(db.clone()))
+            .or(message_encoded(db.clone())),
+    );
+    let json = warp::get()
         .and(
-            connections(db.clone())
-                .or(chunks(db.clone()))
-                .or(chunk(db.clone()))
-                .or(messages(db.clone()))
+            connections(db.clone(), limiter.clone())
+                .or(connections_active(status.clone()))
+                .or(chunks(db.clone(), status.clone()))
+                .or(chunk(db.clone(), status.clone()))
+                .or(connection_crypto(db.clone()))
+                .or(messages(db.clone(), limiter.clone()))
+                .or(messages_count(db.clone(), limiter.clone()))
+                .or(messages_tail(db.clone()))
                 .or(message(db.clone()))
-                .or(logs(db))
+                .or(message_raw(db.clone()))
+                .or(logs(db.clone(), limiter.clone()))
+                .or(throughput(db.clone(), limiter.clone()))
+                .or(timeline(db.clone(), limiter.clone()))
+                .or(db_stats(db.clone(), status.clone()))
+                .or(message_types(db.clone(), limiter.clone()))
+                .or(decode_coverage(db.clone(), limiter))
+                .or(health(status.clone()))
                 .or(version().or(openapi())),
         )
-        .with(with::header("Content-Type", "application/json"))
+        .or(warp::post().and(
+            session(db.clone())
+                .or(connection_finalize(status.clone()))
+                .or(identity_reload(db, status))
+                .or(decode()),
+        ))
+        .with(with::header("Content-Type", "application/json"));
+    auth::bearer(api_token)
+        .and(streaming.or(json))
+        .recover(auth::recover)
+        .recover(limit::recover)
+        .and(degraded)
+        .map(|reply, degraded| {
+            let mut response = Reply::into_response(reply);
+            if degraded {
+                let value = header::HeaderValue::from_static("true");
+                response.headers_mut().insert(DEGRADED_HEADER, value);
+            }
+            response
+        })
         .with(with::header("Access-Control-Allow-Origin", "*"))
 }
 
@@ -211,7 +840,7 @@ where
             move |id: u64, filter: MessagesFilter| -> reply::WithStatus<Json> {
                 let node_name = filter.node_name.clone().unwrap_or("tezedge".to_string());
                 match dbs.get(&node_name) {
-                    Some(db) => match db.fetch_message(id) {
+                    Some(db) => match db.fetch_message(MessageId::Index(id)) {
                         Ok(message) => reply::with_status(reply::json(&message), StatusCode::OK),
                         Err(err) => {
                             let r = &format!("database error: {}", err);
@@ -227,6 +856,40 @@ where
         )
 }
 
+fn verify_session<Db>(
+    dbs: HashMap<String, Arc<Db>>,
+) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static
+where
+    Db: DatabaseFetch + Sync + Send + 'static,
+{
+    warp::path!("v2" / "verify").and(warp::query::query()).map(
+        move |filter: VerifyFilter| -> reply::WithStatus<Json> {
+            let end = |node_name: &str, cn_id: &str| {
+                let db = dbs
+                    .get(node_name)
+                    .ok_or_else(|| format!("no such node: {:?}", node_name))?;
+                let cn_id = cn_id
+                    .parse::<connection::Key>()
+                    .map_err(|err| format!("bad connection id: {}", err))?;
+                Ok::<_, String>((db, cn_id))
+            };
+            let ends = end(&filter.initiator_node, &filter.initiator)
+                .and_then(|i| Ok((i, end(&filter.responder_node, &filter.responder)?)));
+            let ((i_db, i_cn), (r_db, r_cn)) = match ends {
+                Ok(ends) => ends,
+                Err(r) => return reply::with_status(reply::json(&r), StatusCode::BAD_REQUEST),
+            };
+            match verify::verify_session(i_db.as_ref(), &i_cn, r_db.as_ref(), &r_cn) {
+                Ok(report) => reply::with_status(reply::json(&report), StatusCode::OK),
+                Err(err) => {
+                    let r = &format!("database error: {}", err);
+                    reply::with_status(reply::json(&r), StatusCode::INTERNAL_SERVER_ERROR)
+                },
+            }
+        },
+    )
+}
+
 fn log_old<Db>(
     dbs: HashMap<String, Arc<Db>>,
 ) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static
@@ -255,20 +918,395 @@ where
 
 pub fn routes_old<Db>(
     dbs: HashMap<String, Arc<Db>>,
+    api_token: Option<String>,
 ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone + Sync + Send + 'static
 where
     Db: DatabaseFetch + Sync + Send + 'static,
 {
     use warp::reply::with;
 
-    warp::get()
+    auth::bearer(api_token)
+        .and(warp::get())
         .and(
             p2p(dbs.clone())
                 .or(p2p_details(dbs.clone()))
+                .or(verify_session(dbs.clone()))
                 .or(log_old(dbs))
                 .or(version())
                 .or(openapi()),
         )
+        .recover(auth::recover)
         .with(with::header("Content-Type", "application/json"))
         .with(with::header("Access-Control-Allow-Origin", "*"))
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::SystemTime};
+    use super::{OPENAPI, routes};
+    use crate::{
+        common::{Initiator, Sender},
+        database::{Database, DatabaseFetch, MessagesFilter, rocks::Db, temp::TempDb},
+        system::NodeStatus,
+        tables::{connection, message::MessageBuilder},
+    };
+
+    #[test]
+    fn openapi() {
+        let d = serde_json::from_str::<serde_json::Value>(OPENAPI).unwrap();
+        assert!(d["openapi"].as_str().unwrap().starts_with("3."));
+
+        let paths = d["paths"].as_object().unwrap();
+        for path in &[
+            "/v2/version",
+            "/v2/log",
+            "/v2/p2p",
+            "/v2/verify",
+            "/v2/p2p/{id}",
+            "/v3/connections",
+            "/v3/connections/active",
+            "/v3/chunks",
+            "/v3/chunk/{id}",
+            "/v3/connection/{id}/crypto",
+            "/v3/connection/{id}/events",
+            "/v3/messages",
+            "/v3/messages.ndjson",
+            "/v3/messages/count",
+            "/v3/messages/tail",
+            "/v3/message/{id}",
+            "/v3/message/{id}/raw",
+            "/v3/message/{id}/encoded",
+            "/v3/logs",
+            "/v3/throughput",
+            "/v3/timeline",
+            "/v3/db_stats",
+            "/v3/stats/message_types",
+            "/v3/stats/decode_coverage",
+            "/v3/health",
+            "/v3/session",
+            "/v3/connection/{id}/finalize",
+            "/v3/identity/reload",
+            "/v3/decode",
+        ] {
+            assert!(paths.contains_key(*path), "{}", path);
+        }
+
+        // every reference is resolved
+        let schemas = d["components"]["schemas"].as_object().unwrap();
+        let text = OPENAPI.replace(char::is_whitespace, "");
+        for reference in text.split("\"$ref\":\"#/components/schemas/").skip(1) {
+            let name = reference.split('"').next().unwrap();
+            assert!(schemas.contains_key(name), "{}", name);
+        }
+
+        let parameters = |path: &str| -> Vec<String> {
+            d["paths"][path]["get"]["parameters"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|p| p["name"].as_str().unwrap().to_string())
+                .collect()
+        };
+        let messages = parameters("/v3/messages");
+        for name in &["direction", "limit", "cursor", "remote_addr", "types", "session"] {
+            assert!(messages.iter().any(|p| p == name), "{}", name);
+        }
+        let connections = parameters("/v3/connections");
+        for name in &["close_reason", "status", "country", "asn"] {
+            assert!(connections.iter().any(|p| p == name), "{}", name);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn messages_ndjson() {
+        let path = TempDb::new("ndjson");
+        let db = Arc::new(path.open());
+        let cn = connection::Item::new(
+            Initiator::new(true),
+            "51.15.220.7:9732".parse().unwrap(),
+            SystemTime::now(),
+        );
+        // local, remote, local, remote, local
+        for sender in [Sender::Local, Sender::Remote].iter().cycle().take(5) {
+            let message =
+                MessageBuilder::connection_message().build(sender, &cn, SystemTime::now());
+            db.store_message(message);
+        }
+
+        let status = Arc::new(NodeStatus::new(None, NodeStatus::DEFAULT_POW_TARGET));
+        let routes = routes(db.clone(), status);
+        let cases = [("", 5, 4), ("?limit=3", 3, 4), ("?incoming=true", 2, 3)];
+        for (query, expected, newest) in &cases {
+            let response = warp::test::request()
+                .path(&format!("/v3/messages.ndjson{}", query))
+                .reply(&routes)
+                .await;
+            assert_eq!(response.status(), 200);
+            assert_eq!(response.headers()["content-type"], "application/x-ndjson");
+            let body = std::str::from_utf8(response.body()).unwrap();
+            let lines = body
+                .lines()
+                .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+                .collect::<Vec<_>>();
+            assert_eq!(lines.len(), *expected, "{}", query);
+            // the newest first, as in `/v3/messages`
+            assert_eq!(lines[0]["id"], *newest);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn decode() {
+        let path = TempDb::new("decode");
+        let db = Arc::new(path.open());
+        let status = Arc::new(NodeStatus::new(None, NodeStatus::DEFAULT_POW_TARGET));
+        let routes = routes(db.clone(), status);
+
+        let decode = |body: serde_json::Value| {
+            let routes = routes.clone();
+            async move {
+                let response = warp::test::request()
+                    .method("POST")
+                    .path("/v3/decode")
+                    .json(&body)
+                    .reply(&routes)
+                    .await;
+                let v = serde_json::from_slice::<serde_json::Value>(response.body()).unwrap();
+                (response.status(), v)
+            }
+        };
+
+        // `get_current_branch` of the chain `NetXdQprcVkpaWU`
+        let hex = "0000000600107a06a770";
+        let body = serde_json::json!({ "type": "get_current_branch", "hex": hex });
+        let (status, v) = decode(body).await;
+        assert_eq!(status, 200);
+        assert_eq!(v["category"], "p2p");
+        assert_eq!(v["kind"], "get_current_branch");
+        assert_eq!(v["error"], serde_json::Value::Null);
+        assert_eq!(v["decoded_size"], 10);
+        assert!(!v["message"].is_null());
+        assert!(v.get("id").is_none());
+
+        // the type is recognized by the tag
+        let (status, v) = decode(serde_json::json!({ "hex": hex })).await;
+        assert_eq!(status, 200);
+        assert_eq!(v["kind"], "get_current_branch");
+
+        // the type is validated
+        let body = serde_json::json!({ "type": "get_current_branches", "hex": hex });
+        assert_eq!(decode(body).await.0, 400);
+        let body = serde_json::json!({ "type": "bootstrap", "hex": "0x00" });
+        assert_eq!(decode(body).await.0, 400);
+
+        // nothing is stored
+        assert!(db.fetch_messages(&MessagesFilter::default()).unwrap().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn bearer_token() {
+        use std::collections::HashMap;
+        use super::routes_old;
+
+        let path = TempDb::new("auth");
+        let db = Arc::new(path.open());
+        let token = Some("secret".to_string());
+        let status = NodeStatus::new(None, NodeStatus::DEFAULT_POW_TARGET);
+        let routes = routes(db.clone(), Arc::new(status.with_api_token(token.clone())));
+        let old = routes_old(HashMap::<String, Arc<Db>>::new(), token);
+
+        let request = |path: &str, header: Option<&str>| {
+            let request = warp::test::request().path(path);
+            match header {
+                Some(header) => request.header("authorization", header),
+                None => request,
+            }
+        };
+        for header in &[None, Some("Bearer wrong"), Some("secret"), Some("Basic c2VjcmV0")] {
+            let response = request("/v3/health", *header).reply(&routes).await;
+            assert_eq!(response.status(), 401, "{:?}", header);
+            assert_eq!(response.headers()["www-authenticate"], "Bearer");
+            let response = request("/v2/version", *header).reply(&old).await;
+            assert_eq!(response.status(), 401, "{:?}", header);
+        }
+        let authorized = Some("Bearer secret");
+        let response = request("/v3/health", authorized).reply(&routes).await;
+        assert_eq!(response.status(), 200);
+        let response = request("/v2/version", authorized).reply(&old).await;
+        assert_eq!(response.status(), 200);
+        // other rejections are not affected
+        let response = request("/v3/missing", authorized).reply(&routes).await;
+        assert!(response.status().is_client_error());
+        assert_ne!(response.status(), 401);
+
+        // without the token the access is open
+        let status = NodeStatus::new(None, NodeStatus::DEFAULT_POW_TARGET);
+        let open = super::routes(db.clone(), Arc::new(status));
+        let response = request("/v3/health", None).reply(&open).await;
+        assert_eq!(response.status(), 200);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn read_limit() {
+        use crate::limit::Limiter;
+
+        let path = TempDb::new("limit");
+        let db = Arc::new(path.open());
+        let limiter = Arc::new(Limiter::new(1));
+        let status = NodeStatus::new(None, NodeStatus::DEFAULT_POW_TARGET);
+        let routes = routes(db.clone(), Arc::new(status.with_read_limiter(Some(limiter.clone()))));
+        let request = |path: &str| warp::test::request().path(path);
+
+        // some slow read is in flight
+        let permit = limiter.try_acquire().unwrap();
+        for path in &["/v3/messages", "/v3/messages.ndjson", "/v3/logs", "/v3/connections"] {
+            let response = request(path).reply(&routes).await;
+            assert_eq!(response.status(), 503, "{}", path);
+            assert_eq!(response.headers()["retry-after"], "1");
+        }
+        // the capture continues
+        let cn = connection::Item::new(
+            Initiator::new(true),
+            "51.15.220.7:9732".parse().unwrap(),
+            SystemTime::now(),
+        );
+        for sender in &[Sender::Local, Sender::Remote] {
+            let message =
+                MessageBuilder::connection_message().build(sender, &cn, SystemTime::now());
+            db.store_message(message);
+        }
+        // the cheap requests are not limited
+        assert_eq!(request("/v3/health").reply(&routes).await.status(), 200);
+
+        drop(permit);
+        let response = request("/v3/messages").reply(&routes).await;
+        assert_eq!(response.status(), 200);
+        let messages = serde_json::from_slice::<Vec<serde_json::Value>>(response.body()).unwrap();
+        assert_eq!(messages.len(), 2);
+        // the permit is released after the reply
+        assert!(limiter.try_acquire().is_some());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn capture_window() {
+        use std::time::Duration;
+
+        let path = TempDb::new("uptime");
+        let db = Arc::new(path.open());
+        let status = Arc::new(NodeStatus::new(None, NodeStatus::DEFAULT_POW_TARGET));
+        let routes = routes(db.clone(), status);
+
+        let get = |path: &'static str| {
+            let routes = routes.clone();
+            async move {
+                let response = warp::test::request().path(path).reply(&routes).await;
+                assert_eq!(response.status(), 200);
+                serde_json::from_slice::<serde_json::Value>(response.body()).unwrap()
+            }
+        };
+
+        for path in &["/v3/health", "/v3/db_stats"] {
+            let first = get(*path).await;
+            tokio::time::sleep(Duration::from_millis(1100)).await;
+            let second = get(*path).await;
+            let start = first["capture_start"].as_u64().unwrap();
+            assert!(start > 0);
+            assert_eq!(second["capture_start"].as_u64(), Some(start), "{}", path);
+            let uptime = |v: &serde_json::Value| v["uptime_seconds"].as_u64().unwrap();
+            assert!(uptime(&second) > uptime(&first), "{}", path);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn connection_events() {
+        use std::time::Duration;
+
+        let path = TempDb::new("cn-events");
+        let db = Arc::new(path.open());
+        let status = Arc::new(NodeStatus::new(None, NodeStatus::DEFAULT_POW_TARGET));
+        let routes = routes(db.clone(), status);
+
+        let new_connection = |addr: &str| {
+            connection::Item::new(Initiator::new(true), addr.parse().unwrap(), SystemTime::now())
+        };
+        let mut cn = new_connection("51.15.220.7:9732");
+        let other = new_connection("51.15.220.8:9732");
+        // stored before subscribing, not in the stream
+        db.store_connection(cn.clone());
+        db.store_message(MessageBuilder::connection_message().build(
+            &Sender::Local,
+            &cn,
+            SystemTime::now(),
+        ));
+
+        let request = {
+            let routes = routes.clone();
+            let path = format!("/v3/connection/{}/events", cn.key());
+            tokio::spawn(async move { warp::test::request().path(&path).reply(&routes).await })
+        };
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        db.store_message(MessageBuilder::metadata_message().build(
+            &Sender::Remote,
+            &cn,
+            SystemTime::now(),
+        ));
+        db.store_message(MessageBuilder::connection_message().build(
+            &Sender::Remote,
+            &other,
+            SystemTime::now(),
+        ));
+        cn.set_close_reason(connection::CloseReason::Close);
+        db.update_connection(cn.clone());
+
+        let response = tokio::time::timeout(Duration::from_secs(10), request)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["content-type"], "text/event-stream");
+        let parse = |body: &[u8]| {
+            std::str::from_utf8(body)
+                .unwrap()
+                .split("\n\n")
+                .filter(|event| !event.is_empty())
+                .map(|event| {
+                    let mut lines = event.lines();
+                    let name = lines.next().unwrap().trim_start_matches("event: ").to_string();
+                    let data = lines.next().unwrap().trim_start_matches("data: ");
+                    (name, serde_json::from_str::<serde_json::Value>(data).unwrap())
+                })
+                .collect::<Vec<_>>()
+        };
+        let events = parse(response.body());
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].0, "message");
+        assert_eq!(events[0].1["category"], "meta");
+        assert_eq!(events[0].1["remote_addr"], "51.15.220.7:9732");
+        assert_eq!(events[1].0, "connection");
+        assert_eq!(events[1].1["close_reason"], "close");
+
+        // closed already, the stream is the final state only
+        let response = warp::test::request()
+            .path(&format!("/v3/connection/{}/events", cn.key()))
+            .reply(&routes);
+        let response = tokio::time::timeout(Duration::from_secs(10), response)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        let events = parse(response.body());
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].0, "connection");
+        assert_eq!(events[0].1["close_reason"], "close");
+
+        let response = warp::test::request()
+            .path(&format!("/v3/connection/{}/events", other.key()))
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), 404);
+
+        let response = warp::test::request()
+            .path("/v3/connection/garbage/events")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), 400);
+    }
+}
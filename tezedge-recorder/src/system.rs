@@ -2,25 +2,132 @@
 // SPDX-License-Identifier: MIT
 
 use std::{
-    collections::HashMap,
-    sync::{Arc, atomic::AtomicBool},
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+    sync::{
+        Arc, Mutex,
+        atomic::{Ordering, AtomicBool},
+    },
     net::SocketAddr,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
     io, thread,
 };
-use serde::Deserialize;
+use serde::{Serialize, Deserialize};
 use anyhow::Result;
+use bpf_recorder::ContextStats;
 use thiserror::Error;
 use tokio::{runtime::Runtime, task::JoinHandle};
+use http_common::tls::{self, TlsConfig, TlsError};
 use super::{
-    database::{DatabaseNew, DatabaseFetch, Database},
-    server, log_client,
+    database::{
+        DatabaseNew, DatabaseFetch, Database,
+        batch::BatchConfig,
+        integrity::{self, IntegrityCheckConfig},
+    },
+    server, log_client, node_port,
+    proc_net::CmdlineConfig,
+    cidr::{self, Cidr},
+    disk::{DiskGuard, DiskGuardConfig, FileSystem, Transition},
+    processor::{
+        RateLimit, RateMonitor, ChunkStorage, ChunkSample, ActiveConnection, Candidate,
+        CaptureOptions,
+    },
+    tables::{connection, message::PeerEncoding},
+    common::{MessageType, ParseTypeError},
+    geoip::{GeoIp, MaxMind, Enricher},
+    limit::Limiter,
 };
 
 #[derive(Clone, Deserialize)]
 pub struct P2pConfig {
     identity: String,
-    pub port: u16,
+    // if missing, and not found in `node_config`, detected by the first bind of a watched process
+    pub port: Option<u16>,
+    // the config file of the node, its p2p port takes precedence over `port`
+    node_config: Option<String>,
     store_limit: Option<u64>,
+    rate_limit: Option<RateLimit>,
+    // the difficulty of proof-of-work expected from the peers
+    pow_target: Option<f64>,
+    // store the blake2b of the decrypted bytes of each message
+    #[serde(default)]
+    message_hash: bool,
+    // bytes, a longer message is stored as a placeholder with the first bytes of the content
+    max_message_size: Option<u32>,
+    // store the keys and the nonces of the handshake, served at `/v3/connection/{id}/crypto`
+    #[serde(default)]
+    debug_crypto: bool,
+    // seconds, if set, only the connections with valid connection messages are stored,
+    // the connection which did not exchange them in time is dropped
+    handshake_timeout: Option<u64>,
+    // seconds, the connection without data for so long is finalized, see `CloseReason::Timeout`
+    idle_timeout: Option<u64>,
+    // decode the peer messages of the connection whose connection messages do not tell
+    // the distributed db version as if it were this one
+    distributed_db_version: Option<u16>,
+    // by the address of the peer, decrypt the connection with the key instead of the identity
+    #[serde(default)]
+    precomputed_keys: HashMap<SocketAddr, SessionKey>,
+    // `all`, `failed` or `none`, without the chunks the messages are not decoded
+    #[serde(default)]
+    chunk_storage: ChunkStorage,
+    // with `chunk_storage` `all`, the bytes are stored only for the `first` chunks
+    // of each direction and 1-in-`every` after them, the rest keep only the metadata
+    chunk_sample: Option<ChunkSample>,
+    // the connection message seen twice is dropped, otherwise the connection is not decrypted
+    #[serde(default = "default_drop_duplicate_connection_message")]
+    drop_duplicate_connection_message: bool,
+    // the names of the message types, like `block_header`, the other messages are not stored
+    capture_types: Option<Vec<String>>,
+    // the most recent messages kept in memory for `/v3/messages/tail`, 100 by default
+    tail_size: Option<usize>,
+}
+
+fn default_drop_duplicate_connection_message() -> bool {
+    true
+}
+
+impl P2pConfig {
+    /// Take the port from the config file of the node, keep the explicit `port` if it fails
+    fn resolve_port(&mut self, name: &str) {
+        let path = match &self.node_config {
+            Some(path) => path,
+            None => return,
+        };
+        match node_port::from_config_file(path) {
+            Ok(Some(port)) => {
+                log::info!("node: {}, p2p port: {} from {}", name, port, path);
+                self.port = Some(port);
+            },
+            Ok(None) => log::warn!("node: {}, no p2p port in {}", name, path),
+            Err(error) => log::warn!("node: {}, cannot read {}: {}", name, path, error),
+        }
+    }
+
+    fn capture_types(&self) -> Result<Option<Vec<MessageType>>, ParseTypeError> {
+        self.capture_types
+            .as_ref()
+            .map(|types| types.iter().map(|ty| ty.parse()).collect())
+            .transpose()
+    }
+
+    /// How each connection of the node is recorded
+    fn capture_options(&self) -> CaptureOptions {
+        CaptureOptions {
+            message_hash: self.message_hash,
+            max_message_size: self.max_message_size,
+            chunk_storage: self.chunk_storage,
+            chunk_sample: self.chunk_sample,
+            // the config is validated at start
+            capture_types: self.capture_types().unwrap_or_default(),
+            debug_crypto: self.debug_crypto,
+            handshake_timeout: self.handshake_timeout.map(Duration::from_secs),
+            idle_timeout: self.idle_timeout.map(Duration::from_secs),
+            ddb_version: self.distributed_db_version,
+            drop_duplicate_cm: self.drop_duplicate_connection_message,
+        }
+    }
 }
 
 #[derive(Clone, Deserialize)]
@@ -35,6 +142,15 @@ pub struct NodeConfig {
     name: String,
     http_v3: Option<u16>,
     db: String,
+    // keep the database in memory, nothing is written to `db`, for load testing
+    #[serde(default)]
+    in_memory: bool,
+    compaction_threshold: Option<f64>,
+    #[serde(default)]
+    batch: BatchConfig,
+    disk_guard: Option<DiskGuardConfig>,
+    // read back a sample of the records periodically, off if missing
+    integrity_check: Option<IntegrityCheckConfig>,
     p2p: Option<P2pConfig>,
     log: Option<LogConfig>,
 }
@@ -42,18 +158,408 @@ pub struct NodeConfig {
 #[derive(Clone, Deserialize)]
 struct Config {
     http_v2: Option<u16>,
+    // do not record connections to co-located services
+    #[serde(default)]
+    ignore_loopback: bool,
+    #[serde(default)]
+    ignore: Vec<Cidr>,
+    // the connections tracked at once by all nodes, the new connection above it is not recorded
+    max_connections: Option<usize>,
+    // the certificate and the key, all http servers are https
+    tls: Option<TlsConfig>,
+    // the requests to all http servers need `Authorization: Bearer <api_token>`
+    api_token: Option<String>,
+    // the expensive reads served at once by all http servers, the read above it gets 503
+    max_concurrent_reads: Option<usize>,
+    // the MaxMind databases, like `GeoLite2-Country.mmdb` and `GeoLite2-ASN.mmdb`,
+    // if set, the country and the asn of the peers are resolved after the connection is stored
+    #[serde(default)]
+    geoip_dbs: Vec<String>,
+    // watch the listening ports of the processes whose command line matches the regex,
+    // rather than the configured ports, the processes are rescanned periodically
+    watch_cmdline: Option<CmdlineConfig>,
     nodes: Vec<NodeConfig>,
 }
 
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("nodes {} and {} both receive syslog at port {}", first, second, port)]
+    SyslogPortCollision {
+        port: u16,
+        first: String,
+        second: String,
+    },
+    #[error("{}", _0)]
+    Tls(#[from] TlsError),
+    #[error("nodes {} and {} both store to the database {}", first, second, path)]
+    DbPathCollision {
+        path: String,
+        first: String,
+        second: String,
+    },
+    #[error("node {}, capture_types: {}", node, error)]
+    CaptureType { node: String, error: ParseTypeError },
+    #[error("watch_cmdline.regex: {}", _0)]
+    Cmdline(#[from] regex::Error),
+}
+
+impl Config {
+    const API_TOKEN_VAR: &'static str = "TEZEDGE_RECORDER_API_TOKEN";
+
+    /// The environment variable takes precedence, so the token need not be in the file
+    fn api_token(&self) -> Option<String> {
+        std::env::var(Self::API_TOKEN_VAR)
+            .ok()
+            .filter(|token| !token.is_empty())
+            .or_else(|| self.api_token.clone())
+    }
+
+    /// Each node needs its own syslog port, the port tells which node the log belongs to,
+    /// and its own database, rocksdb locks the directory,
+    /// the certificate and the key of the servers must load, the message types must be known
+    fn validate(&self) -> Result<(), ConfigError> {
+        if let Some(tls) = &self.tls {
+            tls.check()?;
+        }
+        if let Some(cmdline) = &self.watch_cmdline {
+            cmdline.regex()?;
+        }
+        let mut syslog_ports = HashMap::new();
+        let mut db_paths = HashMap::new();
+        for c in &self.nodes {
+            // the database in memory is private to the node, the path is only its name
+            if !c.in_memory {
+                if let Some(first) = db_paths.insert(Path::new(&c.db), &c.name) {
+                    return Err(ConfigError::DbPathCollision {
+                        path: c.db.clone(),
+                        first: first.clone(),
+                        second: c.name.clone(),
+                    });
+                }
+            }
+            if let Some(p2p) = &c.p2p {
+                p2p.capture_types().map_err(|error| ConfigError::CaptureType {
+                    node: c.name.clone(),
+                    error,
+                })?;
+            }
+            let port = match &c.log {
+                Some(log) => log.port,
+                None => continue,
+            };
+            if let Some(first) = syslog_ports.insert(port, &c.name) {
+                return Err(ConfigError::SyslogPortCollision {
+                    port,
+                    first: first.clone(),
+                    second: c.name.clone(),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The problem found by `check_config`, `node` is `None` for the top level settings
+#[derive(Debug, Serialize)]
+pub struct ConfigProblem {
+    pub node: Option<String>,
+    pub problem: String,
+}
+
+/// What `validate-config` prints, the config is fine if there are no problems
+#[derive(Debug, Default, Serialize)]
+pub struct ConfigReport {
+    pub path: String,
+    pub nodes: Vec<String>,
+    pub problems: Vec<ConfigProblem>,
+}
+
+impl ConfigReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    fn add(&mut self, node: Option<&str>, problem: String) {
+        self.problems.push(ConfigProblem {
+            node: node.map(str::to_string),
+            problem,
+        });
+    }
+
+    /// Two settings of the same kind cannot share the port, `what` is the setting
+    fn unique_port(&mut self, ports: &mut HashMap<u16, String>, port: u16, what: String) {
+        if let Some(other) = ports.insert(port, what.clone()) {
+            let problem = format!("port {} is both {} and {}", port, other, what);
+            self.add(None, problem);
+        }
+    }
+}
+
+/// Check the config file without starting the capture: the files it refers to,
+/// the ports, the identities and the databases
+pub fn check_config(path: &str) -> ConfigReport {
+    match std::fs::read_to_string(path) {
+        Ok(s) => check_config_str(path, &s),
+        Err(error) => {
+            let mut report = ConfigReport {
+                path: path.to_string(),
+                ..Default::default()
+            };
+            report.add(None, format!("cannot read the config: {}", error));
+            report
+        },
+    }
+}
+
+fn check_config_str(path: &str, s: &str) -> ConfigReport {
+    let mut report = ConfigReport {
+        path: path.to_string(),
+        ..Default::default()
+    };
+    let mut config = match toml::from_str::<Config>(s) {
+        Ok(config) => config,
+        Err(error) => {
+            report.add(None, format!("cannot parse the config: {}", error));
+            return report;
+        },
+    };
+    report.nodes = config.nodes.iter().map(|c| c.name.clone()).collect();
+
+    // the servers of the recorder, the p2p ports of the nodes, the syslog ports,
+    // each kind of port must be unique
+    let mut http_ports = HashMap::new();
+    let mut p2p_ports = HashMap::new();
+    let mut syslog_ports = HashMap::new();
+    let mut names = HashSet::new();
+    let mut db_paths = HashMap::new();
+    if let Some(port) = config.http_v2 {
+        http_ports.insert(port, "http_v2".to_string());
+    }
+    if let Err(error) = config.tls.as_ref().map_or(Ok(()), TlsConfig::check) {
+        report.add(None, error.to_string());
+    }
+    if config.max_concurrent_reads == Some(0) {
+        report.add(None, "max_concurrent_reads is 0, every read is rejected".to_string());
+    }
+    if let Some(Err(error)) = config.watch_cmdline.as_ref().map(CmdlineConfig::regex) {
+        report.add(None, format!("watch_cmdline.regex: {}", error));
+    }
+    for path in &config.geoip_dbs {
+        if let Err(error) = MaxMind::open(&[path]) {
+            report.add(None, format!("geoip db {}: {}", path, error));
+        }
+    }
+    for c in &mut config.nodes {
+        let node = Some(c.name.as_str());
+        if !names.insert(c.name.clone()) {
+            report.add(node, "the name is used by several nodes".to_string());
+        }
+        if let Some(port) = c.http_v3 {
+            report.unique_port(&mut http_ports, port, format!("http_v3 of node {}", c.name));
+        }
+        if let Some(log) = &c.log {
+            let what = format!("log.port of node {}", c.name);
+            report.unique_port(&mut syslog_ports, log.port, what);
+        }
+        if let Some(p2p) = &mut c.p2p {
+            if let Some(path) = &p2p.node_config {
+                if !Path::new(path).is_file() {
+                    report.add(node, format!("node_config {} does not exist", path));
+                }
+            }
+            p2p.resolve_port(&c.name);
+            if let Err(error) = p2p.capture_types() {
+                report.add(node, format!("capture_types: {}", error));
+            }
+            if let Some(version) = p2p.distributed_db_version {
+                if PeerEncoding::for_version(version).is_none() {
+                    let problem = format!("distributed_db_version {} is not supported", version);
+                    report.add(node, problem);
+                }
+            }
+            if let Some(port) = p2p.port {
+                let what = format!("p2p port of node {}", c.name);
+                report.unique_port(&mut p2p_ports, port, what);
+            }
+            match std::fs::read(&p2p.identity) {
+                Ok(source) => {
+                    if let Err(error) = NodeInfo::parse_identity(&source) {
+                        report.add(node, format!("identity {}: {}", p2p.identity, error));
+                    }
+                },
+                Err(error) => {
+                    report.add(node, format!("identity {}: {}", p2p.identity, error));
+                },
+            }
+        }
+        if c.integrity_check.as_ref().map_or(false, |check| check.sample == Some(0)) {
+            report.add(node, "integrity_check.sample is 0, nothing is checked".to_string());
+        }
+        if !c.in_memory {
+            if let Err(problem) = check_writable(Path::new(&c.db)) {
+                report.add(node, format!("db {}: {}", c.db, problem));
+            }
+            if let Some(other) = db_paths.insert(PathBuf::from(&c.db), c.name.clone()) {
+                let problem = format!("db {} is also used by node {}", c.db, other);
+                report.add(node, problem);
+            }
+        }
+    }
+
+    report
+}
+
+/// Read back a sample of the records, the failure marks the node unhealthy in `/v3/health`
+pub(crate) fn check_integrity<Db>(name: &str, db: &Db, status: &NodeStatus, sample: usize)
+where
+    Db: Database,
+{
+    let report = db.check_integrity(sample);
+    if report.is_healthy() {
+        log::debug!("node: {}, integrity check: {} records are fine", name, report.checked);
+    } else {
+        for failure in &report.failures {
+            log::error!(
+                "node: {}, DATABASE CORRUPTED, table: {}, error: {}",
+                name,
+                failure.table,
+                failure.error,
+            );
+        }
+        if report.background_errors != 0 {
+            log::error!(
+                "node: {}, DATABASE CORRUPTED, rocksdb background errors: {}",
+                name,
+                report.background_errors,
+            );
+        }
+    }
+    status.set_integrity(report);
+}
+
+/// The database creates its directory, so the nearest existing ancestor must be writable
+fn check_writable(path: &Path) -> Result<(), String> {
+    let mut dir = path;
+    while !dir.exists() {
+        dir = match dir.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+    }
+    if !dir.is_dir() {
+        return Err(format!("{} is not a directory", dir.display()));
+    }
+    let probe = dir.join(format!(".tezedge-recorder-probe-{}", std::process::id()));
+    std::fs::File::create(&probe)
+        .map_err(|error| format!("{} is not writable: {}", dir.display(), error))?;
+    let _ = std::fs::remove_file(&probe);
+    Ok(())
+}
+
 #[derive(Clone)]
 pub struct Identity {
     pub public_key: [u8; 32],
     pub secret_key: [u8; 32],
 }
 
+/// The precomputed key of a connection, 32 bytes in hex,
+/// the peers derive it from their keys during the handshake
+#[derive(Clone, Copy, Deserialize)]
+#[serde(try_from = "String")]
+pub struct SessionKey(pub [u8; 32]);
+
+#[derive(Error, Debug)]
+pub enum ParseSessionKeyError {
+    #[error("cannot parse hex: {}", _0)]
+    Hex(hex::FromHexError),
+    #[error("the key must be 32 bytes, got {}", _0)]
+    Length(usize),
+}
+
+impl TryFrom<String> for SessionKey {
+    type Error = ParseSessionKeyError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let bytes = hex::decode(s).map_err(ParseSessionKeyError::Hex)?;
+        <[u8; 32]>::try_from(bytes.as_slice())
+            .map(SessionKey)
+            .map_err(|_| ParseSessionKeyError::Length(bytes.len()))
+    }
+}
+
+// the inode, the length and the modification time of the identity file,
+// the file is read and parsed again only when they change
+#[derive(PartialEq, Eq)]
+struct FileStamp {
+    ino: u64,
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+impl FileStamp {
+    fn of(path: &str) -> io::Result<Self> {
+        use std::os::unix::fs::MetadataExt;
+
+        let metadata = std::fs::metadata(path)?;
+        Ok(FileStamp {
+            ino: metadata.ino(),
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+        })
+    }
+}
+
 pub struct NodeInfo {
-    identity: Identity,
+    // parsed once per change of the file, see `reload_identity`
+    identity: Option<Identity>,
+    // the identity file seen last time, used to detect the node rotated its identity
+    identity_stamp: Option<FileStamp>,
+    identity_path: String,
     name: String,
+    status: Arc<NodeStatus>,
+    rate_limit: Option<Arc<RateLimit>>,
+    capture_options: CaptureOptions,
+    precomputed_keys: HashMap<SocketAddr, SessionKey>,
+    geoip: Option<Enricher>,
+}
+
+/// The state of the node shared with its http server
+pub struct NodeStatus {
+    identity_path: Option<String>,
+    pow_target: f64,
+    chunk_storage: ChunkStorage,
+    // the server rejects the requests without it, see `http_common::auth::bearer`
+    api_token: Option<String>,
+    // shared by the servers of all nodes, see `limit::permit`
+    read_limiter: Option<Arc<Limiter>>,
+    // the identity is missing or invalid, chunks are stored, but not decrypted
+    capture_only: AtomicBool,
+    // the free space is below the threshold, new connections are not recorded
+    low_disk: AtomicBool,
+    // the last integrity check, `None` if the check is off or did not run yet
+    integrity: Mutex<Option<integrity::Report>>,
+    // when the recorder started, milliseconds since the unix epoch
+    capture_start: u64,
+    started: Instant,
+    // fetched from the bpf module periodically, it is shared by all nodes
+    context_stats: Mutex<Option<ContextStats>>,
+    // updated by the main loop, it is shared by all nodes
+    connection_stats: Mutex<ConnectionStats>,
+    // the connections the parser tracks, published by the main loop, shared by all nodes
+    active: Mutex<Vec<ActiveConnection>>,
+    // the connections to finalize, requested by the server, done by the main loop
+    finalize: Mutex<Vec<connection::Key>>,
+}
+
+/// The connections tracked by the recorder against `max_connections`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConnectionStats {
+    pub tracked: usize,
+    pub max: Option<usize>,
+    // the connections not recorded since the start, because of the limit
+    pub rejected: u64,
+    // the limit is reached, new connections are not recorded until some close
+    pub saturated: bool,
 }
 
 #[derive(Error, Debug)]
@@ -66,35 +572,45 @@ pub enum NodeError {
     ParsePk,
     #[error("failed to parse secret key from hex")]
     ParseSk,
+    #[error("the node does not record p2p, no identity configured")]
+    NoIdentityPath,
 }
 
 struct NodeServer {
     _server: Option<JoinHandle<()>>,
     log_client: Option<thread::JoinHandle<()>>,
+    maintenance: thread::JoinHandle<()>,
+    // resolves the country and the asn of the peers, if `geoip_dbs` is configured
+    geoip: Option<(Enricher, thread::JoinHandle<()>)>,
 }
 
 pub struct System<Db> {
     config: Config,
     port_to_pid: HashMap<u16, u32>,
-    node_info: HashMap<u32, NodeInfo>,
+    // by the port where the node listens, several nodes might run in one process
+    node_info: HashMap<u16, NodeInfo>,
+    node_status: HashMap<String, Arc<NodeStatus>>,
     node_servers: HashMap<String, NodeServer>,
     node_dbs: HashMap<String, Arc<Db>>,
+    geoip: Option<Arc<dyn GeoIp>>,
     _old_server: Option<JoinHandle<()>>,
     tokio_rt: Runtime,
 }
 
 impl NodeServer {
     pub fn open_spawn<Db>(
-        db_path: &str,
-        rpc_port: Option<u16>,
-        log_config: &Option<LogConfig>,
-        p2p_config: &Option<P2pConfig>,
+        config: &NodeConfig,
+        tls: Option<&TlsConfig>,
+        status: Arc<NodeStatus>,
+        geoip: Option<Arc<dyn GeoIp>>,
         rt: &Runtime,
         running: Arc<AtomicBool>,
     ) -> Result<(Self, Arc<Db>)>
     where
         Db: DatabaseNew + Database + DatabaseFetch + Sync + Send + 'static,
     {
+        let log_config = &config.log;
+        let p2p_config = &config.p2p;
         let log_search = !log_config
             .as_ref()
             .and_then(|c| c.disable_search)
@@ -105,23 +621,108 @@ impl NodeServer {
         let message_store_limit = p2p_config
             .as_ref()
             .and_then(|c| c.store_limit);
-        let db = Arc::new(Db::open(db_path, log_search, log_store_limit, message_store_limit)?);
-        let server = if let Some(port) = rpc_port {
+        let open = if config.in_memory {
+            Db::open_in_memory
+        } else {
+            Db::open
+        };
+        let db = Arc::new(open(
+            &config.db,
+            log_search,
+            log_store_limit,
+            message_store_limit,
+            config.batch.clone(),
+        )?);
+        if let Some(size) = p2p_config.as_ref().and_then(|c| c.tail_size) {
+            db.set_tail_size(size);
+        }
+        let server = if let Some(port) = config.http_v3 {
             let addr = ([0, 0, 0, 0], port);
-            Some(rt.spawn(warp::serve(server::routes(db.clone())).run(addr)))
+            let routes = server::routes(db.clone(), status.clone());
+            Some(rt.spawn(tls::serve(routes, addr, tls)))
         } else {
             None
         };
+        let geoip = geoip.map(|geoip| Enricher::spawn(geoip, db.clone(), running.clone()));
         let log_client = if let Some(log_config) = log_config {
-            Some(log_client::spawn(log_config.port, db.clone(), running)?)
+            let name = config.name.clone();
+            Some(log_client::spawn(name, log_config.port, db.clone(), running.clone())?)
         } else {
             None
         };
 
+        let maintenance = {
+            let db = db.clone();
+            let threshold = config.compaction_threshold.unwrap_or(0.5);
+            let flush_interval = config.batch.interval();
+            let name = config.name.clone();
+            let path = PathBuf::from(&config.db);
+            let mut disk_guard = config
+                .disk_guard
+                .clone()
+                .map(|c| DiskGuard::new(c, FileSystem));
+            let integrity_check = config.integrity_check.clone();
+            thread::spawn(move || {
+                use std::time::{Duration, Instant};
+
+                // commit the queue every interval, compact every minute, but stop quickly,
+                // the step is short, so the interval longer than the step is counted
+                let step = flush_interval.clamp(Duration::from_millis(10), Duration::from_secs(1));
+                let mut last_flush = Instant::now();
+                let mut last_compaction = Instant::now();
+                let mut last_integrity_check = Instant::now();
+                while running.load(Ordering::Relaxed) {
+                    thread::sleep(step);
+                    if last_flush.elapsed() >= flush_interval {
+                        db.flush();
+                        last_flush = Instant::now();
+                    }
+                    if let Some(disk_guard) = &mut disk_guard {
+                        match disk_guard.check(&path) {
+                            Some(Transition::Low { available }) => {
+                                log::warn!(
+                                    "node: {}, free space: {} bytes is low, capture is paused",
+                                    name,
+                                    available,
+                                );
+                                status.set_low_disk(true);
+                                if let Some(fraction) = disk_guard.prune() {
+                                    db.prune(fraction);
+                                }
+                            },
+                            Some(Transition::Recovered { available }) => {
+                                log::info!(
+                                    "node: {}, free space: {} bytes, capture is resumed",
+                                    name,
+                                    available,
+                                );
+                                status.set_low_disk(false);
+                            },
+                            None => (),
+                        }
+                    }
+                    if last_compaction.elapsed() >= Duration::from_secs(60) {
+                        db.compact(threshold);
+                        last_compaction = Instant::now();
+                    }
+                    if let Some(c) = &integrity_check {
+                        if last_integrity_check.elapsed() >= c.interval() {
+                            check_integrity(&name, db.as_ref(), &status, c.sample());
+                            last_integrity_check = Instant::now();
+                        }
+                    }
+                }
+                // the recorder is stopping, commit what is left
+                db.flush();
+            })
+        };
+
         Ok((
             NodeServer {
                 _server: server,
                 log_client,
+                maintenance,
+                geoip,
             },
             db,
         ))
@@ -131,12 +732,196 @@ impl NodeServer {
         if let Some(log_client) = self.log_client {
             log_client.join().unwrap()
         }
+        self.maintenance.join().unwrap();
+        if let Some((_, enricher)) = self.geoip {
+            enricher.join().unwrap();
+        }
+    }
+
+    fn geoip(&self) -> Option<Enricher> {
+        self.geoip.as_ref().map(|(enricher, _)| enricher.clone())
+    }
+}
+
+impl NodeStatus {
+    pub const DEFAULT_POW_TARGET: f64 = 26.0;
+
+    pub fn new(identity_path: Option<String>, pow_target: f64) -> Self {
+        NodeStatus {
+            identity_path,
+            pow_target,
+            chunk_storage: ChunkStorage::All,
+            api_token: None,
+            read_limiter: None,
+            capture_only: AtomicBool::new(false),
+            low_disk: AtomicBool::new(false),
+            integrity: Mutex::new(None),
+            capture_start: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or(0),
+            started: Instant::now(),
+            context_stats: Mutex::new(None),
+            connection_stats: Mutex::new(ConnectionStats::default()),
+            active: Mutex::new(Vec::new()),
+            finalize: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// When the recorder started, milliseconds since the unix epoch
+    pub fn capture_start(&self) -> u64 {
+        self.capture_start
+    }
+
+    pub fn uptime_seconds(&self) -> u64 {
+        self.started.elapsed().as_secs()
+    }
+
+    pub fn capture_only(&self) -> bool {
+        self.capture_only.load(Ordering::Relaxed)
+    }
+
+    pub fn pow_target(&self) -> f64 {
+        self.pow_target
+    }
+
+    pub fn with_chunk_storage(self, chunk_storage: ChunkStorage) -> Self {
+        NodeStatus {
+            chunk_storage,
+            ..self
+        }
+    }
+
+    pub fn chunk_storage(&self) -> ChunkStorage {
+        self.chunk_storage
+    }
+
+    pub fn with_api_token(self, api_token: Option<String>) -> Self {
+        NodeStatus { api_token, ..self }
+    }
+
+    pub fn api_token(&self) -> Option<String> {
+        self.api_token.clone()
+    }
+
+    pub fn with_read_limiter(self, read_limiter: Option<Arc<Limiter>>) -> Self {
+        NodeStatus {
+            read_limiter,
+            ..self
+        }
+    }
+
+    pub fn read_limiter(&self) -> Option<Arc<Limiter>> {
+        self.read_limiter.clone()
+    }
+
+    fn set_capture_only(&self, capture_only: bool) {
+        self.capture_only.store(capture_only, Ordering::Relaxed);
+    }
+
+    pub fn low_disk(&self) -> bool {
+        self.low_disk.load(Ordering::Relaxed)
+    }
+
+    fn set_low_disk(&self, low_disk: bool) {
+        self.low_disk.store(low_disk, Ordering::Relaxed);
+    }
+
+    /// The report of the last integrity check of the database
+    pub fn integrity(&self) -> Option<integrity::Report> {
+        self.integrity.lock().unwrap().clone()
+    }
+
+    /// The last integrity check failed, the responses of the server are marked
+    pub fn degraded(&self) -> bool {
+        self.integrity
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map_or(false, |report| !report.is_healthy())
+    }
+
+    fn set_integrity(&self, report: integrity::Report) {
+        *self.integrity.lock().unwrap() = Some(report);
+    }
+
+    /// Syscall context counters of the bpf module, `None` until fetched
+    pub fn context_stats(&self) -> Option<ContextStats> {
+        *self.context_stats.lock().unwrap()
+    }
+
+    pub fn set_context_stats(&self, stats: ContextStats) {
+        *self.context_stats.lock().unwrap() = Some(stats);
+    }
+
+    pub fn connection_stats(&self) -> ConnectionStats {
+        *self.connection_stats.lock().unwrap()
+    }
+
+    pub fn set_connection_stats(&self, stats: ConnectionStats) {
+        *self.connection_stats.lock().unwrap() = stats;
+    }
+
+    /// The open connections as of the last tick of the main loop, not read from the database
+    pub fn active_connections(&self) -> Vec<ActiveConnection> {
+        self.active.lock().unwrap().clone()
+    }
+
+    pub fn set_active_connections(&self, active: Vec<ActiveConnection>) {
+        *self.active.lock().unwrap() = active;
+    }
+
+    /// The main loop finalizes the connection when it handles the next events
+    pub fn request_finalize(&self, cn_id: connection::Key) {
+        self.finalize.lock().unwrap().push(cn_id);
+    }
+
+    fn take_finalize_requests(&self) -> Vec<connection::Key> {
+        std::mem::take(&mut *self.finalize.lock().unwrap())
+    }
+
+    /// Read the identity file of the node, on success leave capture-only mode
+    pub fn load_identity(&self) -> Result<Identity, NodeError> {
+        let path = self
+            .identity_path
+            .as_ref()
+            .ok_or(NodeError::NoIdentityPath)?;
+        let source = std::fs::read(path).map_err(NodeError::OpenIdentity)?;
+        let identity = NodeInfo::parse_identity(&source)?;
+        self.set_capture_only(false);
+        Ok(identity)
     }
 }
 
 impl NodeInfo {
-    pub fn new(identity_path: &str, name: String) -> Result<Self, NodeError> {
-        use std::{fs::File, convert::TryInto};
+    /// Missing or invalid identity is not fatal, the node is recorded in capture-only mode
+    /// until the identity file appears.
+    pub fn new(
+        identity_path: &str,
+        name: String,
+        status: Arc<NodeStatus>,
+        rate_limit: Option<RateLimit>,
+    ) -> Self {
+        let mut info = NodeInfo {
+            identity: None,
+            identity_stamp: None,
+            identity_path: identity_path.to_string(),
+            name,
+            status,
+            rate_limit: rate_limit.map(Arc::new),
+            capture_options: CaptureOptions::default(),
+            precomputed_keys: HashMap::new(),
+            geoip: None,
+        };
+        info.reload_identity();
+        if info.identity.is_none() {
+            info.status.set_capture_only(true);
+        }
+        info
+    }
+
+    fn parse_identity(source: &[u8]) -> Result<Identity, NodeError> {
+        use std::convert::TryInto;
 
         #[derive(Deserialize)]
         pub struct Inner {
@@ -148,14 +933,13 @@ impl NodeInfo {
             proof_of_work_stamp: String,
         }
 
-        let file = File::open(identity_path).map_err(NodeError::OpenIdentity)?;
         let Inner {
             public_key,
             secret_key,
             ..
-        } = serde_json::from_reader(file).map_err(NodeError::ParseIdentity)?;
+        } = serde_json::from_slice(source).map_err(NodeError::ParseIdentity)?;
 
-        let identity = Identity {
+        Ok(Identity {
             public_key: {
                 hex::decode(public_key)
                     .map_err(|_| NodeError::ParsePk)?
@@ -168,14 +952,117 @@ impl NodeInfo {
                     .try_into()
                     .map_err(|_| NodeError::ParseSk)?
             },
+        })
+    }
+
+    /// Re-read the identity file, if it changed since the last time, use the new identity.
+    /// Keep the old identity if the file cannot be read or parsed.
+    /// Only the metadata of the file is checked if it did not change.
+    pub fn reload_identity(&mut self) {
+        // taken before reading, the change made while reading is seen next time
+        let stamp = match FileStamp::of(&self.identity_path) {
+            Ok(stamp) => stamp,
+            Err(error) => {
+                self.report(NodeError::OpenIdentity(error));
+                return;
+            },
         };
+        if self.identity_stamp.as_ref() == Some(&stamp) {
+            return;
+        }
+        let source = match std::fs::read(&self.identity_path) {
+            Ok(source) => source,
+            Err(error) => {
+                self.report(NodeError::OpenIdentity(error));
+                return;
+            },
+        };
+        match Self::parse_identity(&source) {
+            Ok(identity) => {
+                log::info!("node: {}, identity reloaded from {}", self.name, self.identity_path);
+                self.identity = Some(identity);
+                self.status.set_capture_only(false);
+            },
+            Err(error) => self.report(error),
+        }
+        // do not report the same malformed file again
+        self.identity_stamp = Some(stamp);
+    }
 
-        Ok(NodeInfo { identity, name })
+    fn report(&self, error: NodeError) {
+        if self.identity.is_some() {
+            log::error!("node: {}, keep old identity, {}", self.name, error);
+        } else if !self.status.capture_only() {
+            log::warn!(
+                "node: {}, {}, capture-only mode: chunks are stored, but not decrypted",
+                self.name,
+                error,
+            );
+            self.status.set_capture_only(true);
+        }
     }
 
-    pub fn identity(&self) -> Identity {
+    /// The identity to use for a new connection,
+    /// connections already in progress keep their own copy.
+    pub fn identity(&mut self) -> Option<Identity> {
+        self.reload_identity();
         self.identity.clone()
     }
+
+    pub fn pow_target(&self) -> f64 {
+        self.status.pow_target()
+    }
+
+    /// The capture is paused, see `DiskGuard`
+    pub fn low_disk(&self) -> bool {
+        self.status.low_disk()
+    }
+
+    /// The monitor for a new connection, if the node has `rate_limit` configured
+    pub fn rate_monitor(&self) -> Option<RateMonitor> {
+        self.rate_limit
+            .clone()
+            .map(|limit| RateMonitor::new(self.name.clone(), limit))
+    }
+
+    /// How the connections of the node are recorded, see `P2pConfig::capture_options`
+    pub fn with_capture_options(self, capture_options: CaptureOptions) -> Self {
+        NodeInfo {
+            capture_options,
+            ..self
+        }
+    }
+
+    pub fn capture_options(&self) -> CaptureOptions {
+        self.capture_options.clone()
+    }
+
+    /// Decrypt the connections with these peers without the identity,
+    /// if the node has `precomputed_keys` configured
+    pub fn with_precomputed_keys(self, precomputed_keys: HashMap<SocketAddr, SessionKey>) -> Self {
+        NodeInfo {
+            precomputed_keys,
+            ..self
+        }
+    }
+
+    pub fn precomputed_key(&self, remote_addr: &SocketAddr) -> Option<[u8; 32]> {
+        self.precomputed_keys.get(remote_addr).map(|key| key.0)
+    }
+
+    /// Resolve the country and the asn of the peers, see `geoip_dbs`
+    pub fn with_geoip(self, geoip: Option<Enricher>) -> Self {
+        NodeInfo { geoip, ..self }
+    }
+
+    pub fn geoip(&self) -> Option<Enricher> {
+        self.geoip.clone()
+    }
+
+    /// The state shared with the http server of the node
+    pub fn status(&self) -> Arc<NodeStatus> {
+        self.status.clone()
+    }
 }
 
 impl<Db> System<Db> {
@@ -187,17 +1074,70 @@ impl<Db> System<Db> {
             .or_else(|_| File::open("/home/appuser/config.toml"))?;
         let mut settings_toml = String::new();
         settings_file.read_to_string(&mut settings_toml)?;
-        let config = toml::from_str(&settings_toml)?;
+        Self::from_toml(&settings_toml)
+    }
+
+    pub fn from_toml(s: &str) -> Result<Self> {
+        let config = toml::from_str::<Config>(s)?;
+        config.validate()?;
 
-        Ok(System {
+        Ok(Self::new(config))
+    }
+
+    fn new(mut config: Config) -> Self {
+        for c in &mut config.nodes {
+            if let Some(p2p) = &mut c.p2p {
+                p2p.resolve_port(&c.name);
+            }
+        }
+        let api_token = config.api_token();
+        let read_limiter = config
+            .max_concurrent_reads
+            .map(|max| Arc::new(Limiter::new(max)));
+        let node_status = config
+            .nodes
+            .iter()
+            .map(|c| {
+                let identity_path = c.p2p.as_ref().map(|p2p| p2p.identity.clone());
+                let pow_target = c
+                    .p2p
+                    .as_ref()
+                    .and_then(|p2p| p2p.pow_target)
+                    .unwrap_or(NodeStatus::DEFAULT_POW_TARGET);
+                let chunk_storage = c
+                    .p2p
+                    .as_ref()
+                    .map(|p2p| p2p.chunk_storage)
+                    .unwrap_or_default();
+                let status = NodeStatus::new(identity_path, pow_target)
+                    .with_chunk_storage(chunk_storage)
+                    .with_api_token(api_token.clone())
+                    .with_read_limiter(read_limiter.clone());
+                (c.name.clone(), Arc::new(status))
+            })
+            .collect();
+        let geoip = if config.geoip_dbs.is_empty() {
+            None
+        } else {
+            match MaxMind::open(config.geoip_dbs.as_slice()) {
+                Ok(geoip) => Some(Arc::new(geoip) as Arc<dyn GeoIp>),
+                Err(error) => {
+                    log::error!("cannot open geoip db, peers are not resolved: {}", error);
+                    None
+                },
+            }
+        };
+        System {
             config,
             port_to_pid: HashMap::new(),
             node_info: HashMap::new(),
+            node_status,
             node_servers: HashMap::new(),
             node_dbs: HashMap::new(),
+            geoip,
             _old_server: None,
             tokio_rt: Runtime::new().unwrap(),
-        })
+        }
     }
 
     pub fn sniffer_path(&self) -> &str {
@@ -208,47 +1148,108 @@ impl<Db> System<Db> {
         self.config.nodes.iter().filter_map(|c| c.p2p.as_ref())
     }
 
+    /// Some node has no port configured, it is detected by the bind
+    pub fn detecting_port(&self) -> bool {
+        self.p2p_configs().any(|p2p| p2p.port.is_none())
+    }
+
+    pub fn is_p2p_port(&self, port: u16) -> bool {
+        self.p2p_configs().any(|p2p| p2p.port == Some(port))
+    }
+
+    /// Assign the port to the first node whose port is not known yet,
+    /// return `true` if the port is assigned, so the recorder should watch it
+    pub fn detect_port(&mut self, port: u16) -> bool {
+        if self.is_p2p_port(port) || self.is_ignored_port(port) {
+            return false;
+        }
+        let c = self
+            .config
+            .nodes
+            .iter_mut()
+            .find(|c| c.p2p.as_ref().map(|p2p| p2p.port.is_none()) == Some(true));
+        match c {
+            Some(c) => {
+                log::info!("node: {}, detected p2p port: {}", c.name, port);
+                c.p2p.as_mut().unwrap().port = Some(port);
+                true
+            },
+            None => false,
+        }
+    }
+
     pub fn need_bpf(&self) -> bool {
         self.config.nodes.iter().any(|c| c.p2p.is_some())
     }
 
+    /// The bpf module is one for all nodes, so are its counters
+    pub fn set_context_stats(&self, stats: ContextStats) {
+        for status in self.node_status.values() {
+            status.set_context_stats(stats);
+        }
+    }
+
+    pub fn max_connections(&self) -> Option<usize> {
+        self.config.max_connections
+    }
+
+    /// The config is validated at start, so the regex compiles
+    pub fn watch_cmdline(&self) -> Option<&CmdlineConfig> {
+        self.config.watch_cmdline.as_ref()
+    }
+
+    /// The connections are tracked in one list for all nodes
+    pub fn set_connection_stats(&self, stats: ConnectionStats) {
+        for status in self.node_status.values() {
+            status.set_connection_stats(stats);
+        }
+    }
+
+    pub fn set_active_connections(&self, active: Vec<ActiveConnection>) {
+        for status in self.node_status.values() {
+            status.set_active_connections(active.clone());
+        }
+    }
+
+    /// The connections to finalize requested for any of the nodes
+    pub fn take_finalize_requests(&self) -> Vec<connection::Key> {
+        self.node_status
+            .values()
+            .flat_map(|status| status.take_finalize_requests())
+            .collect()
+    }
+
     pub fn join(self) {
         for (_, server) in self.node_servers {
             server.join();
         }
     }
 
-    pub fn should_ignore(&self, address: &SocketAddr) -> bool {
-        //use std::net::IpAddr;
-
-        match address.port() {
-            0 | 65535 => {
-                return true;
-            },
+    fn is_ignored_port(&self, port: u16) -> bool {
+        match port {
+            0 | 65535 => true,
             // dns and other well known not tezos
-            53 | 80 | 443 | 22 => {
-                return true;
-            },
+            53 | 80 | 443 | 22 => true,
             // ignore syslog
-            p => {
-                if self
-                    .config
-                    .nodes
-                    .iter()
-                    .any(|n| n.log.as_ref().map(|l| l.port).unwrap_or(0) == p)
-                {
-                    return true;
-                }
-            },
+            p => self
+                .config
+                .nodes
+                .iter()
+                .any(|n| n.log.as_ref().map(|l| l.port).unwrap_or(0) == p),
+        }
+    }
+
+    pub fn should_ignore(&self, address: &SocketAddr) -> bool {
+        if self.is_ignored_port(address.port()) {
+            return true;
+        }
+        let ip = address.ip();
+        if self.config.ignore_loopback && cidr::is_loopback(&ip) {
+            return true;
+        }
+        if self.config.ignore.iter().any(|cidr| cidr.contains(&ip)) {
+            return true;
         }
-        // lo v6
-        //if address.ip() == "::1".parse::<IpAddr>().unwrap() {
-        //    return true;
-        //}
-        // lo v4
-        //if address.ip() == "127.0.0.1".parse::<IpAddr>().unwrap() {
-        //    return true;
-        //}
 
         false
     }
@@ -261,8 +1262,10 @@ where
     pub fn run_dbs(&mut self, running: Arc<AtomicBool>) {
         for c in &self.config.nodes {
             let r = running.clone();
-            let rt = &self.tokio_rt;
-            match NodeServer::open_spawn(&c.db, c.http_v3, &c.log, &c.p2p, rt, r) {
+            let status = self.node_status[&c.name].clone();
+            let tls = self.config.tls.as_ref();
+            let geoip = self.geoip.clone();
+            match NodeServer::open_spawn(c, tls, status, geoip, &self.tokio_rt, r) {
                 Ok((server, db)) => {
                     self.node_servers.insert(c.name.clone(), server);
                     self.node_dbs.insert(c.name.clone(), db);
@@ -275,41 +1278,779 @@ where
 
         if let Some(port) = self.config.http_v2 {
             let addr = ([0, 0, 0, 0], port);
-            let s = warp::serve(server::routes_old(self.node_dbs.clone())).run(addr);
+            let routes = server::routes_old(self.node_dbs.clone(), self.config.api_token());
+            let s = tls::serve(routes, addr, self.config.tls.as_ref());
             self._old_server = Some(self.tokio_rt.spawn(s));
         }
     }
 
     pub fn handle_bind(&mut self, pid: u32, port: u16) -> Result<()> {
-        let info = if let Some(old_pid) = self.port_to_pid.remove(&port) {
+        if !self.is_p2p_port(port) {
+            anyhow::bail!("no node is configured at port: {}", port);
+        }
+        if let Some(old_pid) = self.port_to_pid.insert(port, pid) {
             log::info!("detaching from pid: {} at port: {}", old_pid, port);
-            self.node_info.remove(&old_pid).unwrap()
         } else {
             let c = self
                 .config
                 .nodes
                 .iter()
                 .filter(|c| c.p2p.is_some())
-                .find(|c| c.p2p.as_ref().unwrap().port == port)
+                .find(|c| c.p2p.as_ref().unwrap().port == Some(port))
                 .unwrap();
             let p2p = c.p2p.as_ref().unwrap();
-            NodeInfo::new(&p2p.identity, c.name.clone())?
-        };
+            let status = self.node_status[&c.name].clone();
+            let rate_limit = p2p.rate_limit.clone();
+            let info = NodeInfo::new(&p2p.identity, c.name.clone(), status, rate_limit)
+                .with_capture_options(p2p.capture_options())
+                .with_precomputed_keys(p2p.precomputed_keys.clone())
+                .with_geoip(self.node_servers.get(&c.name).and_then(NodeServer::geoip));
+            self.node_info.insert(port, info);
+        }
         log::info!("attaching to pid: {} at port: {}", pid, port);
-        self.port_to_pid.insert(port, pid);
-        self.node_info.insert(pid, info);
 
         Ok(())
     }
 
-    pub fn get_mut(&mut self, pid: u32) -> Option<(&mut NodeInfo, Arc<Db>)> {
+    /// The port of the node the connection belongs to. The connection accepted
+    /// on the known listening socket belongs to the node listening there,
+    /// otherwise, to the node of the process with the lowest port.
+    pub fn node_port(&self, pid: u32, listen_port: Option<u16>) -> Option<u16> {
+        match listen_port {
+            Some(port) if self.port_to_pid.get(&port) == Some(&pid) => Some(port),
+            _ => self.process_ports(pid).next(),
+        }
+    }
+
+    fn process_ports(&self, pid: u32) -> impl Iterator<Item = u16> {
+        let mut ports = self
+            .port_to_pid
+            .iter()
+            .filter(|&(_, p)| *p == pid)
+            .map(|(port, _)| *port)
+            .collect::<Vec<_>>();
+        ports.sort_unstable();
+        ports.into_iter()
+    }
+
+    /// The identities to decrypt the connection. If the connection is not attributed
+    /// by its listening socket and several nodes run in the process, it might belong
+    /// to any of them, so the identities of the other nodes follow the identity of the node.
+    pub fn identities(&mut self, pid: u32, listen_port: Option<u16>) -> Vec<Identity> {
+        let node_port = match self.node_port(pid, listen_port) {
+            Some(port) => port,
+            None => return vec![],
+        };
+        let ports = if listen_port == Some(node_port) {
+            vec![node_port]
+        } else {
+            let others = self.process_ports(pid).filter(|port| *port != node_port);
+            Some(node_port).into_iter().chain(others).collect()
+        };
+        ports
+            .into_iter()
+            .filter_map(|port| self.node_info.get_mut(&port)?.identity())
+            .collect()
+    }
+
+    /// The other nodes of the process the connection not attributed by its listening socket
+    /// might belong to, see `identities`, the node whose identity makes the keys stores it
+    pub fn candidates(&mut self, pid: u32, listen_port: Option<u16>) -> Vec<Candidate<Db>> {
+        let node_port = match self.node_port(pid, listen_port) {
+            Some(port) if listen_port != Some(port) => port,
+            _ => return vec![],
+        };
+        let others = self
+            .process_ports(pid)
+            .filter(|port| *port != node_port)
+            .collect::<Vec<_>>();
+        others
+            .into_iter()
+            .filter_map(|port| {
+                let (info, db) = self.get_mut(port)?;
+                Some(Candidate {
+                    public_key: info.identity()?.public_key,
+                    db,
+                    geoip: info.geoip(),
+                })
+            })
+            .collect()
+    }
+
+    /// The test opens the database of the node itself, to set its clock
+    #[cfg(test)]
+    pub fn insert_db(&mut self, name: &str, db: Arc<Db>) {
+        self.node_dbs.insert(name.to_string(), db);
+    }
+
+    pub fn get_mut(&mut self, port: u16) -> Option<(&mut NodeInfo, Arc<Db>)> {
         let db = self
             .node_info
-            .get(&pid)
+            .get(&port)
             .map(|i| i.name.clone())
             .and_then(|name| self.node_dbs.get(&name))?
             .clone();
-        let info = self.node_info.get_mut(&pid)?;
+        let info = self.node_info.get_mut(&port)?;
         Some((info, db))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, sync::Arc, time::SystemTime};
+    use super::{NodeInfo, NodeStatus, System, Config, ConfigError};
+    use crate::database::{mock, rocks};
+
+    #[test]
+    fn ignore_loopback() {
+        let config = toml::from_str::<Config>(
+            r#"
+            ignore_loopback = true
+            ignore = ["10.0.0.0/8", "fd00::/8"]
+
+            [[nodes]]
+            name = "test"
+            db = "target/debugger_db/test"
+            "#,
+        )
+        .unwrap();
+        let system = System::<mock::Db>::new(config);
+
+        for ignored in &[
+            "127.0.0.1:9732",
+            "[::1]:9732",
+            "[::ffff:127.0.0.1]:9732",
+            "10.2.3.4:9732",
+            "[fd12::1]:9732",
+        ] {
+            assert!(system.should_ignore(&ignored.parse().unwrap()), "{}", ignored);
+        }
+        for kept in &["51.15.220.7:9732", "[2001:db8::1]:9732"] {
+            assert!(!system.should_ignore(&kept.parse().unwrap()), "{}", kept);
+        }
+    }
+
+    #[test]
+    fn attribute_by_listener() {
+        let config = toml::from_str::<Config>(
+            r#"
+            [[nodes]]
+            name = "initiator"
+            db = "target/debugger_db/i"
+            p2p = { identity = "target/no-identity-i.json", port = 29732 }
+
+            [[nodes]]
+            name = "responder"
+            db = "target/debugger_db/r"
+            p2p = { identity = "target/no-identity-r.json", port = 29733 }
+            "#,
+        )
+        .unwrap();
+        let mut system = System::<mock::Db>::new(config);
+
+        // both nodes listen in the same process
+        system.handle_bind(100, 29732).unwrap();
+        system.handle_bind(100, 29733).unwrap();
+        assert_eq!(system.node_port(100, Some(29732)), Some(29732));
+        assert_eq!(system.node_port(100, Some(29733)), Some(29733));
+        assert_eq!(system.node_info[&29732].name, "initiator");
+        assert_eq!(system.node_info[&29733].name, "responder");
+
+        // outgoing connection, or unknown listener, some node of the process
+        assert!(system.node_port(100, None).is_some());
+        assert_eq!(system.node_port(200, Some(29732)), None);
+
+        // the node restarted in another process
+        system.handle_bind(200, 29733).unwrap();
+        assert_eq!(system.node_port(200, Some(29733)), Some(29733));
+        assert_eq!(system.node_port(200, None), Some(29733));
+        assert_eq!(system.node_port(100, Some(29733)), Some(29732));
+        assert_eq!(system.node_info[&29733].name, "responder");
+    }
+
+    #[test]
+    fn detect_port() {
+        let node_config = std::env::temp_dir().join(format!(
+            "tezedge-recorder-node-config-{}",
+            std::process::id(),
+        ));
+        fs::write(&node_config, "--p2p-port=29736\n").unwrap();
+        let config = format!(
+            r#"
+            [[nodes]]
+            name = "detected"
+            db = "target/debugger_db/d"
+            p2p = {{ identity = "target/no-identity-d.json" }}
+
+            [[nodes]]
+            name = "explicit"
+            db = "target/debugger_db/e"
+            p2p = {{ identity = "target/no-identity-e.json", port = 29734 }}
+
+            [[nodes]]
+            name = "from_file"
+            db = "target/debugger_db/f"
+            p2p = {{ identity = "target/no-identity-f.json", port = 29735, node_config = "{}" }}
+
+            [[nodes]]
+            name = "fallback"
+            db = "target/debugger_db/b"
+            p2p = {{ identity = "target/no-identity-b.json", port = 29737, node_config = "{}" }}
+            "#,
+            node_config.display(),
+            "target/no-such-node-config",
+        );
+        let mut system = System::<mock::Db>::new(toml::from_str::<Config>(&config).unwrap());
+        fs::remove_file(&node_config).unwrap();
+        assert!(system.is_p2p_port(29734));
+        assert!(system.is_p2p_port(29736));
+        assert!(!system.is_p2p_port(29735));
+        assert!(system.is_p2p_port(29737));
+
+        // the bind of a known port, or the ephemeral port, is not a detection
+        assert!(system.detecting_port());
+        assert!(!system.detect_port(29734));
+        assert!(!system.detect_port(0));
+        assert!(system.handle_bind(100, 29738).is_err());
+        // the first bind auto registers the port of the node
+        assert!(system.detect_port(29738));
+        assert!(!system.detecting_port());
+        system.handle_bind(100, 29738).unwrap();
+        assert_eq!(system.node_info[&29738].name, "detected");
+        assert_eq!(system.node_port(100, Some(29738)), Some(29738));
+        // nothing to detect anymore
+        assert!(!system.detect_port(29739));
+        assert!(system.handle_bind(100, 29739).is_err());
+    }
+
+    #[test]
+    fn two_identities() {
+        use std::{env, sync::atomic::{AtomicBool, Ordering}};
+        use crypto::nonce::generate_nonces;
+        use crate::{
+            common::Sender,
+            database::{DatabaseFetch, ConnectionsFilter},
+            processor::{Connection, CaptureOptions, fixture::{connection_message, metadata}},
+            tables::{connection, connection_crypto, chunk},
+        };
+        use super::Identity;
+
+        let dir = env::temp_dir().join(format!("tezedge-recorder-ids-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path_i = dir.join("identity_i.json");
+        let path_r = dir.join("identity_r.json");
+        fs::write(&path_i, include_str!("../identity_i.json")).unwrap();
+        fs::write(&path_r, include_str!("../identity_r.json")).unwrap();
+        let config = format!(
+            r#"
+            [[nodes]]
+            name = "initiator"
+            db = "target/debugger_db/i"
+            in_memory = true
+            p2p = {{ identity = "{}", port = 29732 }}
+
+            [[nodes]]
+            name = "responder"
+            db = "target/debugger_db/r"
+            in_memory = true
+            p2p = {{ identity = "{}", port = 29733 }}
+            "#,
+            path_i.display(),
+            path_r.display(),
+        );
+        let mut system = System::<rocks::Db>::from_toml(&config).unwrap();
+        let running = Arc::new(AtomicBool::new(true));
+        system.run_dbs(running.clone());
+        system.handle_bind(100, 29732).unwrap();
+        system.handle_bind(100, 29733).unwrap();
+
+        let id_i = NodeInfo::parse_identity(include_str!("../identity_i.json").as_bytes()).unwrap();
+        let id_r = NodeInfo::parse_identity(include_str!("../identity_r.json").as_bytes()).unwrap();
+        let pks = |ids: Vec<Identity>| ids.iter().map(|id| id.public_key).collect::<Vec<_>>();
+        // attributed by the listening socket, the identity of the node
+        assert_eq!(pks(system.identities(100, Some(29732))), [id_i.public_key]);
+        assert_eq!(pks(system.identities(100, Some(29733))), [id_r.public_key]);
+        // outgoing, could be either node, the attributed node goes first
+        let ambiguous = system.identities(100, None);
+        assert_eq!(pks(ambiguous), [id_i.public_key, id_r.public_key]);
+        assert!(system.identities(200, None).is_empty());
+
+
+        let cases = [
+            // accepted by the responder, talking to the initiator
+            ("51.15.220.7:9732", true, &id_r, &id_i, Some(29733)),
+            // outgoing from the initiator
+            ("51.15.220.8:9732", false, &id_i, &id_r, None),
+            // outgoing from the responder, the second candidate matches
+            ("51.15.220.9:9732", false, &id_r, &id_i, None),
+        ];
+        // the nonces derived by the handshake, by the remote address
+        let mut expected_nonces = Vec::new();
+        for (addr, incoming, local, remote, listen_port) in cases.iter().cloned() {
+            let address = addr.parse().unwrap();
+            let identities = system.identities(100, listen_port);
+            let candidates = system.candidates(100, listen_port);
+            // the ambiguous connection starts in the database of the first node
+            let node_port = system.node_port(100, listen_port).unwrap();
+            let (_, db) = system.get_mut(node_port).unwrap();
+            let options = CaptureOptions {
+                debug_crypto: true,
+                ..Default::default()
+            };
+            let mut connection = Connection::new(address, incoming, identities, 0.0, options, db)
+                .with_candidates(candidates);
+            let l_cm = connection_message(&local.public_key);
+            let r_cm = connection_message(&remote.public_key);
+            let nonces = generate_nonces(&l_cm, &r_cm, incoming).unwrap();
+            let local_nonce = nonces.local.get_bytes().unwrap();
+            let remote_nonce = nonces.remote.get_bytes().unwrap();
+            expected_nonces.push((addr, local.public_key, local_nonce, remote_nonce));
+            connection.handle_data(&l_cm, true, false, None);
+            connection.handle_data(&r_cm, true, true, None);
+            connection.handle_data(&metadata(local, &l_cm, &r_cm, incoming), true, true, None);
+            connection.join();
+        }
+
+        let filter = ConnectionsFilter::default();
+        // stored by the node whose identity decrypted the connection
+        let mut connections = vec![];
+        let expected = [
+            (29732, vec!["51.15.220.8:9732"]),
+            (29733, vec!["51.15.220.7:9732", "51.15.220.9:9732"]),
+        ];
+        for (port, expected) in &expected {
+            let (_, db) = system.get_mut(*port).unwrap();
+            let stored = db.fetch_connections(&filter).unwrap();
+            let mut addrs = stored
+                .iter()
+                .map(|(_, value)| serde_json::to_value(value).unwrap()["remote_addr"].clone())
+                .collect::<Vec<_>>();
+            addrs.sort_by_key(ToString::to_string);
+            assert_eq!(&addrs, expected, "{}", port);
+            connections.extend(stored.into_iter().map(|cn| (db.clone(), cn)));
+        }
+        for (db, (cn_id, value)) in connections {
+            assert!(!value.comments().outgoing_wrong_pk);
+            let crypto = db.fetch_connection_crypto(&cn_id).unwrap().unwrap();
+            let remote_addr = connection::Item::unite(cn_id.clone(), value).remote_addr;
+            let (_, local_pk, local_nonce, remote_nonce) = expected_nonces
+                .iter()
+                .find(|(addr, ..)| *addr == remote_addr.to_string())
+                .unwrap();
+            let crypto = serde_json::to_value(&crypto).unwrap();
+            assert_eq!(crypto["local"]["public_key"], hex::encode(local_pk));
+            assert_eq!(crypto["local"]["nonce_start"], hex::encode(local_nonce));
+            assert_eq!(crypto["local"]["nonce_current"], hex::encode(local_nonce));
+            assert_eq!(crypto["remote"]["nonce_start"], hex::encode(remote_nonce));
+            // the metadata is the only decrypted chunk
+            assert_eq!(crypto["remote"]["decrypted_chunks"], 1);
+            let next = connection_crypto::nonce_add(remote_nonce, 1);
+            assert_eq!(crypto["remote"]["nonce_current"], hex::encode(next));
+            let key = chunk::Key {
+                cn_id,
+                counter: 1,
+                sender: Sender::Remote,
+            };
+            let chunk = db.fetch_chunk(&key).unwrap().unwrap();
+            assert_eq!(chunk.plain, [0, 0], "{}", remote_addr);
+        }
+
+        running.store(false, Ordering::Relaxed);
+        system.join();
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn decode_stored() {
+        use crate::{
+            common::Sender,
+            database::{temp::TempDb, Database, DatabaseFetch, ConnectionsFilter},
+            processor::{self, Connection, fixture::{connection_message, metadata}},
+            tables::chunk,
+        };
+
+        let dir = TempDb::new("stored");
+        let db = Arc::new(dir.open());
+        let id_i = NodeInfo::parse_identity(include_str!("../identity_i.json").as_bytes()).unwrap();
+        let id_r = NodeInfo::parse_identity(include_str!("../identity_r.json").as_bytes()).unwrap();
+        let l_cm = connection_message(&id_i.public_key);
+        let r_cm = connection_message(&id_r.public_key);
+        let metadata = metadata(&id_i, &l_cm, &r_cm, false);
+
+        // recorded in capture-only mode, the chunks are stored, but not decrypted
+        let address = "51.15.220.7:9732".parse().unwrap();
+        let mut connection =
+            Connection::new(address, false, vec![], 0.0, Default::default(), db.clone());
+        connection.handle_data(&l_cm, true, false, None);
+        connection.handle_data(&r_cm, true, true, None);
+        connection.handle_data(&metadata, true, true, None);
+        let cn_id = connection.key();
+        connection.join();
+        db.flush();
+
+        let filter = ConnectionsFilter::default();
+        let key = chunk::Key {
+            cn_id: cn_id.clone(),
+            counter: 1,
+            sender: Sender::Remote,
+        };
+        assert!(db.fetch_connections(&filter).unwrap()[0].1.comments().outgoing_no_identity);
+        assert!(db.fetch_chunk(&key).unwrap().unwrap().plain.is_empty());
+
+        assert_eq!(processor::decode_stored(&db, &id_i, 0.0).unwrap(), 1);
+        db.flush();
+        // decoded in place, the same connection, its chunk is decrypted now
+        let connections = db.fetch_connections(&filter).unwrap();
+        assert_eq!(connections.len(), 1);
+        assert_eq!(connections[0].0, cn_id);
+        assert!(!connections[0].1.comments().outgoing_no_identity);
+        assert_eq!(db.fetch_chunk(&key).unwrap().unwrap().plain, [0, 0]);
+        // nothing is left to decode
+        assert_eq!(processor::decode_stored(&db, &id_i, 0.0).unwrap(), 0);
+    }
+
+    #[test]
+    fn precomputed_key() {
+        use crypto::{crypto_box::PrecomputedKey, nonce::generate_nonces};
+        use crate::{
+            common::Sender,
+            database::{temp::TempDb, DatabaseFetch, ConnectionsFilter},
+            processor::{Connection, CaptureOptions, fixture::{connection_message, encrypted}},
+            tables::chunk,
+        };
+
+        let config = |key: &str| {
+            let config = format!(
+                r#"
+                [[nodes]]
+                name = "tezedge"
+                db = "target/debugger_db/tezedge"
+                [nodes.p2p]
+                identity = "target/missing-identity.json"
+                port = 29734
+                precomputed_keys = {{ "51.15.220.7:9732" = "{}" }}
+                "#,
+                key,
+            );
+            toml::from_str::<Config>(&config)
+        };
+        // the length of the key is validated
+        assert!(config(&"ab".repeat(31)).is_err());
+        assert!(config("not a hex").is_err());
+
+        let key = [0x5a; 32];
+        let mut system = System::<mock::Db>::new(config(&hex::encode(key)).unwrap());
+        system.handle_bind(100, 29734).unwrap();
+        // no database is opened, look at the node directly
+        let info = &system.node_info[&29734];
+        let remote_addr = "51.15.220.7:9732".parse().unwrap();
+        assert_eq!(info.precomputed_key(&remote_addr), Some(key));
+        assert_eq!(info.precomputed_key(&"51.15.220.8:9732".parse().unwrap()), None);
+        let precomputed_key = info.precomputed_key(&remote_addr);
+
+        let dir = TempDb::new("pck");
+        let db = Arc::new(dir.open());
+        let (l_cm, r_cm) = (connection_message(&[1; 32]), connection_message(&[2; 32]));
+        // the tester knows only the precomputed key, neither of the identities
+        let nonces = generate_nonces(&l_cm, &r_cm, false).unwrap();
+        let metadata = encrypted(&PrecomputedKey::from_bytes(key), &[0, 0], &nonces.remote);
+
+        let options = CaptureOptions {
+            debug_crypto: true,
+            ..Default::default()
+        };
+        let mut connection = Connection::new(remote_addr, false, vec![], 0.0, options, db.clone())
+            .with_precomputed_key(precomputed_key);
+        connection.handle_data(&l_cm, true, false, None);
+        connection.handle_data(&r_cm, true, true, None);
+        connection.handle_data(&metadata, true, true, None);
+        connection.join();
+
+        let filter = ConnectionsFilter::default();
+        let connections = db.fetch_connections(&filter).unwrap();
+        assert_eq!(connections.len(), 1);
+        let (cn_id, value) = connections.into_iter().next().unwrap();
+        assert!(!value.comments().outgoing_no_identity);
+        assert!(value.comments().incoming_cannot_decrypt.is_none());
+        let crypto = db.fetch_connection_crypto(&cn_id).unwrap().unwrap();
+        let crypto = serde_json::to_value(&crypto).unwrap();
+        assert_eq!(crypto["remote"]["decrypted_chunks"], 1);
+        let key = chunk::Key {
+            cn_id,
+            counter: 1,
+            sender: Sender::Remote,
+        };
+        let chunk = db.fetch_chunk(&key).unwrap().unwrap();
+        assert_eq!(chunk.plain, [0, 0]);
+    }
+
+    #[test]
+    fn swap_identity() {
+        use crate::{
+            common::Sender,
+            database::{temp::TempDb, Database, DatabaseFetch},
+            processor::{Connection, fixture::{connection_message, metadata}},
+            tables::chunk,
+        };
+        use super::Identity;
+
+        let identity_dir = TempDb::new("swap-identity");
+        fs::create_dir_all(&*identity_dir).unwrap();
+        let path = identity_dir.join("identity.json");
+        let path_str = path.to_str().unwrap();
+
+        fs::write(&path, include_str!("../identity_i.json")).unwrap();
+        let target = NodeStatus::DEFAULT_POW_TARGET;
+        let status = Arc::new(NodeStatus::new(Some(path_str.to_string()), target));
+        let mut info = NodeInfo::new(path_str, "test".to_string(), status, None);
+        let first = info.identity().unwrap();
+
+        let dir = TempDb::new("swap");
+        let db = Arc::new(dir.open());
+        // the outgoing connection of the node with the `local` identity, the peer has `remote`,
+        // the local connection message is already seen, the rest is supplied by `finish`
+        let start = |local: Identity, remote: &Identity, addr: &str| {
+            let l_cm = connection_message(&local.public_key);
+            let r_cm = connection_message(&remote.public_key);
+            let metadata = metadata(&local, &l_cm, &r_cm, false);
+            let address = addr.parse().unwrap();
+            let mut connection =
+                Connection::new(address, false, vec![local], 0.0, Default::default(), db.clone());
+            connection.handle_data(&l_cm, true, false, None);
+            (connection, r_cm, metadata)
+        };
+        let finish = |(mut connection, r_cm, metadata): (Connection<_>, Vec<u8>, Vec<u8>)| {
+            connection.handle_data(&r_cm, true, true, None);
+            connection.handle_data(&metadata, true, true, None);
+            let cn_id = connection.key();
+            connection.join();
+            db.flush();
+            let key = chunk::Key {
+                cn_id,
+                counter: 1,
+                sender: Sender::Remote,
+            };
+            db.fetch_chunk(&key).unwrap().unwrap().plain
+        };
+        let id_i = NodeInfo::parse_identity(include_str!("../identity_i.json").as_bytes()).unwrap();
+        let id_r = NodeInfo::parse_identity(include_str!("../identity_r.json").as_bytes()).unwrap();
+        let in_progress = start(first.clone(), &id_r, "51.15.220.7:9732");
+
+        fs::write(&path, include_str!("../identity_r.json")).unwrap();
+        let second = info.identity().unwrap();
+        assert_ne!(first.public_key, second.public_key);
+        assert_ne!(first.secret_key, second.secret_key);
+        // the new connection is decrypted with the new identity,
+        // the connection in progress keeps the old one
+        let new = start(second.clone(), &id_i, "51.15.220.8:9732");
+        assert_eq!(finish(new), [0, 0]);
+        assert_eq!(finish(in_progress), [0, 0]);
+
+        // malformed file, keep using the last good identity
+        fs::write(&path, "{ \"public_key\": ").unwrap();
+        assert_eq!(info.identity().unwrap().public_key, second.public_key);
+
+        fs::write(&path, include_str!("../identity_i.json")).unwrap();
+        assert_eq!(info.identity().unwrap().public_key, first.public_key);
+    }
+
+    #[test]
+    fn without_identity() {
+        use crate::database::temp::TempDb;
+
+        let dir = TempDb::new("no-identity");
+        fs::create_dir_all(&*dir).unwrap();
+        let path = dir.join("identity.json");
+        let path_str = path.to_str().unwrap();
+
+        let target = NodeStatus::DEFAULT_POW_TARGET;
+        let status = Arc::new(NodeStatus::new(Some(path_str.to_string()), target));
+        let mut info = NodeInfo::new(path_str, "test".to_string(), status.clone(), None);
+        assert!(status.capture_only());
+        assert!(info.identity().is_none());
+        assert!(status.load_identity().is_err());
+
+        // malformed file, still capture-only
+        fs::write(&path, "{ \"public_key\": ").unwrap();
+        assert!(info.identity().is_none());
+        assert!(status.capture_only());
+
+        // the identity supplied later is used for new connections
+        fs::write(&path, include_str!("../identity_i.json")).unwrap();
+        assert!(status.load_identity().is_ok());
+        assert!(info.identity().is_some());
+        assert!(!status.capture_only());
+    }
+
+    #[test]
+    fn syslog_port_collision() {
+        let config = |second_port: u16| {
+            let config = format!(
+                r#"
+                [[nodes]]
+                name = "tezedge"
+                db = "target/debugger_db/tezedge"
+                log = {{ port = 10000 }}
+
+                [[nodes]]
+                name = "ocaml"
+                db = "target/debugger_db/ocaml"
+                log = {{ port = {} }}
+
+                [[nodes]]
+                name = "no_log"
+                db = "target/debugger_db/no_log"
+                "#,
+                second_port,
+            );
+            toml::from_str::<Config>(&config).unwrap()
+        };
+
+        assert!(config(10001).validate().is_ok());
+        match config(10000).validate() {
+            Err(ConfigError::SyslogPortCollision {
+                port,
+                first,
+                second,
+            }) => {
+                assert_eq!(port, 10000);
+                assert_eq!((first.as_str(), second.as_str()), ("tezedge", "ocaml"));
+            },
+            Ok(()) => panic!("the collision is not detected"),
+            Err(error) => panic!("unexpected error: {}", error),
+        }
+    }
+
+    #[test]
+    fn separate_dbs() {
+        use std::{net::SocketAddr, sync::atomic::{AtomicBool, Ordering}};
+        use crate::{
+            common::Initiator,
+            database::{temp::TempDb, Database, DatabaseFetch, ConnectionsFilter},
+            tables::connection,
+        };
+
+        let dir = TempDb::new("dbs");
+        let (a, b) = (dir.join("a"), dir.join("b"));
+        let config = |b: &std::path::Path| {
+            let config = format!(
+                "[[nodes]]\nname = \"a\"\ndb = \"{}\"\n[[nodes]]\nname = \"b\"\ndb = \"{}\"\n",
+                a.display(),
+                b.display(),
+            );
+            toml::from_str::<Config>(&config).unwrap()
+        };
+
+        // rocksdb locks the directory, the nodes cannot share it
+        match config(&a).validate() {
+            Err(ConfigError::DbPathCollision { first, second, .. }) => {
+                assert_eq!((first.as_str(), second.as_str()), ("a", "b"));
+            },
+            Ok(()) => panic!("the collision is not detected"),
+            Err(error) => panic!("unexpected error: {}", error),
+        }
+
+        let config = config(&b);
+        config.validate().unwrap();
+        let running = Arc::new(AtomicBool::new(true));
+        let mut system = System::<rocks::Db>::new(config);
+        system.run_dbs(running.clone());
+        assert!(a.is_dir() && b.is_dir());
+
+        let store = |node: &str, addr: &str| {
+            let addr = addr.parse::<SocketAddr>().unwrap();
+            let item = connection::Item::new(Initiator::new(true), addr, SystemTime::now());
+            system.node_dbs[node].store_connection(item);
+            system.node_dbs[node].flush();
+        };
+        store("a", "51.15.220.7:9732");
+        store("a", "51.15.220.8:9732");
+        store("b", "51.15.220.9:9732");
+        let filter = ConnectionsFilter::default();
+        let addrs = |node: &str| {
+            system.node_dbs[node]
+                .fetch_connections(&filter)
+                .unwrap()
+                .into_iter()
+                .map(|(_, c)| serde_json::to_value(&c).unwrap()["remote_addr"].clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(addrs("a").len(), 2);
+        assert_eq!(addrs("b"), ["51.15.220.9:9732"]);
+
+        running.store(false, Ordering::Relaxed);
+        system.join();
+    }
+
+    #[test]
+    fn validate_config() {
+        use std::path::Path;
+        use super::{check_config, check_config_str};
+
+        let dir = std::env::temp_dir().join(format!("tezedge-recorder-cfg-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let identity = dir.join("identity.json");
+        fs::write(&identity, include_str!("../identity_i.json")).unwrap();
+        let malformed = dir.join("malformed.json");
+        fs::write(&malformed, "{ \"public_key\": ").unwrap();
+        let file = dir.join("file");
+        fs::write(&file, "").unwrap();
+
+        let node = |name: &str, port: u16, identity: &Path, db: &Path| {
+            format!(
+                "[[nodes]]\nname = \"{}\"\ndb = \"{}\"\n\
+                 [nodes.p2p]\nidentity = \"{}\"\nport = {}\n",
+                name,
+                db.display(),
+                identity.display(),
+                port,
+            )
+        };
+        let problems = |s: &str| {
+            check_config_str("config.toml", s)
+                .problems
+                .into_iter()
+                .map(|p| p.problem)
+                .collect::<Vec<_>>()
+        };
+
+        let (a, b) = (dir.join("a"), dir.join("b"));
+        let valid = node("a", 9732, &identity, &a) + &node("b", 9733, &identity, &b);
+        let report = check_config_str("config.toml", &valid);
+        assert!(report.is_ok(), "{:?}", report);
+        assert_eq!(report.nodes, ["a", "b"]);
+
+        let same_port = node("a", 9732, &identity, &a) + &node("b", 9732, &identity, &b);
+        assert_eq!(
+            problems(&same_port),
+            ["port 9732 is both p2p port of node a and p2p port of node b"],
+        );
+
+        let p = problems(&node("a", 9732, &malformed, &a));
+        assert_eq!(p.len(), 1);
+        assert!(p[0].starts_with("identity"), "{}", p[0]);
+        let p = problems(&node("a", 9732, &dir.join("missing.json"), &a));
+        assert_eq!(p.len(), 1);
+        assert!(p[0].starts_with("identity"), "{}", p[0]);
+
+        let db = file.join("db");
+        assert_eq!(
+            problems(&node("a", 9732, &identity, &db)),
+            [format!("db {}: {} is not a directory", db.display(), file.display())],
+        );
+
+        let shared = node("a", 9732, &identity, &a) + &node("b", 9733, &identity, &a);
+        assert_eq!(
+            problems(&shared),
+            [format!("db {} is also used by node a", a.display())],
+        );
+
+        let p = problems("nodes = 1");
+        assert!(p[0].starts_with("cannot parse the config"), "{}", p[0]);
+        assert!(!check_config(dir.join("missing.toml").to_str().unwrap()).is_ok());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
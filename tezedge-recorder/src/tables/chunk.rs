@@ -1,7 +1,13 @@
 // Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
 // SPDX-License-Identifier: MIT
 
-use std::{convert::TryFrom, fmt, str::FromStr, num::ParseIntError};
+use std::{
+    convert::TryFrom,
+    fmt,
+    str::FromStr,
+    num::ParseIntError,
+    time::{SystemTime, UNIX_EPOCH},
+};
 use thiserror::Error;
 use serde::{
     Serialize,
@@ -11,7 +17,7 @@ use rocksdb::{Cache, ColumnFamilyDescriptor};
 use storage::persistent::{
     KeyValueSchema, Encoder, Decoder, SchemaError, database::RocksDbKeyValueSchema,
 };
-use super::{common::Sender, connection};
+use super::{common::Sender, connection, chunk_event};
 
 #[derive(Clone)]
 pub struct Item {
@@ -20,8 +26,14 @@ pub struct Item {
     pub counter: u64,
     timestamp: u64,
     net: bool,
+    // false if only the metadata is stored, see `ChunkSample`
+    payload: bool,
     pub bytes: Vec<u8>,
     pub plain: Vec<u8>,
+    // stored in the separate table
+    pub event: Option<chunk_event::Value>,
+    // the number of chunks lost before this one, stored in the separate table
+    pub gap: Option<u64>,
 }
 
 impl Item {
@@ -31,22 +43,21 @@ impl Item {
         counter: u64,
         bytes: Vec<u8>,
         plain: Vec<u8>,
+        now: SystemTime,
     ) -> Self {
-        use std::time::{SystemTime, UNIX_EPOCH};
-
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
+        let timestamp = now.duration_since(UNIX_EPOCH).unwrap().as_secs();
 
         Item {
             cn_id,
             sender,
             counter,
             net: true,
+            payload: true,
             timestamp,
             bytes,
             plain,
+            event: None,
+            gap: None,
         }
     }
 
@@ -54,10 +65,17 @@ impl Item {
         self.net = net;
     }
 
+    /// Drop the bytes, the chunk is stored with the metadata only
+    pub fn strip_payload(&mut self) {
+        self.payload = false;
+        self.bytes = Vec::new();
+        self.plain = Vec::new();
+    }
+
     #[rustfmt::skip]
     pub fn split(self) -> (Key, Value) {
-        let Item { cn_id, counter, sender, net, timestamp, bytes, plain } = self;
-        (Key { cn_id, counter, sender }, Value { net, timestamp, bytes, plain })
+        let Item { cn_id, counter, sender, net, payload, timestamp, bytes, plain, .. } = self;
+        (Key { cn_id, counter, sender }, Value { net, payload, timestamp, bytes, plain })
     }
 }
 
@@ -68,6 +86,7 @@ impl fmt::Debug for Item {
             .field("sender", &self.sender)
             .field("counter", &self.counter)
             .field("timestamp", &self.timestamp)
+            .field("payload", &self.payload)
             .field("bytes", &hex::encode(&self.bytes))
             .field("plain", &hex::encode(&self.plain))
             .finish()
@@ -187,20 +206,90 @@ impl Decoder for Key {
 
 pub struct Value {
     net: bool,
+    payload: bool,
     timestamp: u64,
     pub bytes: Vec<u8>,
     pub plain: Vec<u8>,
 }
 
-pub struct ValueTruncated(pub Value);
+/// The chunk with `bytes` and `plain` cut to the preview length,
+/// remembers the original length to report how many bytes were truncated
+pub struct ValueTruncated {
+    net: bool,
+    payload: bool,
+    timestamp: u64,
+    bytes: Vec<u8>,
+    bytes_len: usize,
+    plain: Vec<u8>,
+    plain_len: usize,
+    // the number of chunks lost before this one, see `chunk_gap`
+    gap: Option<u64>,
+}
+
+impl ValueTruncated {
+    pub const DEFAULT_PREVIEW: usize = 0x10000;
+    pub const MAX_PREVIEW: usize = 0x10000;
+
+    /// Preview length for the requested one, clamped to the max
+    pub fn preview(requested: Option<u64>) -> usize {
+        match requested {
+            Some(preview) => (preview.min(Self::MAX_PREVIEW as u64)) as usize,
+            None => Self::DEFAULT_PREVIEW,
+        }
+    }
+
+    /// Decode the stored value, copies only `preview` bytes of each payload
+    pub fn decode(bytes: &[u8], preview: usize) -> Result<Self, SchemaError> {
+        if bytes.len() < 17 {
+            return Err(SchemaError::DecodeError);
+        }
+
+        let len = u64::from_le_bytes(TryFrom::try_from(&bytes[8..16]).unwrap()) as usize;
+        if bytes.len() < 17 + len {
+            return Err(SchemaError::DecodeError);
+        }
+        let data = &bytes[17..(17 + len)];
+        let plain = &bytes[(17 + len)..];
+        Ok(ValueTruncated {
+            net: bytes[16] & Value::NET != 0,
+            payload: bytes[16] & Value::NO_PAYLOAD == 0,
+            timestamp: u64::from_le_bytes(TryFrom::try_from(&bytes[..8]).unwrap()),
+            bytes: data[..data.len().min(preview)].to_vec(),
+            bytes_len: data.len(),
+            plain: plain[..plain.len().min(preview)].to_vec(),
+            plain_len: plain.len(),
+            gap: None,
+        })
+    }
+
+    pub fn set_gap(&mut self, gap: Option<u64>) {
+        self.gap = gap;
+    }
+}
+
+impl Value {
+    // the bits of the flags byte, the records written before the sampling have only `NET`
+    const NET: u8 = 1;
+    const NO_PAYLOAD: u8 = 2;
+
+    pub fn net(&self) -> bool {
+        self.net
+    }
+
+    /// False if the bytes are not stored, the message of the chunk cannot be decoded
+    pub fn payload(&self) -> bool {
+        self.payload
+    }
+}
 
 impl Serialize for Value {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: ser::Serializer,
     {
-        let mut s = serializer.serialize_struct("Chunk", 4)?;
+        let mut s = serializer.serialize_struct("Chunk", 5)?;
         s.serialize_field("net", &self.net)?;
+        s.serialize_field("payload", &self.payload)?;
         s.serialize_field("timestamp", &self.timestamp)?;
         s.serialize_field("bytes", &hex::encode(&self.bytes))?;
         s.serialize_field("plain", &hex::encode(&self.plain))?;
@@ -213,23 +302,23 @@ impl Serialize for ValueTruncated {
     where
         S: ser::Serializer,
     {
-        let truncated_hex = |d: &[u8]| -> String {
-            if d.len() > 0x10000 {
-                format!(
-                    "{}...truncated {} bytes",
-                    hex::encode(&d[..0x10000]),
-                    d.len() - 0x10000,
-                )
+        let truncated_hex = |d: &[u8], len: usize| -> String {
+            if len > d.len() {
+                format!("{}...truncated {} bytes", hex::encode(d), len - d.len())
             } else {
                 hex::encode(d)
             }
         };
 
-        let mut s = serializer.serialize_struct("Chunk", 3)?;
-        s.serialize_field("net", &self.0.net)?;
-        s.serialize_field("timestamp", &self.0.timestamp)?;
-        s.serialize_field("bytes", &truncated_hex(&self.0.bytes))?;
-        s.serialize_field("plain", &truncated_hex(&self.0.plain))?;
+        let mut s = serializer.serialize_struct("Chunk", 5)?;
+        s.serialize_field("net", &self.net)?;
+        s.serialize_field("payload", &self.payload)?;
+        s.serialize_field("timestamp", &self.timestamp)?;
+        s.serialize_field("bytes", &truncated_hex(&self.bytes, self.bytes_len))?;
+        s.serialize_field("plain", &truncated_hex(&self.plain, self.plain_len))?;
+        if let Some(gap) = self.gap {
+            s.serialize_field("gap", &gap)?;
+        }
         s.end()
     }
 }
@@ -239,7 +328,14 @@ impl Encoder for Value {
         let mut v = Vec::with_capacity(self.bytes.len() + self.plain.len() + 17);
         v.extend_from_slice(&self.timestamp.to_le_bytes());
         v.extend_from_slice(&(self.bytes.len() as u64).to_le_bytes());
-        v.push(if self.net { 1 } else { 0 });
+        let mut flags = 0;
+        if self.net {
+            flags |= Value::NET;
+        }
+        if !self.payload {
+            flags |= Value::NO_PAYLOAD;
+        }
+        v.push(flags);
         v.extend_from_slice(&self.bytes);
         v.extend_from_slice(&self.plain);
         Ok(v)
@@ -254,7 +350,8 @@ impl Decoder for Value {
 
         let len = u64::from_le_bytes(TryFrom::try_from(&bytes[8..16]).unwrap()) as usize;
         Ok(Value {
-            net: bytes[16] != 0,
+            net: bytes[16] & Value::NET != 0,
+            payload: bytes[16] & Value::NO_PAYLOAD == 0,
             timestamp: u64::from_le_bytes(TryFrom::try_from(&bytes[..8]).unwrap()),
             bytes: {
                 if bytes.len() < 16 + len {
@@ -288,3 +385,41 @@ impl RocksDbKeyValueSchema for Schema {
         "chunk_storage"
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use storage::persistent::Encoder;
+    use super::{Value, ValueTruncated};
+
+    #[test]
+    fn preview() {
+        let value = Value {
+            net: true,
+            payload: true,
+            timestamp: 1,
+            bytes: vec![0xab; 1000],
+            plain: vec![0xcd; 100],
+        };
+        let encoded = value.encode().unwrap();
+
+        let truncated = ValueTruncated::decode(&encoded, 256).unwrap();
+        assert_eq!(truncated.bytes.len(), 256);
+        assert_eq!(truncated.plain.len(), 100);
+        let json = serde_json::to_value(&truncated).unwrap();
+        assert_eq!(
+            json["bytes"],
+            format!("{}...truncated 744 bytes", "ab".repeat(256)),
+        );
+        assert_eq!(json["plain"], "cd".repeat(100));
+
+        assert_eq!(ValueTruncated::preview(Some(256)), 256);
+        assert_eq!(ValueTruncated::preview(None), ValueTruncated::DEFAULT_PREVIEW);
+        assert_eq!(
+            ValueTruncated::preview(Some(u64::MAX)),
+            ValueTruncated::MAX_PREVIEW,
+        );
+        let preview = ValueTruncated::preview(Some(1 << 20));
+        let truncated = ValueTruncated::decode(&encoded, preview).unwrap();
+        assert_eq!(truncated.bytes.len(), 1000);
+    }
+}
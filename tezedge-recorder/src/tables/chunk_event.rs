@@ -0,0 +1,101 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::convert::TryFrom;
+use serde::{
+    Serialize,
+    ser::{self, SerializeStruct},
+};
+use rocksdb::{Cache, ColumnFamilyDescriptor};
+use storage::persistent::{
+    KeyValueSchema, Encoder, Decoder, SchemaError, database::RocksDbKeyValueSchema,
+};
+use super::chunk;
+
+/// The bpf event which delivered the last bytes of the chunk
+/// * bytes layout: `[pid(4)][fd(4)][ts(8)][net(1)][incoming(1)]`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Value {
+    pub pid: u32,
+    pub fd: u32,
+    // nanoseconds since boot, when the syscall finished
+    pub ts: u64,
+    // `send`/`recv` rather than `write`/`read`
+    pub net: bool,
+    pub incoming: bool,
+}
+
+impl Value {
+    pub fn syscall(&self) -> &'static str {
+        match (self.net, self.incoming) {
+            (false, false) => "write",
+            (false, true) => "read",
+            (true, false) => "send",
+            (true, true) => "recv",
+        }
+    }
+}
+
+impl Serialize for Value {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: ser::Serializer,
+    {
+        let mut s = serializer.serialize_struct("ChunkEvent", 4)?;
+        s.serialize_field("pid", &self.pid)?;
+        s.serialize_field("fd", &self.fd)?;
+        s.serialize_field("ts", &self.ts)?;
+        s.serialize_field("syscall", self.syscall())?;
+        s.end()
+    }
+}
+
+impl Encoder for Value {
+    fn encode(&self) -> Result<Vec<u8>, SchemaError> {
+        let mut v = Vec::with_capacity(18);
+        v.extend_from_slice(&self.pid.to_le_bytes());
+        v.extend_from_slice(&self.fd.to_le_bytes());
+        v.extend_from_slice(&self.ts.to_le_bytes());
+        v.push(self.net as u8);
+        v.push(self.incoming as u8);
+        Ok(v)
+    }
+}
+
+impl Decoder for Value {
+    fn decode(bytes: &[u8]) -> Result<Self, SchemaError> {
+        if bytes.len() != 18 {
+            return Err(SchemaError::DecodeError);
+        }
+
+        Ok(Value {
+            pid: u32::from_le_bytes(TryFrom::try_from(&bytes[..4]).unwrap()),
+            fd: u32::from_le_bytes(TryFrom::try_from(&bytes[4..8]).unwrap()),
+            ts: u64::from_le_bytes(TryFrom::try_from(&bytes[8..16]).unwrap()),
+            net: bytes[16] != 0,
+            incoming: bytes[17] != 0,
+        })
+    }
+}
+
+pub struct Schema;
+
+impl KeyValueSchema for Schema {
+    type Key = chunk::Key;
+    type Value = Value;
+}
+
+impl RocksDbKeyValueSchema for Schema {
+    fn descriptor(_cache: &Cache) -> ColumnFamilyDescriptor {
+        use rocksdb::{Options, SliceTransform};
+
+        let mut cf_opts = Options::default();
+        cf_opts.set_prefix_extractor(SliceTransform::create_fixed_prefix(12));
+        cf_opts.set_memtable_prefix_bloom_ratio(0.2);
+        ColumnFamilyDescriptor::new(Self::name(), cf_opts)
+    }
+
+    fn name() -> &'static str {
+        "chunk_event_storage"
+    }
+}
@@ -0,0 +1,54 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::convert::TryFrom;
+use rocksdb::{Cache, ColumnFamilyDescriptor};
+use storage::persistent::{
+    KeyValueSchema, Encoder, Decoder, SchemaError, database::RocksDbKeyValueSchema,
+};
+use super::chunk;
+
+/// The chunks lost before the chunk, likely the ring buffer overflowed,
+/// the decryption of the direction fails after the gap
+/// * bytes layout: `[missing(8)]`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Value {
+    pub missing: u64,
+}
+
+impl Encoder for Value {
+    fn encode(&self) -> Result<Vec<u8>, SchemaError> {
+        Ok(self.missing.to_le_bytes().to_vec())
+    }
+}
+
+impl Decoder for Value {
+    fn decode(bytes: &[u8]) -> Result<Self, SchemaError> {
+        let missing = TryFrom::try_from(bytes).map_err(|_| SchemaError::DecodeError)?;
+        Ok(Value {
+            missing: u64::from_le_bytes(missing),
+        })
+    }
+}
+
+pub struct Schema;
+
+impl KeyValueSchema for Schema {
+    type Key = chunk::Key;
+    type Value = Value;
+}
+
+impl RocksDbKeyValueSchema for Schema {
+    fn descriptor(_cache: &Cache) -> ColumnFamilyDescriptor {
+        use rocksdb::{Options, SliceTransform};
+
+        let mut cf_opts = Options::default();
+        cf_opts.set_prefix_extractor(SliceTransform::create_fixed_prefix(12));
+        cf_opts.set_memtable_prefix_bloom_ratio(0.2);
+        ColumnFamilyDescriptor::new(Self::name(), cf_opts)
+    }
+
+    fn name() -> &'static str {
+        "chunk_gap_storage"
+    }
+}
@@ -1,17 +1,27 @@
 // Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
 // SPDX-License-Identifier: MIT
 
-use std::{convert::TryFrom, net::SocketAddr, num::ParseIntError, str::FromStr, fmt};
+use std::{
+    convert::TryFrom,
+    net::SocketAddr,
+    num::ParseIntError,
+    str::FromStr,
+    fmt,
+    time::{SystemTime, UNIX_EPOCH},
+};
 use thiserror::Error;
 use serde::{
-    Serialize,
+    Serialize, Deserialize,
     ser::{self, SerializeSeq, SerializeStruct},
 };
 use typenum::Bit;
 use storage::persistent::{
     KeyValueSchema, Encoder, Decoder, SchemaError, database::RocksDbKeyValueSchema,
 };
-use super::common::{Initiator, Sender};
+use super::{
+    common::{Initiator, Sender},
+    connection_geo,
+};
 
 #[derive(Debug, Clone, Default)]
 pub struct Comments {
@@ -19,12 +29,19 @@ pub struct Comments {
     pub incoming_too_short: Option<usize>,
     pub incoming_uncertain: bool,
     pub incoming_cannot_decrypt: Option<u64>,
+    // the chunks decrypted before `incoming_cannot_decrypt`, the key might have changed
+    pub incoming_key_changed: bool,
     pub incoming_suspicious: Option<u64>,
+    // the peer sent more messages per window than configured, the count in the window
+    pub incoming_rate_exceeded: Option<u32>,
     pub outgoing_wrong_pow: Option<f64>,
     pub outgoing_too_short: Option<usize>,
     pub outgoing_uncertain: bool,
     pub outgoing_wrong_pk: bool,
     pub outgoing_cannot_decrypt: Option<u64>,
+    pub outgoing_key_changed: bool,
+    // recorded in capture-only mode, the identity of the node was not available
+    pub outgoing_no_identity: bool,
 }
 
 impl Comments {
@@ -63,6 +80,23 @@ impl Comments {
         (i, o)
     }
 
+    /// The comments added after the fixed 36 bytes, see `Value` layout `1`
+    fn ser_tail(&self, v: &mut Vec<u8>) {
+        v.push(self.incoming_key_changed as u8);
+        v.push(self.outgoing_key_changed as u8);
+        v.push(self.outgoing_no_identity as u8);
+        let rate = self.incoming_rate_exceeded.map(u32::to_le_bytes);
+        push_option(v, rate.as_ref().map(|b| &b[..]), 4);
+    }
+
+    fn de_tail(&mut self, r: &mut Reader) -> Result<(), SchemaError> {
+        self.incoming_key_changed = r.u8()? != 0;
+        self.outgoing_key_changed = r.u8()? != 0;
+        self.outgoing_no_identity = r.u8()? != 0;
+        self.incoming_rate_exceeded = r.option(4)?.map(|b| u32::from_le_bytes(to_array(b)));
+        Ok(())
+    }
+
     fn de((i, o): ([u8; 18], [u8; 18])) -> Self {
         let i_c = u64::from_le_bytes(TryFrom::try_from(&i[4..12]).unwrap());
         let i_s = u32::from_le_bytes(TryFrom::try_from(&i[12..16]).unwrap()) as u64;
@@ -77,6 +111,8 @@ impl Comments {
             incoming_uncertain: i[2] != 0,
             incoming_suspicious: if i_s == 0 { None } else { Some(i_c) },
             incoming_cannot_decrypt: if i_c == u64::MAX { None } else { Some(i_c) },
+            incoming_key_changed: false,
+            incoming_rate_exceeded: None,
             outgoing_wrong_pow: if o[0] == 0 { None } else { Some(o[0] as f64) },
             outgoing_too_short: if o[1] == u8::MAX {
                 None
@@ -86,6 +122,8 @@ impl Comments {
             outgoing_uncertain: o[2] != 0,
             outgoing_wrong_pk: o[3] != 0,
             outgoing_cannot_decrypt: if o_c == u64::MAX { None } else { Some(o_c) },
+            outgoing_key_changed: false,
+            outgoing_no_identity: false,
         }
     }
 }
@@ -119,6 +157,14 @@ impl Serialize for Comments {
             let msg = format!("incoming chunk cannot decrypt, position: {}", position);
             s.serialize_element(&msg)?;
         }
+        if self.incoming_key_changed {
+            let msg = "incoming decryption broke after success, the key might have changed";
+            s.serialize_element(&msg)?;
+        }
+        if let Some(messages) = self.incoming_rate_exceeded {
+            let msg = format!("incoming message rate exceeded, {} messages in window", messages);
+            s.serialize_element(&msg)?;
+        }
         if let Some(target) = self.outgoing_wrong_pow {
             let msg = format!(
                 "outgoing connection message bad proof-of-work, target: {}",
@@ -142,11 +188,128 @@ impl Serialize for Comments {
             let msg = format!("outgoing chunk cannot decrypt, position: {}", position);
             s.serialize_element(&msg)?;
         }
+        if self.outgoing_key_changed {
+            let msg = "outgoing decryption broke after success, the key might have changed";
+            s.serialize_element(&msg)?;
+        }
+        if self.outgoing_no_identity {
+            let msg = "recorded without identity of the node, chunks are not decrypted";
+            s.serialize_element(&msg)?;
+        }
 
         s.end()
     }
 }
 
+/// Why the connection ended, the first known reason is kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CloseReason {
+    /// The node closed the socket
+    Close,
+    /// The peer closed the connection, the node has read the end of stream
+    PeerClose,
+    /// The node shut down the socket
+    Shutdown,
+    /// The connection was reset by the peer, or the pipe is broken
+    Reset,
+    /// Other error on the socket
+    Error,
+    /// The chunks cannot be decrypted
+    DecryptionFailure,
+    /// The recorder stopped while the connection was open
+    RecorderShutdown,
+    /// Finalized on request, see `/v3/connection/{id}/finalize`
+    Manual,
+    /// Finalized after no data for `idle_timeout`, the close was likely lost
+    Timeout,
+    /// The outgoing connect failed, see `connect_error`
+    ConnectFailed,
+}
+
+/// Whether the connection is still open, see `ConnectionsFilter::status`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionStatus {
+    Open,
+    Closed,
+    /// The node tried to reach the peer and failed
+    Failed,
+}
+
+impl CloseReason {
+    /// The reason for the error code returned by the socket operation
+    pub fn from_error_code(code: i32) -> Self {
+        // ECONNRESET, EPIPE
+        match code {
+            -104 | -32 => CloseReason::Reset,
+            _ => CloseReason::Error,
+        }
+    }
+
+    fn to_byte(reason: Option<Self>) -> u8 {
+        match reason {
+            None => 0,
+            Some(CloseReason::Close) => 1,
+            Some(CloseReason::PeerClose) => 2,
+            Some(CloseReason::Shutdown) => 3,
+            Some(CloseReason::Reset) => 4,
+            Some(CloseReason::Error) => 5,
+            Some(CloseReason::DecryptionFailure) => 6,
+            Some(CloseReason::RecorderShutdown) => 7,
+            Some(CloseReason::Manual) => 8,
+            Some(CloseReason::Timeout) => 9,
+            Some(CloseReason::ConnectFailed) => 10,
+        }
+    }
+
+    fn from_byte(b: u8) -> Option<Self> {
+        match b {
+            1 => Some(CloseReason::Close),
+            2 => Some(CloseReason::PeerClose),
+            3 => Some(CloseReason::Shutdown),
+            4 => Some(CloseReason::Reset),
+            5 => Some(CloseReason::Error),
+            6 => Some(CloseReason::DecryptionFailure),
+            7 => Some(CloseReason::RecorderShutdown),
+            8 => Some(CloseReason::Manual),
+            9 => Some(CloseReason::Timeout),
+            10 => Some(CloseReason::ConnectFailed),
+            _ => None,
+        }
+    }
+}
+
+/// The flags of the metadata message, sent by each peer after the connection message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Metadata {
+    pub disable_mempool: bool,
+    pub private_node: bool,
+}
+
+impl Metadata {
+    // the first bit tells the metadata is known, the other two are the flags
+    fn to_byte(metadata: Option<Self>) -> u8 {
+        match metadata {
+            None => 0,
+            Some(Metadata {
+                disable_mempool,
+                private_node,
+            }) => 1 | (disable_mempool as u8) << 1 | (private_node as u8) << 2,
+        }
+    }
+
+    fn from_byte(b: u8) -> Option<Self> {
+        if b & 1 == 0 {
+            return None;
+        }
+        Some(Metadata {
+            disable_mempool: b & 2 != 0,
+            private_node: b & 4 != 0,
+        })
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Item {
     pub ts: u64,
@@ -155,16 +318,28 @@ pub struct Item {
     pub remote_addr: SocketAddr,
     peer_pk: [u8; 32],
     comments: Comments,
+    session: Option<String>,
+    version: Option<u16>,
+    close_reason: Option<CloseReason>,
+    pow_valid: Option<bool>,
+    listen_port: Option<u16>,
+    unix_path: Option<String>,
+    local_metadata: Option<Metadata>,
+    peer_metadata: Option<Metadata>,
+    connect_error: Option<i32>,
+    // the compression flags announced in the metadata messages, only the negotiated is stored
+    local_compression: Option<u8>,
+    peer_compression: Option<u8>,
 }
 
-impl Item {
-    pub fn new(initiator: Initiator, remote_addr: SocketAddr) -> Self {
-        use std::time::{SystemTime, UNIX_EPOCH};
+/// The peers compress the payloads if both announced the same flag
+fn negotiated_compression(local: Option<u8>, peer: Option<u8>) -> Option<u8> {
+    local.filter(|_| local == peer)
+}
 
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_nanos();
+impl Item {
+    pub fn new(initiator: Initiator, remote_addr: SocketAddr, now: SystemTime) -> Self {
+        let timestamp = now.duration_since(UNIX_EPOCH).unwrap().as_nanos();
 
         let ts = (timestamp / 1_000_000_000) as u64;
         let ts_nanos = (timestamp % 1_000_000_000) as u32;
@@ -176,6 +351,17 @@ impl Item {
             remote_addr,
             peer_pk: [0; 32],
             comments: Comments::default(),
+            session: None,
+            version: None,
+            close_reason: None,
+            pow_valid: None,
+            listen_port: None,
+            unix_path: None,
+            local_metadata: None,
+            peer_metadata: None,
+            connect_error: None,
+            local_compression: None,
+            peer_compression: None,
         }
     }
 
@@ -183,6 +369,68 @@ impl Item {
         self.peer_pk = peer_pk;
     }
 
+    pub fn set_session(&mut self, session: Option<String>) {
+        self.session = session;
+    }
+
+    /// The distributed db version negotiated by the peers
+    pub fn set_version(&mut self, version: u16) {
+        self.version = Some(version);
+    }
+
+    /// Keep the first reason, the later events are consequences
+    pub fn set_close_reason(&mut self, reason: CloseReason) {
+        if self.close_reason.is_none() {
+            self.close_reason = Some(reason);
+        }
+    }
+
+    pub fn close_reason(&self) -> Option<CloseReason> {
+        self.close_reason
+    }
+
+    /// The outgoing connect failed with the `errno`, it is an attempt rather than a connection
+    pub fn set_connect_error(&mut self, errno: i32) {
+        self.connect_error = Some(errno);
+        self.set_close_reason(CloseReason::ConnectFailed);
+    }
+
+    /// The port of the listening socket which accepted the connection
+    pub fn set_listen_port(&mut self, port: u16) {
+        self.listen_port = Some(port);
+    }
+
+    /// The peer is a unix socket, the path identifies it, `remote_addr` is meaningless
+    pub fn set_unix_path(&mut self, path: String) {
+        self.unix_path = Some(path);
+    }
+
+    /// Whether the proof-of-work stamp of the peer meets the target
+    pub fn set_pow_valid(&mut self, pow_valid: bool) {
+        self.pow_valid = Some(pow_valid);
+    }
+
+    /// The metadata message sent by the `sender`
+    pub fn set_metadata(&mut self, sender: &Sender, metadata: Metadata) {
+        match sender {
+            Sender::Local => self.local_metadata = Some(metadata),
+            Sender::Remote => self.peer_metadata = Some(metadata),
+        }
+    }
+
+    /// The compression flag announced in the metadata message sent by the `sender`
+    pub fn set_compression(&mut self, sender: &Sender, flag: Option<u8>) {
+        match sender {
+            Sender::Local => self.local_compression = flag,
+            Sender::Remote => self.peer_compression = flag,
+        }
+    }
+
+    /// The compression flag both peers announced, `None` if they did not agree on any
+    pub fn compression(&self) -> Option<u8> {
+        negotiated_compression(self.local_compression, self.peer_compression)
+    }
+
     pub fn add_comment(&mut self) -> &mut Comments {
         &mut self.comments
     }
@@ -197,7 +445,9 @@ impl Item {
         self.add_comment().outgoing_uncertain = true;
     }
 
-    pub fn mark_cannot_decrypt<S>(&mut self, position: u64)
+    /// The chunk at `position` cannot be decrypted, even if the chunks before it are lost,
+    /// if `decrypted` chunks before it were fine, the key has likely changed
+    pub fn mark_cannot_decrypt<S>(&mut self, position: u64, decrypted: u64)
     where
         S: Bit,
     {
@@ -206,29 +456,35 @@ impl Item {
             Err(s) => format!("{:?}", s),
         };
         log::warn!(
-            "cannot decrypt: {}-{}-{}, connection: {}",
+            "cannot decrypt: {}-{}-{}, after {} decrypted chunks, connection: {}",
             self.key(),
             Sender::new(S::BOOL),
             position,
+            decrypted,
             cn_value,
         );
+        let key_changed = decrypted > 0;
         if S::BOOL {
             self.add_comment().incoming_cannot_decrypt = Some(position);
+            self.add_comment().incoming_key_changed = key_changed;
         } else {
             self.add_comment().outgoing_cannot_decrypt = Some(position);
+            self.add_comment().outgoing_key_changed = key_changed;
         }
+        self.set_close_reason(CloseReason::DecryptionFailure);
     }
 
     #[rustfmt::skip]
     pub fn split(self) -> (Key, Value) {
-        let Item { ts, ts_nanos, initiator, remote_addr, peer_pk, comments } = self;
-        (Key { ts, ts_nanos }, Value { initiator, remote_addr, peer_pk, comments })
+        let Item { ts, ts_nanos, initiator, remote_addr, peer_pk, comments, session, version, close_reason, pow_valid, listen_port, unix_path, local_metadata, peer_metadata, connect_error, local_compression, peer_compression } = self;
+        let compression = negotiated_compression(local_compression, peer_compression);
+        (Key { ts, ts_nanos }, Value { initiator, remote_addr, peer_pk, comments, session, version, close_reason, pow_valid, listen_port, unix_path, local_metadata, peer_metadata, connect_error, compression, geo: Default::default() })
     }
 
     #[rustfmt::skip]
     pub fn unite(key: Key, value: Value) -> Self {
-        let (Key { ts, ts_nanos }, Value { initiator, remote_addr, peer_pk, comments }) = (key, value);
-        Item { ts, ts_nanos, initiator, remote_addr, peer_pk, comments }
+        let (Key { ts, ts_nanos }, Value { initiator, remote_addr, peer_pk, comments, session, version, close_reason, pow_valid, listen_port, unix_path, local_metadata, peer_metadata, connect_error, compression, .. }) = (key, value);
+        Item { ts, ts_nanos, initiator, remote_addr, peer_pk, comments, session, version, close_reason, pow_valid, listen_port, unix_path, local_metadata, peer_metadata, connect_error, local_compression: compression, peer_compression: compression }
     }
 
     pub fn key(&self) -> Key {
@@ -244,11 +500,23 @@ impl Item {
             remote_addr: self.remote_addr,
             peer_pk: self.peer_pk,
             comments: self.comments.clone(),
+            session: self.session.clone(),
+            version: self.version,
+            close_reason: self.close_reason,
+            pow_valid: self.pow_valid,
+            listen_port: self.listen_port,
+            unix_path: self.unix_path.clone(),
+            local_metadata: self.local_metadata,
+            peer_metadata: self.peer_metadata,
+            connect_error: self.connect_error,
+            compression: self.compression(),
+            geo: connection_geo::Value::default(),
         }
     }
 }
 
-#[derive(Debug, Default, Clone)]
+// the order of the fields is the order of the encoded keys
+#[derive(Debug, Default, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Key {
     pub ts: u64,
     pub ts_nanos: u32,
@@ -320,19 +588,169 @@ impl Decoder for Key {
     }
 }
 
-// ip 16 bytes, port 2 bytes, initiator 1 byte, padding 1 byte, comments 36 bytes, peer_pk 32 bytes
+// ip 16 bytes, port 2 bytes, initiator 1 byte, layout 1 byte, comments 36 bytes, peer_pk 32 bytes,
+// it is the whole record of the layout `0`, the records written before the layout byte
+// have zero there, it was a padding,
+// the layout `1` appends the fields in this order, the optional ones are one byte presence
+// followed by the value of fixed width, zeroes if absent, the integers are little endian:
+// optional distributed db version 2 bytes, close reason 1 byte, see `CloseReason::to_byte`,
+// proof-of-work 1 byte, zero unknown, one valid, two invalid, optional listening port 2 bytes,
+// local and peer metadata 1 byte each, see `Metadata::to_byte`, optional connect errno 4 bytes,
+// optional compression flag 1 byte, the comments, see `Comments::ser_tail`,
+// then the session label and the path of the unix socket, each is 4 bytes length and utf8,
+// the next layout appends its fields after them
 pub struct Value {
     initiator: Initiator,
     remote_addr: SocketAddr,
     peer_pk: [u8; 32],
     comments: Comments,
+    session: Option<String>,
+    version: Option<u16>,
+    close_reason: Option<CloseReason>,
+    pow_valid: Option<bool>,
+    listen_port: Option<u16>,
+    unix_path: Option<String>,
+    local_metadata: Option<Metadata>,
+    peer_metadata: Option<Metadata>,
+    connect_error: Option<i32>,
+    compression: Option<u8>,
+    // not encoded, stored apart when resolved, see `connection_geo`
+    geo: connection_geo::Value,
+}
+
+impl Value {
+    // the length of the layout `0`, see the layout above
+    const BASE_LEN: usize = 88;
+    const LAYOUT: u8 = 1;
+
+    pub fn session(&self) -> Option<&str> {
+        self.session.as_deref()
+    }
+
+    pub fn version(&self) -> Option<u16> {
+        self.version
+    }
+
+    pub fn comments(&self) -> &Comments {
+        &self.comments
+    }
+
+    pub fn close_reason(&self) -> Option<CloseReason> {
+        self.close_reason
+    }
+
+    pub fn pow_valid(&self) -> Option<bool> {
+        self.pow_valid
+    }
+
+    pub fn listen_port(&self) -> Option<u16> {
+        self.listen_port
+    }
+
+    pub fn unix_path(&self) -> Option<&str> {
+        self.unix_path.as_deref()
+    }
+
+    pub fn local_metadata(&self) -> Option<Metadata> {
+        self.local_metadata
+    }
+
+    pub fn peer_metadata(&self) -> Option<Metadata> {
+        self.peer_metadata
+    }
+
+    /// The `errno` of the failed outgoing connect
+    pub fn connect_error(&self) -> Option<i32> {
+        self.connect_error
+    }
+
+    /// The compression flag the peers negotiated, see `Item::compression`
+    pub fn compression(&self) -> Option<u8> {
+        self.compression
+    }
+
+    pub fn status(&self) -> ConnectionStatus {
+        match self.close_reason {
+            None => ConnectionStatus::Open,
+            Some(CloseReason::ConnectFailed) => ConnectionStatus::Failed,
+            Some(_) => ConnectionStatus::Closed,
+        }
+    }
+
+    pub fn set_geo(&mut self, geo: connection_geo::Value) {
+        self.geo = geo;
+    }
+
+    pub fn geo(&self) -> &connection_geo::Value {
+        &self.geo
+    }
+}
+
+fn push_option(v: &mut Vec<u8>, value: Option<&[u8]>, width: usize) {
+    match value {
+        Some(value) => {
+            v.push(1);
+            v.extend_from_slice(value);
+        },
+        None => {
+            v.push(0);
+            v.resize(v.len() + width, 0);
+        },
+    }
+}
+
+fn push_str(v: &mut Vec<u8>, s: Option<&str>) {
+    let s = s.unwrap_or_default();
+    v.extend_from_slice(&(s.len() as u32).to_le_bytes());
+    v.extend_from_slice(s.as_bytes());
+}
+
+fn to_array<const N: usize>(b: &[u8]) -> [u8; N] {
+    TryFrom::try_from(b).unwrap()
+}
+
+/// Reads the fields of the layout one after another, see `Value`
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], SchemaError> {
+        if self.bytes.len() < n {
+            return Err(SchemaError::DecodeError);
+        }
+        let (head, tail) = self.bytes.split_at(n);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, SchemaError> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn option(&mut self, width: usize) -> Result<Option<&'a [u8]>, SchemaError> {
+        let present = self.u8()? != 0;
+        let value = self.take(width)?;
+        Ok(Some(value).filter(|_| present))
+    }
+
+    fn str(&mut self) -> Result<Option<String>, SchemaError> {
+        let len = u32::from_le_bytes(to_array(self.take(4)?)) as usize;
+        let b = self.take(len)?;
+        if b.is_empty() {
+            return Ok(None);
+        }
+        std::str::from_utf8(b)
+            .map(|s| Some(s.to_string()))
+            .map_err(|e| SchemaError::DecodeValidationError(e.to_string()))
+    }
 }
 
 impl Encoder for Value {
     fn encode(&self) -> Result<Vec<u8>, SchemaError> {
         use std::net::IpAddr;
 
-        let mut v = Vec::with_capacity(88);
+        let mut v = Vec::with_capacity(Self::BASE_LEN + 32);
 
         let ip = match self.remote_addr.ip() {
             IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
@@ -342,7 +760,7 @@ impl Encoder for Value {
         v.extend_from_slice(&self.remote_addr.port().to_le_bytes());
 
         v.push(if self.initiator.incoming() { 1 } else { 0 });
-        v.push(0);
+        v.push(Self::LAYOUT);
 
         let (i, o) = self.comments.ser();
         v.extend_from_slice(&i);
@@ -350,17 +768,37 @@ impl Encoder for Value {
 
         v.extend_from_slice(&self.peer_pk);
 
+        let version = self.version.map(u16::to_le_bytes);
+        push_option(&mut v, version.as_ref().map(|b| &b[..]), 2);
+        v.push(CloseReason::to_byte(self.close_reason));
+        v.push(match self.pow_valid {
+            None => 0,
+            Some(true) => 1,
+            Some(false) => 2,
+        });
+        let listen_port = self.listen_port.map(u16::to_le_bytes);
+        push_option(&mut v, listen_port.as_ref().map(|b| &b[..]), 2);
+        v.push(Metadata::to_byte(self.local_metadata));
+        v.push(Metadata::to_byte(self.peer_metadata));
+        let connect_error = self.connect_error.map(i32::to_le_bytes);
+        push_option(&mut v, connect_error.as_ref().map(|b| &b[..]), 4);
+        let compression = self.compression.map(|flag| [flag]);
+        push_option(&mut v, compression.as_ref().map(|b| &b[..]), 1);
+        self.comments.ser_tail(&mut v);
+        push_str(&mut v, self.session.as_deref());
+        push_str(&mut v, self.unix_path.as_deref());
+
         Ok(v)
     }
 }
 
 impl Decoder for Value {
     fn decode(bytes: &[u8]) -> Result<Self, SchemaError> {
-        if bytes.len() != 88 {
+        if bytes.len() < Self::BASE_LEN {
             return Err(SchemaError::DecodeError);
         }
 
-        Ok(Value {
+        let mut value = Value {
             initiator: Initiator::new(bytes[18] != 0),
             remote_addr: {
                 let ip = <[u8; 16]>::try_from(&bytes[0..16]).unwrap();
@@ -373,7 +811,43 @@ impl Decoder for Value {
                 let o = TryFrom::try_from(&bytes[38..56]).unwrap();
                 Comments::de((i, o))
             },
-        })
+            version: None,
+            close_reason: None,
+            pow_valid: None,
+            listen_port: None,
+            session: None,
+            unix_path: None,
+            local_metadata: None,
+            peer_metadata: None,
+            connect_error: None,
+            compression: None,
+            geo: connection_geo::Value::default(),
+        };
+        // the layout `0` has nothing else, the newer layouts extend the layout `1`
+        if bytes[19] == 0 {
+            return Ok(value);
+        }
+
+        let mut r = Reader {
+            bytes: &bytes[Self::BASE_LEN..],
+        };
+        value.version = r.option(2)?.map(|b| u16::from_le_bytes(to_array(b)));
+        value.close_reason = CloseReason::from_byte(r.u8()?);
+        value.pow_valid = match r.u8()? {
+            1 => Some(true),
+            2 => Some(false),
+            _ => None,
+        };
+        value.listen_port = r.option(2)?.map(|b| u16::from_le_bytes(to_array(b)));
+        value.local_metadata = Metadata::from_byte(r.u8()?);
+        value.peer_metadata = Metadata::from_byte(r.u8()?);
+        value.connect_error = r.option(4)?.map(|b| i32::from_le_bytes(to_array(b)));
+        value.compression = r.option(1)?.map(|b| b[0]);
+        value.comments.de_tail(&mut r)?;
+        value.session = r.str()?;
+        value.unix_path = r.str()?;
+
+        Ok(value)
     }
 }
 
@@ -398,11 +872,25 @@ impl Serialize for Value {
             Err(s) => s,
         };
 
-        let mut s = serializer.serialize_struct("Connection", 4)?;
+        let mut s = serializer.serialize_struct("Connection", 15)?;
         s.serialize_field("initiator", &self.initiator)?;
-        s.serialize_field("remote_addr", &self.remote_addr)?;
+        match &self.unix_path {
+            Some(path) => s.serialize_field("remote_addr", path)?,
+            None => s.serialize_field("remote_addr", &self.remote_addr)?,
+        }
         s.serialize_field("peer_id", &peer_id)?;
         s.serialize_field("comments", &self.comments)?;
+        s.serialize_field("session", &self.session)?;
+        s.serialize_field("encoding_version", &self.version)?;
+        s.serialize_field("close_reason", &self.close_reason)?;
+        s.serialize_field("pow_valid", &self.pow_valid)?;
+        s.serialize_field("listen_port", &self.listen_port)?;
+        s.serialize_field("local_metadata", &self.local_metadata)?;
+        s.serialize_field("peer_metadata", &self.peer_metadata)?;
+        s.serialize_field("connect_error", &self.connect_error)?;
+        s.serialize_field("compression", &self.compression)?;
+        s.serialize_field("country", &self.geo.country)?;
+        s.serialize_field("asn", &self.geo.asn)?;
         s.end()
     }
 }
@@ -419,3 +907,132 @@ impl RocksDbKeyValueSchema for Schema {
         "connection_storage"
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+    use storage::persistent::{Encoder, Decoder};
+    use super::{Item, Value, ConnectionStatus, CloseReason, Metadata};
+    use crate::common::{Initiator, Sender};
+
+    #[test]
+    fn fields_round_trip() {
+        let mut item = Item::new(
+            Initiator::new(true),
+            ([51, 15, 220, 7], 9732).into(),
+            SystemTime::now(),
+        );
+        item.set_version(1);
+        item.set_pow_valid(false);
+        item.set_listen_port(0xfffe);
+        item.set_unix_path("/tmp/node\0.sock".to_string());
+        item.set_session(Some("label\0with null".to_string()));
+        let metadata = Metadata {
+            disable_mempool: true,
+            private_node: false,
+        };
+        item.set_metadata(&Sender::Remote, metadata);
+        item.add_comment().incoming_uncertain = true;
+        item.add_comment().incoming_key_changed = true;
+        item.add_comment().incoming_rate_exceeded = Some(0x1_0000);
+        item.add_comment().outgoing_key_changed = true;
+        item.add_comment().outgoing_cannot_decrypt = Some(3);
+        item.add_comment().outgoing_wrong_pk = true;
+        item.add_comment().outgoing_no_identity = true;
+        // does not fit in a byte
+        item.set_connect_error(-0x1234);
+        item.set_compression(&Sender::Local, Some(0xff));
+        item.set_compression(&Sender::Remote, Some(0xff));
+        let (_, value) = item.split();
+
+        let value = Value::decode(&value.encode().unwrap()).unwrap();
+        assert_eq!(value.version(), Some(1));
+        assert_eq!(value.pow_valid(), Some(false));
+        assert_eq!(value.listen_port(), Some(0xfffe));
+        assert_eq!(value.unix_path(), Some("/tmp/node\0.sock"));
+        assert_eq!(value.session(), Some("label\0with null"));
+        assert_eq!(value.local_metadata(), None);
+        assert_eq!(value.peer_metadata(), Some(metadata));
+        let comments = value.comments();
+        assert!(comments.incoming_uncertain);
+        assert!(comments.incoming_key_changed);
+        assert_eq!(comments.incoming_rate_exceeded, Some(0x1_0000));
+        assert!(!comments.outgoing_uncertain);
+        assert!(comments.outgoing_key_changed);
+        assert_eq!(comments.outgoing_cannot_decrypt, Some(3));
+        assert!(comments.outgoing_wrong_pk);
+        assert!(comments.outgoing_no_identity);
+        assert_eq!(value.connect_error(), Some(-0x1234));
+        assert_eq!(value.close_reason(), Some(CloseReason::ConnectFailed));
+        assert_eq!(value.status(), ConnectionStatus::Failed);
+        assert_eq!(value.compression(), Some(0xff));
+
+        let item = Item::new(
+            Initiator::new(false),
+            ([51, 15, 220, 7], 9732).into(),
+            SystemTime::now(),
+        );
+        let (_, value) = item.split();
+        let value = Value::decode(&value.encode().unwrap()).unwrap();
+        assert_eq!(value.connect_error(), None);
+        assert_eq!(value.listen_port(), None);
+        assert_eq!(value.session(), None);
+        assert_eq!(value.status(), ConnectionStatus::Open);
+        let json = serde_json::to_value(&value).unwrap();
+        assert!(json["close_reason"].is_null());
+        assert!(!value.comments().outgoing_wrong_pk);
+        assert_eq!(value.compression(), None);
+    }
+
+    #[test]
+    fn baseline_record() {
+        // the record as the baseline wrote it: the incoming connection from `51.15.220.7:9732`,
+        // the padding byte, the outgoing chunk 5 cannot be decrypted, the public key of the peer
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 51, 15, 220, 7]);
+        bytes.extend_from_slice(&9732u16.to_le_bytes());
+        bytes.extend_from_slice(&[1, 0]);
+        let mut i = [0; 18];
+        i[1] = 0xff;
+        i[2] = 1;
+        i[4..12].clone_from_slice(&u64::MAX.to_le_bytes());
+        let mut o = [0; 18];
+        o[1] = 0xff;
+        o[4..12].clone_from_slice(&5u64.to_le_bytes());
+        bytes.extend_from_slice(&i);
+        bytes.extend_from_slice(&o);
+        bytes.extend_from_slice(&[0xab; 32]);
+        assert_eq!(bytes.len(), 88);
+
+        let value = Value::decode(&bytes).unwrap();
+        let item = Item::unite(Default::default(), value);
+        assert!(item.initiator.incoming());
+        assert_eq!(item.remote_addr, ([51, 15, 220, 7], 9732).into());
+        let value = item.value();
+        let comments = value.comments();
+        assert!(comments.incoming_uncertain);
+        assert!(!comments.outgoing_uncertain);
+        assert_eq!(comments.incoming_cannot_decrypt, None);
+        assert_eq!(comments.outgoing_cannot_decrypt, Some(5));
+        assert!(!comments.incoming_key_changed && !comments.outgoing_no_identity);
+        assert_eq!(comments.incoming_rate_exceeded, None);
+        assert_eq!(value.version(), None);
+        assert_eq!(value.close_reason(), None);
+        assert_eq!(value.pow_valid(), None);
+        assert_eq!(value.listen_port(), None);
+        assert_eq!(value.unix_path(), None);
+        assert_eq!(value.session(), None);
+        assert_eq!(value.peer_metadata(), None);
+        assert_eq!(value.connect_error(), None);
+        assert_eq!(value.compression(), None);
+        assert_eq!(value.status(), ConnectionStatus::Open);
+
+        // the record written now keeps the baseline bytes, but the layout
+        let encoded = value.encode().unwrap();
+        assert_eq!(encoded[..19], bytes[..19]);
+        assert_eq!(encoded[19], 1);
+        assert_eq!(encoded[20..88], bytes[20..88]);
+        // the truncated record is an error rather than the garbage
+        assert!(Value::decode(&encoded[..90]).is_err());
+    }
+}
@@ -0,0 +1,149 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::convert::TryFrom;
+use serde::Serialize;
+use rocksdb::{Cache, ColumnFamilyDescriptor};
+use storage::persistent::{
+    KeyValueSchema, Encoder, Decoder, SchemaError, database::RocksDbKeyValueSchema,
+};
+use super::connection;
+
+/// The public keys and the initial nonces derived by the handshake,
+/// stored only if `debug_crypto` is configured, the secret key of the node is never stored
+/// * bytes layout: `[local_pk(32)][remote_pk(32)][local_nonce(24)][remote_nonce(24)]`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Value {
+    pub local_pk: [u8; 32],
+    pub remote_pk: [u8; 32],
+    pub local_nonce: [u8; 24],
+    pub remote_nonce: [u8; 24],
+}
+
+impl Encoder for Value {
+    fn encode(&self) -> Result<Vec<u8>, SchemaError> {
+        let mut v = Vec::with_capacity(112);
+        v.extend_from_slice(&self.local_pk);
+        v.extend_from_slice(&self.remote_pk);
+        v.extend_from_slice(&self.local_nonce);
+        v.extend_from_slice(&self.remote_nonce);
+        Ok(v)
+    }
+}
+
+impl Decoder for Value {
+    fn decode(bytes: &[u8]) -> Result<Self, SchemaError> {
+        if bytes.len() != 112 {
+            return Err(SchemaError::DecodeError);
+        }
+
+        Ok(Value {
+            local_pk: TryFrom::try_from(&bytes[..32]).unwrap(),
+            remote_pk: TryFrom::try_from(&bytes[32..64]).unwrap(),
+            local_nonce: TryFrom::try_from(&bytes[64..88]).unwrap(),
+            remote_nonce: TryFrom::try_from(&bytes[88..]).unwrap(),
+        })
+    }
+}
+
+pub struct Schema;
+
+impl KeyValueSchema for Schema {
+    type Key = connection::Key;
+    type Value = Value;
+}
+
+impl RocksDbKeyValueSchema for Schema {
+    fn descriptor(_cache: &Cache) -> ColumnFamilyDescriptor {
+        use rocksdb::Options;
+
+        ColumnFamilyDescriptor::new(Self::name(), Options::default())
+    }
+
+    fn name() -> &'static str {
+        "connection_crypto_storage"
+    }
+}
+
+/// The nonce after `n` increments, the nonce is a big endian number
+pub fn nonce_add(nonce: &[u8; 24], n: u64) -> [u8; 24] {
+    let mut nonce = *nonce;
+    let mut carry = n as u128;
+    for byte in nonce.iter_mut().rev() {
+        if carry == 0 {
+            break;
+        }
+        let sum = *byte as u128 + (carry & 0xff);
+        *byte = sum as u8;
+        carry = (carry >> 8) + (sum >> 8);
+    }
+    nonce
+}
+
+#[derive(Serialize)]
+pub struct Side {
+    public_key: String,
+    nonce_start: String,
+    // the nonce for the next chunk
+    nonce_current: String,
+    decrypted_chunks: u64,
+    // the counter of the chunk which failed to decrypt
+    cannot_decrypt: Option<u64>,
+}
+
+impl Side {
+    fn new(pk: &[u8; 32], nonce: &[u8; 24], decrypted: u64, cannot_decrypt: Option<u64>) -> Self {
+        Side {
+            public_key: hex::encode(pk),
+            nonce_start: hex::encode(nonce),
+            nonce_current: hex::encode(nonce_add(nonce, decrypted)),
+            decrypted_chunks: decrypted,
+            cannot_decrypt,
+        }
+    }
+}
+
+/// The crypto state of both directions of the connection
+#[derive(Serialize)]
+pub struct Diagnostics {
+    pub local: Side,
+    pub remote: Side,
+}
+
+impl Diagnostics {
+    /// The number of decrypted chunks, excluding the connection message, in each direction
+    pub fn new(value: &Value, comments: &connection::Comments, decrypted: (u64, u64)) -> Self {
+        let (local, remote) = decrypted;
+        Diagnostics {
+            local: Side::new(
+                &value.local_pk,
+                &value.local_nonce,
+                local,
+                comments.outgoing_cannot_decrypt,
+            ),
+            remote: Side::new(
+                &value.remote_pk,
+                &value.remote_nonce,
+                remote,
+                comments.incoming_cannot_decrypt,
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::nonce_add;
+
+    #[test]
+    fn nonce_carry() {
+        let mut nonce = [0; 24];
+        nonce[23] = 0xff;
+        nonce[22] = 0xff;
+        let next = nonce_add(&nonce, 1);
+        assert_eq!(next[21..], [1, 0, 0]);
+        assert_eq!(nonce_add(&nonce, 0), nonce);
+        let far = nonce_add(&[0xff; 24], 1);
+        assert_eq!(far, [0; 24]);
+    }
+}
@@ -0,0 +1,78 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::convert::TryFrom;
+use serde::Serialize;
+use rocksdb::{Cache, ColumnFamilyDescriptor};
+use storage::persistent::{
+    KeyValueSchema, Encoder, Decoder, SchemaError, database::RocksDbKeyValueSchema,
+};
+use super::connection;
+
+/// The country and the autonomous system of the remote address, resolved by `geoip`
+/// after the connection is stored, so the connection might lack it for a while
+/// * bytes layout: `[asn(4)][country]`, zero asn means unknown, the country is utf8, might be empty
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Value {
+    // ISO 3166-1 alpha-2 code, like `DE`
+    pub country: Option<String>,
+    pub asn: Option<u32>,
+}
+
+impl Value {
+    pub fn is_empty(&self) -> bool {
+        self.country.is_none() && self.asn.is_none()
+    }
+}
+
+impl Encoder for Value {
+    fn encode(&self) -> Result<Vec<u8>, SchemaError> {
+        let mut v = Vec::with_capacity(6);
+        v.extend_from_slice(&self.asn.unwrap_or(0).to_le_bytes());
+        if let Some(country) = &self.country {
+            v.extend_from_slice(country.as_bytes());
+        }
+        Ok(v)
+    }
+}
+
+impl Decoder for Value {
+    fn decode(bytes: &[u8]) -> Result<Self, SchemaError> {
+        if bytes.len() < 4 {
+            return Err(SchemaError::DecodeError);
+        }
+
+        let country = std::str::from_utf8(&bytes[4..])
+            .map_err(|e| SchemaError::DecodeValidationError(e.to_string()))?;
+        Ok(Value {
+            country: if country.is_empty() {
+                None
+            } else {
+                Some(country.to_string())
+            },
+            asn: match u32::from_le_bytes(TryFrom::try_from(&bytes[..4]).unwrap()) {
+                0 => None,
+                asn => Some(asn),
+            },
+        })
+    }
+}
+
+pub struct Schema;
+
+impl KeyValueSchema for Schema {
+    type Key = connection::Key;
+    type Value = Value;
+}
+
+impl RocksDbKeyValueSchema for Schema {
+    fn descriptor(_cache: &Cache) -> ColumnFamilyDescriptor {
+        use rocksdb::Options;
+
+        ColumnFamilyDescriptor::new(Self::name(), Options::default())
+    }
+
+    fn name() -> &'static str {
+        "connection_geo_storage"
+    }
+}
@@ -1,21 +1,32 @@
 // Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
 // SPDX-License-Identifier: MIT
 
-use std::{net::SocketAddr, ops::Range, convert::TryFrom};
+use std::{
+    net::SocketAddr,
+    ops::Range,
+    convert::TryFrom,
+    fmt,
+    num::ParseIntError,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
 use serde::{Deserialize, Serialize, ser};
 use storage::persistent::{KeyValueSchema, BincodeEncoded, database::RocksDbKeyValueSchema};
 use tezos_messages::p2p::{
     encoding::{
         connection::ConnectionMessage,
         metadata::MetadataMessage,
-        ack::AckMessage,
+        ack::{AckMessage, NackMotive},
         peer::{PeerMessage, PeerMessageResponse},
+        block_header::BlockHeader,
+        protocol::Protocol,
+        swap::SwapMessage,
     },
-    binary_message::BinaryRead,
+    binary_message::{BinaryRead, BinaryWrite},
 };
 use super::{
     common::{Initiator, Sender, MessageCategory, MessageKind, MessageType},
-    connection, chunk,
+    connection, chunk, chunk_event,
 };
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -28,9 +39,33 @@ pub struct Item {
     pub sender: Sender,
     pub ty: MessageType,
     chunks: Range<u64>,
+    // not stored in the table, only in timestamp index
+    #[serde(skip)]
+    pub size: u32,
+    #[serde(skip)]
+    pub hash: Option<[u8; 32]>,
+    // stored in its own table, only for the messages longer than the limit
+    #[serde(skip)]
+    pub oversized: Option<Oversized>,
+}
+
+/// The message is longer than the configured limit, it is not decoded,
+/// only its first bytes are kept for the frontend
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Oversized {
+    // the size announced by the message header, including the 4 bytes of the length
+    pub size: u32,
+    pub head: Vec<u8>,
 }
 
 impl Item {
+    pub fn connection(&self) -> connection::Key {
+        connection::Key {
+            ts: self.cn_ts,
+            ts_nanos: self.cn_ts_nanos,
+        }
+    }
+
     pub fn chunks(&self) -> impl Iterator<Item = chunk::Key> + '_ {
         let cn_id = connection::Key {
             ts: self.cn_ts,
@@ -43,11 +78,68 @@ impl Item {
             sender: sender.clone(),
         })
     }
+
+    /// FNV-1a hash of the connection id, the first chunk counter and the direction,
+    /// it is the same whenever the message is stored, unlike the index
+    /// which depends on the storage order
+    pub fn stable_id(&self) -> u64 {
+        let mut bytes = Vec::with_capacity(21);
+        bytes.extend_from_slice(&self.cn_ts.to_be_bytes());
+        bytes.extend_from_slice(&self.cn_ts_nanos.to_be_bytes());
+        bytes.extend_from_slice(&self.chunks.start.to_be_bytes());
+        bytes.push(self.sender.incoming() as u8);
+        bytes.into_iter().fold(0xcbf29ce484222325, |h, b| {
+            (h ^ (b as u64)).wrapping_mul(0x100000001b3)
+        })
+    }
+}
+
+/// Identifies the message either by the index in the storage,
+/// or by the stable id, see `Item::stable_id`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageId {
+    Index(u64),
+    Stable(u64),
+}
+
+impl MessageId {
+    /// Tells the stable id from the index, any number of digits might be either
+    pub const STABLE_PREFIX: &'static str = "s:";
+}
+
+impl From<u64> for MessageId {
+    fn from(v: u64) -> Self {
+        MessageId::Index(v)
+    }
+}
+
+impl FromStr for MessageId {
+    type Err = ParseIntError;
+
+    // the stable id is `s:` followed by 16 hex digits, the index is decimal
+    // example: 42, s:3f2a9c01d4e5b607
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix(Self::STABLE_PREFIX) {
+            Some(stable_id) => u64::from_str_radix(stable_id, 16).map(MessageId::Stable),
+            None => s.parse().map(MessageId::Index),
+        }
+    }
+}
+
+impl fmt::Display for MessageId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageId::Index(index) => write!(f, "{}", index),
+            MessageId::Stable(id) => write!(f, "{}{:016x}", Self::STABLE_PREFIX, id),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MessageFrontend {
     pub id: u64,
+    // survives rebuilding the database, see `Item::stable_id`
+    pub stable_id: String,
     timestamp: u128,
     remote_addr: SocketAddr,
     pub source_type: Initiator,
@@ -55,13 +147,170 @@ pub struct MessageFrontend {
     pub category: MessageCategory,
     pub kind: Option<MessageKind>,
     message_preview: Option<String>,
+    pub decoded_size: Option<u32>,
+    pub encoding_version: Option<u16>,
+    // the version of the connection is not supported, the message is not decoded
+    #[serde(default)]
+    pub unsupported_version: bool,
+    pub partial: bool,
+    // blake2b of the decrypted bytes, if the recorder is configured to compute it
+    pub hash: Option<String>,
+    // hashes requested by `get_block_headers`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub block_hashes: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub block_header: Option<BlockHeaderFrontend>,
+    // the answer to the handshake
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ack: Option<AckFrontend>,
+    // hashes requested by `get_protocols`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protocol_hashes: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protocol: Option<ProtocolFrontend>,
+    // the point and the peer of `swap_request` and `swap_ack`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub swap: Option<SwapFrontend>,
+    // the chain the peer stops following, carried by `deactivate`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chain_id: Option<String>,
+    // the size of the message which exceeds the limit
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub oversized: Option<u32>,
+}
+
+/// The fields of the header carried by `block_header`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockHeaderFrontend {
+    pub level: i32,
+    pub proto: u8,
+    pub predecessor: String,
+    pub timestamp: i64,
+    pub validation_pass: u8,
+    pub operations_hash: String,
+    // hex of each element
+    pub fitness: Vec<String>,
+    #[serde(alias = "context")]
+    pub context_hash: String,
+}
+
+impl BlockHeaderFrontend {
+    fn new(header: &BlockHeader) -> Self {
+        BlockHeaderFrontend {
+            level: header.level(),
+            proto: header.proto(),
+            predecessor: header.predecessor().to_base58_check(),
+            timestamp: header.timestamp(),
+            validation_pass: header.validation_pass(),
+            operations_hash: header.operations_hash().to_base58_check(),
+            fitness: header.fitness().iter().map(hex::encode).collect(),
+            context_hash: header.context().to_base58_check(),
+        }
+    }
+}
+
+/// The answer to the handshake, the peer which rejects the connection tells why
+/// and may suggest other peers
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum AckFrontend {
+    Ack,
+    // the nack of the old protocol, without the motive
+    NackV0,
+    Nack {
+        motive: String,
+        potential_peers: Vec<String>,
+    },
+}
+
+impl AckFrontend {
+    fn new(message: &AckMessage) -> Self {
+        match message {
+            AckMessage::Ack => AckFrontend::Ack,
+            AckMessage::NackV0 => AckFrontend::NackV0,
+            AckMessage::Nack(info) => {
+                let motive = match info.motive() {
+                    NackMotive::NoMotive => "no_motive",
+                    NackMotive::TooManyConnections => "too_many_connections",
+                    NackMotive::UnknownChainName => "unknown_chain_name",
+                    NackMotive::DeprecatedP2pVersion => "deprecated_p2p_version",
+                    NackMotive::DeprecatedDistributedDbVersion => {
+                        "deprecated_distributed_db_version"
+                    },
+                    NackMotive::AlreadyConnected => "already_connected",
+                };
+                AckFrontend::Nack {
+                    motive: motive.to_string(),
+                    potential_peers: info.potential_peers_to_connect().clone(),
+                }
+            },
+        }
+    }
+}
+
+/// The protocol carried by `protocol`, the source code of the components is omitted
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProtocolFrontend {
+    pub expected_env_version: i16,
+    pub components: Vec<ComponentFrontend>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ComponentFrontend {
+    pub name: String,
+    // bytes of the source code
+    pub interface_size: Option<usize>,
+    pub implementation_size: usize,
+}
+
+impl ProtocolFrontend {
+    fn new(protocol: &Protocol) -> Self {
+        ProtocolFrontend {
+            expected_env_version: *protocol.expected_env_version(),
+            components: protocol
+                .components()
+                .iter()
+                .map(|component| ComponentFrontend {
+                    name: component.name().clone(),
+                    interface_size: component.interface().as_ref().map(String::len),
+                    implementation_size: component.implementation().len(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// The point and the peer suggested by `swap_request`, or accepted by `swap_ack`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SwapFrontend {
+    pub point: String,
+    // the point is `address:port`, the address in brackets if it is ipv6,
+    // the malformed point is kept as it is
+    pub point_valid: bool,
+    pub peer_id: String,
+}
+
+impl SwapFrontend {
+    fn new(message: &SwapMessage) -> Self {
+        SwapFrontend {
+            point: message.point().clone(),
+            point_valid: message.point().parse::<SocketAddr>().is_ok(),
+            peer_id: message.peer_id().to_base58_check(),
+        }
+    }
 }
 
 impl MessageFrontend {
-    pub fn new(item: Item, id: u64, message_preview: Option<String>) -> Self {
+    pub fn new(
+        item: Item,
+        id: u64,
+        message_preview: Option<String>,
+        details: Option<&MessageDetails>,
+    ) -> Self {
         let (category, kind) = item.ty.split();
         MessageFrontend {
             id,
+            stable_id: MessageId::Stable(item.stable_id()).to_string(),
             timestamp: (item.timestamp as u128) * 1_000_000,
             remote_addr: item.remote_addr,
             source_type: item.initiator,
@@ -69,6 +318,48 @@ impl MessageFrontend {
             category,
             kind,
             message_preview,
+            decoded_size: details.and_then(|d| d.decoded_size),
+            encoding_version: details.and_then(|d| d.encoding_version),
+            unsupported_version: details.map(|d| d.unsupported_version).unwrap_or(false),
+            partial: details.map(|d| d.partial).unwrap_or(true),
+            hash: item.hash.map(hex::encode),
+            block_hashes: details.and_then(MessageDetails::block_hashes),
+            block_header: details.and_then(MessageDetails::block_header),
+            ack: details.and_then(MessageDetails::ack),
+            protocol_hashes: details.and_then(MessageDetails::protocol_hashes),
+            protocol: details.and_then(MessageDetails::protocol),
+            swap: details.and_then(MessageDetails::swap),
+            chain_id: details.and_then(MessageDetails::chain_id),
+            oversized: details.and_then(|d| d.oversized),
+        }
+    }
+}
+
+/// The encoding of the peer messages selected by the distributed db version of the connection,
+/// the handshake messages do not depend on it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerEncoding {
+    /// The versions 0 and 1 encode the peer messages the same way
+    V0,
+}
+
+impl PeerEncoding {
+    /// The encoding of the connection whose version is not known
+    pub const LATEST: Self = PeerEncoding::V0;
+
+    /// `None` if the version is not supported, decoding its messages would be a guess
+    pub fn for_version(version: u16) -> Option<Self> {
+        match version {
+            0 | 1 => Some(PeerEncoding::V0),
+            _ => None,
+        }
+    }
+
+    fn decode(self, bytes: &[u8]) -> Result<PeerMessage, String> {
+        match self {
+            PeerEncoding::V0 => PeerMessageResponse::from_bytes(bytes)
+                .map_err(|e| e.to_string())
+                .map(|n| n.message().clone()),
         }
     }
 }
@@ -90,15 +381,75 @@ impl TezosMessage {
             TezosMessage::PeerMessage(m) => serde_json::to_string(m),
         }
     }
+
+    /// The canonical binary encoding of the message,
+    /// the peer message is prefixed by its length as on the wire
+    pub fn encode(&self) -> Result<Vec<u8>, String> {
+        match self {
+            TezosMessage::ConnectionMessage(m) => m.as_bytes(),
+            TezosMessage::MetadataMessage(m) => m.as_bytes(),
+            TezosMessage::AckMessage(m) => m.as_bytes(),
+            TezosMessage::PeerMessage(m) => PeerMessageResponse::from(m.clone()).as_bytes(),
+        }
+        .map_err(|e| e.to_string())
+    }
 }
 
 #[derive(Debug)]
 pub struct MessageDetails {
     id: u64,
+    stable_id: Option<u64>,
     pub message: Option<TezosMessage>,
     original_bytes: Vec<Vec<u8>>,
     pub decrypted_bytes: Vec<Vec<u8>>,
     error: Option<String>,
+    // number of bytes successfully decoded
+    decoded_size: Option<u32>,
+    // distributed db version negotiated by the connection
+    encoding_version: Option<u16>,
+    // the peer message of the version the recorder cannot decode, only the bytes are kept
+    unsupported_version: bool,
+    // some chunks are missing, or the message is shorter than its header says
+    partial: bool,
+    // the size of the message which exceeds the limit, the message is not decoded
+    oversized: Option<u32>,
+}
+
+/// The chunks the message is built from, as they were captured,
+/// and the bpf events which delivered them, if known
+#[derive(Serialize)]
+pub struct MessageRaw {
+    id: u64,
+    chunks: Vec<ChunkRaw>,
+}
+
+#[derive(Serialize)]
+pub struct ChunkRaw {
+    key: chunk::Key,
+    counter: u64,
+    #[serde(flatten)]
+    value: chunk::Value,
+    event: Option<chunk_event::Value>,
+}
+
+impl MessageRaw {
+    pub fn new<I>(id: u64, chunks: I) -> Self
+    where
+        I: IntoIterator<Item = (chunk::Key, chunk::Value, Option<chunk_event::Value>)>,
+    {
+        MessageRaw {
+            id,
+            chunks: chunks
+                .into_iter()
+                .map(|(key, value, event)| ChunkRaw {
+                    counter: key.counter,
+                    key,
+                    value,
+                    event,
+                })
+                .collect(),
+        }
+    }
 }
 
 impl Serialize for MessageDetails {
@@ -129,8 +480,10 @@ impl Serialize for MessageDetails {
             }
         }
 
-        let mut s = serializer.serialize_struct("MessageDetails", 5)?;
+        let mut s = serializer.serialize_struct("MessageDetails", 11)?;
         s.serialize_field("id", &self.id)?;
+        let stable_id = self.stable_id.map(|id| MessageId::Stable(id).to_string());
+        s.serialize_field("stable_id", &stable_id)?;
         match &self.message {
             Some(TezosMessage::ConnectionMessage(m)) => s.serialize_field("message", m)?,
             Some(TezosMessage::MetadataMessage(m)) => s.serialize_field("message", m)?,
@@ -141,46 +494,248 @@ impl Serialize for MessageDetails {
         s.serialize_field("original_bytes", &HexString(&self.original_bytes))?;
         s.serialize_field("decrypted_bytes", &HexString(&self.decrypted_bytes))?;
         s.serialize_field("error", &self.error)?;
+        s.serialize_field("decoded_size", &self.decoded_size)?;
+        s.serialize_field("encoding_version", &self.encoding_version)?;
+        s.serialize_field("unsupported_version", &self.unsupported_version)?;
+        s.serialize_field("partial", &self.partial)?;
+        s.serialize_field("oversized", &self.oversized)?;
         s.end()
     }
 }
 
 impl MessageDetails {
-    pub fn new(id: u64, ty: &MessageType, chunks: &[chunk::Value]) -> Self {
+    /// `complete` is false if some chunks of the message are missing,
+    /// the peer message is decoded by the encoding of the `encoding_version`
+    pub fn new(
+        id: u64,
+        ty: &MessageType,
+        chunks: &[chunk::Value],
+        complete: bool,
+        encoding_version: Option<u16>,
+    ) -> Self {
         let mut bytes = Vec::with_capacity(chunks.iter().map(|c| c.plain.len()).sum());
         for c in chunks {
             bytes.extend_from_slice(&c.plain);
         }
-        let message = match ty {
-            MessageType::Connection => ConnectionMessage::from_bytes(&bytes)
-                .map_err(|e| e.to_string())
-                .map(TezosMessage::ConnectionMessage),
-            MessageType::Meta => MetadataMessage::from_bytes(&bytes)
-                .map_err(|e| e.to_string())
-                .map(TezosMessage::MetadataMessage),
-            MessageType::Ack => AckMessage::from_bytes(&bytes)
-                .map_err(|e| e.to_string())
-                .map(TezosMessage::AckMessage),
-            MessageType::P2p(_) => PeerMessageResponse::from_bytes(&bytes)
-                .map_err(|e| e.to_string())
-                .map(|n| TezosMessage::PeerMessage(n.message().clone())),
+        let encoding = match (ty, encoding_version) {
+            (MessageType::P2p(_), Some(v)) => PeerEncoding::for_version(v).ok_or(v),
+            _ => Ok(PeerEncoding::LATEST),
         };
-        let (message, error) = match message {
-            Ok(m) => (Some(m), None),
-            Err(e) => (None, Some(e)),
+        let (message, error) = match encoding.map(|encoding| Self::decode(ty, &bytes, encoding)) {
+            Ok(Ok(m)) => (Some(m), None),
+            Ok(Err(e)) => (None, Some(e)),
+            Err(version) => {
+                let e = format!("unsupported distributed db version {}, not decoded", version);
+                (None, Some(e))
+            },
+        };
+        // the peer message starts with 4 bytes length of the rest
+        let declared = match ty {
+            MessageType::P2p(_) => <[u8; 4]>::try_from(bytes.get(..4).unwrap_or_default())
+                .map(|l| u32::from_be_bytes(l) as usize + 4)
+                .unwrap_or(usize::MAX),
+            _ => 0,
         };
         MessageDetails {
             id,
-            message,
+            stable_id: None,
             original_bytes: chunks.iter().map(|c| c.bytes.clone()).collect(),
             decrypted_bytes: chunks.iter().map(|c| c.plain.clone()).collect(),
             error,
+            decoded_size: message.as_ref().map(|_| bytes.len() as u32),
+            encoding_version,
+            unsupported_version: encoding.is_err(),
+            partial: !complete || bytes.len() < declared,
+            oversized: None,
+            message,
+        }
+    }
+
+    /// The placeholder of the message longer than the limit, the chunks are not loaded
+    pub fn oversized(id: u64, oversized: Oversized, encoding_version: Option<u16>) -> Self {
+        let error = format!(
+            "the message of {} bytes exceeds the limit, only the first {} bytes are stored",
+            oversized.size,
+            oversized.head.len(),
+        );
+        MessageDetails {
+            id,
+            stable_id: None,
+            message: None,
+            original_bytes: Vec::new(),
+            decrypted_bytes: vec![oversized.head],
+            error: Some(error),
+            decoded_size: None,
+            encoding_version,
+            unsupported_version: false,
+            partial: false,
+            oversized: Some(oversized.size),
+        }
+    }
+
+    pub fn is_decoded(&self) -> bool {
+        self.message.is_some()
+    }
+
+    /// The message is not decoded on purpose, only its bytes are kept,
+    /// it exceeds the limit, or its version is not supported
+    pub fn is_raw(&self) -> bool {
+        self.oversized.is_some() || self.unsupported_version
+    }
+
+    pub fn with_stable_id(self, stable_id: u64) -> Self {
+        MessageDetails {
+            stable_id: Some(stable_id),
+            ..self
+        }
+    }
+
+    /// Decode the decrypted bytes of the message the same way as the stored one,
+    /// nothing is stored, see `/v3/decode`. The type is recognized if not given.
+    pub fn decode_plain(ty: Option<MessageType>, bytes: Vec<u8>) -> (MessageType, Self) {
+        let ty = ty.unwrap_or_else(|| Self::detect(&bytes));
+        let counter = match &ty {
+            MessageType::Connection => 0,
+            MessageType::Meta => 1,
+            MessageType::Ack => 2,
+            MessageType::P2p(_) => 3,
+        };
+        let key = connection::Key::default();
+        let (_, chunk) = chunk::Item::new(
+            key,
+            Sender::Remote,
+            counter,
+            vec![],
+            bytes,
+            SystemTime::now(),
+        ).split();
+        let details = MessageDetails::new(0, &ty, &[chunk], true, None);
+        (ty, details)
+    }
+
+    /// The peer message is recognized by the tag if the length matches,
+    /// otherwise it is the first of the handshake messages which decodes
+    fn detect(bytes: &[u8]) -> MessageType {
+        if let Some(header) = bytes.get(..6).and_then(|h| <[u8; 6]>::try_from(h).ok()) {
+            let builder = MessageBuilder::peer_message(header, 3);
+            let valid = matches!(&builder.ty, MessageType::P2p(kind) if kind.valid_tag());
+            if valid && builder.remaining() == bytes.len() {
+                return builder.ty;
+            }
+        }
+        [MessageType::Connection, MessageType::Meta, MessageType::Ack]
+            .iter()
+            .find(|ty| Self::decode(ty, bytes, PeerEncoding::LATEST).is_ok())
+            .cloned()
+            .unwrap_or(MessageType::P2p(MessageKind::Unknown))
+    }
+
+    fn decode(
+        ty: &MessageType,
+        bytes: &[u8],
+        encoding: PeerEncoding,
+    ) -> Result<TezosMessage, String> {
+        match ty {
+            MessageType::Connection => ConnectionMessage::from_bytes(bytes)
+                .map_err(|e| e.to_string())
+                .map(TezosMessage::ConnectionMessage),
+            MessageType::Meta => MetadataMessage::from_bytes(bytes)
+                .map_err(|e| e.to_string())
+                .map(TezosMessage::MetadataMessage),
+            MessageType::Ack => AckMessage::from_bytes(bytes)
+                .map_err(|e| e.to_string())
+                .map(TezosMessage::AckMessage),
+            MessageType::P2p(_) => encoding.decode(bytes).map(TezosMessage::PeerMessage),
         }
     }
 
     pub fn json_string(&self) -> Result<Option<String>, serde_json::Error> {
         self.message.as_ref().map(|m| m.json_string()).transpose()
     }
+
+    /// Encode the decoded message again, `None` if the message is not decoded.
+    /// The flag is `true` if the encoding is the same as the decrypted bytes.
+    pub fn encoded(&self) -> Option<Result<(Vec<u8>, bool), String>> {
+        let message = self.message.as_ref()?;
+        Some(message.encode().map(|bytes| {
+            let round_trip = bytes.iter().eq(self.decrypted_bytes.iter().flatten());
+            (bytes, round_trip)
+        }))
+    }
+
+    pub fn block_hashes(&self) -> Option<Vec<String>> {
+        match &self.message {
+            Some(TezosMessage::PeerMessage(PeerMessage::GetBlockHeaders(m))) => Some(
+                m.get_block_headers()
+                    .iter()
+                    .map(|hash| hash.to_base58_check())
+                    .collect(),
+            ),
+            _ => None,
+        }
+    }
+
+    /// The header carried by `block_header` or `current_head`
+    pub fn block_header(&self) -> Option<BlockHeaderFrontend> {
+        match &self.message {
+            Some(TezosMessage::PeerMessage(PeerMessage::BlockHeader(m))) => {
+                Some(BlockHeaderFrontend::new(m.block_header()))
+            },
+            Some(TezosMessage::PeerMessage(PeerMessage::CurrentHead(m))) => {
+                Some(BlockHeaderFrontend::new(m.current_block_header()))
+            },
+            _ => None,
+        }
+    }
+
+    /// The ack, the nack with its motive and the suggested peers, or the old nack
+    pub fn ack(&self) -> Option<AckFrontend> {
+        match &self.message {
+            Some(TezosMessage::AckMessage(m)) => Some(AckFrontend::new(m)),
+            _ => None,
+        }
+    }
+
+    pub fn protocol_hashes(&self) -> Option<Vec<String>> {
+        match &self.message {
+            Some(TezosMessage::PeerMessage(PeerMessage::GetProtocols(m))) => Some(
+                m.get_protocols()
+                    .iter()
+                    .map(|hash| hash.to_base58_check())
+                    .collect(),
+            ),
+            _ => None,
+        }
+    }
+
+    pub fn protocol(&self) -> Option<ProtocolFrontend> {
+        match &self.message {
+            Some(TezosMessage::PeerMessage(PeerMessage::Protocol(m))) => {
+                Some(ProtocolFrontend::new(m.protocol()))
+            },
+            _ => None,
+        }
+    }
+
+    pub fn swap(&self) -> Option<SwapFrontend> {
+        match &self.message {
+            Some(TezosMessage::PeerMessage(PeerMessage::SwapRequest(m))) => {
+                Some(SwapFrontend::new(m))
+            },
+            Some(TezosMessage::PeerMessage(PeerMessage::SwapAck(m))) => Some(SwapFrontend::new(m)),
+            _ => None,
+        }
+    }
+
+    /// The base58 chain id of `deactivate`
+    pub fn chain_id(&self) -> Option<String> {
+        match &self.message {
+            Some(TezosMessage::PeerMessage(PeerMessage::Deactivate(m))) => {
+                Some(m.deactivate().to_base58_check())
+            },
+            _ => None,
+        }
+    }
 }
 
 pub struct MessageBuilder {
@@ -249,13 +804,8 @@ impl MessageBuilder {
 }
 
 impl MessageBuilderFull {
-    pub fn build(self, sender: &Sender, connection: &connection::Item) -> Item {
-        use std::time::{SystemTime, UNIX_EPOCH};
-
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_millis() as u64;
+    pub fn build(self, sender: &Sender, connection: &connection::Item, now: SystemTime) -> Item {
+        let timestamp = now.duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
 
         Item {
             cn_ts: connection.ts,
@@ -266,6 +816,9 @@ impl MessageBuilderFull {
             sender: sender.clone(),
             ty: self.0.ty,
             chunks: self.0.chunks,
+            size: 0,
+            hash: None,
+            oversized: None,
         }
     }
 }
@@ -284,3 +837,495 @@ impl RocksDbKeyValueSchema for Schema {
         "message_storage"
     }
 }
+
+impl BincodeEncoded for Oversized {}
+
+pub struct OversizedSchema;
+
+impl KeyValueSchema for OversizedSchema {
+    type Key = u64;
+    type Value = Oversized;
+}
+
+impl RocksDbKeyValueSchema for OversizedSchema {
+    fn name() -> &'static str {
+        "message_oversized_storage"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::TryFrom, time::SystemTime};
+    use tezos_messages::p2p::encoding::peer::PeerMessage;
+    use super::{
+        MessageBuilder, MessageDetails, MessageKind, MessageType, TezosMessage, AckFrontend,
+        PeerEncoding,
+    };
+    use crate::{
+        common::Sender,
+        tables::{chunk, connection},
+    };
+
+    // decrypted bytes of the message in the same format as the extractor prints,
+    // 4 bytes length, 2 bytes tag and the body; assembled by hand, the fields asserted below,
+    // `cargo run -p extractor -- <recorder url>` prints the messages recorded from a node
+    const BOOTSTRAP: &str = "000000020002";
+    const GET_CURRENT_BRANCH: &str = "0000000600107a06a770";
+    const CURRENT_BRANCH: &str = "\
+        000000cf00117a06a7700000008500000001011111111111111111111111111111111111111111111111111111\
+        111111111111000000005c8c4e5004222222222222222222222222222222222222222222222222222222222222\
+        22220000001100000001000000000800000000000000013333333333333333333333333333333333333333333\
+        333333333333333333333abcd44444444444444444444444444444444444444444444444444444444444444445\
+        555555555555555555555555555555555555555555555555555555555555555";
+    const OPERATION: &str = "\
+        0000002400311111111111111111111111111111111111111111111111111111111111111111abcd";
+    const GET_OPERATIONS_FOR_BLOCKS: &str = "\
+        0000004800600000004222222222222222222222222222222222222222222222222222222222222222220033\
+        3333333333333333333333333333333333333333333333333333333333333303";
+    const OPERATIONS_FOR_BLOCKS: &str = "\
+        0000004e00610000002144444444444444444444444444444444444444444444444444444444444444440100\
+        000000225555555555555555555555555555555555555555555555555555555555555555abcd";
+    const GET_OPERATION_HASHES_FOR_BLOCKS: &str = "\
+        0000004800500000004222222222222222222222222222222222222222222222222222222222222222220033\
+        3333333333333333333333333333333333333333333333333333333333333303";
+    const GET_BLOCK_HEADERS: &str = "\
+        00000046002000000040111111111111111111111111111111111111111111111111111111111111111122\
+        22222222222222222222222222222222222222222222222222222222222222";
+    const BLOCK_HEADER: &str = "\
+        0000008b00210000008500000001011111111111111111111111111111111111111111111111111111111111\
+        111111000000005c8c4e50042222222222222222222222222222222222222222222222222222222222222222\
+        0000001100000001000000000800000000000000013333333333333333333333333333333333333333333333\
+        333333333333333333abcd";
+    const GET_PROTOCOLS: &str = "\
+        00000046004000000040111111111111111111111111111111111111111111111111111111111111111122\
+        22222222222222222222222222222222222222222222222222222222222222";
+    // env version 1, components `Main` with interface `sig` and `Util` without interface
+    const PROTOCOL: &str = "\
+        00000032004100010000002a000000044d61696eff00000003736967000000096c65742078203d2031000000\
+        045574696c0000000000";
+    // point `51.15.220.7:9732`
+    const SWAP_REQUEST: &str = "\
+        0000002600040000001035312e31352e3232302e373a3937333277777777\
+        777777777777777777777777";
+    // point `[::1]:9732`
+    const SWAP_ACK: &str = "\
+        0000002000050000000a5b3a3a315d3a3937333288888888888888888888888888888888";
+    // the chain id of the mainnet, `NetXdQprcVkpaWU`
+    const DEACTIVATE: &str = "0000000600127a06a770";
+    const OPERATION_HASHES_FOR_BLOCK: &str = "\
+        0000006800510000002166666666666666666666666666666666666666666666666666666666666666660200\
+        7777777777777777777777777777777777777777777777777777777777777777888888888888888888888888\
+        8888888888888888888888888888888888888888";
+
+    fn decode(hex_str: &str, kind: MessageKind, name: &str) -> PeerMessage {
+        let bytes = hex::decode(hex_str).unwrap();
+
+        // the type is recognized by the tag and can be used in the filter
+        let header = <[u8; 6]>::try_from(&bytes[..6]).unwrap();
+        let ty = MessageBuilder::peer_message(header, 3).ty;
+        assert_eq!(ty.clone().split().1, Some(kind.clone()));
+        match name.parse::<MessageType>().unwrap() {
+            MessageType::P2p(k) => assert_eq!(k, kind),
+            _ => panic!(),
+        }
+
+        match MessageDetails::decode(&ty, &bytes, PeerEncoding::LATEST).unwrap() {
+            TezosMessage::PeerMessage(message) => message,
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn bootstrap() {
+        let message = decode(BOOTSTRAP, MessageKind::Bootstrap, "bootstrap");
+        assert!(matches!(message, PeerMessage::Bootstrap));
+    }
+
+    #[test]
+    fn get_current_branch() {
+        let message = decode(
+            GET_CURRENT_BRANCH,
+            MessageKind::GetCurrentBranch,
+            "get_current_branch",
+        );
+        assert!(matches!(message, PeerMessage::GetCurrentBranch(_)));
+    }
+
+    #[test]
+    fn current_branch() {
+        let message = decode(CURRENT_BRANCH, MessageKind::CurrentBranch, "current_branch");
+        match message {
+            PeerMessage::CurrentBranch(m) => {
+                let branch = m.current_branch();
+                assert_eq!(branch.current_head().level(), 1);
+                assert_eq!(branch.history().len(), 2);
+            },
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn operation() {
+        let message = decode(OPERATION, MessageKind::Operation, "operation");
+        match &message {
+            PeerMessage::Operation(m) => {
+                assert_eq!(m.operation().data(), &vec![0xab, 0xcd]);
+            },
+            _ => panic!(),
+        }
+        let json = serde_json::to_string(&message).unwrap();
+        assert!(json.contains("branch"));
+    }
+
+    #[test]
+    fn get_operations_for_blocks() {
+        let message = decode(
+            GET_OPERATIONS_FOR_BLOCKS,
+            MessageKind::GetOperationsForBlocks,
+            "get_operations_for_blocks",
+        );
+        match &message {
+            PeerMessage::GetOperationsForBlocks(m) => {
+                assert_eq!(m.get_operations_for_blocks().len(), 2);
+            },
+            _ => panic!(),
+        }
+        let json = serde_json::to_string(&message).unwrap();
+        assert!(json.contains("validation_pass"));
+    }
+
+    #[test]
+    fn operations_for_blocks() {
+        let message = decode(
+            OPERATIONS_FOR_BLOCKS,
+            MessageKind::OperationsForBlocks,
+            "operations_for_blocks",
+        );
+        match message {
+            PeerMessage::OperationsForBlocks(m) => {
+                assert_eq!(m.operations().len(), 1);
+                assert_eq!(m.operations()[0].data(), &vec![0xab, 0xcd]);
+            },
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn get_operation_hashes_for_blocks() {
+        let message = decode(
+            GET_OPERATION_HASHES_FOR_BLOCKS,
+            MessageKind::GetOperationHashesForBlocks,
+            "get_operation_hashes_for_blocks",
+        );
+        match message {
+            PeerMessage::GetOperationHashesForBlocks(m) => {
+                assert_eq!(m.get_operation_hashes_for_blocks().len(), 2);
+            },
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn operation_hashes_for_block() {
+        let message = decode(
+            OPERATION_HASHES_FOR_BLOCK,
+            MessageKind::OperationHashesForBlocks,
+            "operation_hashes_for_block",
+        );
+        match &message {
+            PeerMessage::OperationHashesForBlock(m) => {
+                assert_eq!(m.operation_hashes().len(), 2);
+            },
+            _ => panic!(),
+        }
+        let json = serde_json::to_string(&message).unwrap();
+        assert!(json.contains("validation_pass"));
+    }
+
+    fn peer_details(hex_str: &str, kind: MessageKind) -> MessageDetails {
+        let bytes = hex::decode(hex_str).unwrap();
+        let item = chunk::Item::new(
+            connection::Key::default(),
+            Sender::Remote,
+            3,
+            vec![],
+            bytes,
+            SystemTime::now(),
+        );
+        MessageDetails::new(0, &MessageType::P2p(kind), &[item.split().1], true, None)
+    }
+
+    #[test]
+    fn get_block_headers() {
+        let message = decode(
+            GET_BLOCK_HEADERS,
+            MessageKind::GetBlockHeaders,
+            "get_block_headers",
+        );
+        assert!(matches!(message, PeerMessage::GetBlockHeaders(_)));
+
+        let details = peer_details(GET_BLOCK_HEADERS, MessageKind::GetBlockHeaders);
+        let hashes = details.block_hashes().unwrap();
+        assert_eq!(hashes.len(), 2);
+        assert!(hashes.iter().all(|h| h.starts_with('B')));
+        assert_ne!(hashes[0], hashes[1]);
+        assert!(details.block_header().is_none());
+
+        // the list is longer than the protocol allows, the message is not decoded
+        let hashes = "44".repeat(32 * 11);
+        let too_long = format!("000001660020{:08x}{}", 32 * 11, hashes);
+        let details = peer_details(&too_long, MessageKind::GetBlockHeaders);
+        assert!(details.message.is_none());
+        assert!(details.block_hashes().is_none());
+        assert!(details.error.is_some());
+    }
+
+    #[test]
+    fn block_header() {
+        let message = decode(BLOCK_HEADER, MessageKind::BlockHeader, "block_header");
+        assert!(matches!(message, PeerMessage::BlockHeader(_)));
+
+        let details = peer_details(BLOCK_HEADER, MessageKind::BlockHeader);
+        let header = details.block_header().unwrap();
+        assert_eq!(header.level, 1);
+        assert_eq!(header.proto, 1);
+        assert_eq!(header.timestamp, 0x5c8c4e50);
+        assert_eq!(header.validation_pass, 4);
+        assert_eq!(header.fitness, ["00", "0000000000000001"]);
+        assert_eq!(
+            header.predecessor,
+            "BKqoHEY3C15u8zdGwi9Hhj3ArCz2Q8sRQuHVtcWZqUPopsfNZfh",
+        );
+        assert_eq!(
+            header.operations_hash,
+            "LLoZajuLxQ3Zs9tVYbqbVqcFPuD2KGTQvKrV1XfVAxEec8YryeK6e",
+        );
+        assert_eq!(
+            header.context_hash,
+            "CoV2rh4GntvnVYGFBa3zLM9uiwo1PxJ6HeXoegcJZcsZVrUUqBXo",
+        );
+        let json = serde_json::to_value(&header).unwrap();
+        assert_eq!(json["fitness"][1], "0000000000000001");
+        assert_eq!(json["context_hash"], header.context_hash);
+        assert!(details.block_hashes().is_none());
+    }
+
+    #[test]
+    fn get_protocols() {
+        let message = decode(GET_PROTOCOLS, MessageKind::GetProtocols, "get_protocols");
+        assert!(matches!(message, PeerMessage::GetProtocols(_)));
+
+        let details = peer_details(GET_PROTOCOLS, MessageKind::GetProtocols);
+        let hashes = details.protocol_hashes().unwrap();
+        assert_eq!(hashes.len(), 2);
+        assert!(hashes.iter().all(|h| h.starts_with('P')));
+        assert_ne!(hashes[0], hashes[1]);
+        assert!(details.protocol().is_none());
+        assert!(details.block_hashes().is_none());
+    }
+
+    #[test]
+    fn protocol() {
+        let message = decode(PROTOCOL, MessageKind::Protocol, "protocol");
+        assert!(matches!(message, PeerMessage::Protocol(_)));
+
+        let details = peer_details(PROTOCOL, MessageKind::Protocol);
+        let protocol = details.protocol().unwrap();
+        assert_eq!(protocol.expected_env_version, 1);
+        let components = protocol
+            .components
+            .iter()
+            .map(|c| (c.name.as_str(), c.interface_size, c.implementation_size))
+            .collect::<Vec<_>>();
+        assert_eq!(components, [("Main", Some(3), 9), ("Util", None, 0)]);
+        assert!(details.protocol_hashes().is_none());
+    }
+
+    #[test]
+    fn swap_request() {
+        let message = decode(SWAP_REQUEST, MessageKind::SwapRequest, "swap_request");
+        assert!(matches!(message, PeerMessage::SwapRequest(_)));
+
+        let details = peer_details(SWAP_REQUEST, MessageKind::SwapRequest);
+        let swap = details.swap().unwrap();
+        assert_eq!(swap.point, "51.15.220.7:9732");
+        assert!(swap.point_valid);
+        assert_eq!(swap.peer_id, "ids6CDCEi134Awp4t4tcVg7KFgU5BB");
+        assert!(details.block_header().is_none());
+
+        // the point is not an address, it is decoded anyway
+        let point = hex::encode("seed.example:9732");
+        let malformed = format!("000000270004{:08x}{}{}", 17, point, "77".repeat(16));
+        let swap = peer_details(&malformed, MessageKind::SwapRequest).swap().unwrap();
+        assert_eq!(swap.point, "seed.example:9732");
+        assert!(!swap.point_valid);
+    }
+
+    #[test]
+    fn swap_ack() {
+        let message = decode(SWAP_ACK, MessageKind::SwapAck, "swap_ack");
+        assert!(matches!(message, PeerMessage::SwapAck(_)));
+
+        let details = peer_details(SWAP_ACK, MessageKind::SwapAck);
+        let swap = details.swap().unwrap();
+        assert_eq!(swap.point, "[::1]:9732");
+        assert!(swap.point_valid);
+        assert_eq!(swap.peer_id, "idsKz4TXMqEgFRD6cXPdrU9M8bsC8a");
+        let json = serde_json::to_value(&swap).unwrap();
+        assert_eq!(json["peer_id"], swap.peer_id);
+        assert!(peer_details(BOOTSTRAP, MessageKind::Bootstrap).swap().is_none());
+    }
+
+    #[test]
+    fn deactivate() {
+        let message = decode(DEACTIVATE, MessageKind::Deactivate, "deactivate");
+        assert!(matches!(message, PeerMessage::Deactivate(_)));
+        let ty = MessageType::P2p(MessageKind::Deactivate);
+        assert_eq!(MessageType::from_int(ty.clone().into_int()), ty);
+
+        let details = peer_details(DEACTIVATE, MessageKind::Deactivate);
+        assert_eq!(details.chain_id().as_deref(), Some("NetXdQprcVkpaWU"));
+        assert!(details.swap().is_none());
+        assert!(peer_details(BOOTSTRAP, MessageKind::Bootstrap).chain_id().is_none());
+    }
+
+    #[test]
+    fn decode_info() {
+        let chunk = |hex_str: &str| {
+            let bytes = hex::decode(hex_str).unwrap();
+            let item = chunk::Item::new(
+                connection::Key::default(),
+                Sender::Remote,
+                3,
+                vec![],
+                bytes,
+                SystemTime::now(),
+            );
+            item.split().1
+        };
+        let ty = MessageType::P2p(MessageKind::CurrentBranch);
+
+        let details = MessageDetails::new(0, &ty, &[chunk(CURRENT_BRANCH)], true, Some(1));
+        assert_eq!(details.decoded_size, Some((CURRENT_BRANCH.len() / 2) as u32));
+        assert_eq!(details.encoding_version, Some(1));
+        assert!(!details.partial);
+
+        // the second chunk is missing
+        let details = MessageDetails::new(0, &ty, &[chunk(&CURRENT_BRANCH[..100])], false, None);
+        assert_eq!(details.decoded_size, None);
+        assert_eq!(details.encoding_version, None);
+        assert!(details.partial);
+    }
+
+    #[test]
+    fn decode_by_version() {
+        let bytes = hex::decode(CURRENT_BRANCH).unwrap();
+        let item = chunk::Item::new(
+            connection::Key::default(),
+            Sender::Remote,
+            3,
+            vec![],
+            bytes,
+            SystemTime::now(),
+        );
+        let chunks = [item.split().1];
+        let ty = MessageType::P2p(MessageKind::CurrentBranch);
+
+        // the same message negotiated by the connections of both versions
+        let v0 = MessageDetails::new(0, &ty, &chunks, true, Some(0));
+        let v1 = MessageDetails::new(0, &ty, &chunks, true, Some(1));
+        assert_eq!(v0.json_string().unwrap(), v1.json_string().unwrap());
+        assert!(matches!(
+            v0.message,
+            Some(TezosMessage::PeerMessage(PeerMessage::CurrentBranch(_))),
+        ));
+        assert_eq!(v0.encoding_version, Some(0));
+        assert_eq!(v1.encoding_version, Some(1));
+        assert!(!v0.unsupported_version && !v1.unsupported_version);
+
+        // the unknown version, the bytes are kept as they are
+        let details = MessageDetails::new(0, &ty, &chunks, true, Some(7));
+        assert!(details.message.is_none());
+        assert!(details.unsupported_version);
+        assert_eq!(details.decrypted_bytes, [hex::decode(CURRENT_BRANCH).unwrap()]);
+        let json = serde_json::to_value(&details).unwrap();
+        assert_eq!(json["unsupported_version"], true);
+        assert!(json["error"].as_str().unwrap().contains("unsupported"));
+
+        // the handshake messages do not depend on the version
+        let ack = chunk::Item::new(
+            connection::Key::default(),
+            Sender::Remote,
+            2,
+            vec![],
+            vec![0],
+            SystemTime::now(),
+        );
+        let details = MessageDetails::new(0, &MessageType::Ack, &[ack.split().1], true, Some(7));
+        assert!(details.message.is_some());
+        assert!(!details.unsupported_version);
+    }
+
+    #[test]
+    fn encode_round_trip() {
+        let peer = [
+            BOOTSTRAP,
+            GET_CURRENT_BRANCH,
+            CURRENT_BRANCH,
+            OPERATION,
+            GET_OPERATIONS_FOR_BLOCKS,
+            GET_BLOCK_HEADERS,
+            BLOCK_HEADER,
+        ];
+        let peer = peer.iter().map(|hex_str| (None, *hex_str));
+        let handshake = [(MessageType::Meta, "0000"), (MessageType::Ack, "00")];
+        let handshake = handshake.iter().map(|(ty, hex_str)| (Some(ty.clone()), *hex_str));
+        for (ty, hex_str) in peer.chain(handshake) {
+            let bytes = hex::decode(hex_str).unwrap();
+            let (_, details) = MessageDetails::decode_plain(ty, bytes.clone());
+            let (encoded, round_trip) = details.encoded().unwrap().unwrap();
+            assert_eq!(hex::encode(encoded), hex_str);
+            assert!(round_trip, "{}", hex_str);
+        }
+
+        // the message is not decoded, nothing to encode
+        let (_, details) = MessageDetails::decode_plain(Some(MessageType::Ack), vec![]);
+        assert!(details.encoded().is_none());
+    }
+
+    #[test]
+    fn ack_variants() {
+        // the tag, for the nack the motive `too_many_connections` as u16
+        // and the list of the points to connect instead
+        const NACK: &str = "\
+            0100010000001035312e31352e3232302e373a393733320000001035312e31352e3232302e383a393733\
+            32";
+        let cases = [
+            ("00", AckFrontend::Ack),
+            ("ff", AckFrontend::NackV0),
+            (
+                NACK,
+                AckFrontend::Nack {
+                    motive: "too_many_connections".to_string(),
+                    potential_peers: vec![
+                        "51.15.220.7:9732".to_string(),
+                        "51.15.220.8:9732".to_string(),
+                    ],
+                },
+            ),
+        ];
+        for (hex_str, expected) in cases.iter() {
+            let bytes = hex::decode(hex_str).unwrap();
+            let (ty, details) = MessageDetails::decode_plain(Some(MessageType::Ack), bytes);
+            assert_eq!(ty, MessageType::Ack);
+            assert_eq!(details.ack().as_ref(), Some(expected), "{}", hex_str);
+            let (encoded, round_trip) = details.encoded().unwrap().unwrap();
+            assert_eq!(hex::encode(encoded), *hex_str);
+            assert!(round_trip);
+        }
+
+        // shown in the brief of the message
+        let json = serde_json::to_value(&AckFrontend::NackV0).unwrap();
+        assert_eq!(json["kind"], "nack_v0");
+    }
+}
@@ -4,7 +4,11 @@
 use super::common;
 
 pub mod connection;
+pub mod connection_crypto;
+pub mod connection_geo;
 pub mod chunk;
+pub mod chunk_event;
+pub mod chunk_gap;
 pub mod message;
 pub mod node_log;
 
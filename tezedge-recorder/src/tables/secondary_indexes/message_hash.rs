@@ -0,0 +1,67 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::convert::TryFrom;
+use storage::persistent::{
+    KeyValueSchema, Encoder, Decoder, SchemaError, database::RocksDbKeyValueSchema,
+};
+use rocksdb::{ColumnFamilyDescriptor, Cache};
+
+/// Blake2b hash of the decrypted bytes of the message
+pub fn hash(bytes: &[u8]) -> Option<[u8; 32]> {
+    let digest = crypto::blake2b::digest_256(bytes).ok()?;
+    <[u8; 32]>::try_from(digest.as_slice()).ok()
+}
+
+/// * bytes layout: `[hash(32)][index(8)]`
+pub struct Item {
+    pub hash: [u8; 32],
+    pub index: u64,
+}
+
+impl Encoder for Item {
+    fn encode(&self) -> Result<Vec<u8>, SchemaError> {
+        let mut v = Vec::with_capacity(40);
+
+        v.extend_from_slice(&self.hash);
+        v.extend_from_slice(&self.index.to_be_bytes());
+
+        Ok(v)
+    }
+}
+
+impl Decoder for Item {
+    fn decode(bytes: &[u8]) -> Result<Self, SchemaError> {
+        if bytes.len() != 40 {
+            return Err(SchemaError::DecodeError);
+        }
+
+        Ok(Item {
+            hash: <[u8; 32]>::try_from(&bytes[..32]).unwrap(),
+            index: u64::from_be_bytes(<[u8; 8]>::try_from(&bytes[32..]).unwrap()),
+        })
+    }
+}
+
+pub struct Schema;
+
+impl KeyValueSchema for Schema {
+    type Key = Item;
+    type Value = ();
+}
+
+impl RocksDbKeyValueSchema for Schema {
+    fn descriptor(_cache: &Cache) -> ColumnFamilyDescriptor {
+        use rocksdb::{Options, SliceTransform};
+
+        let mut cf_opts = Options::default();
+        cf_opts.set_prefix_extractor(SliceTransform::create_fixed_prefix(32));
+        cf_opts.set_memtable_prefix_bloom_ratio(0.2);
+        ColumnFamilyDescriptor::new(Self::name(), cf_opts)
+    }
+
+    fn name() -> &'static str {
+        "message_hash_secondary_index"
+    }
+}
+
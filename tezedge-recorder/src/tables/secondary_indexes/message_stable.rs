@@ -0,0 +1,18 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use storage::persistent::{KeyValueSchema, database::RocksDbKeyValueSchema};
+
+/// The stable id of the message, see `message::Item::stable_id`, maps to the index
+pub struct Schema;
+
+impl KeyValueSchema for Schema {
+    type Key = u64;
+    type Value = u64;
+}
+
+impl RocksDbKeyValueSchema for Schema {
+    fn name() -> &'static str {
+        "message_stable_secondary_index"
+    }
+}
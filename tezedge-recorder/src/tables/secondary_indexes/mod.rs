@@ -4,6 +4,7 @@
 use super::{
     common::{MessageType, Sender, Initiator},
     node_log::LogLevel,
+    connection,
 };
 
 pub mod message_ty;
@@ -12,3 +13,6 @@ pub mod message_initiator;
 pub mod message_addr;
 pub mod timestamp;
 pub mod log_level;
+pub mod session;
+pub mod message_hash;
+pub mod message_stable;
@@ -0,0 +1,88 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::convert::TryFrom;
+use storage::persistent::{
+    KeyValueSchema, Encoder, Decoder, SchemaError, database::RocksDbKeyValueSchema,
+};
+use rocksdb::{ColumnFamilyDescriptor, Cache};
+
+/// Stable 64 bit hash of the session label (FNV-1a), the label itself is not stored in the index
+pub fn hash(label: &str) -> u64 {
+    label.bytes().fold(0xcbf29ce484222325, |h, b| {
+        (h ^ (b as u64)).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// * bytes layout: `[session(8)][index(8)]`
+pub struct Item {
+    pub session: u64,
+    pub index: u64,
+}
+
+impl Encoder for Item {
+    fn encode(&self) -> Result<Vec<u8>, SchemaError> {
+        let mut v = Vec::with_capacity(16);
+
+        v.extend_from_slice(&self.session.to_be_bytes());
+        v.extend_from_slice(&self.index.to_be_bytes());
+
+        Ok(v)
+    }
+}
+
+impl Decoder for Item {
+    fn decode(bytes: &[u8]) -> Result<Self, SchemaError> {
+        if bytes.len() != 16 {
+            return Err(SchemaError::DecodeError);
+        }
+
+        Ok(Item {
+            session: u64::from_be_bytes(<[u8; 8]>::try_from(&bytes[..8]).unwrap()),
+            index: u64::from_be_bytes(<[u8; 8]>::try_from(&bytes[8..]).unwrap()),
+        })
+    }
+}
+
+fn descriptor(name: &'static str) -> ColumnFamilyDescriptor {
+    use rocksdb::{Options, SliceTransform};
+
+    let mut cf_opts = Options::default();
+    cf_opts.set_prefix_extractor(SliceTransform::create_fixed_prefix(8));
+    cf_opts.set_memtable_prefix_bloom_ratio(0.2);
+    ColumnFamilyDescriptor::new(name, cf_opts)
+}
+
+pub struct MessageSchema;
+
+impl KeyValueSchema for MessageSchema {
+    type Key = Item;
+    type Value = ();
+}
+
+impl RocksDbKeyValueSchema for MessageSchema {
+    fn descriptor(_cache: &Cache) -> ColumnFamilyDescriptor {
+        descriptor(Self::name())
+    }
+
+    fn name() -> &'static str {
+        "message_session_secondary_index"
+    }
+}
+
+pub struct LogSchema;
+
+impl KeyValueSchema for LogSchema {
+    type Key = Item;
+    type Value = ();
+}
+
+impl RocksDbKeyValueSchema for LogSchema {
+    fn descriptor(_cache: &Cache) -> ColumnFamilyDescriptor {
+        descriptor(Self::name())
+    }
+
+    fn name() -> &'static str {
+        "log_session_secondary_index"
+    }
+}
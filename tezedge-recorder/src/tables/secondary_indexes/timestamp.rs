@@ -5,6 +5,7 @@ use std::convert::TryFrom;
 use storage::persistent::{
     KeyValueSchema, Encoder, Decoder, SchemaError, database::RocksDbKeyValueSchema,
 };
+use super::connection;
 
 /// * bytes layout: `[timestamp(8)][index(8)]`
 pub struct Item {
@@ -36,11 +37,83 @@ impl Decoder for Item {
     }
 }
 
+/// Size of the message in bytes, allows to compute throughput using only the index,
+/// the hash of the session label, allows to remove the session index entry,
+/// and the hash of the message content, if configured
+/// * bytes layout: `[size(4)][session(8)][hash(32)]`, the session and the hash are optional,
+/// or empty if the record is written by older version
+#[derive(Default)]
+pub struct MessageValue {
+    pub size: u32,
+    pub session: Option<u64>,
+    pub hash: Option<[u8; 32]>,
+}
+
+impl Encoder for MessageValue {
+    fn encode(&self) -> Result<Vec<u8>, SchemaError> {
+        let mut v = Vec::with_capacity(44);
+        v.extend_from_slice(&self.size.to_be_bytes());
+        if let Some(session) = self.session {
+            v.extend_from_slice(&session.to_be_bytes());
+        }
+        if let Some(hash) = &self.hash {
+            v.extend_from_slice(hash);
+        }
+        Ok(v)
+    }
+}
+
+impl Decoder for MessageValue {
+    fn decode(bytes: &[u8]) -> Result<Self, SchemaError> {
+        let (session, hash) = match bytes.len() {
+            0 => return Ok(MessageValue::default()),
+            4 => (None, None),
+            12 => (Some(&bytes[4..12]), None),
+            36 => (None, Some(&bytes[4..])),
+            44 => (Some(&bytes[4..12]), Some(&bytes[12..])),
+            _ => return Err(SchemaError::DecodeError),
+        };
+        Ok(MessageValue {
+            size: u32::from_be_bytes(<[u8; 4]>::try_from(&bytes[..4]).unwrap()),
+            session: session.map(|s| u64::from_be_bytes(<[u8; 8]>::try_from(s).unwrap())),
+            hash: hash.map(|h| <[u8; 32]>::try_from(h).unwrap()),
+        })
+    }
+}
+
+/// The hash of the session label
+/// * bytes layout: `[session(8)]`, or empty if there is no session
+#[derive(Default)]
+pub struct LogValue {
+    pub session: Option<u64>,
+}
+
+impl Encoder for LogValue {
+    fn encode(&self) -> Result<Vec<u8>, SchemaError> {
+        Ok(self
+            .session
+            .map(|s| s.to_be_bytes().to_vec())
+            .unwrap_or_default())
+    }
+}
+
+impl Decoder for LogValue {
+    fn decode(bytes: &[u8]) -> Result<Self, SchemaError> {
+        match bytes.len() {
+            0 => Ok(LogValue::default()),
+            8 => Ok(LogValue {
+                session: Some(u64::from_be_bytes(<[u8; 8]>::try_from(bytes).unwrap())),
+            }),
+            _ => Err(SchemaError::DecodeError),
+        }
+    }
+}
+
 pub struct MessageSchema;
 
 impl KeyValueSchema for MessageSchema {
     type Key = Item;
-    type Value = ();
+    type Value = MessageValue;
 }
 
 impl RocksDbKeyValueSchema for MessageSchema {
@@ -53,7 +126,7 @@ pub struct LogSchema;
 
 impl KeyValueSchema for LogSchema {
     type Key = Item;
-    type Value = ();
+    type Value = LogValue;
 }
 
 impl RocksDbKeyValueSchema for LogSchema {
@@ -61,3 +134,48 @@ impl RocksDbKeyValueSchema for LogSchema {
         "log_timestamp_secondary_index"
     }
 }
+
+/// The connection closed at the timestamp
+/// * bytes layout: `[timestamp(8)][cn_id(12)]`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseItem {
+    pub timestamp: u64,
+    pub cn_id: connection::Key,
+}
+
+impl Encoder for CloseItem {
+    fn encode(&self) -> Result<Vec<u8>, SchemaError> {
+        let mut v = Vec::with_capacity(20);
+
+        v.extend_from_slice(&self.timestamp.to_be_bytes());
+        v.extend_from_slice(&self.cn_id.encode()?);
+
+        Ok(v)
+    }
+}
+
+impl Decoder for CloseItem {
+    fn decode(bytes: &[u8]) -> Result<Self, SchemaError> {
+        if bytes.len() != 20 {
+            return Err(SchemaError::DecodeError);
+        }
+
+        Ok(CloseItem {
+            timestamp: u64::from_be_bytes(<[u8; 8]>::try_from(&bytes[..8]).unwrap()),
+            cn_id: connection::Key::decode(&bytes[8..])?,
+        })
+    }
+}
+
+pub struct ConnectionCloseSchema;
+
+impl KeyValueSchema for ConnectionCloseSchema {
+    type Key = CloseItem;
+    type Value = ();
+}
+
+impl RocksDbKeyValueSchema for ConnectionCloseSchema {
+    fn name() -> &'static str {
+        "connection_close_timestamp_secondary_index"
+    }
+}
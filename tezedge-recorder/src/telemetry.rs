@@ -0,0 +1,86 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use tokio::runtime::Runtime;
+
+/// The target of the spans around the stages of the capture pipeline. The spans are at debug level,
+/// so they are disabled and cost nothing unless exported to OpenTelemetry.
+pub const TARGET: &str = "tezedge_recorder::pipeline";
+
+/// Keeps the exporter running, flushes the remaining spans on drop
+pub struct Telemetry {
+    // the batch exporter runs in its own runtime
+    runtime: Option<Runtime>,
+}
+
+impl Telemetry {
+    /// Install the global subscriber, the log is printed at info level,
+    /// the spans are exported to the OTLP collector at `otlp_endpoint`, if any,
+    /// it requires the `otlp` feature
+    pub fn init(otlp_endpoint: Option<String>) -> anyhow::Result<Self> {
+        match otlp_endpoint {
+            #[cfg(feature = "otlp")]
+            Some(endpoint) => Self::init_otlp(&endpoint),
+            #[cfg(not(feature = "otlp"))]
+            Some(_) => {
+                Self::init_log();
+                log::warn!("built without the `otlp` feature, the spans are not exported");
+                Ok(Telemetry { runtime: None })
+            },
+            None => {
+                Self::init_log();
+                Ok(Telemetry { runtime: None })
+            },
+        }
+    }
+
+    fn init_log() {
+        tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::INFO)
+            .init();
+    }
+
+    #[cfg(feature = "otlp")]
+    fn init_otlp(endpoint: &str) -> anyhow::Result<Self> {
+        use opentelemetry::{sdk::{trace, Resource}, KeyValue};
+        use opentelemetry_otlp::WithExportConfig;
+        use tracing_subscriber::{EnvFilter, layer::SubscriberExt, util::SubscriberInitExt};
+
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()?;
+        let tracer = {
+            let _guard = runtime.enter();
+            let exporter = opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint);
+            let resource = Resource::new(vec![KeyValue::new("service.name", "tezedge-recorder")]);
+            opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(exporter)
+                .with_trace_config(trace::config().with_resource(resource))
+                .install_batch(opentelemetry::runtime::Tokio)?
+        };
+        tracing_subscriber::registry()
+            .with(EnvFilter::new(format!("info,{}=debug", TARGET)))
+            .with(tracing_subscriber::fmt::layer())
+            .with(tracing_opentelemetry::layer().with_tracer(tracer))
+            .init();
+        log::info!("exporting the spans to: {}", endpoint);
+
+        Ok(Telemetry {
+            runtime: Some(runtime),
+        })
+    }
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if let Some(runtime) = self.runtime.take() {
+            #[cfg(feature = "otlp")]
+            opentelemetry::global::shutdown_tracer_provider();
+            drop(runtime);
+        }
+    }
+}
@@ -38,7 +38,11 @@ run_recorder
 # populate p2p messages
 ./target/none/release/pseudonode p2p-responder 29733 29732 & RESPONDER_PID=$! && sleep 1
 ./target/none/release/pseudonode p2p-initiator 29732 29733 && wait $RESPONDER_PID && sleep 5
-./target/none/release/deps/p2p-???????????????? --nocapture check_messages || fail
+./target/none/release/deps/p2p-???????????????? --nocapture check_messages count || fail
+# nobody listens on 29790, the connect is refused
+./target/none/release/pseudonode p2p-refused 29732 29790 && sleep 1
+./target/none/release/deps/p2p-???????????????? --nocapture connect_refused || fail
 ./target/none/release/pseudonode log 2 && sleep 4 # populate words log messages
 ./target/none/release/deps/log-???????????????? --nocapture full_text_search || fail
+./target/none/release/deps/log-???????????????? --nocapture session || fail
 stop_recorder
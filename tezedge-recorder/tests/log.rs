@@ -183,3 +183,55 @@ async fn full_text_search() {
         case.run().await;
     }
 }
+
+#[tokio::test]
+async fn session() {
+    use std::{net::UdpSocket, time::Duration};
+
+    let debugger = env::var("DEBUGGER_V3_URL").unwrap_or("http://localhost:17742".to_string());
+    let client = reqwest::Client::new();
+    let set_session = |label: Option<&str>| {
+        let url = match label {
+            Some(label) => format!("{}/v3/session?label={}", debugger, label),
+            None => format!("{}/v3/session", debugger),
+        };
+        client.post(&url).send()
+    };
+    let get_session_log = |label: &str| {
+        let url = format!("{}/v3/logs?limit=1000&session={}", debugger, label);
+        async move {
+            let res = reqwest::get(&url).await.unwrap().text().await.unwrap();
+            serde_json::from_str::<Vec<node_log::ItemWithId>>(&res).unwrap()
+        }
+    };
+    let send_log = |label: &str, count: usize| {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let local = chrono::Local::now().to_rfc3339();
+        for i in 0..count {
+            let msg = format!(
+                "<27>1 {} wsvl eb3fdbc716e5 665 eb3fdbc716e5 - Jul 14 12:00:00.000 INFO session {} {}",
+                local, label, i,
+            );
+            socket.send_to(msg.as_bytes(), "127.0.0.1:10000").unwrap();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    };
+
+    set_session(Some("first")).await.unwrap();
+    send_log("first", 10);
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    set_session(Some("second")).await.unwrap();
+    send_log("second", 20);
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    set_session(None).await.unwrap();
+    send_log("none", 5);
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    let first = get_session_log("first").await;
+    assert_eq!(first.len(), 10);
+    assert!(first.iter().all(|item| item.message.contains("session first")));
+    let second = get_session_log("second").await;
+    assert_eq!(second.len(), 20);
+    assert!(second.iter().all(|item| item.message.contains("session second")));
+    assert!(get_session_log("none").await.is_empty());
+}
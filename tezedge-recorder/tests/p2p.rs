@@ -44,6 +44,54 @@ async fn check_messages() {
     }
 }
 
+#[tokio::test]
+async fn count() {
+    let debugger = env::var("DEBUGGER_V3_URL").unwrap_or("http://localhost:17742".to_string());
+
+    let filters = [
+        "",
+        "direction=forward",
+        "incoming=true",
+        "types=connection_message,ack_message",
+        "incoming=false&types=operation",
+        "source_type=remote",
+        "cursor=3&direction=forward",
+    ];
+    for filter in &filters {
+        let url = format!("{}/v3/messages?limit=1000000&{}", debugger, filter);
+        let res = reqwest::get(&url).await.unwrap().text().await.unwrap();
+        let messages = serde_json::from_str::<Vec<serde_json::Value>>(&res).unwrap();
+
+        let url = format!("{}/v3/messages/count?{}", debugger, filter);
+        let res = reqwest::get(&url).await.unwrap().text().await.unwrap();
+        let count = serde_json::from_str::<serde_json::Value>(&res).unwrap()["count"]
+            .as_u64()
+            .unwrap();
+
+        assert_eq!(count, messages.len() as u64, "filter: {:?}", filter);
+        println!("filter {:?} count {}", filter, count);
+    }
+}
+
+#[tokio::test]
+async fn connect_refused() {
+    let debugger = env::var("DEBUGGER_V3_URL").unwrap_or("http://localhost:17742".to_string());
+
+    let url = format!("{}/v3/connections?status=failed", debugger);
+    let res = reqwest::get(&url).await.unwrap().text().await.unwrap();
+    let connections = serde_json::from_str::<Vec<serde_json::Value>>(&res).unwrap();
+    // the pairs of the id and the connection
+    let connection = connections
+        .iter()
+        .map(|v| &v[1])
+        .find(|cn| cn["remote_addr"] == "127.0.0.1:29790")
+        .expect("the failed connect is not recorded");
+    assert_eq!(connection["initiator"], "local");
+    assert_eq!(connection["close_reason"], "connect_failed");
+    // ECONNREFUSED
+    assert_eq!(connection["connect_error"], 111);
+}
+
 #[tokio::test]
 async fn wait() {
     let mut t = 0u8;